@@ -0,0 +1,324 @@
+//! Arp - arpeggiates whatever keys are held
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin arp -- --rate-ms 125 --pattern up-down --octaves 2
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "arp-out".
+//! Held notes are played one at a time, each lasting one step.
+//! Non-note messages pass straight through.
+//!
+//! Flags:
+//! - `--rate-ms <ms>`: step length (default 125)
+//! - `--pattern up|down|up-down|random` (default up)
+//! - `--octaves <n>`: how many octaves the held notes span (default 1)
+//! - `--swing <0.0-0.75>`: lengthens every other step by that fraction of
+//!   a step and shortens the next to match (0 straight, about 0.33 triplet)
+//! - `--probability <0.0-1.0>`: the chance each step plays (default 1);
+//!   a skipped step is a rest, and the pattern still moves on past it
+//! - `--seed <n>`: seeds the random choices, so a run can be repeated
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::rng::{parse_probability, Rng};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::timing::{parse_swing, swung_offset};
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, release_velocity};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pattern {
+  Up,
+  Down,
+  UpDown,
+  Random,
+}
+
+impl FromStr for Pattern {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "up" => Ok(Pattern::Up),
+      "down" => Ok(Pattern::Down),
+      "up-down" => Ok(Pattern::UpDown),
+      "random" => Ok(Pattern::Random),
+      _ => Err("expected up, down, up-down or random".to_string()),
+    }
+  }
+}
+
+/// How the timer thread steps through the held notes.
+struct Steps {
+  rate: Duration,
+  swing: f64,
+  pattern: Pattern,
+  octaves: u8,
+  probability: f64, // that a step plays
+}
+
+struct ArpState {
+  held: BTreeSet<u8>,
+  channel: u8, // of the latest note-on, for the notes to come
+  velocity: u8,
+  sounding: Option<(u8, u8)>, // (channel, note) of the arp note on, if any
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let rate: Duration = Duration::from_millis(args.parse_or("--rate-ms", 125)?);
+  if rate.is_zero() {
+    return Err("--rate-ms must be at least 1".into());
+  }
+  let pattern: Pattern = args.parse_or("--pattern", Pattern::Up)?;
+  let octaves: u8 = args.parse_or("--octaves", 1)?;
+  let swing: f64 = parse_swing(&args)?;
+  if octaves == 0 {
+    return Err("--octaves must be at least 1".into());
+  }
+  let probability: f64 = parse_probability(&args)?;
+  let rng: Rng = Rng::from_args(&args)?;
+  let steps: Steps = Steps { rate, swing, pattern, octaves, probability };
+
+  let midi_in: MidiInput = MidiInput::new("arp-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("arp-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "arp-out")?;
+  self_test(&args, &mut conn_out, "arp-out");
+
+  let state: Arc<Mutex<ArpState>> = Arc::new(Mutex::new(ArpState {
+    held: BTreeSet::new(),
+    channel: 0,
+    velocity: 100,
+    sounding: None,
+  }));
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<ArpState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_timer_thread(state_for_timer, tx_for_timer, running_for_timer, steps, rng)
+  });
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      if !is_note_event(message) {
+        let _ = tx_for_callback.send(message.to_vec());
+        return;
+      }
+      let mut state = lock(&state);
+      for msg in handle_note(&mut state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  println!("Arpeggiator started!");
+  println!("  rate: {:?}, swing: {}, pattern: {:?}, octaves: {}, probability: {}",
+           rate, swing, pattern, octaves, probability);
+  println!("Ports: 'arp-in:midi-in' (input), 'arp-out:arp-out' (output)");
+  auto_connect(&args, Some("arp-in:midi-in"), Some("arp-out:arp-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+/// Updates the held set. Returns a note-off for the sounding arp note
+/// if that was the last key released, so silence is immediate.
+fn handle_note(state: &mut ArpState, message: &[u8]) -> Vec<Vec<u8>> {
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![],
+    };
+  if is_note_on(message) {
+    state.held.insert(note);
+    state.channel = channel;
+    state.velocity = message[2];
+  } else if is_note_off(message) {
+    state.held.remove(&note);
+    if state.held.is_empty() {
+      if let Some((channel, note)) = state.sounding.take() {
+        return vec![note_off(channel, note, release_velocity(message))];
+      }
+    }
+  }
+  vec![]
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<ArpState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+  steps: Steps,
+  mut rng: Rng,
+) {
+  let mut step: usize = 0; // position in the pattern
+  let start: Instant = Instant::now();
+  let mut tick: u64 = 0; // steps since start, for timing
+  while running.load(Ordering::SeqCst) {
+    for msg in arp_step(&mut lock(&state), &mut step, &mut rng, &steps) {
+      let _ = tx.send(msg);
+    }
+    tick += 1;
+    let due: Instant = start + swung_offset(tick, steps.rate, steps.swing);
+    thread::sleep(due.saturating_duration_since(Instant::now()));
+  }
+}
+
+/// Ends the note the last step played and plays the next one, unless
+/// this step loses its draw, in which case it only moves on.
+fn arp_step(state: &mut ArpState, step: &mut usize, rng: &mut Rng, steps: &Steps)
+            -> Vec<Vec<u8>> {
+  let mut messages: Vec<Vec<u8>> = Vec::new();
+  if let Some((channel, note)) = state.sounding.take() {
+    messages.push(note_off(channel, note, 0));
+  }
+  let notes: Vec<u8> = arp_sequence(&state.held, steps.octaves, steps.pattern);
+  if notes.is_empty() {
+    *step = 0;
+    return messages;
+  }
+  let index: usize = if steps.pattern == Pattern::Random {
+    rng.below(notes.len())
+  } else {
+    *step % notes.len()
+  };
+  *step += 1;
+  if rng.chance(steps.probability) {
+    let note: u8 = notes[index];
+    messages.push(note_on(state.channel, note, state.velocity));
+    state.sounding = Some((state.channel, note));
+  }
+  messages
+}
+
+/// The notes one cycle of the pattern steps through.
+/// (For `Random` that's just the pool to draw from.)
+fn arp_sequence(held: &BTreeSet<u8>, octaves: u8, pattern: Pattern) -> Vec<u8> {
+  let ascending: Vec<u8> = (0..octaves as u16)
+    .flat_map(|octave| held.iter().map(move |&n| n as u16 + 12 * octave))
+    .filter(|&n| n <= 127)
+    .map(|n| n as u8)
+    .collect();
+  match pattern {
+    Pattern::Up | Pattern::Random => ascending,
+    Pattern::Down => ascending.into_iter().rev().collect(),
+    Pattern::UpDown => {
+      // Don't repeat the top and bottom notes at the turnarounds.
+      let mut notes: Vec<u8> = ascending.clone();
+      if ascending.len() > 2 {
+        notes.extend(ascending[1..ascending.len() - 1].iter().rev());
+      }
+      notes
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sequences() {
+    let held: BTreeSet<u8> = [64, 60, 67].into_iter().collect();
+    assert_eq!(arp_sequence(&held, 1, Pattern::Up), vec![60, 64, 67]);
+    assert_eq!(arp_sequence(&held, 1, Pattern::Down), vec![67, 64, 60]);
+    assert_eq!(arp_sequence(&held, 1, Pattern::UpDown), vec![60, 64, 67, 64]);
+    assert_eq!(arp_sequence(&held, 2, Pattern::Up),
+               vec![60, 64, 67, 72, 76, 79]);
+  }
+
+  #[test]
+  fn octaves_above_127_are_dropped() {
+    let held: BTreeSet<u8> = [120].into_iter().collect();
+    assert_eq!(arp_sequence(&held, 3, Pattern::Up), vec![120]);
+  }
+
+  #[test]
+  fn releasing_last_key_silences_arp_note() {
+    let mut state: ArpState = ArpState {
+      held: BTreeSet::new(), channel: 0, velocity: 100, sounding: None };
+    handle_note(&mut state, &[0x92, 60, 90]);
+    handle_note(&mut state, &[0x92, 64, 90]);
+    state.sounding = Some((2, 64));
+    assert!(handle_note(&mut state, &[0x82, 64, 0]).is_empty());
+    assert_eq!(handle_note(&mut state, &[0x82, 60, 0]),
+               vec![vec![0x82, 64, 0]]);
+    assert_eq!(state.sounding, None);
+  }
+
+  #[test]
+  fn a_note_off_goes_to_the_channel_its_note_was_played_on() {
+    let steps: Steps = Steps { rate: Duration::from_millis(125), swing: 0.0,
+                               pattern: Pattern::Up, octaves: 1, probability: 1.0 };
+    let mut state: ArpState = ArpState {
+      held: BTreeSet::new(), channel: 0, velocity: 100, sounding: None };
+    let mut rng: Rng = Rng::seeded(1);
+    let mut step: usize = 0;
+    handle_note(&mut state, &[0x92, 60, 90]);
+    assert_eq!(arp_step(&mut state, &mut step, &mut rng, &steps), vec![vec![0x92, 60, 90]]);
+    handle_note(&mut state, &[0x95, 64, 80]);
+    assert_eq!(arp_step(&mut state, &mut step, &mut rng, &steps),
+               vec![vec![0x82, 60, 0], vec![0x95, 64, 80]]);
+    handle_note(&mut state, &[0x92, 60, 0]);
+    assert_eq!(handle_note(&mut state, &[0x85, 64, 0]), vec![vec![0x85, 64, 0]]);
+  }
+
+  #[test]
+  fn skipped_steps_rest_but_keep_the_pattern_moving() {
+    let steps: Steps = Steps { rate: Duration::from_millis(125), swing: 0.0,
+                               pattern: Pattern::Up, octaves: 1, probability: 0.5 };
+    let mut state: ArpState = ArpState {
+      held: [60, 64, 67].into_iter().collect(), channel: 0, velocity: 100, sounding: None };
+    let mut rng: Rng = Rng::seeded(1);
+    let mut step: usize = 0;
+    let mut played: Vec<Option<u8>> = Vec::new();
+    for _ in 0..12 {
+      let messages: Vec<Vec<u8>> = arp_step(&mut state, &mut step, &mut rng, &steps);
+      assert!(messages.len() <= 2);
+      played.push(state.sounding.map(|(_, note)| note));
+    }
+    assert_eq!(step, 12);
+    for (i, note) in played.iter().enumerate() {
+      assert!(note.is_none() || *note == Some([60, 64, 67][i % 3])); // no shifting
+    }
+    assert!(played.iter().any(|n| n.is_none()) && played.iter().any(|n| n.is_some()));
+  }
+}
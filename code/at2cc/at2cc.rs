@@ -0,0 +1,168 @@
+//! At2cc - turns aftertouch into a control change
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin at2cc -- --cc 74 --source channel
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "at2cc-out".
+//! Pressure messages from the chosen source become the CC given by `--cc`
+//! on the same channel, with the pressure as its value.
+//! With `--source poly`, every key's pressure moves the one CC;
+//! add `--last-note` to follow only the most recently pressed key on each
+//! channel, so other held keys can't fight over the value.
+//! Pressure of the other kind, notes and everything else pass straight through.
+//!
+//! Flags:
+//! - `--cc <0-127>`: the controller to send (default 74)
+//! - `--source channel|poly` (default channel)
+//! - `--last-note`: with `--source poly`, ignore all but the newest key
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{control_change, get_channel, get_note, is_note_off, is_note_on,
+                 CHANNEL_PRESSURE, POLY_PRESSURE};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+  Channel,
+  Poly,
+}
+
+impl FromStr for Source {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "channel" => Ok(Source::Channel),
+      "poly" => Ok(Source::Poly),
+      _ => Err("expected channel or poly".to_string()),
+    }
+  }
+}
+
+struct At2CcState {
+  cc: u8,
+  source: Source,
+  last_note_only: bool,
+  last_note: [Option<u8>; 16], // per channel, the newest key still held
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let cc: u8 = args.parse_or("--cc", 74)?;
+  if cc > 127 {
+    return Err("--cc must be in 0-127".into());
+  }
+  let source: Source = args.parse_or("--source", Source::Channel)?;
+  let last_note_only: bool = args.flag("--last-note");
+
+  let midi_in: MidiInput = MidiInput::new("at2cc-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("at2cc-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "at2cc-out")?;
+  self_test(&args, &mut conn_out, "at2cc-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<At2CcState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut At2CcState| {
+      if let Some(msg) = transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    At2CcState { cc, source, last_note_only, last_note: [None; 16] },
+  )?;
+
+  println!("At2cc started! {:?} pressure -> CC {}", source, cc);
+  println!("Ports: 'at2cc-in:midi-in' (input), 'at2cc-out:at2cc-out' (output)");
+  auto_connect(&args, Some("at2cc-in:midi-in"), Some("at2cc-out:at2cc-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn transform_message(state: &mut At2CcState, message: &[u8]) -> Option<Vec<u8>> {
+  let channel: u8 = match get_channel(message) {
+    Some(c) => c,
+    None => return Some(message.to_vec()),
+  };
+  if let Some(note) = get_note(message) {
+    let last: &mut Option<u8> = &mut state.last_note[channel as usize];
+    if is_note_on(message) {
+      *last = Some(note);
+    } else if is_note_off(message) && *last == Some(note) {
+      *last = None;
+    }
+    return Some(message.to_vec());
+  }
+  let status: u8 = message[0] & 0xF0;
+  match (state.source, status) {
+    (Source::Channel, CHANNEL_PRESSURE) if message.len() >= 2 =>
+      Some(control_change(channel, state.cc, message[1])),
+    (Source::Poly, POLY_PRESSURE) if message.len() >= 3 => {
+      if state.last_note_only && state.last_note[channel as usize] != Some(message[1]) {
+        return None;
+      }
+      Some(control_change(channel, state.cc, message[2]))
+    }
+    _ => Some(message.to_vec()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(source: Source, last_note_only: bool) -> At2CcState {
+    At2CcState { cc: 74, source, last_note_only, last_note: [None; 16] }
+  }
+
+  #[test]
+  fn channel_pressure_becomes_cc() {
+    let mut s: At2CcState = state(Source::Channel, false);
+    assert_eq!(transform_message(&mut s, &[0xD3, 90]), Some(vec![0xB3, 74, 90]));
+    assert_eq!(transform_message(&mut s, &[0xA3, 60, 90]), Some(vec![0xA3, 60, 90]));
+    assert_eq!(transform_message(&mut s, &[0x93, 60, 90]), Some(vec![0x93, 60, 90]));
+    assert_eq!(transform_message(&mut s, &[0xF8]), Some(vec![0xF8]));
+  }
+
+  #[test]
+  fn poly_pressure_can_follow_the_newest_key() {
+    let mut s: At2CcState = state(Source::Poly, true);
+    transform_message(&mut s, &[0x90, 60, 100]);
+    transform_message(&mut s, &[0x90, 64, 100]);
+    assert_eq!(transform_message(&mut s, &[0xA0, 60, 50]), None);
+    assert_eq!(transform_message(&mut s, &[0xA0, 64, 70]), Some(vec![0xB0, 74, 70]));
+    assert_eq!(transform_message(&mut s, &[0xD0, 70]), Some(vec![0xD0, 70]));
+    s.last_note_only = false;
+    assert_eq!(transform_message(&mut s, &[0xA0, 60, 50]), Some(vec![0xB0, 74, 50]));
+  }
+}
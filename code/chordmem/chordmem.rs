@@ -0,0 +1,309 @@
+//! Chordmem - plays a remembered chord from a single key
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin chordmem
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "chordmem-out".
+//! Hold a chord and press the learn key (C8, note 108, by default): the
+//! chord's shape, its intervals above its lowest note, goes into the
+//! current bank. From then on each key plays that shape rooted on
+//! itself, and releasing the key releases every note it started.
+//! Notes that would fall outside 0-127 are dropped. Pressing learn with
+//! nothing held empties the bank, so keys play single notes again.
+//!
+//! There are 8 banks, each with its own chord, chosen by the 8 keys
+//! from C7 (note 96) up; a key held while switching keeps the chord it
+//! started. Control keys don't sound. Other messages pass through.
+//!
+//! Flags:
+//! - `--learn-note <note>`: the learn key, as a number or a name (default 108, C8)
+//! - `--bank-base <note>`: the key that picks bank 1 (default 96, C7)
+//! - `--banks <n>`: how many banks, on that many keys up from the base (default 8)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::note_names::OctaveConvention;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on, note_off, note_on};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::thread;
+
+const TOP_C: u8 = 108; // C8 - default learn key
+const BANK_BASE: u8 = 96; // C7 - default key for bank 1
+const BANKS: u8 = 8;
+
+struct Controls {
+  learn: u8,
+  bank_base: u8,
+  banks: u8,
+}
+
+impl Controls {
+  /// Which bank `note` picks, if it's a bank key.
+  fn bank(&self, note: u8) -> Option<usize> {
+    note.checked_sub(self.bank_base)
+      .filter(|&offset| offset < self.banks)
+      .map(usize::from)
+  }
+}
+
+struct ChordMemState {
+  controls: Controls,
+  chords: Vec<Vec<u8>>, // per bank, intervals above the root; empty if unlearned
+  bank: usize,
+  // (channel, key) -> every output note its note-on produced
+  held: BTreeMap<(u8, u8), Vec<u8>>,
+  // (channel, output note) -> how many held keys produce it
+  sounding: HashMap<(u8, u8), u32>,
+}
+
+impl ChordMemState {
+  fn new(controls: Controls) -> Self {
+    ChordMemState {
+      chords: vec![Vec::new(); usize::from(controls.banks)],
+      controls,
+      bank: 0,
+      held: BTreeMap::new(),
+      sounding: HashMap::new(),
+    }
+  }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let octaves: OctaveConvention = args.octave_convention()?;
+  let controls: Controls = Controls {
+    learn: args.note_or("--learn-note", TOP_C)?,
+    bank_base: args.note_or("--bank-base", BANK_BASE)?,
+    banks: args.parse_or("--banks", BANKS)?,
+  };
+  if controls.banks == 0 {
+    return Err("--banks must be at least 1".into());
+  }
+  let last_bank_key: u16 = u16::from(controls.bank_base) + u16::from(controls.banks) - 1;
+  if last_bank_key > 127 {
+    return Err("the bank keys run past note 127; lower --bank-base or --banks".into());
+  }
+  if controls.bank(controls.learn).is_some() {
+    return Err("--learn-note is one of the bank keys".into());
+  }
+
+  let midi_in: MidiInput = MidiInput::new("chordmem-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("chordmem-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "chordmem-out")?;
+  self_test(&args, &mut conn_out, "chordmem-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let key_name = |note: u8| format!("{} (note {})", octaves.name(note), note);
+  println!("Chord memory started!");
+  println!("  - {}: learn the chord held (or forget, with none)", key_name(controls.learn));
+  println!("  - {} to {}: banks 1 to {}", key_name(controls.bank_base),
+           key_name(last_bank_key as u8), controls.banks);
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<ChordMemState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut ChordMemState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    ChordMemState::new(controls),
+  )?;
+
+  println!("Ports: 'chordmem-in:midi-in' (input), 'chordmem-out:chordmem-out' (output)");
+
+  auto_connect(&args, Some("chordmem-in:midi-in"), Some("chordmem-out:chordmem-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+/// The shape of the keys held: each one's distance above the lowest.
+fn shape(keys: impl Iterator<Item = u8>) -> Vec<u8> {
+  let mut notes: Vec<u8> = keys.collect();
+  notes.sort();
+  notes.dedup();
+  let root: u8 = match notes.first() {
+    Some(&root) => root,
+    None => return Vec::new(),
+  };
+  notes.iter().map(|&n| n - root).collect()
+}
+
+/// The chord rooted on `note`, without the notes above 127.
+/// An unlearned chord is just the note.
+fn chord(intervals: &[u8], note: u8) -> Vec<u8> {
+  if intervals.is_empty() {
+    return vec![note];
+  }
+  intervals.iter()
+    .map(|&i| u16::from(note) + u16::from(i))
+    .filter(|&n| n <= 127)
+    .map(|n| n as u8)
+    .collect()
+}
+
+fn transform_message(state: &mut ChordMemState, message: &[u8]) -> Vec<Vec<u8>> {
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![message.to_vec()],
+    };
+  if note == state.controls.learn || state.controls.bank(note).is_some() {
+    if is_note_on(message) {
+      handle_control(state, note);
+    }
+    return vec![];
+  }
+  let velocity: u8 = message[2];
+  let mut results: Vec<Vec<u8>> = vec![];
+  if is_note_on(message) {
+    if let Some(old) = state.held.remove(&(channel, note)) {
+      // Retriggered before release; forget the earlier press.
+      release(state, channel, &old, 0, &mut results);
+    }
+    let outputs: Vec<u8> = chord(&state.chords[state.bank], note);
+    for &output in &outputs {
+      *state.sounding.entry((channel, output)).or_insert(0) += 1;
+      results.push(note_on(channel, output, velocity));
+    }
+    state.held.insert((channel, note), outputs);
+  } else if is_note_off(message) {
+    if let Some(outputs) = state.held.remove(&(channel, note)) {
+      release(state, channel, &outputs, velocity, &mut results);
+    }
+  }
+  results
+}
+
+fn handle_control(state: &mut ChordMemState, note: u8) {
+  if let Some(bank) = state.controls.bank(note) {
+    state.bank = bank;
+    println!("[chordmem] bank {}: {}", bank + 1, describe(&state.chords[bank]));
+    return;
+  }
+  let learned: Vec<u8> = shape(state.held.keys().map(|&(_, key)| key));
+  println!("[chordmem] bank {} learned: {}", state.bank + 1, describe(&learned));
+  state.chords[state.bank] = learned;
+}
+
+fn describe(intervals: &[u8]) -> String {
+  if intervals.is_empty() {
+    return "single notes".to_string();
+  }
+  intervals.iter().map(|i| i.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+/// Sends a note-off for each output once nothing else holds it.
+fn release(
+  state: &mut ChordMemState,
+  channel: u8,
+  outputs: &[u8],
+  velocity: u8,
+  results: &mut Vec<Vec<u8>>,
+) {
+  for &output in outputs {
+    let count: &mut u32 = state.sounding.entry((channel, output)).or_insert(1);
+    *count -= 1;
+    if *count == 0 {
+      state.sounding.remove(&(channel, output));
+      results.push(note_off(channel, output, velocity));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> ChordMemState {
+    ChordMemState::new(Controls { learn: TOP_C, bank_base: BANK_BASE, banks: BANKS })
+  }
+
+  #[test]
+  fn learned_chord_follows_the_key() {
+    let mut s: ChordMemState = state();
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 100]), vec![vec![0x90, 60, 100]]);
+    transform_message(&mut s, &[0x90, 67, 100]);
+    transform_message(&mut s, &[0x90, 64, 100]);
+    assert!(transform_message(&mut s, &[0x90, TOP_C, 100]).is_empty());
+    assert!(transform_message(&mut s, &[0x80, TOP_C, 0]).is_empty());
+    assert_eq!(s.chords[0], vec![0, 4, 7]);
+    for note in [60, 64, 67] {
+      transform_message(&mut s, &[0x80, note, 0]);
+    }
+    assert!(s.sounding.is_empty());
+    assert_eq!(transform_message(&mut s, &[0x91, 62, 90]),
+               vec![vec![0x91, 62, 90], vec![0x91, 66, 90], vec![0x91, 69, 90]]);
+    assert_eq!(transform_message(&mut s, &[0x81, 62, 0]),
+               vec![vec![0x81, 62, 0], vec![0x81, 66, 0], vec![0x81, 69, 0]]);
+  }
+
+  #[test]
+  fn banks_keep_their_own_chords() {
+    let mut s: ChordMemState = state();
+    s.chords[0] = vec![0, 4, 7];
+    transform_message(&mut s, &[0x90, 60, 100]); // held across the switch
+    transform_message(&mut s, &[0x90, BANK_BASE + 1, 100]);
+    assert_eq!(s.bank, 1);
+    assert_eq!(transform_message(&mut s, &[0x90, 50, 100]), vec![vec![0x90, 50, 100]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]),
+               vec![vec![0x80, 60, 0], vec![0x80, 64, 0], vec![0x80, 67, 0]]);
+  }
+
+  #[test]
+  fn learning_nothing_forgets_the_chord() {
+    let mut s: ChordMemState = state();
+    s.chords[0] = vec![0, 3, 7];
+    transform_message(&mut s, &[0x90, TOP_C, 100]);
+    assert!(s.chords[0].is_empty());
+  }
+
+  #[test]
+  fn shared_and_out_of_range_notes() {
+    assert_eq!(chord(&[0, 4, 7, 12], 120), vec![120, 124, 127]);
+    let mut s: ChordMemState = state();
+    s.chords[0] = vec![0, 7];
+    transform_message(&mut s, &[0x90, 60, 100]); // 60, 67
+    transform_message(&mut s, &[0x90, 67, 100]); // 67, 74
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 67, 0]),
+               vec![vec![0x80, 67, 0], vec![0x80, 74, 0]]);
+  }
+
+  #[test]
+  fn shapes() {
+    assert_eq!(shape([67, 60, 64, 60].into_iter()), vec![0, 4, 7]);
+    assert!(shape(std::iter::empty()).is_empty());
+  }
+}
@@ -0,0 +1,192 @@
+//! Compress - evens out note-on velocities, like an audio compressor
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin compress -- --threshold 80 --ratio 3 --makeup 10
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "compress-out".
+//! Velocities below `--threshold` pass unchanged; above it, each step
+//! of input adds only 1/`--ratio` of a step of output. Across the
+//! `--knee` (centred on the threshold) the curve bends gradually
+//! instead of abruptly. Then `--makeup` is added to every velocity,
+//! and the result is kept within 1-127.
+//!
+//! Only note-on velocities change. Note-offs, velocity-0 note-ons
+//! (which mean note-off) and everything else pass through untouched.
+//!
+//! Flags:
+//! - `--threshold <v>`: where compression starts (default 80)
+//! - `--ratio <r>`: at least 1; 1 changes nothing (default 2)
+//! - `--knee <v>`: width of the soft knee, 0 for a hard one (default 10)
+//! - `--makeup <v>`: added afterwards, may be negative (default 0)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::is_note_on;
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Debug)]
+struct Compressor {
+  threshold: f64,
+  ratio: f64,
+  knee: f64,
+  makeup: f64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let compressor: Compressor = parse_compressor(&args)?;
+
+  let midi_in: MidiInput = MidiInput::new("compress-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("compress-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "compress-out")?;
+  self_test(&args, &mut conn_out, "compress-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Velocity compressor started! {:?}", compressor);
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let _ = tx_for_callback.send(transform_message(&compressor, message));
+    },
+    (),
+  )?;
+
+  println!("Ports: 'compress-in:midi-in' (input), 'compress-out:compress-out' (output)");
+
+  auto_connect(&args, Some("compress-in:midi-in"), Some("compress-out:compress-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_compressor(args: &Args) -> Result<Compressor, String> {
+  let threshold: f64 = args.parse_or("--threshold", 80.0)?;
+  let ratio: f64 = args.parse_or("--ratio", 2.0)?;
+  let knee: f64 = args.parse_or("--knee", 10.0)?;
+  let makeup: f64 = args.parse_or("--makeup", 0.0)?;
+  if !(0.0..=127.0).contains(&threshold) {
+    return Err("--threshold must be in 0-127".to_string());
+  }
+  if !(ratio >= 1.0 && ratio.is_finite()) {
+    return Err("--ratio must be at least 1".to_string());
+  }
+  if !(knee >= 0.0 && knee.is_finite()) {
+    return Err("--knee can't be negative".to_string());
+  }
+  if !makeup.is_finite() {
+    return Err("--makeup must be a number".to_string());
+  }
+  Ok(Compressor { threshold, ratio, knee, makeup })
+}
+
+/// The usual soft-knee compressor curve, in velocity units:
+/// straight below the knee, slope 1/ratio above it, and a quadratic
+/// joining the two across it.
+fn compress(c: &Compressor, velocity: u8) -> u8 {
+  let x: f64 = velocity as f64;
+  let over: f64 = x - c.threshold;
+  let y: f64 = if 2.0 * over < -c.knee {
+    x
+  } else if c.knee > 0.0 && 2.0 * over <= c.knee {
+    x + (1.0 / c.ratio - 1.0) * (over + c.knee / 2.0).powi(2) / (2.0 * c.knee)
+  } else {
+    c.threshold + over / c.ratio
+  };
+  (y + c.makeup).round().clamp(1.0, 127.0) as u8
+}
+
+fn transform_message(c: &Compressor, message: &[u8]) -> Vec<u8> {
+  let mut out: Vec<u8> = message.to_vec();
+  if is_note_on(message) {
+    out[2] = compress(c, message[2]);
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn compressor(knee: f64, makeup: f64) -> Compressor {
+    Compressor { threshold: 80.0, ratio: 2.0, knee, makeup }
+  }
+
+  #[test]
+  fn mapping_at_a_few_velocities() {
+    let c: Compressor = compressor(10.0, 0.0);
+    assert_eq!(compress(&c, 1), 1);
+    assert_eq!(compress(&c, 60), 60);
+    assert_eq!(compress(&c, 75), 75); // where the knee starts
+    assert_eq!(compress(&c, 80), 79); // 80 - 5^2 / 40
+    assert_eq!(compress(&c, 85), 83); // where the knee ends: 80 + 5/2
+    assert_eq!(compress(&c, 100), 90);
+    assert_eq!(compress(&c, 127), 104);
+  }
+
+  #[test]
+  fn hard_knee_and_makeup() {
+    let hard: Compressor = compressor(0.0, 0.0);
+    assert_eq!(compress(&hard, 80), 80);
+    assert_eq!(compress(&hard, 90), 85);
+    let loud: Compressor = compressor(10.0, 30.0);
+    assert_eq!(compress(&loud, 120), 127);
+    assert_eq!(compress(&loud, 40), 70);
+    let quiet: Compressor = compressor(10.0, -50.0);
+    assert_eq!(compress(&quiet, 20), 1);
+  }
+
+  #[test]
+  fn curve_never_decreases() {
+    let c: Compressor = Compressor { threshold: 64.0, ratio: 4.0, knee: 20.0, makeup: 5.0 };
+    for v in 1..127 {
+      assert!(compress(&c, v) <= compress(&c, v + 1));
+    }
+  }
+
+  #[test]
+  fn note_offs_are_untouched() {
+    let c: Compressor = compressor(0.0, 20.0);
+    assert_eq!(transform_message(&c, &[0x90, 60, 0]), vec![0x90, 60, 0]);
+    assert_eq!(transform_message(&c, &[0x80, 60, 100]), vec![0x80, 60, 100]);
+    assert_eq!(transform_message(&c, &[0x90, 60, 100]), vec![0x90, 60, 110]);
+  }
+
+  #[test]
+  fn settings_must_be_finite() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    assert!(parse_compressor(&args(&["--ratio", "3", "--makeup", "-5"])).is_ok());
+    for bad in [["--ratio", "nan"], ["--ratio", "inf"], ["--makeup", "nan"],
+                ["--makeup", "-inf"], ["--knee", "nan"], ["--ratio", "0.5"]] {
+      assert!(parse_compressor(&args(&bad)).is_err(), "{:?}", bad);
+    }
+  }
+}
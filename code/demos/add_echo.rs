@@ -9,29 +9,182 @@
 //! Creates three virtual MIDI ports:
 //! - "midi-in": Input port - connect MIDI source (keyboard) here
 //! - "immediate-out": Outputs MIDI immediately (pass-through)
-//! - "echo-out": Outputs MIDI delayed by 300ms
+//! - "echo-out": Outputs MIDI delayed, by 300ms unless `--delay-ms` says otherwise
+//!
+//! The delay can be played live from a controller: with `--delay-cc 20`,
+//! CC 20 sets it between `--delay-min-ms` (at 0) and `--delay-max-ms`
+//! (at 127), and is not passed on. Echoes already waiting keep their
+//! time; only what arrives afterwards uses the new delay. A note-off's
+//! echo never goes out before its note-on's, however the delay moved.
+//!
+//! Or it can be tapped: with `--tap-note 36`, pressing that key in rhythm
+//! sets the delay to the time between taps (it isn't passed on either).
+//! The estimate is the typical gap among the last few taps, ignoring
+//! gaps far from it, so one missed or doubled tap doesn't throw it off.
+//! A pause of more than two seconds starts a fresh count.
+//!
+//! Each note can echo more than once: with `--repeats 4` it comes back
+//! after one delay, two delays, and so on. The kth repeat's velocity is
+//! scaled by `--decay` k times (never below 1), and its pitch moves by
+//! k times `--echo-transpose` semitones, so `--echo-transpose 12` climbs
+//! an octave per repeat. A repeat pushed outside 0-127 is dropped,
+//! note-off and all. Other messages echo once, with the first repeat.
+//!
+//! `--echo-range <low>:<high>` (say `60:96`, or `C4:C7`) echoes only the
+//! notes in that range, so a melody can repeat while the bass stays dry.
+//! Notes outside it go out the pass-through and nowhere else, note-offs
+//! included, so their echoes can't hang; other messages echo as usual.
+//!
+//! At most `--max-queued` echoes wait at once. Past that the oldest is
+//! given up, with a warning (at most one a second): a note-off is sent
+//! early rather than dropped, so nothing is left hanging, and a dropped
+//! note-on is answered with an immediate note-off in case an earlier
+//! strike of that note is still sounding.
+//!
+//! Flags:
+//! - `--delay-ms <ms>`: the starting delay, at most 60000 (default 300)
+//! - `--delay-cc <n>`: the controller that sets the delay (default none)
+//! - `--delay-min-ms <ms>`, `--delay-max-ms <ms>`: its range, within the
+//!   same limit (defaults 50 and 1000)
+//! - `--tap-note <note>`: the key to tap the delay on, as a number or
+//!   a name like C2 (default none)
+//! - `--repeats <n>`: how many times each note echoes, 1-100 (default 1)
+//! - `--decay <f>`: velocity scale per repeat, 0-1 (default 1)
+//! - `--echo-transpose <semitones>`: pitch change per repeat (default 0)
+//! - `--echo-range <low>:<high>`: the notes to echo, inclusive (default all)
+//! - `--max-queued <n>`: how many echoes may wait at once (default 4096)
+//! - `--log-delay`: print the delay when the controller or taps move it,
+//!   at most a few times a second
+//! - `--echo-port <substring>`: send the echoes to that existing port
+//!   instead of a virtual "echo-out", as `--output-port` does for the
+//!   pass-through
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`: see `midi_utils::ports`
+//! - `--zero-release-velocity`: see `midi_utils::sink::ReleaseVelocity`
 
 use midir::{MidiInput, MidiOutput, MidiInputConnection, MidiOutputConnection};
-use midir::os::unix::{VirtualInput, VirtualOutput};
-use std::sync::mpsc;
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit, SendMonitor};
+use midi_utils::sink::{MidiSink, ReleaseVelocity};
+use midi_utils::sync::lock;
+use midi_utils::{all_notes_off, get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, CONTROL_CHANGE};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{thread, io};
+use std::thread;
+
+const DELAY_LOG_INTERVAL_MS: u64 = 250;
+const OVERFLOW_WARNING_INTERVAL_MS: u64 = 1000;
+const TAP_HISTORY: usize = 5; // taps, so one fewer gaps
+const TAP_RESET_MS: u64 = 2000; // so a tapped delay is never longer
+const MAX_DELAY_MS: u64 = 60_000; // keeps every echo's time representable
+const MAX_REPEATS: u32 = 100; // each message's repeats are built at once
+const TAP_TOLERANCE: f64 = 0.35; // how far from the median a gap may be
 
 struct DelayedMessage {
     data: Vec<u8>,
     send_at: Instant,
 }
 
+/// How a controller moves the delay.
+struct DelayControl {
+    cc: u8,
+    min: Duration,
+    max: Duration,
+}
+
+/// How each message turns into its repeats.
+struct EchoShape {
+    repeats: u32,
+    decay: f64,
+    transpose: i8,
+}
+
+struct TapTempo {
+    note: u8,
+    taps: Vec<Instant>, // oldest first
+}
+
+/// The echoes waiting to go out, and what it takes to schedule more.
+struct Echoes {
+    shape: EchoShape,
+    max_queued: usize,
+    queue: Vec<DelayedMessage>,
+    last_send: HashMap<(u32, u8, u8), Instant>, // see `schedule`
+    overflow_warning: Throttle,
+}
+
+/// What the input callback does with a message.
+#[derive(Debug, PartialEq)]
+enum Routing {
+    SetDelay(Duration), // from the controller or a tap; not passed on
+    Swallow, // a tap too few to set a tempo
+    Pass, // out now, and echoed
+}
+
+/// Keeps something (printing, mostly) from happening too often.
+struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    logging::init();
+    let args: Args = Args::from_env()?;
+    if args.flag("--list-ports") {
+        return list_ports();
+    }
+    let delay: Duration = delay_flag(&args, "--delay-ms", 300)?;
+    let delay_control: Option<DelayControl> = match args.parse::<u8>("--delay-cc")? {
+        None => None,
+        Some(cc) if cc > 127 => return Err("--delay-cc must be in 0-127".into()),
+        Some(cc) => Some(DelayControl {
+            cc,
+            min: delay_flag(&args, "--delay-min-ms", 50)?,
+            max: delay_flag(&args, "--delay-max-ms", 1000)?,
+        }),
+    };
+    if delay_control.as_ref().is_some_and(|c| c.min > c.max) {
+        return Err("--delay-min-ms can't be more than --delay-max-ms".into());
+    }
+    let mut tap_tempo: Option<TapTempo> = args.note("--tap-note")?
+        .map(|note| TapTempo { note, taps: Vec::new() });
+    let log_delays: bool = args.flag("--log-delay");
+    let mut delay_log: Throttle = Throttle::new(Duration::from_millis(DELAY_LOG_INTERVAL_MS));
+    let shape: EchoShape = EchoShape {
+        repeats: repeats_flag(&args)?,
+        decay: args.parse_or("--decay", 1.0)?,
+        transpose: args.parse_or("--echo-transpose", 0)?,
+    };
+    if !(0.0..=1.0).contains(&shape.decay) {
+        return Err("--decay must be in 0-1".into());
+    }
+    let echo_range: Option<RangeInclusive<u8>> = args.note_range("--echo-range")?;
+    let max_queued: usize = args.parse_or("--max-queued", 4096)?;
+    if max_queued == 0 {
+        return Err("--max-queued must be at least 1".into());
+    }
+
     let midi_in: MidiInput = MidiInput::new("add-echo-in")?;
     let midi_out_immediate: MidiOutput = MidiOutput::new("add-echo-immediate")?;
     let midi_out_echo: MidiOutput = MidiOutput::new("add-echo-echo")?;
 
     // Create virtual output ports
-    let mut conn_immediate: MidiOutputConnection =
-        midi_out_immediate.create_virtual("immediate-out")?;
-    let conn_echo: MidiOutputConnection =
-        midi_out_echo.create_virtual("echo-out")?;
+    let mut conn_immediate: MidiOutputConnection = open_output(
+        midi_out_immediate, args.value("--output-port"), "immediate-out")?;
+    self_test(&args, &mut conn_immediate, "immediate-out");
+    let mut conn_echo: MidiOutputConnection =
+        open_output(midi_out_echo, args.value("--echo-port"), "echo-out")?;
+    self_test(&args, &mut conn_echo, "echo-out");
+    let zero_release: bool = args.flag("--zero-release-velocity");
+    let conn_immediate: ReleaseVelocity<MidiOutputConnection> =
+        ReleaseVelocity { sink: conn_immediate, zero: zero_release };
+    let conn_echo: ReleaseVelocity<MidiOutputConnection> =
+        ReleaseVelocity { sink: conn_echo, zero: zero_release };
 
     // Channel for sending messages to the delay thread
     let (tx_immediate, rx_immediate): (
@@ -43,53 +196,42 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         mpsc::Receiver<Vec<u8>>,
     ) = mpsc::channel();
 
+    // The input callback sets it; the echo thread reads it.
+    let shared_delay: Arc<Mutex<Duration>> = Arc::new(Mutex::new(delay));
+    let delay_for_echo: Arc<Mutex<Duration>> = Arc::clone(&shared_delay);
+
     // Spawn thread for immediate output
-    let _immediate_thread: thread::JoinHandle<()> = thread::spawn(move || {
-        while let Ok(data) = rx_immediate.recv() {
-            let _ = conn_immediate.send(&data);
-        }
-    });
+    let immediate_thread: thread::JoinHandle<()> =
+        thread::spawn(move || run_immediate_thread(conn_immediate, rx_immediate));
 
     // Spawn thread for delayed echo output
-    let _echo_thread: thread::JoinHandle<()> = thread::spawn(move || {
-        let mut conn: MidiOutputConnection = conn_echo;
-        let mut queue: Vec<DelayedMessage> = Vec::new();
-        let delay: Duration = Duration::from_millis(300);
-
-        loop {
-            // Check for new messages (non-blocking)
-            while let Ok(data) = rx_echo.try_recv() {
-                let msg: DelayedMessage = DelayedMessage {
-                    data,
-                    send_at: Instant::now() + delay,
-                };
-                queue.push(msg);
-            }
-
-            // Send any messages whose time has come
-            let now: Instant = Instant::now();
-            let mut i: usize = 0;
-            while i < queue.len() {
-                if queue[i].send_at <= now {
-                    let msg: DelayedMessage = queue.remove(i);
-                    let _ = conn.send(&msg.data);
-                } else {
-                    i += 1;
-                }
-            }
-
-            // Sleep briefly to avoid busy-waiting
-            thread::sleep(Duration::from_millis(1));
-        }
-    });
+    let echoes: Echoes = Echoes::new(shape, max_queued);
+    let echo_thread: thread::JoinHandle<()> =
+        thread::spawn(move || run_echo_thread(conn_echo, rx_echo, echoes, delay_for_echo));
 
     // Create virtual input port with callback
-    let _conn_in: MidiInputConnection<()> = midi_in.create_virtual(
+    let tx_for_callback: mpsc::Sender<Vec<u8>> = tx_immediate.clone();
+    let conn_in: MidiInputConnection<()> = open_input(
+        midi_in,
+        args.value("--input-port"),
         "midi-in",
         move |_timestamp: u64, message: &[u8], _: &mut ()| {
-            let data: Vec<u8> = message.to_vec();
-            let _ = tx_immediate.send(data.clone());
-            let _ = tx_echo.send(data);
+            match route(delay_control.as_ref(), tap_tempo.as_mut(), message, Instant::now()) {
+                Routing::SetDelay(delay) => {
+                    *lock(&shared_delay) = delay;
+                    if log_delays && delay_log.ready(Instant::now()) {
+                        println!("[echo] Delay {}ms", delay.as_millis());
+                    }
+                }
+                Routing::Swallow => {}
+                Routing::Pass => {
+                    let data: Vec<u8> = message.to_vec();
+                    let _ = tx_for_callback.send(data.clone());
+                    if echoed(echo_range.as_ref(), message) {
+                        let _ = tx_echo.send(data);
+                    }
+                }
+            }
         },
         (),
     )?;
@@ -99,13 +241,431 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Virtual ports created:");
     println!("  - 'add-echo-in:midi-in' (input)");
     println!("  - 'add-echo-immediate:immediate-out' (pass-through)");
-    println!("  - 'add-echo-echo:echo-out' (300ms delay)");
+    println!("  - 'add-echo-echo:echo-out' ({}ms delay)", delay.as_millis());
     println!();
     println!("Use 'aconnect -l' to see ports, 'aconnect <src> <dst>' to connect.");
+    auto_connect(&args, Some("add-echo-in:midi-in"), Some("add-echo-immediate:immediate-out"));
     println!("Press Enter to exit...");
 
-    let mut input: String = String::new();
-    io::stdin().read_line(&mut input)?;
+    let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+    // Closing the input also ends the echo thread, which silences its port.
+    silence_after(exit, conn_in, None, tx_immediate, immediate_thread);
+    if exit == Exit::InputLost {
+        let _ = echo_thread.join();
+    }
 
     Ok(())
 }
+
+/// A delay flag, in ms, held to MAX_DELAY_MS: with many repeats, a
+/// longer one could put an echo past the end of time.
+fn delay_flag(args: &Args, name: &str, default: u64) -> Result<Duration, String> {
+    let ms: u64 = args.parse_or(name, default)?;
+    if ms > MAX_DELAY_MS {
+        return Err(format!("{} must be at most {}", name, MAX_DELAY_MS));
+    }
+    Ok(Duration::from_millis(ms))
+}
+
+/// `--repeats`, held to 1-MAX_REPEATS: every message's repeats are made
+/// together, so a huge count would stall the input on one note.
+fn repeats_flag(args: &Args) -> Result<u32, String> {
+    let repeats: u32 = args.parse_or("--repeats", 1)?;
+    if !(1..=MAX_REPEATS).contains(&repeats) {
+        return Err(format!("--repeats must be in 1-{}", MAX_REPEATS));
+    }
+    Ok(repeats)
+}
+
+/// Passes every message straight on.
+fn run_immediate_thread(mut sink: impl MidiSink, rx: mpsc::Receiver<Vec<u8>>) {
+    let mut monitor: SendMonitor = SendMonitor::new();
+    while let Ok(data) = rx.recv() {
+        monitor.send(&mut sink, &data);
+    }
+}
+
+/// Sends each message's echoes when their time comes, at whatever
+/// delay is current when the message arrives. Once the input is closed
+/// it drops the echoes still waiting and sends all-notes-off instead.
+fn run_echo_thread(
+    mut sink: impl MidiSink,
+    rx: mpsc::Receiver<Vec<u8>>,
+    mut echoes: Echoes,
+    delay: Arc<Mutex<Duration>>,
+) {
+    let mut monitor: SendMonitor = SendMonitor::new();
+
+    loop {
+        // Check for new messages (non-blocking)
+        loop {
+            match rx.try_recv() {
+                Ok(data) => {
+                    let delay: Duration = *lock(&delay);
+                    for data in echoes.receive(&data, Instant::now(), delay) {
+                        monitor.send(&mut sink, &data);
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    for data in (0..16).map(all_notes_off) {
+                        monitor.send(&mut sink, &data);
+                    }
+                    return;
+                }
+            }
+        }
+
+        // Send any messages whose time has come
+        for data in echoes.due(Instant::now()) {
+            monitor.send(&mut sink, &data);
+        }
+
+        // Sleep briefly to avoid busy-waiting
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Whether a message moves the delay, by the controller or a tap on
+/// the tap key, or goes on its way.
+fn route(
+    delay_control: Option<&DelayControl>,
+    tap_tempo: Option<&mut TapTempo>,
+    message: &[u8],
+    now: Instant,
+) -> Routing {
+    if let Some(delay) = delay_control.and_then(|control| delay_from_cc(control, message)) {
+        return Routing::SetDelay(delay);
+    }
+    match tap_tempo {
+        Some(tap) if is_note_event(message) && get_note(message) == Some(tap.note) => {
+            match is_note_on(message).then(|| tap.tap(now)).flatten() {
+                Some(delay) => Routing::SetDelay(delay),
+                None => Routing::Swallow,
+            }
+        }
+        _ => Routing::Pass,
+    }
+}
+
+impl Echoes {
+    fn new(shape: EchoShape, max_queued: usize) -> Self {
+        Echoes {
+            shape,
+            max_queued,
+            queue: Vec::new(),
+            last_send: HashMap::new(),
+            overflow_warning: Throttle::new(Duration::from_millis(OVERFLOW_WARNING_INTERVAL_MS)),
+        }
+    }
+
+    /// Queues the repeats of a message that arrived at `now`.
+    /// Returns what must go out at once to keep the queue bounded.
+    fn receive(&mut self, data: &[u8], now: Instant, delay: Duration) -> Vec<Vec<u8>> {
+        let mut evicted: Vec<Vec<u8>> = Vec::new();
+        for (k, repeat) in repeats(&self.shape, data) {
+            let msg: DelayedMessage = schedule(&mut self.last_send, k, repeat, now, delay);
+            let dropped: Vec<Vec<u8>> = push_bounded(&mut self.queue, msg, self.max_queued);
+            if !dropped.is_empty() && self.overflow_warning.ready(now) {
+                println!("[echo] Warning: more than {} echoes waiting; dropping the oldest",
+                         self.max_queued);
+            }
+            evicted.extend(dropped);
+        }
+        evicted
+    }
+
+    /// Takes out every echo whose time has come, in the order queued.
+    fn due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut out: Vec<Vec<u8>> = Vec::new();
+        let mut i: usize = 0;
+        while i < self.queue.len() {
+            if self.queue[i].send_at <= now {
+                out.push(self.queue.remove(i).data);
+            } else {
+                i += 1;
+            }
+        }
+        out
+    }
+}
+
+/// The repeats of a message, numbered from 1, each transposed and faded
+/// by its number. The same input always gives the same pitches, so a
+/// note-off's repeats end its note-on's.
+fn repeats(shape: &EchoShape, data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    if !is_note_event(data) || data.len() < 3 {
+        return vec![(1, data.to_vec())];
+    }
+    (1..=shape.repeats)
+        .filter_map(|k| {
+            let note: i32 = data[1] as i32 + k as i32 * shape.transpose as i32;
+            if !(0..=127).contains(&note) {
+                return None;
+            }
+            let mut repeat: Vec<u8> = data.to_vec();
+            repeat[1] = note as u8;
+            if is_note_on(data) {
+                repeat[2] = ((data[2] as f64 * shape.decay.powi(k as i32)).round() as u8).max(1);
+            }
+            Some((k, repeat))
+        })
+        .collect()
+}
+
+/// When the `k`th repeat of `data` should go out. Messages for the same
+/// note and repeat never go out before the ones already scheduled for it,
+/// even if the delay has shrunk.
+fn schedule(
+    last_send: &mut HashMap<(u32, u8, u8), Instant>,
+    k: u32,
+    data: Vec<u8>,
+    now: Instant,
+    delay: Duration,
+) -> DelayedMessage {
+    let mut send_at: Instant = now + delay * k;
+    if let (Some(channel), Some(note)) = (get_channel(&data), get_note(&data)) {
+        if let Some(&previous) = last_send.get(&(k, channel, note)) {
+            send_at = send_at.max(previous);
+        }
+        last_send.insert((k, channel, note), send_at);
+    }
+    DelayedMessage { data, send_at }
+}
+
+/// Whether a message passed on is echoed too: a note only if it's in
+/// `--echo-range`, anything else always.
+fn echoed(echo_range: Option<&RangeInclusive<u8>>, message: &[u8]) -> bool {
+    match (echo_range, get_note(message)) {
+        (Some(range), Some(note)) => range.contains(&note),
+        _ => true,
+    }
+}
+
+/// The delay a controller message asks for, if it's the delay controller.
+fn delay_from_cc(control: &DelayControl, message: &[u8]) -> Option<Duration> {
+    let is_delay_cc: bool = message.len() >= 3
+        && message[0] & 0xF0 == CONTROL_CHANGE
+        && message[1] == control.cc;
+    is_delay_cc.then(|| control.min + (control.max - control.min).mul_f64(message[2] as f64 / 127.0))
+}
+
+impl TapTempo {
+    /// Records a tap, returning the delay the taps so far suggest.
+    fn tap(&mut self, now: Instant) -> Option<Duration> {
+        let stale: bool = self.taps.last()
+            .is_some_and(|&t| now - t > Duration::from_millis(TAP_RESET_MS));
+        if stale {
+            self.taps.clear();
+        }
+        self.taps.push(now);
+        if self.taps.len() > TAP_HISTORY {
+            self.taps.remove(0);
+        }
+        let mut gaps: Vec<Duration> = self.taps.windows(2).map(|w| w[1] - w[0]).collect();
+        if gaps.is_empty() {
+            return None;
+        }
+        gaps.sort();
+        let median: Duration = gaps[(gaps.len() - 1) / 2];
+        let near: Vec<Duration> = gaps.into_iter()
+            .filter(|g| g.as_secs_f64() >= median.as_secs_f64() * (1.0 - TAP_TOLERANCE)
+                        && g.as_secs_f64() <= median.as_secs_f64() * (1.0 + TAP_TOLERANCE))
+            .collect();
+        Some(near.iter().sum::<Duration>() / near.len() as u32)
+    }
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Self {
+        Throttle { interval, last: None }
+    }
+
+    /// True, and restarts the wait, if `interval` has passed since the last time.
+    fn ready(&mut self, now: Instant) -> bool {
+        let ready: bool = self.last.is_none_or(|t| now - t >= self.interval);
+        if ready {
+            self.last = Some(now);
+        }
+        ready
+    }
+}
+
+/// Queues `msg`, giving up the oldest waiting messages if that makes
+/// more than `max`. Returns what must be sent now in their place:
+/// an evicted note-off itself, or a note-off for an evicted note-on.
+fn push_bounded(queue: &mut Vec<DelayedMessage>, msg: DelayedMessage, max: usize) -> Vec<Vec<u8>> {
+    queue.push(msg);
+    let excess: usize = queue.len().saturating_sub(max);
+    queue.drain(..excess)
+        .filter_map(|old| {
+            if is_note_off(&old.data) {
+                Some(old.data)
+            } else if is_note_on(&old.data) {
+                Some(note_off(old.data[0] & 0x0F, old.data[1], 0))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_sets_the_delay_across_its_range() {
+        let control: DelayControl = DelayControl {
+            cc: 20,
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(1000),
+        };
+        assert_eq!(delay_from_cc(&control, &[0xB0, 20, 0]), Some(Duration::from_millis(50)));
+        assert_eq!(delay_from_cc(&control, &[0xB3, 20, 127]), Some(Duration::from_millis(1000)));
+        assert_eq!(delay_from_cc(&control, &[0xB0, 21, 127]), None);
+        assert_eq!(delay_from_cc(&control, &[0x90, 20, 127]), None);
+    }
+
+    #[test]
+    fn delays_are_held_to_the_limit() {
+        let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+        assert_eq!(delay_flag(&args(&[]), "--delay-ms", 300), Ok(Duration::from_millis(300)));
+        assert_eq!(delay_flag(&args(&["--delay-ms", "60000"]), "--delay-ms", 300),
+                   Ok(Duration::from_secs(60)));
+        assert!(delay_flag(&args(&["--delay-ms", "60001"]), "--delay-ms", 300).is_err());
+        assert!(delay_flag(&args(&["--delay-max-ms", "18446744073709551615"]),
+                           "--delay-max-ms", 1000).is_err());
+    }
+
+    #[test]
+    fn repeats_are_held_to_the_limit() {
+        let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+        assert_eq!(repeats_flag(&args(&[])), Ok(1));
+        assert_eq!(repeats_flag(&args(&["--repeats", "100"])), Ok(100));
+        assert!(repeats_flag(&args(&["--repeats", "0"])).is_err());
+        assert!(repeats_flag(&args(&["--repeats", "101"])).is_err());
+        assert!(repeats_flag(&args(&["--repeats", "4294967295"])).is_err());
+    }
+
+    #[test]
+    fn note_off_never_echoes_before_its_note_on() {
+        let mut last_send: HashMap<(u32, u8, u8), Instant> = HashMap::new();
+        let t0: Instant = Instant::now();
+        let on: DelayedMessage =
+            schedule(&mut last_send, 1, vec![0x90, 60, 100], t0, Duration::from_millis(900));
+        let off: DelayedMessage = schedule(&mut last_send, 1, vec![0x80, 60, 0],
+                                           t0 + Duration::from_millis(100), Duration::from_millis(50));
+        assert_eq!(off.send_at, on.send_at);
+        let cc: DelayedMessage = schedule(&mut last_send, 1, vec![0xB0, 1, 2], t0, Duration::from_millis(50));
+        assert_eq!(cc.send_at, t0 + Duration::from_millis(50));
+        // A later repeat of the note doesn't hold back the first repeat's note-off.
+        schedule(&mut last_send, 2, vec![0x90, 62, 100], t0, Duration::from_millis(100));
+        schedule(&mut last_send, 1, vec![0x90, 62, 100], t0, Duration::from_millis(100));
+        let off: DelayedMessage = schedule(&mut last_send, 1, vec![0x80, 62, 0],
+                                           t0 + Duration::from_millis(10), Duration::from_millis(100));
+        assert_eq!(off.send_at, t0 + Duration::from_millis(110));
+    }
+
+    #[test]
+    fn taps_set_the_delay_and_ignore_a_missed_one() {
+        let mut tap: TapTempo = TapTempo { note: 36, taps: Vec::new() };
+        let t0: Instant = Instant::now();
+        let ms = |ms: u64| t0 + Duration::from_millis(ms);
+        assert_eq!(tap.tap(ms(0)), None);
+        assert_eq!(tap.tap(ms(500)), Some(Duration::from_millis(500)));
+        assert_eq!(tap.tap(ms(1010)), Some(Duration::from_millis(505)));
+        // Missed the tap at 1500.
+        assert_eq!(tap.tap(ms(2000)), Some(Duration::from_millis(505)));
+        assert_eq!(tap.tap(ms(2500)).map(|d| d.as_millis()), Some(503));
+        // A long pause starts over.
+        assert_eq!(tap.tap(ms(5000)), None);
+        assert_eq!(tap.tap(ms(5300)), Some(Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn flooded_queue_stays_bounded() {
+        let mut queue: Vec<DelayedMessage> = Vec::new();
+        let t0: Instant = Instant::now();
+        let mut evicted: Vec<Vec<u8>> = Vec::new();
+        for i in 0..10_000u32 {
+            let data: Vec<u8> = match i % 3 {
+                0 => vec![0x90, 60, 100],
+                1 => vec![0x80, 60, 0],
+                _ => vec![0xB0, 1, 5],
+            };
+            evicted.extend(push_bounded(&mut queue, DelayedMessage { data, send_at: t0 }, 100));
+            assert!(queue.len() <= 100);
+        }
+        assert_eq!(queue.len(), 100);
+        // Every evicted note message comes out as a note-off; CCs just go.
+        assert!(evicted.iter().all(|d| d == &vec![0x80, 60, 0]));
+        assert_eq!(evicted.len(), 2 * 9_900 / 3);
+    }
+
+    #[test]
+    fn echoes_come_out_one_delay_per_repeat() {
+        let mut echoes: Echoes =
+            Echoes::new(EchoShape { repeats: 2, decay: 0.5, transpose: 0 }, 4096);
+        let t0: Instant = Instant::now();
+        let ms = |ms: u64| t0 + Duration::from_millis(ms);
+        let delay: Duration = Duration::from_millis(100);
+        assert!(echoes.receive(&[0x90, 60, 100], ms(0), delay).is_empty());
+        assert!(echoes.receive(&[0x80, 60, 0], ms(30), delay).is_empty());
+        assert!(echoes.due(ms(99)).is_empty());
+        assert_eq!(echoes.due(ms(100)), vec![vec![0x90, 60, 50]]);
+        assert_eq!(echoes.due(ms(200)), vec![vec![0x90, 60, 25], vec![0x80, 60, 0]]);
+        assert_eq!(echoes.due(ms(300)), vec![vec![0x80, 60, 0]]);
+        assert!(echoes.queue.is_empty());
+    }
+
+    #[test]
+    fn closing_the_input_drops_waiting_echoes_and_silences() {
+        let echoes: Echoes = Echoes::new(EchoShape { repeats: 1, decay: 1.0, transpose: 0 }, 4096);
+        let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+        tx.send(vec![0x90, 60, 100]).unwrap();
+        drop(tx);
+        let mut sent: Vec<Vec<u8>> = vec![];
+        let delay: Arc<Mutex<Duration>> = Arc::new(Mutex::new(Duration::from_secs(60)));
+        run_echo_thread(&mut sent, rx, echoes, delay);
+        assert_eq!(sent, (0..16).map(all_notes_off).collect::<Vec<Vec<u8>>>());
+    }
+
+    #[test]
+    fn routing_takes_the_delay_controls_out() {
+        let control: DelayControl = DelayControl {
+            cc: 20, min: Duration::from_millis(50), max: Duration::from_millis(1000) };
+        let mut tap: TapTempo = TapTempo { note: 36, taps: Vec::new() };
+        let t0: Instant = Instant::now();
+        assert_eq!(route(Some(&control), Some(&mut tap), &[0xB0, 20, 0], t0),
+                   Routing::SetDelay(Duration::from_millis(50)));
+        assert_eq!(route(Some(&control), Some(&mut tap), &[0x90, 36, 100], t0), Routing::Swallow);
+        assert_eq!(route(Some(&control), Some(&mut tap), &[0x80, 36, 0], t0), Routing::Swallow);
+        assert_eq!(route(None, Some(&mut tap), &[0x90, 36, 100], t0 + Duration::from_millis(400)),
+                   Routing::SetDelay(Duration::from_millis(400)));
+        assert_eq!(route(Some(&control), Some(&mut tap), &[0x90, 60, 100], t0), Routing::Pass);
+        assert_eq!(route(None, None, &[0xB0, 20, 0], t0), Routing::Pass);
+    }
+
+    #[test]
+    fn only_notes_in_the_echo_range_echo() {
+        let range: RangeInclusive<u8> = 60..=96;
+        assert!(echoed(Some(&range), &[0x90, 60, 100]));
+        assert!(!echoed(Some(&range), &[0x90, 40, 100]));
+        assert!(!echoed(Some(&range), &[0x80, 40, 0]));
+        assert!(echoed(Some(&range), &[0xB0, 64, 127]));
+        assert!(echoed(None, &[0x90, 40, 100]));
+    }
+
+    #[test]
+    fn repeats_climb_fade_and_stop_at_the_top() {
+        let shape: EchoShape = EchoShape { repeats: 3, decay: 0.5, transpose: 12 };
+        assert_eq!(repeats(&shape, &[0x90, 90, 100]),
+                   vec![(1, vec![0x90, 102, 50]), (2, vec![0x90, 114, 25]), (3, vec![0x90, 126, 13])]);
+        assert_eq!(repeats(&shape, &[0x80, 100, 64]),
+                   vec![(1, vec![0x80, 112, 64]), (2, vec![0x80, 124, 64])]);
+        assert_eq!(repeats(&shape, &[0xB0, 64, 127]), vec![(1, vec![0xB0, 64, 127])]);
+        let quiet: EchoShape = EchoShape { repeats: 2, decay: 0.0, transpose: -60 };
+        assert_eq!(repeats(&quiet, &[0x90, 60, 100]), vec![(1, vec![0x90, 0, 1])]);
+    }
+}
@@ -4,8 +4,31 @@
 //!
 //! ```sh
 //! cargo run --bin polite_ping
+//! cargo run --bin polite_ping -- --note 60 --velocity 90 --on-ms 50 --off-ms 450
 //! ```
 //!
+//! Flags:
+//! - `--note <note>`: which note, as a number or a name like F#6 (default 96, C7)
+//! - `--velocity <1-127>`: how hard (default 10, quiet)
+//! - `--accents <v1,v2,...>`: instead of one velocity, cycle through these,
+//!   e.g. `127,80,80,80` makes every fourth pulse louder
+//! - `--channel <0-15>`: which channel (default 0, i.e. MIDI channel 1)
+//! - `--on-ms <ms>`: how long each note lasts (default 100)
+//! - `--off-ms <ms>`: the gap before the next one (default 200)
+//! - `--swing <0.0-0.75>`: delays every second pulse, so the gap before it
+//!   grows and the one after shrinks by that fraction of a pulse period
+//!   (on + off). 0 is straight, about 0.33 a triplet shuffle, 0.5 a hard swing.
+//! - `--clock-bpm <bpm>`: also act as a MIDI clock master, sending Start,
+//!   then 24 clocks per quarter note at that tempo, then Stop on Ctrl+C.
+//!   Clocks are timed against the start time, so they don't drift.
+//! - `--no-notes`: with `--clock-bpm`, send only the clock
+//!
+//! `--output-port <substring>` sends to an existing port
+//! whose name contains the substring, instead of creating a virtual one;
+//! `--connect-to <substring>` keeps the virtual one and connects it there.
+//! `--list-ports` prints the available ports and exits.
+//! For `--self-test`, see `midi_utils::ports`.
+//!
 //! # Where to see it in QJackCtl
 //! Claude wrote this. I haven't got it to work, but I haven't tried much. See the USAGE section of orientation.org for what I've been doing.
 //!
@@ -21,32 +44,235 @@
 //! ```
 
 use midir::MidiOutput;
-use midir::os::unix::VirtualOutput;
-use std::{thread, time::Duration};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_output, self_test};
+use midi_utils::timing::{parse_swing, swung_offset};
+use midi_utils::{note_off, note_on, CLOCKS_PER_QUARTER, START, STOP, TIMING_CLOCK};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::thread;
+
+const STOP_CHECK_MS: u64 = 20;
+
+struct Pulse {
+  note: u8,
+  velocities: Vec<u8>, // one per pulse, cycled
+  channel: u8,
+  on: Duration,
+  off: Duration,
+  swing: f64,
+}
+
+impl Pulse {
+  fn from_args(args: &Args) -> Result<Self, String> {
+    let pulse: Pulse = Pulse {
+      note: args.note_or("--note", 96)?,       // C7 - high note
+      velocities: match args.value("--accents") {
+        Some(list) => list.split(',')
+          .map(|v| parse_value("--accents", v.trim()))
+          .collect::<Result<Vec<u8>, String>>()?,
+        None => vec![args.parse_or("--velocity", 10)?], // quiet
+      },
+      channel: args.parse_or("--channel", 0)?,  // channel 1
+      on: Duration::from_millis(args.parse_or("--on-ms", 100)?),
+      off: Duration::from_millis(args.parse_or("--off-ms", 200)?),
+      swing: parse_swing(args)?,
+    };
+    if pulse.velocities.iter().any(|v| !(1..=127).contains(v)) {
+      return Err("velocities must be in 1-127 (0 would be a note-off)".to_string()); }
+    if pulse.channel > 15 {
+      return Err("--channel must be in 0-15".to_string()); }
+    if pulse.on.is_zero() {
+      return Err("--on-ms must be at least 1".to_string()); }
+    if pulse.on > pulse.period().mul_f64(1.0 - pulse.swing) {
+      return Err("--on-ms is too long to fit between swung pulses".to_string()); }
+    Ok(pulse)
+  }
+
+  fn period(&self) -> Duration {
+    self.on + self.off
+  }
+
+  /// When the `n`th pulse (counting from 0) starts, relative to the first.
+  fn start_time(&self, n: u64) -> Duration {
+    swung_offset(n, self.period(), self.swing)
+  }
+
+  /// The velocity of the `n`th pulse, counting from 0.
+  fn velocity(&self, n: u64) -> u8 {
+    self.velocities[(n % self.velocities.len() as u64) as usize]
+  }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports(); }
+  let pulse: Pulse = Pulse::from_args(&args)?;
+  let clock_bpm: Option<f64> = args.parse("--clock-bpm")?;
+  if clock_bpm.is_some_and(|bpm| bpm <= 0.0 || !bpm.is_finite()) {
+    return Err("--clock-bpm must be positive".into()); }
+  let notes: bool = !args.flag("--no-notes");
+  if !notes && clock_bpm.is_none() {
+    return Err("--no-notes only makes sense with --clock-bpm".into()); }
   let midi_out: MidiOutput = MidiOutput::new("polite-ping")?;
 
   // Create a virtual output port (appears in ALSA/JACK)
-  let mut conn: midir::MidiOutputConnection = midi_out.create_virtual("pulse-out")?;
+  let mut conn: midir::MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "pulse-out")?;
+  self_test(&args, &mut conn, "pulse-out");
 
   println!("Created virtual MIDI port 'polite-ping:pulse-out'");
   println!("Look for 'polite-ping' in QJackCtl's ALSA tab or aconnect -l");
-  println!("Sending note 96 (C7), velocity 10, every 300ms. Ctrl+C to stop.");
+  auto_connect(&args, None, Some("polite-ping:pulse-out"));
+  if notes {
+    println!("Sending note {}, velocities {:?}, channel {}: on {:?}, off {:?}, swing {}.",
+             pulse.note, pulse.velocities, pulse.channel, pulse.on, pulse.off, pulse.swing); }
+  if let Some(bpm) = clock_bpm {
+    println!("Sending MIDI clock at {} BPM.", bpm); }
+  println!("Ctrl+C to stop.");
+
+  let stop: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+  let stop_for_handler: Arc<AtomicBool> = Arc::clone(&stop);
+  ctrlc::set_handler(move || stop_for_handler.store(true, Ordering::SeqCst))?;
 
-  let note: u8 = 96;    // C7 - high note
-  let velocity: u8 = 10;  // quiet
-  let channel: u8 = 0;  // channel 1
+  run_pulses(&pulse, clock_bpm, notes, &stop, |message: &[u8]| conn.send(message))?;
+  println!("Stopped.");
+  Ok(())
+}
 
-  loop {
-    // Note on: 0x90 + channel, note, velocity
-    conn.send(&[0x90 | channel, note, velocity])?;
+/// Sends pulses (and clock, if `clock_bpm` is set) until `stop`.
+/// Everything is scheduled against one start time, sleeping only until
+/// the next due event, so neither sleep overshoot nor the time `send`
+/// takes accumulates into drift.
+fn run_pulses<E>(
+  pulse: &Pulse,
+  clock_bpm: Option<f64>,
+  notes: bool,
+  stop: &AtomicBool,
+  mut send: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+  let start: Instant = Instant::now();
+  let mut next_clock: u64 = 0;
+  let mut next_toggle: Instant = start;
+  let mut sounding: bool = false;
+  let mut pulses: u64 = 0;
+  if clock_bpm.is_some() {
+    send(&[START])?; }
 
-    thread::sleep(Duration::from_millis(100));
+  while !stop.load(Ordering::SeqCst) {
+    let clock_due: Option<Instant> =
+      clock_bpm.map(|bpm| start + clock_time(next_clock, bpm));
+    let toggle_due: Option<Instant> = notes.then_some(next_toggle);
+    let due: Instant = match (clock_due, toggle_due) {
+      (Some(c), Some(t)) => c.min(t),
+      (Some(c), None) => c,
+      (None, Some(t)) => t,
+      (None, None) => break,
+    };
+    let now: Instant = Instant::now();
+    if due > now {
+      thread::sleep((due - now).min(Duration::from_millis(STOP_CHECK_MS)));
+      continue; }
+    if clock_due.is_some_and(|c| c <= now) {
+      send(&[TIMING_CLOCK])?;
+      next_clock += 1; }
+    if toggle_due.is_some_and(|t| t <= now) {
+      if sounding {
+        send(&note_off(pulse.channel, pulse.note, 0))?;
+        next_toggle = start + pulse.start_time(pulses);
+      } else {
+        send(&note_on(pulse.channel, pulse.note, pulse.velocity(pulses)))?;
+        next_toggle = start + pulse.start_time(pulses) + pulse.on;
+        pulses += 1; }
+      sounding = !sounding; }
+  }
 
-    // Note off: 0x80 + channel, note, velocity
-    conn.send(&[0x80 | channel, note, 0])?;
+  if sounding {
+    send(&note_off(pulse.channel, pulse.note, 0))?; }
+  if clock_bpm.is_some() {
+    send(&[STOP])?; }
+  Ok(())
+}
+
+/// When the `n`th clock (counting from 0) is due, relative to the start:
+/// n * 60_000_000 / (bpm * 24) microseconds.
+fn clock_time(n: u64, bpm: f64) -> Duration {
+  Duration::from_secs_f64(n as f64 * 60.0 / (bpm * CLOCKS_PER_QUARTER as f64))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(list: &[&str]) -> Args {
+    Args::new(list.iter().map(|a| a.to_string()).collect())
+  }
+
+  #[test]
+  fn defaults_match_the_old_fixed_pulse() {
+    let pulse: Pulse = Pulse::from_args(&args(&[])).unwrap();
+    assert_eq!((pulse.note, pulse.velocities.clone(), pulse.channel), (96, vec![10], 0));
+    assert_eq!((pulse.on, pulse.off), (Duration::from_millis(100), Duration::from_millis(200)));
+  }
+
+  #[test]
+  fn accents_cycle() {
+    let pulse: Pulse = Pulse::from_args(&args(&["--accents", "127, 80,80,80"])).unwrap();
+    let velocities: Vec<u8> = (0..6).map(|n| pulse.velocity(n)).collect();
+    assert_eq!(velocities, vec![127, 80, 80, 80, 127, 80]);
+  }
+
+  #[test]
+  fn swing_must_leave_room_for_the_note() {
+    assert!(Pulse::from_args(&args(&["--swing", "0.5"])).is_ok());
+    assert!(Pulse::from_args(&args(&["--swing", "0.7"])).is_err()); // 90ms gap < 100ms note
+    let pulse: Pulse = Pulse::from_args(&args(&["--swing", "0.5"])).unwrap();
+    assert_eq!(pulse.start_time(1), Duration::from_millis(450));
+    assert_eq!(pulse.start_time(2), Duration::from_millis(600));
+  }
+
+  #[test]
+  fn pulses_do_not_drift() {
+    let pulse: Pulse = Pulse::from_args(&args(&["--on-ms", "1", "--off-ms", "2"])).unwrap();
+    let stop: AtomicBool = AtomicBool::new(false);
+    let start: Instant = Instant::now();
+    let mut note_ons: Vec<Duration> = Vec::new();
+    run_pulses(&pulse, None, true, &stop, |message: &[u8]| {
+      if message[0] == 0x90 {
+        note_ons.push(start.elapsed());
+        thread::sleep(Duration::from_micros(500)); // a slow send
+        if note_ons.len() == 100 {
+          stop.store(true, Ordering::SeqCst);
+        }
+      }
+      Ok::<(), ()>(())
+    }).unwrap();
+    // Summing sleeps would put the 100th pulse at least 50ms late by now.
+    let late: Duration = note_ons[99].saturating_sub(pulse.start_time(99));
+    assert!(late < Duration::from_millis(25), "100th pulse {:?} late", late);
+    assert!(note_ons.iter().enumerate().all(|(n, &t)| t >= pulse.start_time(n as u64)));
+  }
+
+  #[test]
+  fn clock_runs_at_24_per_quarter() {
+    assert_eq!(clock_time(0, 120.0), Duration::ZERO);
+    assert_eq!(clock_time(24, 120.0), Duration::from_millis(500));
+    assert_eq!(clock_time(96 * 1000, 120.0), Duration::from_secs(2000)); // no drift
+    assert_eq!(clock_time(1, 125.0), Duration::from_micros(20_000));
+  }
 
-    thread::sleep(Duration::from_millis(200));
+  #[test]
+  fn out_of_range_values_are_rejected() {
+    assert!(Pulse::from_args(&args(&["--note", "128"])).is_err());
+    assert!(Pulse::from_args(&args(&["--velocity", "0"])).is_err());
+    assert!(Pulse::from_args(&args(&["--accents", "127,0,80"])).is_err());
+    assert!(Pulse::from_args(&args(&["--accents", "127,,80"])).is_err());
+    assert!(Pulse::from_args(&args(&["--channel", "16"])).is_err());
+    assert!(Pulse::from_args(&args(&["--on-ms", "0"])).is_err());
+    assert!(Pulse::from_args(&args(&["--channel=15", "--off-ms", "0"])).is_ok());
   }
 }
@@ -7,13 +7,42 @@
 //! Be sure the 'const' definitions in the code make sense --
 //! they depend on the synth being used.
 //!
+//! Flags:
+//! - `--extra-input <substring>`, repeatable: connects another existing
+//!   port as well, for a second controller. Their messages are merged in
+//!   the order they reach edo72 (roughly the order they were played), and
+//!   share one tuning: a shift key held on one keyboard shifts notes
+//!   played on the other. Only `--input-port` is watched for going away.
+//! - `--extra-output <substring>`, repeatable: also sends everything
+//!   edo72 produces to the first existing port whose name contains it,
+//!   as well as to the main output: to record the transformed stream,
+//!   say, or watch it in a monitor, while hearing it.
+//! - `--min-gap-us <n>`: keeps at least n microseconds (up to 10000)
+//!   between messages sent, holding back any that would come sooner,
+//!   for synths that drop some of several messages arriving at once,
+//!   as the note-off and note-on of a retune do. Default 0, no gap;
+//!   a few hundred is usually enough, and too short to hear.
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`: see `midi_utils::ports`
+//! - `--zero-release-velocity`: see `midi_utils::sink::ReleaseVelocity`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+//!
+//! # NOTE NAMES
+//! Note names here and in the code's comments take middle C (60) to be C4,
+//! so the piano's lowest A (21) is A0 and its top C (108) is C8.
+//! `--offset-octave-start` takes a note number or a name; names follow
+//! `--octave-convention` (C3, C4 or C5, the name of note 60, default C4),
+//! but the comments stay in C4 = 60 whatever it says.
+//!
 //! # PURPOSE
 //! Transforms piano notes into multi-channel output for 72-EDO tuning.
+//! The transform itself is `midi_utils::edo72`'s `Edo72Transformer`,
+//! so another host can embed it without this binary's ports and threads.
 //! For this first pass, uses every 6th note (so really 12-EDO).
-//! For each piano note (21-96):
-//! - Subtract lowest A (21) to get 0-75
+//! For each piano note (21-95):
+//! - Subtract lowest A (21) and add the transpose (see below)
 //! - divmod by 12: quotient -> channel offset,
-//!                 remainder -> note offset
+//!   remainder -> note offset
 //! - Add those offsets to min_channel and min_note
 //!   (The earlier channel value is discarded.)
 //!
@@ -21,88 +50,239 @@
 //! The top octave (notes 97-108, C#7 to C8) controls microtonal offset:
 //! - F#7 (102) = 0 offset (12-EDO)
 //! - G7 (103) = +1, G#7 = +2, ... C8 (108) = +6
-//! - F7 (101) = -1, E7 = -2, ... C#7 (97) = -5
+//! - F7 (101) = -1, E7 = -2, ... D7 (98) = -4
+//!
 //! This offset is added to the output note, shifting all played notes.
+//!
+//! `--offset-octave-start <note>` moves that whole zone: the reset key,
+//! with the 11 shift keys above it (0 offset at the 6th key),
+//! and the print key just below it. Keys outside the zone play as usual.
+//! With `--control-channel <0-15>`, only notes arriving on that channel
+//! control anything, so a second controller (or a keyboard split)
+//! can drive the tuning while the whole main keyboard stays playable.
+//! Without it, the zone must lie above the lowest A (21).
+//!
+//! # OCTAVE KEYS
+//! `--octave-down-key <note>` and `--octave-up-key <note>` name two keys
+//! that move the whole keyboard down or up an octave (12 input keys,
+//! up to 4 octaves either way), to reach below or above the piano.
+//! It happens before anything else, so it composes with the
+//! microtonal shifts, which follow pitch class and so carry over.
+//! Like the tuning controls, they listen only on `--control-channel`
+//! if it is given, and aren't passed on. A note held through an octave
+//! change keeps sounding where it began, and its note-off goes there.
+//!
+//! # TRANSPOSE
+//! `--transpose <semitones>` (default SHIFT_IN_12_EDO, -5) is added to
+//! every key after the lowest A (LOWEST_A, 21) is subtracted, so the key
+//! that lands on MIN_CHANNEL's MIN_NOTE is LOWEST_A minus the transpose
+//! (26, D1, by default), and every key sounds that many semitones from
+//! its usual pitch. The two together are one transpose: moving LOWEST_A
+//! up a semitone is the same as moving the transpose down one.
+//! Unlike the octave keys it moves the channel layout itself, so it also
+//! changes which channels the keyboard covers.
+//! With `--transpose-cc <n>`, that controller sets it while playing:
+//! value 64 is 0, and each step above or below is a semitone, from -64
+//! to +63. The controller listens only on `--control-channel` if it is
+//! given, and isn't passed on. Unlike the octave keys, it moves held
+//! notes too, as a reset does: each is ended and struck again where its
+//! key now lands (in MTS mode, just retuned where it sounds).
+//!
+//! C#7 (97) resets the tuning: it forgets every accumulated
+//! pitch-class shift, returning to 12-EDO,
+//! and (if RETUNE_HELD_ON_RESET) re-sends any held notes at the new pitch.
+//!
+//! # TUNING TABLE
+//! C7 (96) prints the current tuning to stdout:
+//! the held shift keys, and for each pitch class
+//! its accumulated shift (in 72-EDO steps and in cents)
+//! and where that pitch class in the middle octave (C4-B4) is sent.
+//!
+//! # MTS MODE
+//! With `--mode mts`, nothing is split across channels. Instead every
+//! note goes out on one channel (`--mts-channel`, default MIN_CHANNEL)
+//! with the note number of the key that was pressed, preceded by a
+//! MIDI Tuning Standard single-note tuning change (real-time SysEx,
+//! F0 7F 7F 08 02 ...) that tunes that note number to the exact pitch
+//! the channel layout would have given it. So the synth must support
+//! real-time MTS, on tuning program 0. A reset retunes held notes
+//! in place rather than restriking them.
+//! Pitches in MTS mode are counted in 12-EDO semitones above MIDI note 0,
+//! with the lowest A at its usual 21 and the transpose applied,
+//! so unshifted keys sound the transpose's semitones from their
+//! usual pitch. A pitch outside 0-127 semitones is dropped;
+//! `--out-of-range` applies only to the channel layout.
+//!
+//! # OUT-OF-RANGE NOTES
+//! Keys far enough below the piano, or shifted far enough,
+//! would land outside MIDI's channels 0-15 or notes 0-127.
+//! `--out-of-range` says what happens to them:
+//! - `drop` (the default): they are silent.
+//! - `fold`: they move by whole octaves until they fit,
+//!   keeping their pitch class.
+//!   (Each channel is an octave, and 72 notes make an octave,
+//!   so a note above 127 moves down 72 onto the next channel,
+//!   and a channel above 15 becomes 15, an octave or more lower.)
+//! - `clamp`: the channel and note are each pinned to the nearest
+//!   legal value, so the pitch is wrong but something sounds.
+//!
+//! At startup, edo72 warns of any piano keys that MIN_CHANNEL, MIN_NOTE
+//! and the transpose already put out of range, and of those the largest
+//! single shift key either way would, so a badly chosen MIN_NOTE shows
+//! up before a key goes quiet.
+//!
+//! # VELOCITY GAIN
+//! Spread across channels, notes can come out louder on some
+//! than on others. `--channel-gain <channel>:<gain>`, repeatable,
+//! scales the velocity of note-ons sent on that output channel
+//! (e.g. `--channel-gain 3:1.1`), keeping it within 1-127.
+//! Note-off velocities are left alone.
+//!
+//! # OTHER MESSAGES
+//! Since the notes are spread across channels,
+//! channel-wide messages would otherwise reach only one of them.
+//! - CC (including the sustain pedal, CC 64), program change,
+//!   channel pressure and pitch bend are copied
+//!   to every channel the playable keys can land on.
+//! - Poly aftertouch follows its note to the output channel and note
+//!   that the note-on was sent to, and is dropped if that note isn't held.
+//! - System messages pass through unchanged.
 
 use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
-use midir::os::unix::{VirtualInput, VirtualOutput};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::edo72::{output_channels, unreachable_key_warnings, ControlZone, Edo72Transformer,
+                        Notice, OutOfRange, OutputMode, MIN_CHANNEL, MIN_NOTE,
+                        SHIFT_IN_12_EDO};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_extra_inputs, open_input, open_output,
+                        self_test, silence_after, wait_for_exit, Exit};
+use midi_utils::running_status::RunningStatus;
+use midi_utils::sink::{Broadcast, MinGap, ReleaseVelocity};
+use midi_utils::sync::lock;
+use midi_utils::watchdog::{relay, Watchdog};
 use std::collections::HashMap;
-use std::sync::mpsc;
-use std::sync::{Mutex, OnceLock};
-use std::{io, thread};
+use std::ops::RangeInclusive;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-struct TransformedNote {
-  output_channel: u8,
-  output_note: u8,
-}
-
-struct ShiftPress {
-  input_note: u8,
-  shift_value: i8,
-}
-
-fn ongoing_notes(
-) -> &'static Mutex<HashMap<u8, TransformedNote>> {
-  static ONGOING: OnceLock<Mutex<HashMap<u8, TransformedNote>>> =
-    OnceLock::new();
-  ONGOING.get_or_init(
-    || Mutex::new(HashMap::new() )) }
-
-fn ongoing_shifts(
-) -> &'static Mutex<HashMap<u8, ShiftPress>> {
-  static ONGOING: OnceLock<Mutex<HashMap<u8, ShiftPress>>> =
-    OnceLock::new();
-  ONGOING.get_or_init(
-    || Mutex::new(HashMap::new() )) }
-
-fn pitch_class_shifts(
-) -> &'static Mutex<HashMap<u8, i8>> {
-  static SHIFTS: OnceLock<Mutex<HashMap<u8, i8>>> =
-    OnceLock::new();
-  SHIFTS.get_or_init(
-    || Mutex::new(HashMap::new() )) }
-
-fn current_total_shift() -> Option<i16> {
-  let shifts = ongoing_shifts() . lock() . unwrap();
-  if shifts . is_empty()
-  { None
-  } else { Some( shifts . values() . map(
-                   |s| s . shift_value as i16)
-                 . sum( )) }}
-
-const SHIFT_IN_12_EDO : i8 = -5;  // Added to the MIDI note before processing.
-const LOWEST_A        : u8 = 21;  // A0, lowest note on 88-key piano
-const MIN_CHANNEL     : u8 = 1;   // adjust for whatever the synth wants
-const MIN_NOTE        : u8 = 28;  // could also be adjusted for the synth. I like to adjust the synth for this instead, though, because 28 = (128 - 72) / 2 puts the notes closest to the middle of the range [0,127], which makes future MIDI edits less constrained -- plenty of room to adjust up or down in either direction without switching channels.
-const EDO_OVER_12     : u8 = 6;   // 72 / 12 = 6
-const OFFSET_OCTAVE_START: u8 = 97;  // C#7 - first note of offset control octave (top 12 keys)
-const OFFSET_ZERO_NOTE   : u8 = 102; // F#7 - this note means offset = 0
+const MAX_MIN_GAP_US : u64 = 10_000; // past this, --min-gap-us would be heard
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports(); }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let out_of_range: OutOfRange =
+    args.parse_or("--out-of-range", OutOfRange::Drop)?;
+  let controls: ControlZone = ControlZone::from_args(&args)?;
+  let channel_gains: HashMap<u8, f64> =
+    args.values("--channel-gain") . iter()
+    . map( |g| parse_channel_gain(g))
+    . collect::<Result<HashMap<u8, f64>, String>>()?;
+  let mode: OutputMode = args.parse_or("--mode", OutputMode::Channels)?;
+  let mts_channel: u8 = args.parse_or("--mts-channel", MIN_CHANNEL)?;
+  if mts_channel > 15 {
+    return Err("--mts-channel must be in 0-15".into()); }
+  let transpose: i8 = args.parse_or("--transpose", SHIFT_IN_12_EDO)?;
+  if !(-64..=63).contains(&transpose) {
+    return Err("--transpose must be in -64-63".into()); }
+  let min_gap_us: u64 = args.parse_or("--min-gap-us", 0)?;
+  if min_gap_us > MAX_MIN_GAP_US {
+    return Err(format!("--min-gap-us must be at most {}", MAX_MIN_GAP_US).into()); }
   let midi_in: MidiInput =
     MidiInput::new("edo72-in")?;
   let midi_out: MidiOutput =
     MidiOutput::new("edo72-out")?;
-  let conn_out: MidiOutputConnection =
-    midi_out.create_virtual("out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "out")?;
+  self_test(&args, &mut conn_out, "out");
+  let mut conns_out: Vec<MidiOutputConnection> = vec![conn_out];
+  for (i, name) in args.values("--extra-output").into_iter().enumerate() {
+    let midi_out: MidiOutput =
+      MidiOutput::new(&format!("edo72-out-{}", i + 2))?;
+    let mut conn: MidiOutputConnection =
+      open_output(midi_out, Some(name), "out")?;
+    self_test(&args, &mut conn, name);
+    conns_out.push(conn); }
   let (tx, rx): (mpsc::Sender<Vec<u8>>,
                  mpsc::Receiver<Vec<u8>>) = mpsc::channel();
-  let _out_thread: thread::JoinHandle<()> =
+  let conn_out: ReleaseVelocity<MinGap<Broadcast<MidiOutputConnection>>> =
+    ReleaseVelocity { sink: MinGap::new(Broadcast(conns_out),
+                                        Duration::from_micros(min_gap_us)),
+                      zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
     thread::spawn(move || {
-      run_output_thread(conn_out, rx); });
-  let _conn_in: MidiInputConnection<()> =
-    midi_in.create_virtual(
+      relay(conn_out, rx, watchdog); });
+  let mut transformer: Edo72Transformer = Edo72Transformer::new();
+  transformer.out_of_range = out_of_range;
+  transformer.controls = controls;
+  transformer.channel_gains = channel_gains;
+  transformer.mode = mode;
+  transformer.mts_channel = mts_channel;
+  transformer.transpose = transpose;
+  let state: Arc<Mutex<Edo72Transformer>> = Arc::new(Mutex::new(transformer));
+  let conn_in: MidiInputConnection<RunningStatus> =
+    open_input(
+      midi_in,
+      args.value("--input-port"),
       "in",
-      move |_timestamp: u64, message: &[u8], _: &mut ()| {
-        for msg in transform_message(message) {
-          let _ = tx.send(msg); }},
-      () )?;
-  print_startup_message();
-  let mut input: String = String::new();
-  io::stdin().read_line(&mut input)?;
+      input_callback(Arc::clone(&state), tx.clone()),
+      RunningStatus::new() )?;
+  let extra_ins: Vec<MidiInputConnection<RunningStatus>> =
+    open_extra_inputs(&args, "edo72-in", "in", || {
+      (input_callback(Arc::clone(&state), tx.clone()),
+       RunningStatus::new()) })?;
+  print_startup_message(&lock(&state));
+  auto_connect(&args, Some("edo72-in:in"), Some("edo72-out:out"));
+  println!("Press Enter to exit...");
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, (conn_in, extra_ins), None, tx, out_thread);
   Ok (( )) }
 
-fn print_startup_message() {
+/// What each input connection does with a message. Every input gets
+/// one, each with its own running status, all sharing the one state.
+fn input_callback(
+  state: Arc<Mutex<Edo72Transformer>>,
+  tx: mpsc::Sender<Vec<u8>>
+) -> impl FnMut(u64, &[u8], &mut RunningStatus) + Send + 'static {
+  move |_timestamp: u64, message: &[u8], running_status: &mut RunningStatus| {
+    let message: Vec<u8> = running_status.expand(message);
+    let mut state = lock(&state);
+    for msg in state.process(&message) {
+      let _ = tx.send(msg); }
+    for notice in state.take_notices() {
+      print_notice(&notice); }}}
+
+/// Reports what the player changed.
+fn print_notice(
+  notice: &Notice
+) {
+  match notice {
+    Notice::TuningTable(table) => print!("[edo72] Tuning table\n{}", table),
+    Notice::OctaveShift(shift) => println!("[edo72] Octave shift: {:+}", shift),
+    Notice::Transpose(transpose) => println!("[edo72] Transpose: {:+}", transpose),
+    Notice::TuningReset => println!("[edo72] Tuning reset to 12-EDO"), }}
+
+/// Parses `<channel>:<gain>`, as in `--channel-gain 3:1.1`.
+fn parse_channel_gain(
+  text: &str
+) -> Result<(u8, f64), String> {
+  let (channel, gain): (&str, &str) = text.split_once(':')
+    .ok_or(format!("bad channel gain '{}', expected <channel>:<gain>",
+                   text))?;
+  let channel: u8 = parse_value("--channel-gain", channel)?;
+  let gain: f64 = parse_value("--channel-gain", gain)?;
+  if channel > 15 || !gain.is_finite() || gain <= 0.0 {
+    return Err(format!("bad channel gain '{}': channel must be 0-15 \
+                        and gain positive", text)); }
+  Ok((channel, gain)) }
+
+fn print_startup_message(
+  state: &Edo72Transformer
+) {
+  let controls: ControlZone = state.controls;
   println!("72-EDO transformer started!");
   println!();
   println!("Virtual ports created:");
@@ -112,126 +292,60 @@ fn print_startup_message() {
   println!("Config:");
   println!("  - min_channel: {}", MIN_CHANNEL);
   println!("  - min_midi_note: {}", MIN_NOTE);
-  println!("  - offset control: notes {}-108 (F#7=0)",
-           OFFSET_OCTAVE_START);
+  println!("  - offset control: notes {}-{} ({}=0)",
+           controls.start + 1, controls.start + 11, controls.zero_note());
+  println!("  - reset tuning: note {}", controls.reset_note());
+  println!("  - print tuning table: note {}", controls.print_note());
+  for (name, key) in [("down", controls.octave_down), ("up", controls.octave_up)] {
+    if let Some(key) = key {
+      println!("  - octave {}: note {}", name, key); }}
+  println!("  - transpose: {:+} semitones", state.transpose);
+  if let Some(cc) = controls.transpose_cc {
+    println!("  - transpose control: CC {}", cc); }
+  match controls.channel {
+    Some(channel) => println!("  - controls listen on channel {} only", channel),
+    None => println!("  - controls listen on every channel"), }
+  println!("  - out-of-range notes: {:?}", state.out_of_range);
+  let mut gains: Vec<(&u8, &f64)> = state.channel_gains.iter().collect();
+  gains.sort_by_key(|(channel, _)| **channel);
+  for (channel, gain) in gains {
+    println!("  - channel {} velocity gain: {}", channel, gain); }
+  let channels: RangeInclusive<u8> = output_channels(state);
+  if state.mode == OutputMode::Mts {
+    println!("  - MTS mode: notes retuned by SysEx on channel {}", state.mts_channel); }
+  println!("  - CC, program change and pitch bend go to channels {}-{}",
+           channels.start(), channels.end());
+  for warning in unreachable_key_warnings(state) {
+    eprintln!("Warning: {}", warning); }
   println!();
-  println!("Press Enter to exit...");
 }
 
-fn run_output_thread(
-  mut conn: MidiOutputConnection,
-  rx: mpsc::Receiver<Vec<u8>>)
-{ while let Ok(data) = rx.recv() {
-    let _ = conn.send(&data); }}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use midi_utils::harness::messages;
 
-fn transform_message(
-  message: &[u8]
-) -> Vec<Vec<u8>> {
-  if message.len() < 2 {
-    return vec![message.to_vec()]; }
-  let status: u8 = message[0] & 0xF0;
-  if message.len() < 3 ||
-    ! ( status == 0x80 || status == 0x90)
-  { // Not a note event, so pass through unchanged.
-    return vec![message.to_vec()]; }
-  let original_note: u8 = message[1];
-  let velocity: u8 = message[2];
-  if original_note >= OFFSET_OCTAVE_START {
-    handle_offset_control(
-      status, velocity, original_note)
-  } else {
-    handle_regular_note(
-      status, velocity, original_note) }}
+  #[test]
+  fn channel_gains_are_parsed() {
+    assert_eq!(parse_channel_gain("3:1.1"), Ok((3, 1.1)));
+    assert!(parse_channel_gain("16:1.0").is_err());
+    assert!(parse_channel_gain("3:0").is_err());
+    assert!(parse_channel_gain("3").is_err()); }
 
-/// Modifies the set of shifts.
-fn handle_offset_control(
-  status: u8,
-  velocity: u8,
-  input_note: u8
-) -> Vec<Vec<u8>> {
-  // Top octave controls the offset (F#7 = 0, G7 = +1, F7 = -1, etc.)
-  // Total shift = sum of all held shift notes.
-  let is_note_on: bool =
-    status == 0x90 && velocity > 0;
-  let is_note_off: bool =
-    status == 0x80 || (status == 0x90 && velocity == 0);
-  let mut shifts = ongoing_shifts().lock().unwrap();
-  if is_note_on {
-    let shift_value: i8 = input_note as i8
-                          - OFFSET_ZERO_NOTE as i8;
-    shifts.insert(input_note,
-                  ShiftPress { input_note, shift_value });
-  } else if is_note_off {
-    shifts.remove(&input_note); }
-  vec![] } // don't pass through offset control notes
-
-fn handle_regular_note(
-  status: u8,
-  velocity: u8,
-  original_note: u8
-) -> Vec<Vec<u8>> {
-  let is_note_on: bool =
-    status == 0x90 && velocity > 0;
-  let is_note_off: bool =
-    status == 0x80 || (status == 0x90 && velocity == 0);
-  if is_note_on {
-    // Update the persistent pitch class shift before transformation,
-    // but only if shift keys are being held (we find a Some).
-    if let Some(total_shift) = current_total_shift() {
-      let pitch_class: u8 = original_note % 12;
-      pitch_class_shifts().lock().unwrap()
-        .insert(pitch_class, total_shift as i8); }}
-  let (new_channel, new_note): (i16, i16) =
-    edo72_instruction(original_note);
-  let output_in_range: bool = // what the MIDI standard allows
-    new_channel >= 0 && new_channel <= 15 &&
-    new_note >= 0 && new_note <= 127;
-  let mut results: Vec<Vec<u8>> = vec![];
-  let mut ongoing = ongoing_notes().lock().unwrap();
-  if is_note_on {
-    if let Some(old) = ongoing.get(&original_note) {
-      // The input note is already playing.
-      if !output_in_range ||
-         old.output_channel != new_channel as u8 ||
-         old.output_note != new_note as u8
-      { // The old note is somehow different. Silence it.
-        let off_status: u8 = 0x80 | old.output_channel;
-        results.push(vec![off_status, old.output_note, 0]); }}
-    if output_in_range {
-      // Send the new note.
-      ongoing.insert(original_note, TransformedNote {
-        output_channel: new_channel as u8,
-        output_note: new_note as u8 });
-      let on_status: u8 = 0x90 | new_channel as u8;
-      results.push(vec![on_status, new_note as u8, velocity]); }
-  } else if is_note_off {
-    if let Some(old) = ongoing.remove(&original_note) {
-      // Look up what output the earlier note-on produced.
-      let off_status: u8 = 0x80 | old.output_channel;
-      results.push(vec![off_status, old.output_note, velocity]);
-    } else if output_in_range {
-      // Somehow there is no record of the earlier note-on.
-      // Send a note-off anyway, using current settings.
-      let off_status: u8 = 0x80 | new_channel as u8;
-      results.push(vec![off_status, new_note as u8, velocity]); }}
-  results }
-
-fn edo72_instruction(
-  original_note: u8
-) -> (i16, // channel
-      i16) { // note
-  let normalized: i16 = original_note as i16
-                        - LOWEST_A as i16
-                        + SHIFT_IN_12_EDO as i16;
-  let channel_offset: i16 = normalized.div_euclid(12);
-  let note_offset: i16 = normalized.rem_euclid(12);
-  let channel: i16 = MIN_CHANNEL as i16 + channel_offset;
-  let pitch_class: u8 = original_note % 12;
-  let shift :  i16 =
-    pitch_class_shifts() . lock() . unwrap()
-    . get(&pitch_class) . copied()
-    . unwrap_or(0) as i16;
-  let note: i16 = MIN_NOTE as i16
-                  + note_offset * EDO_OVER_12 as i16
-                  + shift;
-  (channel, note) }
+  #[test]
+  fn merged_inputs_share_the_state_but_not_running_status() {
+    let state: Arc<Mutex<Edo72Transformer>> = Arc::new(Mutex::new(Edo72Transformer::new()));
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    let mut first = input_callback(Arc::clone(&state), tx.clone());
+    let mut second = input_callback(Arc::clone(&state), tx);
+    let (mut status_1, mut status_2): (RunningStatus, RunningStatus) =
+      (RunningStatus::new(), RunningStatus::new());
+    first(0, &[0x90, 0x25, 0x64], &mut status_1);
+    second(0, &[0x90, 0x26, 0x5A], &mut status_2);
+    first(0, &[0x25, 0x00], &mut status_1);
+    second(0, &[0x26, 0x00], &mut status_2);
+    assert_eq!(rx.try_iter().collect::<Vec<Vec<u8>>>(),
+               messages("91 5E 64, 92 1C 5A, 81 5E 00, 82 1C 00"));
+    // Nothing is left held for aftertouch to follow.
+    assert!(lock(&state).process(&[0xA0, 0x25, 0x10]).is_empty()); }
+}
@@ -0,0 +1,194 @@
+//! Filter - passes MIDI through, dropping the kinds of message you name
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin filter -- --keep-channels 0,1 --drop-cc 1,64 --note-range 36:96
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "filter-out".
+//! For taming a noisy controller before it reaches the other tools:
+//! each message is checked against the rules below, in this order, and
+//! dropped by the first one it fails. What gets through goes out
+//! unchanged and at once, never held back.
+//!
+//! 1. `--keep-channels`: channel messages on any other channel go.
+//! 2. `--drop-aftertouch`: poly and channel pressure go.
+//! 3. `--drop-pitchbend`: pitch bend goes.
+//! 4. `--drop-cc`: those controllers go.
+//! 5. `--note-range`: notes outside it go, note-offs and poly pressure
+//!    included, so nothing is left hanging.
+//!
+//! Since every rule only drops, the order doesn't change what passes;
+//! it decides which rule is named in the debug log (`RUST_LOG=debug`).
+//! System messages (clock, SysEx and the like) have no channel and
+//! always pass.
+//!
+//! Flags:
+//! - `--keep-channels <c,...>`: the channels (0-15) to keep (default all)
+//! - `--drop-cc <n,...>`: the controllers to drop (default none)
+//! - `--drop-aftertouch`, `--drop-pitchbend`
+//! - `--note-range <low>:<high>`: the notes to keep, inclusive, as numbers
+//!   or names like C2 (following `--octave-convention`; default all)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_list, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, CHANNEL_PRESSURE, CONTROL_CHANGE,
+                 PITCH_BEND, POLY_PRESSURE};
+use std::ops::RangeInclusive;
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Debug, Default, PartialEq)]
+struct FilterConfig {
+  keep_channels: Option<Vec<u8>>, // None keeps them all
+  drop_aftertouch: bool,
+  drop_pitchbend: bool,
+  drop_cc: Vec<u8>,
+  note_range: Option<RangeInclusive<u8>>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let config: FilterConfig = parse_config(&args)?;
+
+  let midi_in: MidiInput = MidiInput::new("filter-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("filter-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "filter-out")?;
+  self_test(&args, &mut conn_out, "filter-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Filter started! {:?}", config);
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      match dropped_by(&config, message) {
+        None => { let _ = tx_for_callback.send(message.to_vec()); }
+        Some(rule) => log::debug!("{} dropped {}", rule, logging::hex(message)),
+      }
+    },
+    (),
+  )?;
+
+  println!("Ports: 'filter-in:midi-in' (input), 'filter-out:filter-out' (output)");
+  auto_connect(&args, Some("filter-in:midi-in"), Some("filter-out:filter-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_config(args: &Args) -> Result<FilterConfig, String> {
+  let keep_channels: Option<Vec<u8>> = args.value("--keep-channels")
+    .map(|text| parse_list("--keep-channels", text))
+    .transpose()?;
+  if keep_channels.as_ref().is_some_and(|channels| channels.iter().any(|&c| c > 15)) {
+    return Err("--keep-channels must be in 0-15".to_string());
+  }
+  let drop_cc: Vec<u8> = match args.value("--drop-cc") {
+    Some(text) => parse_list("--drop-cc", text)?,
+    None => vec![],
+  };
+  if drop_cc.iter().any(|&cc| cc > 127) {
+    return Err("--drop-cc must be in 0-127".to_string());
+  }
+  Ok(FilterConfig {
+    keep_channels,
+    drop_aftertouch: args.flag("--drop-aftertouch"),
+    drop_pitchbend: args.flag("--drop-pitchbend"),
+    drop_cc,
+    note_range: args.note_range("--note-range")?,
+  })
+}
+
+/// The first rule `message` fails, if any; None means it passes.
+fn dropped_by(config: &FilterConfig, message: &[u8]) -> Option<&'static str> {
+  let channel: u8 = get_channel(message)?; // system messages always pass
+  let kind: u8 = message[0] & 0xF0;
+  if config.keep_channels.as_ref().is_some_and(|channels| !channels.contains(&channel)) {
+    return Some("--keep-channels");
+  }
+  if config.drop_aftertouch && (kind == POLY_PRESSURE || kind == CHANNEL_PRESSURE) {
+    return Some("--drop-aftertouch");
+  }
+  if config.drop_pitchbend && kind == PITCH_BEND {
+    return Some("--drop-pitchbend");
+  }
+  if kind == CONTROL_CHANGE && message.get(1).is_some_and(|cc| config.drop_cc.contains(cc)) {
+    return Some("--drop-cc");
+  }
+  let note: Option<u8> = get_note(message)
+    .or_else(|| (kind == POLY_PRESSURE).then(|| message.get(1).copied()).flatten());
+  if let (Some(range), Some(note)) = (&config.note_range, note) {
+    if !range.contains(&note) {
+      return Some("--note-range");
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use midi_utils::harness::{feed, messages};
+
+  fn passing(config: &FilterConfig, script: &str) -> Vec<Vec<u8>> {
+    feed(&mut (), &messages(script), |_, message| match dropped_by(config, message) {
+      None => vec![message.to_vec()],
+      Some(_) => vec![],
+    })
+  }
+
+  #[test]
+  fn nothing_is_dropped_by_default() {
+    let script: &str = "90 3C 64, B0 01 10, E0 00 40, D0 20, F8, F0 7E 7F F7";
+    assert_eq!(passing(&FilterConfig::default(), script), messages(script));
+  }
+
+  #[test]
+  fn rules_drop_their_kinds() {
+    let config: FilterConfig = FilterConfig {
+      keep_channels: Some(vec![0, 1]),
+      drop_aftertouch: true,
+      drop_pitchbend: true,
+      drop_cc: vec![1, 64],
+      note_range: Some(36..=96),
+    };
+    assert_eq!(passing(&config, "90 3C 64, 92 3C 64, B1 40 7F, B1 07 64, E0 00 40, \
+                                 A0 3C 10, D1 20, 90 20 64, 80 20 00, 81 3C 00, F8"),
+               messages("90 3C 64, B1 07 64, 81 3C 00, F8"));
+    assert_eq!(dropped_by(&config, &[0x92, 0x3C, 0x64]), Some("--keep-channels"));
+    assert_eq!(dropped_by(&config, &[0xA2, 0x20, 0x10]), Some("--keep-channels")); // first wins
+    assert_eq!(dropped_by(&config, &[0x90, 0x20, 0x00]), Some("--note-range"));
+  }
+
+  #[test]
+  fn poly_pressure_outside_the_range_goes_too() {
+    let config: FilterConfig = FilterConfig { note_range: Some(36..=96), ..Default::default() };
+    assert_eq!(passing(&config, "A0 3C 10, A0 20 10, D0 10"), messages("A0 3C 10, D0 10"));
+  }
+}
@@ -0,0 +1,358 @@
+//! Gate - chops held notes into a rhythm
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin gate -- --bpm 120 --pattern x.x.xx..
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "gate-out".
+//! Held notes sound only on the pattern's `x` steps: each step sends a
+//! note-off for every held note that was sounding, and then, on an `x`,
+//! a note-on for each again, so a run of `x`s retriggers and a `.`
+//! leaves a gap. A key pressed during a gap waits for the next `x`;
+//! releasing a key ends its note at once. The pattern repeats.
+//! Non-note messages pass straight through.
+//!
+//! Flags:
+//! - `--pattern <steps>`: `x` for on, `.` (or `-`) for off (default x.x.x.x.)
+//! - `--bpm <bpm>`: tempo (default 120)
+//! - `--steps-per-beat <n>`: how many steps fill a beat (default 4, sixteenths)
+//! - `--swing <0.0-0.75>`: as in arp
+//! - `--probability <0.0-1.0>`: the chance each `x` step plays (default 1);
+//!   one that doesn't is a gap, and the pattern still moves on past it
+//! - `--seed <n>`: seeds the random choices, so a run can be repeated
+//! - `--sync-clock`: step with the MIDI clock arriving on the input
+//!   instead of `--bpm`; Start puts the pattern back at its first step,
+//!   and while the clock is stopped the gate stays open
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::clock::ClockFollower;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::rng::{parse_probability, Rng};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::timing::{parse_swing, swung_offset};
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, CLOCKS_PER_QUARTER};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const CLOCK_POLL: Duration = Duration::from_millis(1);
+
+/// Which steps of a bar let the held notes through.
+#[derive(Clone, Debug, PartialEq)]
+struct Pattern(Vec<bool>);
+
+impl FromStr for Pattern {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    let steps: Vec<bool> = s.chars()
+      .map(|c| match c {
+        'x' | 'X' => Ok(true),
+        '.' | '-' => Ok(false),
+        _ => Err(format!("unexpected '{}' in pattern; use x for on and . for off", c)),
+      })
+      .collect::<Result<Vec<bool>, String>>()?;
+    if steps.is_empty() {
+      return Err("the pattern needs at least one step".to_string());
+    }
+    Ok(Pattern(steps))
+  }
+}
+
+/// Decides whether each step of the pattern plays.
+struct Stepper {
+  pattern: Pattern,
+  probability: f64, // that an `x` step plays
+  rng: Rng,
+}
+
+impl Stepper {
+  /// Whether step `n` (counting from 0, across repeats) lets notes through.
+  fn plays(&mut self, n: u64) -> bool {
+    self.pattern.0[n as usize % self.pattern.0.len()] && self.rng.chance(self.probability)
+  }
+}
+
+struct GateState {
+  held: BTreeMap<(u8, u8), u8>, // (channel, note) -> velocity
+  open: bool, // whether held notes are sounding
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let pattern: Pattern = args.parse_or("--pattern", Pattern([true, false].repeat(4)))?;
+  let bpm: f64 = args.parse_or("--bpm", 120.0)?;
+  if bpm <= 0.0 || !bpm.is_finite() {
+    return Err("--bpm must be positive".into());
+  }
+  let steps_per_beat: u32 = args.parse_or("--steps-per-beat", 4)?;
+  if steps_per_beat == 0 {
+    return Err("--steps-per-beat must be at least 1".into());
+  }
+  let swing: f64 = parse_swing(&args)?;
+  let probability: f64 = parse_probability(&args)?;
+  let rng: Rng = Rng::from_args(&args)?;
+  let step: Duration = Duration::from_secs_f64(60.0 / bpm / steps_per_beat as f64);
+  let clock: Option<Arc<Mutex<ClockFollower>>> =
+    args.flag("--sync-clock").then(|| Arc::new(Mutex::new(ClockFollower::new())));
+
+  let midi_in: MidiInput = MidiInput::new("gate-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("gate-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "gate-out")?;
+  self_test(&args, &mut conn_out, "gate-out");
+
+  let state: Arc<Mutex<GateState>> = Arc::new(Mutex::new(GateState {
+    held: BTreeMap::new(),
+    open: true,
+  }));
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<GateState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let clock_for_timer: Option<Arc<Mutex<ClockFollower>>> = clock.clone();
+  let stepper: Stepper = Stepper { pattern: pattern.clone(), probability, rng };
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || match clock_for_timer {
+    None => run_timer_thread(state_for_timer, tx_for_timer, running_for_timer,
+                             stepper, step, swing),
+    Some(clock) => run_clock_thread(state_for_timer, tx_for_timer, running_for_timer,
+                                    stepper, clock, steps_per_beat),
+  });
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      if let Some(clock) = &clock {
+        lock(clock).handle(message, Instant::now());
+      }
+      if !is_note_event(message) {
+        let _ = tx_for_callback.send(message.to_vec());
+        return;
+      }
+      let mut state = lock(&state);
+      for msg in handle_note(&mut state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  let pattern_text: String = pattern.0.iter().map(|&on| if on { 'x' } else { '.' }).collect();
+  println!("Gate started!");
+  match args.flag("--sync-clock") {
+    true => println!("  pattern: {}, {} steps per beat, following MIDI clock, probability: {}",
+                     pattern_text, steps_per_beat, probability),
+    false => println!("  pattern: {}, {} steps per beat at {} bpm, swing: {}, probability: {}",
+                      pattern_text, steps_per_beat, bpm, swing, probability),
+  }
+  println!("Ports: 'gate-in:midi-in' (input), 'gate-out:gate-out' (output)");
+  auto_connect(&args, Some("gate-in:midi-in"), Some("gate-out:gate-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+/// Updates the held notes. A note-on sounds at once if the gate is
+/// open; a note-off always goes out, ending the note for good.
+fn handle_note(state: &mut GateState, message: &[u8]) -> Vec<Vec<u8>> {
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![],
+    };
+  if is_note_on(message) {
+    state.held.insert((channel, note), message[2]);
+    if state.open {
+      return vec![message.to_vec()];
+    }
+  } else if is_note_off(message) && state.held.remove(&(channel, note)).is_some() {
+    return vec![message.to_vec()];
+  }
+  vec![]
+}
+
+/// One step of the pattern: silences whatever was sounding, then
+/// strikes every held note again if the step is on.
+fn gate_step(state: &mut GateState, on: bool) -> Vec<Vec<u8>> {
+  let mut messages: Vec<Vec<u8>> = Vec::new();
+  if state.open {
+    messages.extend(state.held.keys().map(|&(channel, note)| note_off(channel, note, 0)));
+  }
+  if on {
+    messages.extend(state.held.iter()
+      .map(|(&(channel, note), &velocity)| note_on(channel, note, velocity)));
+  }
+  state.open = on;
+  messages
+}
+
+/// Opens the gate without retriggering notes already sounding.
+fn open_gate(state: &mut GateState) -> Vec<Vec<u8>> {
+  if state.open {
+    return vec![];
+  }
+  gate_step(state, true)
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<GateState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+  mut stepper: Stepper,
+  step: Duration,
+  swing: f64,
+) {
+  let start: Instant = Instant::now();
+  let mut tick: u64 = 0; // steps since start
+  while running.load(Ordering::SeqCst) {
+    let on: bool = stepper.plays(tick);
+    for msg in gate_step(&mut lock(&state), on) {
+      let _ = tx.send(msg);
+    }
+    tick += 1;
+    let due: Instant = start + swung_offset(tick, step, swing);
+    thread::sleep(due.saturating_duration_since(Instant::now()));
+  }
+}
+
+/// Like `run_timer_thread`, but steps on the incoming clock's ticks.
+fn run_clock_thread(
+  state: Arc<Mutex<GateState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+  mut stepper: Stepper,
+  clock: Arc<Mutex<ClockFollower>>,
+  steps_per_beat: u32,
+) {
+  let mut last: Option<(u64, u64)> = None; // (start, step) last played
+  while running.load(Ordering::SeqCst) {
+    let position: Option<(u64, f64)> = lock(&clock).position(Instant::now());
+    let messages: Vec<Vec<u8>> = match position {
+      None => {
+        last = None;
+        open_gate(&mut lock(&state))
+      }
+      Some((starts, ticks)) => {
+        let step: u64 = clock_step(ticks, steps_per_beat);
+        if last == Some((starts, step)) {
+          vec![]
+        } else {
+          last = Some((starts, step));
+          let on: bool = stepper.plays(step);
+          gate_step(&mut lock(&state), on)
+        }
+      }
+    };
+    for msg in messages {
+      let _ = tx.send(msg);
+    }
+    thread::sleep(CLOCK_POLL);
+  }
+}
+
+/// Which step clock position `ticks` falls in.
+fn clock_step(ticks: f64, steps_per_beat: u32) -> u64 {
+  (ticks * steps_per_beat as f64 / CLOCKS_PER_QUARTER as f64).floor() as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn gate() -> GateState {
+    GateState { held: BTreeMap::new(), open: true }
+  }
+
+  #[test]
+  fn patterns() {
+    assert_eq!("x.X-".parse(), Ok(Pattern(vec![true, false, true, false])));
+    assert!("".parse::<Pattern>().is_err());
+    assert!("x.o".parse::<Pattern>().is_err());
+  }
+
+  #[test]
+  fn steps_retrigger_and_silence_held_notes() {
+    let mut state: GateState = gate();
+    assert_eq!(handle_note(&mut state, &[0x91, 60, 90]), vec![vec![0x91, 60, 90]]);
+    assert_eq!(gate_step(&mut state, true), vec![vec![0x81, 60, 0], vec![0x91, 60, 90]]);
+    assert_eq!(gate_step(&mut state, false), vec![vec![0x81, 60, 0]]);
+    assert!(gate_step(&mut state, false).is_empty());
+    assert_eq!(gate_step(&mut state, true), vec![vec![0x91, 60, 90]]);
+  }
+
+  #[test]
+  fn keys_pressed_in_a_gap_wait_and_releases_always_go_out() {
+    let mut state: GateState = gate();
+    gate_step(&mut state, false);
+    assert!(handle_note(&mut state, &[0x90, 64, 80]).is_empty());
+    assert_eq!(gate_step(&mut state, true), vec![vec![0x90, 64, 80]]);
+    gate_step(&mut state, false);
+    assert_eq!(handle_note(&mut state, &[0x80, 64, 0]), vec![vec![0x80, 64, 0]]);
+    assert!(state.held.is_empty());
+    assert!(gate_step(&mut state, true).is_empty());
+    assert!(handle_note(&mut state, &[0x80, 64, 0]).is_empty()); // never held
+  }
+
+  #[test]
+  fn opening_does_not_retrigger() {
+    let mut state: GateState = gate();
+    handle_note(&mut state, &[0x90, 60, 90]);
+    assert!(open_gate(&mut state).is_empty());
+    gate_step(&mut state, false);
+    assert_eq!(open_gate(&mut state), vec![vec![0x90, 60, 90]]);
+  }
+
+  #[test]
+  fn skipped_steps_are_gaps_in_place() {
+    let mut stepper: Stepper = Stepper {
+      pattern: "xx.x".parse().unwrap(), probability: 0.5, rng: Rng::seeded(3) };
+    let plays: Vec<bool> = (0..40).map(|n| stepper.plays(n)).collect();
+    assert!((0..40).filter(|n| n % 4 == 2).all(|n| !plays[n])); // rests stay rests
+    assert!(plays.iter().any(|&p| p));
+    assert!((0..40).filter(|n| n % 4 != 2).any(|n| !plays[n]));
+    let mut state: GateState = gate();
+    handle_note(&mut state, &[0x90, 60, 90]);
+    assert_eq!(gate_step(&mut state, false), vec![vec![0x80, 60, 0]]); // a skip ends the note
+  }
+
+  #[test]
+  fn clock_steps() {
+    assert_eq!(clock_step(0.0, 4), 0);
+    assert_eq!(clock_step(5.9, 4), 0);
+    assert_eq!(clock_step(6.0, 4), 1);
+    assert_eq!(clock_step(23.5, 1), 0);
+    assert_eq!(clock_step(48.0, 2), 4);
+  }
+}
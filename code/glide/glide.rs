@@ -0,0 +1,344 @@
+//! Glide - portamento for synths without it, by pitch bend
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin glide -- --glide-ms 120 --bend-range 12
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "glide-out".
+//! Playing is made monophonic, as in mono. While a note sounds, moving
+//! to another key doesn't start a new note: the sounding one is bent
+//! there, over `--glide-ms`. Only a jump wider than the synth's bend
+//! range (or to another channel, or striking the sounding key again)
+//! starts a new note, which glides in from where the old one had got
+//! to if it can. A note struck with nothing held starts unbent.
+//!
+//! Set `--bend-range` to what the synth's pitch bend spans, in
+//! semitones each way, or the glides land out of tune. Because glide
+//! drives the bend itself, pitch bend from the input is dropped;
+//! everything else passes straight through.
+//!
+//! Flags:
+//! - `--glide-ms <ms>`: how long each glide takes (default 100)
+//! - `--bend-range <semitones>`: the synth's bend range (default 2)
+//! - `--priority last|low|high`: which held key sounds, as in mono (default last)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::note_stack::{Key, NoteStack, Priority};
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, pitch_bend, release_velocity, BEND_CENTER, PITCH_BEND};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const TIMER_CHECK_MS: u64 = 1;
+
+/// A bend moving between two offsets, in semitones from the sounding note.
+struct Ramp {
+  from: f64,
+  to: f64,
+  start: Instant,
+}
+
+impl Ramp {
+  fn still(offset: f64, now: Instant) -> Self {
+    Ramp { from: offset, to: offset, start: now }
+  }
+
+  fn position(&self, now: Instant, length: Duration) -> f64 {
+    let progress: f64 = if length.is_zero() { 1.0 } else {
+      (now.saturating_duration_since(self.start).as_secs_f64() / length.as_secs_f64()).min(1.0)
+    };
+    self.from + (self.to - self.from) * progress
+  }
+}
+
+struct GlideState {
+  glide: Duration,
+  bend_range: f64,
+  held: NoteStack,
+  sounding: Option<Key>, // the note actually on, which the bend is relative to
+  ramp: Ramp,
+  sent_bend: [u16; 16], // the last bend actually sent, per channel
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let glide: Duration = Duration::from_millis(args.parse_or("--glide-ms", 100)?);
+  let bend_range: f64 = args.parse_or("--bend-range", 2.0)?;
+  if bend_range <= 0.0 || !bend_range.is_finite() {
+    return Err("--bend-range must be positive".into());
+  }
+  let priority: Priority = args.parse_or("--priority", Priority::Last)?;
+
+  let midi_in: MidiInput = MidiInput::new("glide-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("glide-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "glide-out")?;
+  self_test(&args, &mut conn_out, "glide-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let state: Arc<Mutex<GlideState>> = Arc::new(Mutex::new(GlideState {
+    glide,
+    bend_range,
+    held: NoteStack::new(priority),
+    sounding: None,
+    ramp: Ramp::still(0.0, Instant::now()),
+    sent_bend: [BEND_CENTER; 16],
+  }));
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<GlideState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_timer_thread(state_for_timer, tx_for_timer, running_for_timer)
+  });
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let mut state = lock(&state);
+      for msg in transform_message(&mut state, Instant::now(), message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  println!("Glide started! {:?} glides, bend range {} semitones, priority {:?}",
+           glide, bend_range, priority);
+  println!("Ports: 'glide-in:midi-in' (input), 'glide-out:glide-out' (output)");
+  auto_connect(&args, Some("glide-in:midi-in"), Some("glide-out:glide-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<GlideState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+) {
+  while running.load(Ordering::SeqCst) {
+    let due: Option<Vec<u8>> = {
+      let mut state = lock(&state);
+      due_bend(&mut state, Instant::now())
+    };
+    if let Some(msg) = due {
+      if tx.send(msg).is_err() {
+        return;
+      }
+    }
+    thread::sleep(Duration::from_millis(TIMER_CHECK_MS));
+  }
+}
+
+/// The 14-bit bend for an offset in semitones.
+fn bend_value(semitones: f64, bend_range: f64) -> u16 {
+  let center: f64 = BEND_CENTER as f64;
+  (center + semitones / bend_range * center).round().clamp(0.0, 0x3FFF as f64) as u16
+}
+
+/// The next step of the ramp, if the bend has moved since it was last sent.
+fn due_bend(state: &mut GlideState, now: Instant) -> Option<Vec<u8>> {
+  let key: Key = state.sounding?;
+  let value: u16 = bend_value(state.ramp.position(now, state.glide), state.bend_range);
+  if value == state.sent_bend[key.channel as usize] {
+    return None;
+  }
+  state.sent_bend[key.channel as usize] = value;
+  Some(pitch_bend(key.channel, value))
+}
+
+/// Sets the bend at once, returning the message if it changed.
+fn set_bend(state: &mut GlideState, channel: u8, semitones: f64) -> Vec<Vec<u8>> {
+  let value: u16 = bend_value(semitones, state.bend_range);
+  if value == state.sent_bend[channel as usize] {
+    return vec![];
+  }
+  state.sent_bend[channel as usize] = value;
+  vec![pitch_bend(channel, value)]
+}
+
+fn transform_message(state: &mut GlideState, now: Instant, message: &[u8]) -> Vec<Vec<u8>> {
+  if message.first().is_some_and(|&status| status & 0xF0 == PITCH_BEND) {
+    return vec![];
+  }
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (channel, note): (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) => (c, n),
+    _ => return vec![message.to_vec()],
+  };
+  let struck: Option<Key> = is_note_on(message).then_some(Key { channel, note, velocity: message[2] });
+  match struck {
+    Some(key) => state.held.press(key),
+    None if is_note_off(message) => state.held.release(channel, note),
+    None => return vec![],
+  }
+  let wanted: Option<Key> = state.held.winner();
+  let sounding: Option<Key> = state.sounding;
+  match (sounding, wanted) {
+    (None, None) => vec![],
+    (Some(old), None) => {
+      // The last key let go, however far the note had been bent from it.
+      state.sounding = None;
+      vec![note_off(old.channel, old.note, release_velocity(message))]
+    }
+    (None, Some(new)) => {
+      // Nothing to glide from.
+      let mut results: Vec<Vec<u8>> = set_bend(state, new.channel, 0.0);
+      results.push(note_on(new.channel, new.note, new.velocity));
+      state.sounding = Some(new);
+      state.ramp = Ramp::still(0.0, now);
+      results
+    }
+    (Some(old), Some(new)) => {
+      let restruck: bool = struck.is_some_and(|k| k.same_key(&old));
+      let offset: f64 = new.note as f64 - old.note as f64;
+      if !restruck && old.channel == new.channel && offset.abs() <= state.bend_range {
+        if offset != state.ramp.to {
+          let from: f64 = state.ramp.position(now, state.glide);
+          state.ramp = Ramp { from, to: offset, start: now };
+        }
+        return vec![];
+      }
+      retrigger(state, now, old, new)
+    }
+  }
+}
+
+/// Starts `new` in place of `old`, gliding in from the pitch `old` had
+/// reached if that's within the bend range of `new`.
+fn retrigger(state: &mut GlideState, now: Instant, old: Key, new: Key) -> Vec<Vec<u8>> {
+  let pitch: f64 = old.note as f64 + state.ramp.position(now, state.glide);
+  let from: f64 = pitch - new.note as f64;
+  let from: f64 =
+    if old.channel == new.channel && from.abs() <= state.bend_range { from } else { 0.0 };
+  let mut results: Vec<Vec<u8>> = set_bend(state, new.channel, from);
+  // The new note starts before the old one ends, as legato as the synth allows.
+  results.push(note_on(new.channel, new.note, new.velocity));
+  results.push(note_off(old.channel, old.note, 0));
+  state.sounding = Some(new);
+  state.ramp = Ramp { from, to: 0.0, start: now };
+  results
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> GlideState {
+    GlideState {
+      glide: Duration::from_millis(100),
+      bend_range: 12.0,
+      held: NoteStack::new(Priority::Last),
+      sounding: None,
+      ramp: Ramp::still(0.0, Instant::now()),
+      sent_bend: [BEND_CENTER; 16],
+    }
+  }
+
+  fn ms(t0: Instant, ms: u64) -> Instant {
+    t0 + Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn bend_values() {
+    assert_eq!(bend_value(0.0, 2.0), BEND_CENTER);
+    assert_eq!(bend_value(2.0, 2.0), 0x3FFF);
+    assert_eq!(bend_value(-2.0, 2.0), 0);
+    assert_eq!(bend_value(6.0, 12.0), 0x3000);
+    assert_eq!(bend_value(30.0, 12.0), 0x3FFF);
+  }
+
+  #[test]
+  fn moving_within_range_bends_the_sounding_note() {
+    let mut s: GlideState = state();
+    let t0: Instant = Instant::now();
+    assert_eq!(transform_message(&mut s, t0, &[0x90, 60, 100]), vec![vec![0x90, 60, 100]]);
+    assert!(transform_message(&mut s, t0, &[0x90, 66, 100]).is_empty()); // up 6
+    assert!(due_bend(&mut s, t0).is_none());
+    assert_eq!(due_bend(&mut s, ms(t0, 50)), Some(pitch_bend(0, bend_value(3.0, 12.0))));
+    assert_eq!(due_bend(&mut s, ms(t0, 100)), Some(pitch_bend(0, 0x3000)));
+    assert!(due_bend(&mut s, ms(t0, 200)).is_none());
+    // Letting go of the newer key glides back to the older one.
+    assert!(transform_message(&mut s, ms(t0, 200), &[0x80, 66, 0]).is_empty());
+    assert_eq!(due_bend(&mut s, ms(t0, 300)), Some(pitch_bend(0, BEND_CENTER)));
+    assert_eq!(transform_message(&mut s, ms(t0, 300), &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert!(s.sounding.is_none());
+  }
+
+  #[test]
+  fn a_wide_jump_retriggers_and_glides_in() {
+    let mut s: GlideState = state();
+    let t0: Instant = Instant::now();
+    transform_message(&mut s, t0, &[0x90, 60, 100]);
+    transform_message(&mut s, t0, &[0x90, 70, 100]);
+    due_bend(&mut s, ms(t0, 100)); // at 70 by bend
+    assert_eq!(transform_message(&mut s, ms(t0, 100), &[0x90, 80, 90]),
+               vec![pitch_bend(0, bend_value(-10.0, 12.0)), vec![0x90, 80, 90], vec![0x80, 60, 0]]);
+    assert_eq!(due_bend(&mut s, ms(t0, 200)), Some(pitch_bend(0, BEND_CENTER)));
+    let far: Vec<Vec<u8>> = transform_message(&mut s, ms(t0, 200), &[0x90, 20, 90]);
+    assert_eq!(far, vec![vec![0x90, 20, 90], vec![0x80, 80, 0]]); // too far: starts unbent
+  }
+
+  #[test]
+  fn a_fresh_note_starts_unbent_and_input_bend_is_dropped() {
+    let mut s: GlideState = state();
+    let t0: Instant = Instant::now();
+    transform_message(&mut s, t0, &[0x90, 60, 100]);
+    transform_message(&mut s, t0, &[0x90, 64, 100]);
+    due_bend(&mut s, ms(t0, 100));
+    transform_message(&mut s, ms(t0, 100), &[0x80, 64, 0]);
+    transform_message(&mut s, ms(t0, 100), &[0x80, 60, 0]);
+    assert_eq!(transform_message(&mut s, ms(t0, 200), &[0x90, 50, 100]),
+               vec![pitch_bend(0, BEND_CENTER), vec![0x90, 50, 100]]);
+    assert!(transform_message(&mut s, t0, &[0xE0, 0, 0x50]).is_empty());
+    assert_eq!(transform_message(&mut s, t0, &[0xB0, 1, 64]), vec![vec![0xB0, 1, 64]]);
+  }
+
+  #[test]
+  fn each_channel_keeps_its_own_bend() {
+    let mut s: GlideState = state();
+    let t0: Instant = Instant::now();
+    transform_message(&mut s, t0, &[0x90, 60, 100]);
+    transform_message(&mut s, t0, &[0x90, 66, 100]);
+    assert_eq!(due_bend(&mut s, ms(t0, 100)), Some(pitch_bend(0, 0x3000)));
+    // Channel 2 was never bent, so its note needs no bend first ...
+    assert_eq!(transform_message(&mut s, ms(t0, 100), &[0x91, 60, 100]),
+               vec![vec![0x91, 60, 100], vec![0x80, 60, 0]]);
+    // ... but channel 1 still is, and a fresh note there is re-centered.
+    assert_eq!(transform_message(&mut s, ms(t0, 200), &[0x90, 50, 100]),
+               vec![pitch_bend(0, BEND_CENTER), vec![0x90, 50, 100], vec![0x81, 60, 0]]);
+  }
+}
@@ -0,0 +1,243 @@
+//! Harmonize - adds notes at fixed intervals above (or below) each note played
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin harmonize -- --intervals 4,7
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "harmonize-out".
+//! Each note-on is sent along with a note at each interval (in semitones,
+//! negative for below); releasing the key releases all of them.
+//! Harmony notes that would fall outside 0-127 are dropped.
+//! Poly aftertouch on a key reaches every note it sounds (and is dropped
+//! for a key not held). Other messages pass through unchanged; since the
+//! harmony stays on the key's channel, bend and the mod wheel reach it too.
+//!
+//! Flags:
+//! - `--intervals <list>`: comma-separated semitone offsets (default 4,7)
+//! - `--mono-pressure`: send poly aftertouch as channel pressure instead,
+//!   the hardest press among the notes held on the channel, for synths
+//!   that only respond to that
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::voices::PressureSummer;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, POLY_PRESSURE};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+struct HarmonizeState {
+  intervals: Vec<i8>,
+  // (channel, input note) -> every output note its note-on produced
+  ongoing_notes: HashMap<(u8, u8), Vec<u8>>,
+  // (channel, output note) -> how many held input notes produce it
+  sounding: HashMap<(u8, u8), u32>,
+  pressure: Option<PressureSummer>, // Some if --mono-pressure
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let intervals: Vec<i8> = args.value("--intervals").unwrap_or("4,7")
+    .split(',')
+    .map(|i| parse_value::<i8>("--intervals", i))
+    .collect::<Result<Vec<i8>, String>>()?;
+
+  let midi_in: MidiInput = MidiInput::new("harmonize-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("harmonize-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "harmonize-out")?;
+  self_test(&args, &mut conn_out, "harmonize-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Harmonizer started!");
+  println!("  intervals: {:?}", intervals);
+  let state: HarmonizeState = HarmonizeState {
+    intervals,
+    ongoing_notes: HashMap::new(),
+    sounding: HashMap::new(),
+    pressure: args.flag("--mono-pressure").then(PressureSummer::new),
+  };
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<HarmonizeState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut HarmonizeState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    state,
+  )?;
+
+  println!("Ports: 'harmonize-in:midi-in' (input), 'harmonize-out:harmonize-out' (output)");
+
+  auto_connect(&args, Some("harmonize-in:midi-in"), Some("harmonize-out:harmonize-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+/// The note itself plus each in-range harmony note, without duplicates.
+fn voices(intervals: &[i8], note: u8) -> Vec<u8> {
+  let mut notes: Vec<u8> = vec![note];
+  for &interval in intervals {
+    let voice: i16 = note as i16 + interval as i16;
+    if (0..=127).contains(&voice) && !notes.contains(&(voice as u8)) {
+      notes.push(voice as u8);
+    }
+  }
+  notes
+}
+
+fn transform_message(state: &mut HarmonizeState, message: &[u8]) -> Vec<Vec<u8>> {
+  if message.len() >= 3 && message[0] & 0xF0 == POLY_PRESSURE {
+    return spread_pressure(state, message[0] & 0x0F, message[1], message[2]);
+  }
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![message.to_vec()],
+    };
+  let velocity: u8 = message[2];
+  let mut results: Vec<Vec<u8>> = vec![];
+  if is_note_on(message) {
+    if let Some(old) = state.ongoing_notes.remove(&(channel, note)) {
+      // Retriggered before release; forget the earlier press.
+      release(state, channel, &old, 0, &mut results);
+    }
+    let outputs: Vec<u8> = voices(&state.intervals, note);
+    for &output in &outputs {
+      *state.sounding.entry((channel, output)).or_insert(0) += 1;
+      results.push(note_on(channel, output, velocity));
+    }
+    state.ongoing_notes.insert((channel, note), outputs);
+  } else if is_note_off(message) {
+    if let Some(outputs) = state.ongoing_notes.remove(&(channel, note)) {
+      release(state, channel, &outputs, velocity, &mut results);
+    }
+  }
+  results
+}
+
+/// Sends a note-off for each output once nothing else holds it.
+fn release(
+  state: &mut HarmonizeState,
+  channel: u8,
+  outputs: &[u8],
+  velocity: u8,
+  results: &mut Vec<Vec<u8>>,
+) {
+  for &output in outputs {
+    let count: &mut u32 = state.sounding.entry((channel, output)).or_insert(1);
+    *count -= 1;
+    if *count == 0 {
+      state.sounding.remove(&(channel, output));
+      results.push(note_off(channel, output, velocity));
+      if let Some(pressure) = state.pressure.as_mut().and_then(|p| p.release(channel, output)) {
+        results.push(pressure);
+      }
+    }
+  }
+}
+
+/// Poly aftertouch on a key, for every note that key sounds.
+fn spread_pressure(state: &mut HarmonizeState, channel: u8, note: u8, pressure: u8) -> Vec<Vec<u8>> {
+  let outputs: &[u8] = match state.ongoing_notes.get(&(channel, note)) {
+    Some(outputs) => outputs,
+    None => return vec![],
+  };
+  match &mut state.pressure {
+    None => outputs.iter().map(|&output| vec![POLY_PRESSURE | channel, output, pressure]).collect(),
+    Some(summer) => outputs.iter()
+      .filter_map(|&output| summer.press(channel, output, pressure))
+      .collect(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(intervals: &[i8]) -> HarmonizeState {
+    HarmonizeState {
+      intervals: intervals.to_vec(),
+      ongoing_notes: HashMap::new(),
+      sounding: HashMap::new(),
+      pressure: None,
+    }
+  }
+
+  #[test]
+  fn triad_on_and_off() {
+    let mut s: HarmonizeState = state(&[4, 7]);
+    assert_eq!(transform_message(&mut s, &[0x91, 60, 100]),
+               vec![vec![0x91, 60, 100], vec![0x91, 64, 100], vec![0x91, 67, 100]]);
+    assert_eq!(transform_message(&mut s, &[0x81, 60, 10]),
+               vec![vec![0x81, 60, 10], vec![0x81, 64, 10], vec![0x81, 67, 10]]);
+    assert!(s.sounding.is_empty());
+  }
+
+  #[test]
+  fn out_of_range_harmony_is_dropped() {
+    assert_eq!(voices(&[4, 7, -12], 124), vec![124, 112]);
+    assert_eq!(voices(&[-12], 5), vec![5]);
+  }
+
+  #[test]
+  fn overlapping_voices_stay_on_until_both_keys_are_up() {
+    let mut s: HarmonizeState = state(&[4]);
+    transform_message(&mut s, &[0x90, 60, 100]); // 60, 64
+    transform_message(&mut s, &[0x90, 64, 100]); // 64, 68
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 64, 0]),
+               vec![vec![0x80, 64, 0], vec![0x80, 68, 0]]);
+  }
+
+  #[test]
+  fn poly_pressure_reaches_every_voice() {
+    let mut s: HarmonizeState = state(&[4, 7]);
+    transform_message(&mut s, &[0x92, 60, 100]);
+    assert_eq!(transform_message(&mut s, &[0xA2, 60, 30]),
+               vec![vec![0xA2, 60, 30], vec![0xA2, 64, 30], vec![0xA2, 67, 30]]);
+    assert!(transform_message(&mut s, &[0xA2, 61, 30]).is_empty());
+  }
+
+  #[test]
+  fn mono_pressure_follows_the_hardest_key() {
+    let mut s: HarmonizeState = HarmonizeState { pressure: Some(PressureSummer::new()), ..state(&[4]) };
+    transform_message(&mut s, &[0x90, 60, 100]); // 60, 64
+    transform_message(&mut s, &[0x90, 72, 100]); // 72, 76
+    assert_eq!(transform_message(&mut s, &[0xA0, 60, 30]), vec![vec![0xD0, 30]]);
+    assert_eq!(transform_message(&mut s, &[0xA0, 72, 80]), vec![vec![0xD0, 80]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 72, 0]),
+               vec![vec![0x80, 72, 0], vec![0x80, 76, 0], vec![0xD0, 30]]);
+  }
+}
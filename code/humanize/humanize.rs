@@ -0,0 +1,192 @@
+//! Humanize - adds small random changes to velocity and timing
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin humanize -- --vel-jitter 10 --time-jitter-ms 15
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "humanize-out".
+//! Each note-on's velocity moves up or down by up to `--vel-jitter`
+//! (staying within 1-127), and each note is delayed by a random
+//! 0 to `--time-jitter-ms` milliseconds. A note-off gets the same delay
+//! as its note-on, so durations are kept. Everything else passes through
+//! without delay.
+//!
+//! Flags:
+//! - `--vel-jitter <n>`: largest velocity change (default 8)
+//! - `--time-jitter-ms <ms>`: largest delay, at most 1000 (default 10)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::delay::{run_delay_thread, DelayedMessage};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::rng::{jitter_velocity, Rng};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::{get_channel, get_note, is_note_off, is_note_on};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::thread;
+
+const MAX_TIME_JITTER_MS: u64 = 1000; // keeps every delayed time representable
+
+struct HumanizeState {
+  rng: Rng,
+  vel_jitter: u8,
+  time_jitter: Duration,
+  // (channel, note) -> the delay its note-on got
+  delays: HashMap<(u8, u8), Duration>,
+  // (channel, note) -> when its latest message goes out, so a quick
+  // repeat can't overtake the previous note-off
+  last_send: HashMap<(u8, u8), Instant>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let vel_jitter: u8 = args.parse_or("--vel-jitter", 8)?;
+  if vel_jitter > 126 {
+    return Err("--vel-jitter must be at most 126".into());
+  }
+  let time_jitter: Duration = time_jitter_flag(&args)?;
+
+  let midi_in: MidiInput = MidiInput::new("humanize-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("humanize-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "humanize-out")?;
+  self_test(&args, &mut conn_out, "humanize-out");
+  let (tx, rx): (mpsc::Sender<DelayedMessage>, mpsc::Receiver<DelayedMessage>) =
+    mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || run_delay_thread(conn_out, rx));
+
+  let state: HumanizeState = HumanizeState {
+    rng: Rng::from_time(),
+    vel_jitter,
+    time_jitter,
+    delays: HashMap::new(),
+    last_send: HashMap::new(),
+  };
+  let tx_for_callback: mpsc::Sender<DelayedMessage> = tx.clone();
+  let conn_in: MidiInputConnection<HumanizeState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut HumanizeState| {
+      let _ = tx_for_callback.send(humanize(state, Instant::now(), message));
+    },
+    state,
+  )?;
+
+  println!("Humanizer started! Velocity +/-{}, delay up to {:?}", vel_jitter, time_jitter);
+  println!("Ports: 'humanize-in:midi-in' (input), 'humanize-out:humanize-out' (output)");
+  auto_connect(&args, Some("humanize-in:midi-in"), Some("humanize-out:humanize-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+/// `--time-jitter-ms`, held to MAX_TIME_JITTER_MS, as add_echo holds
+/// its delays.
+fn time_jitter_flag(args: &Args) -> Result<Duration, String> {
+  let ms: u64 = args.parse_or("--time-jitter-ms", 10)?;
+  if ms > MAX_TIME_JITTER_MS {
+    return Err(format!("--time-jitter-ms must be at most {}", MAX_TIME_JITTER_MS));
+  }
+  Ok(Duration::from_millis(ms))
+}
+
+fn humanize(state: &mut HumanizeState, now: Instant, message: &[u8]) -> DelayedMessage {
+  let mut data: Vec<u8> = message.to_vec();
+  let key: (u8, u8) = match (get_note(message), get_channel(message)) {
+    (Some(note), Some(channel)) if message.len() >= 3 => (channel, note),
+    _ => return DelayedMessage { data, send_at: now },
+  };
+  let delay: Duration = if is_note_on(message) {
+    data[2] = jitter_velocity(&mut state.rng, message[2], state.vel_jitter);
+    let delay: Duration = state.time_jitter.mul_f64(state.rng.unit());
+    state.delays.insert(key, delay);
+    delay
+  } else if is_note_off(message) {
+    state.delays.remove(&key).unwrap_or(Duration::ZERO)
+  } else {
+    Duration::ZERO
+  };
+  let mut send_at: Instant = now + delay;
+  if let Some(&previous) = state.last_send.get(&key) {
+    send_at = send_at.max(previous);
+  }
+  state.last_send.insert(key, send_at);
+  DelayedMessage { data, send_at }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(vel_jitter: u8, time_jitter_ms: u64) -> HumanizeState {
+    HumanizeState {
+      rng: Rng::seeded(7),
+      vel_jitter,
+      time_jitter: Duration::from_millis(time_jitter_ms),
+      delays: HashMap::new(),
+      last_send: HashMap::new(),
+    }
+  }
+
+  #[test]
+  fn note_off_keeps_its_note_on_delay() {
+    let mut s: HumanizeState = state(5, 20);
+    let t0: Instant = Instant::now();
+    let on: DelayedMessage = humanize(&mut s, t0, &[0x90, 60, 100]);
+    let off: DelayedMessage =
+      humanize(&mut s, t0 + Duration::from_millis(300), &[0x80, 60, 0]);
+    assert_eq!(off.send_at - on.send_at, Duration::from_millis(300));
+    assert!(on.send_at - t0 <= Duration::from_millis(20));
+    assert_eq!(off.data, vec![0x80, 60, 0]);
+  }
+
+  #[test]
+  fn quick_repeats_stay_in_order() {
+    let mut s: HumanizeState = state(0, 50);
+    let t0: Instant = Instant::now();
+    let mut previous: Instant = t0;
+    for i in 0..50 {
+      let data: [u8; 3] = if i % 2 == 0 { [0x90, 60, 100] } else { [0x80, 60, 0] };
+      let msg: DelayedMessage = humanize(&mut s, t0 + Duration::from_millis(i), &data);
+      assert!(msg.send_at >= previous);
+      previous = msg.send_at;
+    }
+  }
+
+  #[test]
+  fn other_messages_are_not_delayed() {
+    let mut s: HumanizeState = state(5, 20);
+    let t0: Instant = Instant::now();
+    let cc: DelayedMessage = humanize(&mut s, t0, &[0xB0, 64, 127]);
+    assert_eq!((cc.send_at, cc.data), (t0, vec![0xB0, 64, 127]));
+  }
+
+  #[test]
+  fn time_jitter_is_held_to_the_limit() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    assert_eq!(time_jitter_flag(&args(&[])), Ok(Duration::from_millis(10)));
+    assert_eq!(time_jitter_flag(&args(&["--time-jitter-ms", "1000"])), Ok(Duration::from_secs(1)));
+    assert!(time_jitter_flag(&args(&["--time-jitter-ms", "1001"])).is_err());
+    assert!(time_jitter_flag(&args(&["--time-jitter-ms", "18446744073709551615"])).is_err());
+  }
+}
@@ -0,0 +1,150 @@
+//! Latch - keeps notes on after their keys are released
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin latch
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "latch-out".
+//! Pressing a key turns its note on, and it stays on after release;
+//! pressing the key again turns it off. Releases are ignored.
+//! The panic key (C8, note 108, by default) turns every latched note off,
+//! and so does exiting. Non-note messages pass straight through.
+//!
+//! Flags:
+//! - `--panic-note <note>`: which key clears everything, as a number or
+//!   a name (default 108, C8)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        wait_for_exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_on, note_off};
+use std::collections::BTreeSet;
+use std::sync::mpsc;
+use std::thread;
+
+const TOP_C: u8 = 108; // C8 - default panic key
+
+struct LatchState {
+  panic_note: u8,
+  latched: BTreeSet<(u8, u8)>, // (channel, note)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let panic_note: u8 = args.note_or("--panic-note", TOP_C)?;
+
+  let midi_in: MidiInput = MidiInput::new("latch-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("latch-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "latch-out")?;
+  self_test(&args, &mut conn_out, "latch-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<LatchState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut LatchState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    LatchState { panic_note, latched: BTreeSet::new() },
+  )?;
+
+  println!("Latch started! Note {} releases everything.", panic_note);
+  println!("Ports: 'latch-in:midi-in' (input), 'latch-out:latch-out' (output)");
+  auto_connect(&args, Some("latch-in:midi-in"), Some("latch-out:latch-out"));
+  println!("Press Enter to exit...");
+
+  wait_for_exit(args.value("--input-port"))?;
+
+  // Don't leave a drone behind.
+  let (_, mut state): (MidiInput, LatchState) = conn_in.close();
+  for msg in release_all(&mut state) {
+    let _ = tx.send(msg);
+  }
+  drop(tx);
+  let _ = out_thread.join();
+  Ok(())
+}
+
+fn transform_message(state: &mut LatchState, message: &[u8]) -> Vec<Vec<u8>> {
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let key: (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) => (c, n),
+    _ => return vec![message.to_vec()],
+  };
+  if !is_note_on(message) {
+    return vec![]; // releases don't matter here
+  }
+  if key.1 == state.panic_note {
+    return release_all(state);
+  }
+  if state.latched.remove(&key) {
+    vec![note_off(key.0, key.1, 0)]
+  } else {
+    state.latched.insert(key);
+    vec![message.to_vec()]
+  }
+}
+
+fn release_all(state: &mut LatchState) -> Vec<Vec<u8>> {
+  let latched: BTreeSet<(u8, u8)> = std::mem::take(&mut state.latched);
+  latched.into_iter()
+    .map(|(channel, note)| note_off(channel, note, 0))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> LatchState {
+    LatchState { panic_note: TOP_C, latched: BTreeSet::new() }
+  }
+
+  #[test]
+  fn presses_toggle_and_releases_are_ignored() {
+    let mut s: LatchState = state();
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 80]), vec![vec![0x90, 60, 80]]);
+    assert!(transform_message(&mut s, &[0x80, 60, 0]).is_empty());
+    assert!(transform_message(&mut s, &[0x90, 60, 0]).is_empty());
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 90]), vec![vec![0x80, 60, 0]]);
+    assert!(s.latched.is_empty());
+    assert_eq!(transform_message(&mut s, &[0xB0, 64, 127]), vec![vec![0xB0, 64, 127]]);
+  }
+
+  #[test]
+  fn panic_key_releases_everything() {
+    let mut s: LatchState = state();
+    transform_message(&mut s, &[0x90, 64, 80]);
+    transform_message(&mut s, &[0x91, 60, 80]);
+    assert_eq!(transform_message(&mut s, &[0x90, 108, 100]),
+               vec![vec![0x80, 64, 0], vec![0x81, 60, 0]]);
+    assert!(transform_message(&mut s, &[0x80, 108, 0]).is_empty());
+    assert!(release_all(&mut s).is_empty());
+  }
+}
@@ -0,0 +1,248 @@
+//! Minimal command-line flag parsing.
+//!
+//! Flags look like `--name value` or `--name=value`;
+//! a flag with no value (like `--list-ports`) is a switch.
+//! Unrecognized flags are ignored.
+//!
+//! Defaults can also come from a config file; see `config`.
+//!
+//! Flags naming a key take a note number or a note name (see `note`);
+//! `--octave-convention` says how names map to numbers.
+
+use crate::config::{self, Config};
+use crate::note_names::OctaveConvention;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub struct Args {
+  args: Vec<String>,
+}
+
+impl Args {
+  /// The arguments the program was run with, minus the program name,
+  /// on top of the config file's section for this binary.
+  pub fn from_env() -> Result<Self, String> {
+    let mut argv = std::env::args();
+    let binary: String = argv.next()
+      .and_then(|path| Some(Path::new(&path).file_stem()?.to_string_lossy().into_owned()))
+      .unwrap_or_default();
+    let args: Args = Args::new(argv.collect());
+    if args.flag("--no-config") {
+      return Ok(args);
+    }
+    let path: Option<PathBuf> = match args.value("--config") {
+      Some(path) => Some(PathBuf::from(path)),
+      None => config::default_path().filter(|path| path.exists()),
+    };
+    match path {
+      None => Ok(args),
+      Some(path) => Ok(args.over(&config::load(&path)?, &binary)),
+    }
+  }
+
+  pub fn new(args: Vec<String>) -> Self {
+    Args { args }
+  }
+
+  /// These arguments, with the config's flags for `binary` behind them:
+  /// a flag given here hides every value the config has for it.
+  pub fn over(self, config: &Config, binary: &str) -> Self {
+    let mut merged: Vec<String> = Vec::new();
+    let flags: Vec<String> = config.flags_for(binary);
+    let mut i: usize = 0;
+    while i < flags.len() {
+      let takes_value: bool = flags.get(i + 1).is_some_and(|next| !next.starts_with("--"));
+      let name: &str = &flags[i];
+      let given: bool = self.args.iter()
+        .any(|a| a == name || a.strip_prefix(name).is_some_and(|rest| rest.starts_with('=')));
+      let width: usize = if takes_value { 2 } else { 1 };
+      if !given {
+        merged.extend_from_slice(&flags[i..i + width]);
+      }
+      i += width;
+    }
+    merged.extend(self.args);
+    Args::new(merged)
+  }
+
+  /// Whether the switch `name` (e.g. "--list-ports") was given.
+  pub fn flag(&self, name: &str) -> bool {
+    self.args.iter().any(|a| a == name)
+  }
+
+  /// The value of the last occurrence of `name`, if any.
+  pub fn value(&self, name: &str) -> Option<&str> {
+    self.values(name).pop()
+  }
+
+  /// The values of every occurrence of a repeatable flag, in order.
+  pub fn values(&self, name: &str) -> Vec<&str> {
+    let prefix: String = format!("{}=", name);
+    let mut found: Vec<&str> = Vec::new();
+    let mut i: usize = 0;
+    while i < self.args.len() {
+      let arg: &str = &self.args[i];
+      if let Some(v) = arg.strip_prefix(prefix.as_str()) {
+        found.push(v);
+      } else if arg == name && i + 1 < self.args.len() {
+        found.push(&self.args[i + 1]);
+        i += 1;
+      }
+      i += 1;
+    }
+    found
+  }
+
+  /// Parses the value of `name`, if it was given.
+  pub fn parse<T>(&self, name: &str) -> Result<Option<T>, String>
+  where T: FromStr, T::Err: Display {
+    match self.value(name) {
+      None => Ok(None),
+      Some(raw) => parse_value(name, raw).map(Some),
+    }
+  }
+
+  /// Parses the value of `name`, or returns `default` if it wasn't given.
+  pub fn parse_or<T>(&self, name: &str, default: T) -> Result<T, String>
+  where T: FromStr, T::Err: Display {
+    Ok(self.parse(name)?.unwrap_or(default))
+  }
+
+  /// `--octave-convention`, the name of note 60: C3, C4 (the default) or C5.
+  pub fn octave_convention(&self) -> Result<OctaveConvention, String> {
+    self.parse_or("--octave-convention", OctaveConvention::default())
+  }
+
+  /// Reads a note given as a number (`108`) or a name (`C8`), if given.
+  /// Names follow `--octave-convention`.
+  pub fn note(&self, name: &str) -> Result<Option<u8>, String> {
+    let octaves: OctaveConvention = self.octave_convention()?;
+    self.value(name).map(|raw| parse_note(name, raw, octaves)).transpose()
+  }
+
+  /// Like `note`, or returns `default` if it wasn't given.
+  pub fn note_or(&self, name: &str, default: u8) -> Result<u8, String> {
+    Ok(self.note(name)?.unwrap_or(default))
+  }
+
+  /// Reads an inclusive range of notes like `36:96` or `C2:C7`, if given.
+  pub fn note_range(&self, name: &str) -> Result<Option<RangeInclusive<u8>>, String> {
+    let octaves: OctaveConvention = self.octave_convention()?;
+    self.value(name).map(|raw| parse_note_range(name, raw, octaves)).transpose()
+  }
+}
+
+pub fn parse_value<T>(name: &str, raw: &str) -> Result<T, String>
+where T: FromStr, T::Err: Display {
+  raw.trim().parse::<T>()
+    .map_err(|e| format!("bad value for {}: '{}' ({})", name, raw, e))
+}
+
+/// Parses a note number in 0-127 or a note name like `C8` or `F#2`.
+pub fn parse_note(name: &str, raw: &str, octaves: OctaveConvention) -> Result<u8, String> {
+  let raw_note: &str = raw.trim();
+  raw_note.parse::<u8>().ok()
+    .or_else(|| octaves.parse(raw_note))
+    .filter(|&note| note <= 127)
+    .ok_or(format!("bad value for {}: '{}' (expected 0-127 or a note name like C8)",
+                   name, raw))
+}
+
+/// Parses `<low>:<high>`, each a note as in `parse_note`.
+pub fn parse_note_range(
+  name: &str,
+  raw: &str,
+  octaves: OctaveConvention,
+) -> Result<RangeInclusive<u8>, String> {
+  let (low, high): (&str, &str) = raw.split_once(':')
+    .ok_or(format!("bad value for {}: '{}' (expected <low>:<high>)", name, raw))?;
+  let (low, high): (u8, u8) = (parse_note(name, low, octaves)?, parse_note(name, high, octaves)?);
+  if low > high {
+    return Err(format!("bad value for {}: '{}' (an empty range)", name, raw));
+  }
+  Ok(low..=high)
+}
+
+/// Parses a comma-separated value like `1,74`.
+pub fn parse_list<T>(name: &str, raw: &str) -> Result<Vec<T>, String>
+where T: FromStr, T::Err: Display {
+  raw.split(',').map(|item| parse_value(name, item)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(list: &[&str]) -> Args {
+    Args::new(list.iter().map(|s| s.to_string()).collect())
+  }
+
+  #[test]
+  fn values_with_space_or_equals() {
+    let a: Args = args(&["--rate-ms", "120", "--zone=0:59:bass", "--zone", "60:127:lead"]);
+    assert_eq!(a.value("--rate-ms"), Some("120"));
+    assert_eq!(a.values("--zone"), vec!["0:59:bass", "60:127:lead"]);
+    assert_eq!(a.value("--zone"), Some("60:127:lead"));
+    assert_eq!(a.value("--missing"), None);
+  }
+
+  #[test]
+  fn switches() {
+    let a: Args = args(&["--list-ports", "--rate-ms", "5"]);
+    assert!(a.flag("--list-ports"));
+    assert!(!a.flag("--legato"));
+  }
+
+  #[test]
+  fn parsing() {
+    let a: Args = args(&["--rate-ms", "120", "--gamma", "x"]);
+    assert_eq!(a.parse::<u64>("--rate-ms"), Ok(Some(120)));
+    assert_eq!(a.parse_or::<u64>("--off-ms", 200), Ok(200));
+    assert!(a.parse::<f64>("--gamma").is_err());
+    assert_eq!(parse_list::<u8>("--cc", "1, 74"), Ok(vec![1, 74]));
+    assert!(parse_list::<u8>("--cc", "1,,74").is_err());
+  }
+
+  #[test]
+  fn notes_by_number_or_name() {
+    let a: Args = args(&["--trigger-note", "C8", "--stop-note", "106", "--bad", "C10"]);
+    assert_eq!(a.note("--trigger-note"), Ok(Some(108)));
+    assert_eq!(a.note_or("--stop-note", 0), Ok(106));
+    assert_eq!(a.note_or("--pause-note", 105), Ok(105));
+    assert!(a.note("--bad").is_err());
+    assert!(parse_note("--note", "128", OctaveConvention::C4).is_err());
+  }
+
+  #[test]
+  fn note_ranges() {
+    let a: Args = args(&["--note-range", "C2:96", "--backwards", "96:36", "--one", "36"]);
+    assert_eq!(a.note_range("--note-range"), Ok(Some(36..=96)));
+    assert_eq!(a.note_range("--missing"), Ok(None));
+    assert!(a.note_range("--backwards").is_err());
+    assert!(a.note_range("--one").is_err());
+  }
+
+  #[test]
+  fn command_line_flags_override_the_config() {
+    let config: Config = config::parse(
+      "[sampler]\ntrigger-note = \"C8\"\nclick-bpm = 96\ntrim = true\n\
+       [edo72]\nchannel-gain = [\"3:1.1\", \"4:0.9\"]\n").unwrap();
+    let a: Args = args(&["--click-bpm=120"]).over(&config, "sampler");
+    assert_eq!(a.note("--trigger-note"), Ok(Some(108)));
+    assert_eq!(a.value("--click-bpm"), Some("120"));
+    assert!(a.flag("--trim"));
+    let a: Args = args(&[]).over(&config, "edo72");
+    assert_eq!(a.values("--channel-gain"), vec!["3:1.1", "4:0.9"]);
+    let a: Args = args(&["--channel-gain", "5:2"]).over(&config, "edo72");
+    assert_eq!(a.values("--channel-gain"), vec!["5:2"]);
+  }
+
+  #[test]
+  fn note_names_follow_the_octave_convention() {
+    let a: Args = args(&["--trigger-note", "C8", "--octave-convention", "C3"]);
+    assert_eq!(a.note("--trigger-note"), Ok(Some(120)));
+    assert!(args(&["--octave-convention", "C9"]).note("--x").is_err());
+  }
+}
@@ -0,0 +1,141 @@
+//! 14-bit controllers, sent as an MSB CC and an LSB CC (like CC 1 and CC 33).
+//!
+//! The spec says the MSB comes first, but plenty of hardware sends the LSB
+//! first, so a value counts as complete once both halves have arrived,
+//! in either order.
+
+use crate::{control_change, get_channel, CONTROL_CHANGE};
+use std::collections::HashMap;
+
+pub const MAX_14_BIT: u16 = 0x3FFF;
+
+/// An MSB controller and the LSB controller that goes with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cc14Pair {
+  pub msb: u8,
+  pub lsb: u8,
+}
+
+impl Cc14Pair {
+  /// The usual pairing: controllers 0-31 with 32-63.
+  pub fn standard(msb: u8) -> Self {
+    Cc14Pair { msb, lsb: msb + 32 }
+  }
+
+  /// The two CC messages carrying `value`, MSB first.
+  pub fn messages(&self, channel: u8, value: u16) -> [Vec<u8>; 2] {
+    let (msb, lsb): (u8, u8) = split(value);
+    [control_change(channel, self.msb, msb), control_change(channel, self.lsb, lsb)]
+  }
+}
+
+pub fn combine(msb: u8, lsb: u8) -> u16 {
+  ((msb as u16 & 0x7F) << 7) | (lsb as u16 & 0x7F)
+}
+
+/// (MSB, LSB) of a 14-bit value. Values above 0x3FFF are clamped.
+pub fn split(value: u16) -> (u8, u8) {
+  let value: u16 = value.min(MAX_14_BIT);
+  ((value >> 7) as u8, (value & 0x7F) as u8)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cc14Event {
+  /// Not a half of any configured pair.
+  Other,
+  /// One half has arrived; waiting for the other.
+  Pending,
+  /// Both halves are in.
+  Complete { channel: u8, pair: Cc14Pair, value: u16 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Half {
+  Msb(u8),
+  Lsb(u8),
+}
+
+/// Pairs up the halves of 14-bit controllers, per channel.
+pub struct Cc14Receiver {
+  pairs: Vec<Cc14Pair>,
+  pending: HashMap<(u8, u8), Half>, // (channel, msb cc)
+}
+
+impl Cc14Receiver {
+  pub fn new(pairs: Vec<Cc14Pair>) -> Self {
+    Cc14Receiver { pairs, pending: HashMap::new() }
+  }
+
+  pub fn receive(&mut self, message: &[u8]) -> Cc14Event {
+    let channel: u8 = match get_channel(message) {
+      Some(c) if message[0] & 0xF0 == CONTROL_CHANGE && message.len() >= 3 => c,
+      _ => return Cc14Event::Other,
+    };
+    let (cc, data): (u8, u8) = (message[1], message[2]);
+    let (pair, half): (Cc14Pair, Half) =
+      match self.pairs.iter().find(|p| p.msb == cc || p.lsb == cc) {
+        Some(&p) if p.msb == cc => (p, Half::Msb(data)),
+        Some(&p) => (p, Half::Lsb(data)),
+        None => return Cc14Event::Other,
+      };
+    let key: (u8, u8) = (channel, pair.msb);
+    match (self.pending.remove(&key), half) {
+      (Some(Half::Msb(msb)), Half::Lsb(lsb)) | (Some(Half::Lsb(lsb)), Half::Msb(msb)) =>
+        Cc14Event::Complete { channel, pair, value: combine(msb, lsb) },
+      _ => {
+        // The same half twice means the other was lost; keep the newer.
+        self.pending.insert(key, half);
+        Cc14Event::Pending
+      }
+    }
+  }
+
+  /// Passes other messages through unchanged, holds back a lone half,
+  /// and re-sends each completed pair with `f` applied to its value.
+  pub fn transform(&mut self, message: &[u8], f: impl FnOnce(u16) -> u16) -> Vec<Vec<u8>> {
+    match self.receive(message) {
+      Cc14Event::Other => vec![message.to_vec()],
+      Cc14Event::Pending => vec![],
+      Cc14Event::Complete { channel, pair, value } => pair.messages(channel, f(value)).to_vec(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_and_combine_round_trip() {
+    assert_eq!(split(0x2345), (0x46, 0x45));
+    assert_eq!(combine(0x46, 0x45), 0x2345);
+    assert_eq!(split(u16::MAX), (127, 127));
+    assert_eq!(Cc14Pair::standard(1).messages(2, 8192), [vec![0xB2, 1, 64], vec![0xB2, 33, 0]]);
+  }
+
+  #[test]
+  fn msb_then_lsb() {
+    let mut r: Cc14Receiver = Cc14Receiver::new(vec![Cc14Pair::standard(1)]);
+    assert_eq!(r.receive(&[0xB0, 1, 64]), Cc14Event::Pending);
+    assert_eq!(r.receive(&[0xB0, 33, 5]), Cc14Event::Complete {
+      channel: 0, pair: Cc14Pair::standard(1), value: 64 * 128 + 5 });
+  }
+
+  #[test]
+  fn lsb_then_msb() {
+    let mut r: Cc14Receiver = Cc14Receiver::new(vec![Cc14Pair { msb: 7, lsb: 39 }]);
+    assert_eq!(r.receive(&[0xB3, 39, 127]), Cc14Event::Pending);
+    assert_eq!(r.receive(&[0xB0, 7, 1]), Cc14Event::Pending); // other channel
+    assert_eq!(r.receive(&[0xB3, 7, 1]), Cc14Event::Complete {
+      channel: 3, pair: Cc14Pair { msb: 7, lsb: 39 }, value: 255 });
+  }
+
+  #[test]
+  fn transform_re_emits_the_pair() {
+    let mut r: Cc14Receiver = Cc14Receiver::new(vec![Cc14Pair::standard(1)]);
+    assert_eq!(r.transform(&[0xB0, 74, 9], |v| v), vec![vec![0xB0, 74, 9]]);
+    assert!(r.transform(&[0xB0, 33, 0], |v| v).is_empty());
+    assert_eq!(r.transform(&[0xB0, 1, 32], |v| v * 2),
+               vec![vec![0xB0, 1, 64], vec![0xB0, 33, 0]]);
+  }
+}
@@ -0,0 +1,98 @@
+//! Following an incoming MIDI clock.
+
+use crate::{CONTINUE, START, STOP, TIMING_CLOCK};
+use std::time::{Duration, Instant};
+
+/// Where an external clock has got to, counted in clock ticks
+/// (24 per quarter note) since the last Start or Continue.
+#[derive(Debug, Default)]
+pub struct ClockFollower {
+  running: bool,
+  ticks: u64, // received since the last Start
+  starts: u64, // how many Starts (or Continues) so far
+  last_tick: Option<Instant>,
+  period: Option<Duration>, // between the last two ticks
+}
+
+impl ClockFollower {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes note of a real-time message. Returns false for anything else.
+  /// Continue is treated like Start: the count begins again.
+  pub fn handle(&mut self, data: &[u8], now: Instant) -> bool {
+    match data.first() {
+      Some(&TIMING_CLOCK) => {
+        if self.running {
+          self.ticks += 1;
+        }
+        if let Some(last) = self.last_tick {
+          self.period = Some(now - last);
+        }
+        self.last_tick = Some(now);
+      }
+      Some(&START) | Some(&CONTINUE) => {
+        self.running = true;
+        self.ticks = 0;
+        self.starts += 1;
+      }
+      Some(&STOP) => self.running = false,
+      _ => return false,
+    }
+    true
+  }
+
+  /// Which Start the count belongs to, and the position in ticks,
+  /// with tick 0 on the first clock after the Start. Between clocks the
+  /// position moves on smoothly at the recent tempo, but never reaches
+  /// the next tick before it arrives. None if stopped or not yet ticking.
+  pub fn position(&self, now: Instant) -> Option<(u64, f64)> {
+    if !self.running || self.ticks == 0 {
+      return None;
+    }
+    let fraction: f64 = match (self.last_tick, self.period) {
+      (Some(last), Some(period)) if !period.is_zero() =>
+        (now.saturating_duration_since(last).as_secs_f64() / period.as_secs_f64()).min(0.999),
+      _ => 0.0,
+    };
+    Some((self.starts, (self.ticks - 1) as f64 + fraction))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_from_start_and_interpolates() {
+    let mut clock: ClockFollower = ClockFollower::new();
+    let t0: Instant = Instant::now();
+    let ms = |ms: u64| t0 + Duration::from_millis(ms);
+    assert!(clock.handle(&[TIMING_CLOCK], t0));
+    assert_eq!(clock.position(t0), None); // not started
+    clock.handle(&[START], ms(5));
+    assert_eq!(clock.position(ms(5)), None); // no tick yet
+    clock.handle(&[TIMING_CLOCK], ms(20));
+    assert_eq!(clock.position(ms(20)), Some((1, 0.0)));
+    clock.handle(&[TIMING_CLOCK], ms(40));
+    assert_eq!(clock.position(ms(50)), Some((1, 1.5)));
+    assert_eq!(clock.position(ms(500)), Some((1, 1.999)));
+    assert!(!clock.handle(&[0x90, 60, 100], ms(50)));
+  }
+
+  #[test]
+  fn stop_halts_and_continue_restarts_the_count() {
+    let mut clock: ClockFollower = ClockFollower::new();
+    let t0: Instant = Instant::now();
+    clock.handle(&[START], t0);
+    clock.handle(&[TIMING_CLOCK], t0);
+    clock.handle(&[TIMING_CLOCK], t0);
+    clock.handle(&[STOP], t0);
+    assert_eq!(clock.position(t0), None);
+    clock.handle(&[TIMING_CLOCK], t0); // clocks while stopped don't count
+    clock.handle(&[CONTINUE], t0);
+    clock.handle(&[TIMING_CLOCK], t0);
+    assert_eq!(clock.position(t0).map(|(starts, pos)| (starts, pos as u64)), Some((2, 0)));
+  }
+}
@@ -0,0 +1,257 @@
+//! Defaults for every binary's flags, from a config file.
+//!
+//! The file is `$LIVE_MIDI_CONFIG` if that's set, or else
+//! `$XDG_CONFIG_HOME/live-midi/config.toml` (by default
+//! `~/.config/live-midi/config.toml`); `--config <path>` names another,
+//! and `--no-config` ignores it. It's optional: with no file, nothing
+//! changes.
+//!
+//! It holds flags, written the TOML way:
+//!
+//! ```toml
+//! # Before any section: for every binary.
+//! octave-convention = "C3"
+//!
+//! [sampler]           # sections are named after binaries
+//! trigger-note = "C8"
+//! click-bpm = 96
+//! trim = true         # a switch; false leaves it off
+//!
+//! [edo72]
+//! channel-gain = ["3:1.1", "4:0.9"]   # a repeatable flag
+//!
+//! [add_echo]
+//! delay-ms = 250
+//!
+//! [polite_ping]
+//! note = "C7"
+//! on-ms = 80
+//! ```
+//!
+//! Each key is a flag without its `--`, so a section can set
+//! whatever the binary's own flags can. This module only turns the
+//! file into flag strings, which `Args::over` merges with the command
+//! line's; there are no typed settings here. The binary then parses and
+//! checks them as it would anything typed, so a bad value in the file
+//! gets the same error as on the command line. Precedence runs built-in
+//! defaults < config file < command-line flags: a key in a binary's section overrides the same
+//! key before any section, and a flag given on the command line
+//! replaces the file's value altogether, even for repeatable flags.
+//!
+//! Only that much of TOML is understood: comments, `[section]`
+//! headers, and `key = value` lines whose value is a string, a number,
+//! a bare word, `true`/`false`, or a one-line array of those.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+  /// (section, key, values); the section is "" before the first header.
+  entries: Vec<(String, String, Value)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+  Switch(bool),
+  Values(Vec<String>), // one per occurrence of the flag
+}
+
+impl Config {
+  /// The flags the file gives `binary`, as if typed on its command line.
+  pub fn flags_for(&self, binary: &str) -> Vec<String> {
+    let mut flags: Vec<String> = Vec::new();
+    for (section, key, value) in self.section("").chain(self.section(binary)) {
+      let overridden: bool = section.is_empty()
+        && self.section(binary).any(|(_, k, _)| k == key);
+      if overridden {
+        continue;
+      }
+      let flag: String = format!("--{}", key);
+      match value {
+        Value::Switch(true) => flags.push(flag),
+        Value::Switch(false) => {}
+        Value::Values(values) => for v in values {
+          flags.push(flag.clone());
+          flags.push(v.clone());
+        },
+      }
+    }
+    flags
+  }
+
+  fn section<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a (String, String, Value)> {
+    self.entries.iter().filter(move |(s, _, _)| s == name)
+  }
+}
+
+/// Where the config file is looked for when `--config` isn't given.
+pub fn default_path() -> Option<PathBuf> {
+  if let Some(path) = std::env::var_os("LIVE_MIDI_CONFIG") {
+    return Some(PathBuf::from(path));
+  }
+  let config_home: PathBuf = match std::env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) => PathBuf::from(dir),
+    None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+  };
+  Some(config_home.join("live-midi").join("config.toml"))
+}
+
+pub fn load(path: &Path) -> Result<Config, String> {
+  let text: String = std::fs::read_to_string(path)
+    .map_err(|e| format!("can't read config '{}': {}", path.display(), e))?;
+  parse(&text).map_err(|e| format!("in config '{}': {}", path.display(), e))
+}
+
+pub fn parse(text: &str) -> Result<Config, String> {
+  let mut config: Config = Config::default();
+  let mut section: String = String::new();
+  for (number, raw) in text.lines().enumerate() {
+    let line: &str = strip_comment(raw).trim();
+    if line.is_empty() {
+      continue;
+    }
+    let error = |what: &str| format!("line {}: {}: '{}'", number + 1, what, raw.trim());
+    if let Some(header) = line.strip_prefix('[') {
+      section = header.strip_suffix(']')
+        .filter(|name| !name.trim().is_empty())
+        .ok_or_else(|| error("bad section header"))?
+        .trim().to_string();
+      continue;
+    }
+    let (key, value): (&str, &str) = line.split_once('=')
+      .ok_or_else(|| error("expected key = value"))?;
+    let key: &str = key.trim().trim_start_matches("--");
+    if key.is_empty() {
+      return Err(error("missing key"));
+    }
+    let value: Value = parse_value(value.trim()).ok_or_else(|| error("bad value"))?;
+    config.entries.retain(|(s, k, _)| !(s == &section && k == key)); // the last one counts
+    config.entries.push((section.clone(), key.to_string(), value));
+  }
+  Ok(config)
+}
+
+/// A line without any `#` comment, leaving `#` inside strings alone
+/// (as in `"C#4"`).
+fn strip_comment(line: &str) -> &str {
+  let mut in_string: bool = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      '"' => in_string = !in_string,
+      '#' if !in_string => return &line[..i],
+      _ => {}
+    }
+  }
+  line
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+  match text {
+    "true" => return Some(Value::Switch(true)),
+    "false" => return Some(Value::Switch(false)),
+    _ => {}
+  }
+  match text.strip_prefix('[') {
+    Some(list) => {
+      let items: &str = list.strip_suffix(']')?.trim();
+      if items.is_empty() {
+        return Some(Value::Values(Vec::new()));
+      }
+      split_items(items).into_iter()
+        .map(parse_scalar)
+        .collect::<Option<Vec<String>>>()
+        .map(Value::Values)
+    }
+    None => parse_scalar(text).map(|v| Value::Values(vec![v])),
+  }
+}
+
+/// Splits an array's contents at commas outside strings.
+fn split_items(items: &str) -> Vec<&str> {
+  let mut result: Vec<&str> = Vec::new();
+  let mut in_string: bool = false;
+  let mut start: usize = 0;
+  for (i, c) in items.char_indices() {
+    match c {
+      '"' => in_string = !in_string,
+      ',' if !in_string => {
+        result.push(items[start..i].trim());
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  let last: &str = items[start..].trim();
+  if !last.is_empty() {
+    result.push(last); // a trailing comma is allowed
+  }
+  result
+}
+
+fn parse_scalar(text: &str) -> Option<String> {
+  let text: &str = text.trim();
+  match text.strip_prefix('"') {
+    Some(quoted) => {
+      let inner: &str = quoted.strip_suffix('"')?;
+      if inner.contains('"') {
+        return None;
+      }
+      Some(inner.to_string())
+    }
+    None if text.is_empty() || text.contains(char::is_whitespace) => None,
+    None => Some(text.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EXAMPLE: &str = r#"
+# shared
+octave-convention = "C3"
+swing = 0.2
+
+[sampler]
+trigger-note = "C#7"  # not a comment inside the string
+trim = true
+no-panic = false
+swing = 0.33
+
+[edo72]
+channel-gain = ["3:1.1", "4:0.9",]
+"#;
+
+  fn strings(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn sections_become_flags() {
+    let config: Config = parse(EXAMPLE).unwrap();
+    assert_eq!(config.flags_for("sampler"),
+               strings(&["--octave-convention", "C3",
+                         "--trigger-note", "C#7", "--trim", "--swing", "0.33"]));
+    assert_eq!(config.flags_for("edo72"),
+               strings(&["--octave-convention", "C3", "--swing", "0.2",
+                         "--channel-gain", "3:1.1", "--channel-gain", "4:0.9"]));
+    assert_eq!(config.flags_for("arp"),
+               strings(&["--octave-convention", "C3", "--swing", "0.2"]));
+  }
+
+  #[test]
+  fn later_keys_replace_earlier_ones() {
+    let config: Config = parse("[arp]\nrate-ms = 100\nrate-ms = 120\n").unwrap();
+    assert_eq!(config.flags_for("arp"), strings(&["--rate-ms", "120"]));
+  }
+
+  #[test]
+  fn malformed_lines_are_reported() {
+    assert!(parse("[sampler").unwrap_err().contains("line 1"));
+    assert!(parse("\nrate-ms 100").unwrap_err().contains("line 2"));
+    assert!(parse("note = \"C4").is_err());
+    assert!(parse("note = C 4").is_err());
+    assert!(parse("= 4").is_err());
+    assert_eq!(parse("").unwrap(), Config::default());
+  }
+}
@@ -0,0 +1,83 @@
+//! Sending messages later: a queue that holds each one until its time.
+//!
+//! Callbacks put `DelayedMessage`s on a channel and a thread running
+//! `run_delay_thread` sends them when they fall due, so a binary that
+//! moves notes in time (humanize, strum) never sleeps in its callback.
+
+use crate::ports::SendMonitor;
+use crate::sink::MidiSink;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct DelayedMessage {
+  pub data: Vec<u8>,
+  pub send_at: Instant,
+}
+
+/// Goes out as soon as it can, as the all-notes-off at exit does.
+impl From<Vec<u8>> for DelayedMessage {
+  fn from(data: Vec<u8>) -> Self {
+    DelayedMessage { data, send_at: Instant::now() }
+  }
+}
+
+/// Holds each message until its time comes, like add_echo's echo thread.
+/// Once every sender is gone it sends what is due and drops the rest,
+/// which would otherwise sound after the all-notes-off that came last.
+pub fn run_delay_thread(
+  mut conn: impl MidiSink,
+  rx: mpsc::Receiver<DelayedMessage>,
+) {
+  let mut queue: Vec<DelayedMessage> = Vec::new();
+  let mut monitor: SendMonitor = SendMonitor::new();
+  loop {
+    let mut closed: bool = false;
+    loop {
+      match rx.try_recv() {
+        Ok(msg) => queue.push(msg),
+        Err(mpsc::TryRecvError::Empty) => break,
+        Err(mpsc::TryRecvError::Disconnected) => {
+          closed = true;
+          break;
+        }
+      }
+    }
+
+    // Send any messages whose time has come, oldest first
+    let now: Instant = Instant::now();
+    let mut i: usize = 0;
+    while i < queue.len() {
+      if queue[i].send_at <= now {
+        let msg: DelayedMessage = queue.remove(i);
+        monitor.send(&mut conn, &msg.data);
+      } else {
+        i += 1;
+      }
+    }
+    if closed {
+      return;
+    }
+
+    // Sleep briefly to avoid busy-waiting
+    thread::sleep(Duration::from_millis(1));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn closing_sends_what_is_due_and_drops_the_rest() {
+    let (tx, rx): (mpsc::Sender<DelayedMessage>, mpsc::Receiver<DelayedMessage>) =
+      mpsc::channel();
+    let later: Instant = Instant::now() + Duration::from_secs(60);
+    tx.send(DelayedMessage { data: vec![0x90, 60, 100], send_at: later }).unwrap();
+    tx.send(DelayedMessage::from(vec![0xB0, 123, 0])).unwrap();
+    drop(tx);
+    let mut sent: Vec<Vec<u8>> = vec![];
+    run_delay_thread(&mut sent, rx);
+    assert_eq!(sent, vec![vec![0xB0, 123, 0]]);
+  }
+}
@@ -0,0 +1,928 @@
+//! The 72-EDO transform behind the edo72 binary, without its ports
+//! and threads, for embedding in another host: make an
+//! `Edo72Transformer`, set what differs from the defaults, and hand
+//! each incoming message to `process`, sending on whatever it returns.
+//! It keeps the held notes and shifts itself, so it wants every message
+//! from the keyboard, in order. What the keys do, and the settings,
+//! are described in the binary's docs. It prints nothing: what the
+//! player changes (and the tuning table, when asked for) comes back
+//! from `take_notices`, for the host to show however suits it.
+
+use crate::args::Args;
+use crate::voices::fan_out;
+use crate::{is_note_event, is_note_off, is_note_on, note_off, note_on, single_note_tuning,
+            POLY_PRESSURE, CONTROL_CHANGE, PITCH_BEND};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+struct TransformedNote {
+  output_channel: u8,
+  output_note: u8,
+  velocity: u8, // kept so the note can be re-sent if retuned
+}
+
+/// What to do with a note whose channel or note
+/// falls outside what MIDI allows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutOfRange {
+  Drop,
+  Fold,
+  Clamp,
+}
+
+impl FromStr for OutOfRange {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "drop" => Ok(OutOfRange::Drop),
+      "fold" => Ok(OutOfRange::Fold),
+      "clamp" => Ok(OutOfRange::Clamp),
+      _ => Err("expected fold, drop or clamp".to_string()), }}}
+
+/// How the tuning reaches the synth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputMode {
+  Channels, // a channel per octave, each tuned to 72-EDO
+  Mts, // one channel, each note retuned by SysEx
+}
+
+impl FromStr for OutputMode {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "channels" => Ok(OutputMode::Channels),
+      "mts" => Ok(OutputMode::Mts),
+      _ => Err("expected channels or mts".to_string()), }}}
+
+struct ShiftPress {
+  shift_value: i8,
+}
+
+/// Something the player changed, for the host to report.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Notice {
+  TuningTable(String), // the print key was pressed; one line per row
+  OctaveShift(i8),
+  Transpose(i8),
+  TuningReset,
+}
+
+/// The transform, and everything it remembers between messages.
+/// Its settings are public, to set once it's made; the rest is what
+/// the playing so far has left behind, and only changes through `process`.
+pub struct Edo72Transformer {
+  // input note -> what its note-on was sent as
+  ongoing_notes: HashMap<u8, TransformedNote>,
+  // offset-control note -> its shift, while held
+  ongoing_shifts: HashMap<u8, ShiftPress>,
+  // pitch class -> shift, persisting after the shift keys are released
+  pitch_class_shifts: HashMap<u8, i8>,
+  // whole octaves added to every input note, by the octave keys
+  octave_shift: i8,
+  // Semitones added to every key once LOWEST_A is subtracted, so the
+  // key landing on MIN_CHANNEL's MIN_NOTE is LOWEST_A minus this, and
+  // every key sounds this far from its usual pitch. It moves the
+  // channel layout itself, unlike the octave keys.
+  pub transpose: i8,
+  pub out_of_range: OutOfRange,
+  pub controls: ControlZone,
+  // output channel -> what its note-on velocities are multiplied by
+  pub channel_gains: HashMap<u8, f64>,
+  pub mode: OutputMode,
+  pub mts_channel: u8,
+  // what has changed since the host last asked
+  notices: Vec<Notice>,
+}
+
+/// Where the tuning controls are: which keys, on which channel.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlZone {
+  pub start: u8, // the reset key; the shift keys follow it
+  pub channel: Option<u8>, // None for any channel
+  pub octave_down: Option<u8>,
+  pub octave_up: Option<u8>,
+  pub transpose_cc: Option<u8>,
+}
+
+impl ControlZone {
+  pub fn reset_note(&self) -> u8 {
+    self.start }
+
+  pub fn zero_note(&self) -> u8 {
+    self.start + 5 }
+
+  pub fn print_note(&self) -> u8 {
+    self.start - 1 }
+
+  pub fn notes(&self) -> RangeInclusive<u8> {
+    self.print_note() ..= self.start + 11 }
+
+  /// Whether a note event is a control rather than something to play.
+  fn claims(&self, message: &[u8]) -> bool {
+    self.channel.is_none_or(|c| c == message[0] & 0x0F)
+      && ( self.notes().contains(&message[1])
+           || self.octave_step(message[1]).is_some() ) }
+
+  /// -1 for the octave-down key, +1 for the octave-up key.
+  fn octave_step(&self, note: u8) -> Option<i8> {
+    if self.octave_down == Some(note) { Some(-1) }
+    else if self.octave_up == Some(note) { Some(1) }
+    else { None }}
+
+  /// Whether a CC is the transpose control.
+  fn claims_cc(&self, message: &[u8]) -> bool {
+    self.channel.is_none_or(|c| c == message[0] & 0x0F)
+      && self.transpose_cc.is_some_and(|cc| Some(&cc) == message.get(1)) }
+
+  /// Reads `--offset-octave-start`, `--control-channel`,
+  /// `--octave-down-key`, `--octave-up-key` and `--transpose-cc`.
+  pub fn from_args(args: &Args) -> Result<Self, String> {
+    let zone: ControlZone = ControlZone {
+      start: args.note_or("--offset-octave-start", OFFSET_OCTAVE_START)?,
+      channel: args.parse("--control-channel")?,
+      octave_down: args.note("--octave-down-key")?,
+      octave_up: args.note("--octave-up-key")?,
+      transpose_cc: args.parse("--transpose-cc")?, };
+    if !(1..=116).contains(&zone.start) {
+      return Err("--offset-octave-start must be in 1-116".to_string()); }
+    if zone.channel.is_some_and(|c| c > 15) {
+      return Err("--control-channel must be in 0-15".to_string()); }
+    if zone.transpose_cc.is_some_and(|cc| cc > 127) {
+      return Err("--transpose-cc must be in 0-127".to_string()); }
+    if zone.channel.is_none() && zone.print_note() <= LOWEST_A {
+      return Err(format!(
+        "the control keys {}-{} would cover the lowest A ({}); \
+         move them up, or give them their own --control-channel",
+        zone.print_note(), zone.start + 11, LOWEST_A)); }
+    for key in [zone.octave_down, zone.octave_up].into_iter().flatten() {
+      if zone.notes().contains(&key) {
+        return Err(format!("octave key {} is one of the tuning controls", key)); }}
+    if zone.octave_down.is_some() && zone.octave_down == zone.octave_up {
+      return Err("--octave-down-key and --octave-up-key must differ".to_string()); }
+    Ok(zone) }}
+
+impl Default for Edo72Transformer {
+  fn default() -> Self {
+    Edo72Transformer {
+      ongoing_notes: HashMap::new(),
+      ongoing_shifts: HashMap::new(),
+      pitch_class_shifts: HashMap::new(),
+      octave_shift: 0,
+      transpose: SHIFT_IN_12_EDO,
+      out_of_range: OutOfRange::Drop,
+      controls: ControlZone { start: OFFSET_OCTAVE_START,
+                              channel: None,
+                              octave_down: None,
+                              octave_up: None,
+                              transpose_cc: None },
+      channel_gains: HashMap::new(),
+      mode: OutputMode::Channels,
+      mts_channel: MIN_CHANNEL,
+      notices: vec![], }}}
+
+impl Edo72Transformer {
+  /// As the edo72 binary starts with no flags.
+  pub fn new() -> Self {
+    Edo72Transformer::default() }
+
+  /// Turns one whole incoming message (running status already
+  /// expanded) into what to send, which may be nothing.
+  pub fn process(
+    &mut self,
+    message: &[u8]
+  ) -> Vec<Vec<u8>> {
+    transform_message(self, message) }
+
+  /// What the player has changed since the last call, oldest first.
+  pub fn take_notices(
+    &mut self
+  ) -> Vec<Notice> {
+    std::mem::take(&mut self.notices) }}
+
+fn current_total_shift(
+  shifts: &HashMap<u8, ShiftPress>
+) -> Option<i16> {
+  if shifts . is_empty()
+  { None
+  } else { Some( shifts . values() . map(
+                   |s| s . shift_value as i16)
+                 . sum( )) }}
+
+pub const SHIFT_IN_12_EDO : i8 = -5;  // the default transpose: key 26 (D1) lands on MIN_NOTE
+const LOWEST_A        : u8 = 21;  // A0, lowest note on 88-key piano
+pub const MIN_CHANNEL : u8 = 1;   // adjust for whatever the synth wants
+pub const MIN_NOTE    : u8 = 28;  // could also be adjusted for the synth. I like to adjust the synth for this instead, though, because 28 = (128 - 72) / 2 puts the notes closest to the middle of the range [0,127], which makes future MIDI edits less constrained -- plenty of room to adjust up or down in either direction without switching channels.
+const EDO_OVER_12     : u8 = 6;   // 72 / 12 = 6
+const OFFSET_OCTAVE_START: u8 = 97;  // C#7 - default first note of offset control octave (top 12 keys), which clears all shifts; F#7 (102) means offset = 0
+const HIGHEST_KEY        : u8 = 108; // C8, highest note on 88-key piano
+const RETUNE_HELD_ON_RESET: bool = true; // whether held notes follow a reset
+const MAX_OCTAVE_SHIFT   : i8 = 4;   // how far the octave keys go either way
+const TABLE_OCTAVE_START : u8 = 60;  // C4 - the octave the tuning table describes
+const CENTS_PER_STEP     : f64 = 1200.0 / 72.0;
+const PITCH_CLASS_NAMES  : [&str; 12] =
+  ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// A note-on velocity, scaled by its output channel's gain.
+fn scaled_velocity(
+  channel_gains: &HashMap<u8, f64>,
+  channel: u8,
+  velocity: u8
+) -> u8 {
+  match channel_gains.get(&channel) {
+    None => velocity,
+    Some(gain) => (velocity as f64 * gain)
+                  . round() . clamp(1.0, 127.0) as u8 }}
+
+fn transform_message(
+  state: &mut Edo72Transformer,
+  message: &[u8]
+) -> Vec<Vec<u8>> {
+  if message.len() < 2 {
+    return vec![message.to_vec()]; }
+  let status: u8 = message[0] & 0xF0;
+  if status == POLY_PRESSURE {
+    return remap_poly_pressure(state, message); }
+  if status == CONTROL_CHANGE && message.len() >= 3
+     && state.controls.claims_cc(message) {
+    // Don't pass through the transpose control, only what it moves.
+    return set_transpose(state, message[2] as i8 - 64); }
+  if (CONTROL_CHANGE..=PITCH_BEND).contains(&status) {
+    return broadcast_channel_message(state, message); }
+  if message.len() < 3 || ! is_note_event(message)
+  { // Not a note event, so pass through unchanged.
+    return vec![message.to_vec()]; }
+  let original_note: u8 = message[1];
+  if ! state.controls.claims(message) {
+    handle_regular_note(state, message)
+  } else if let Some(step) = state.controls.octave_step(original_note) {
+    if is_note_on(message) {
+      shift_octave(state, step); }
+    vec![] // don't pass through the octave keys
+  } else if original_note == state.controls.print_note() {
+    if is_note_on(message) {
+      let table: String = tuning_table(state);
+      state.notices.push(Notice::TuningTable(table)); }
+    vec![] // don't pass through the print key
+  } else {
+    handle_offset_control(state, message) }}
+
+/// Copies a channel-wide message (CC, program change,
+/// channel pressure, pitch bend) onto every output channel,
+/// because the notes it should affect are spread across them.
+/// This is what makes the sustain pedal work across the split.
+fn broadcast_channel_message(
+  state: &Edo72Transformer,
+  message: &[u8]
+) -> Vec<Vec<u8>> {
+  fan_out(message, output_channels(state)) }
+
+/// Poly aftertouch names a note,
+/// so it goes wherever that note's note-on went.
+fn remap_poly_pressure(
+  state: &Edo72Transformer,
+  message: &[u8]
+) -> Vec<Vec<u8>> {
+  if message.len() < 3 {
+    return vec![]; }
+  match state.ongoing_notes.get(&message[1]) {
+    Some(old) => vec![vec![POLY_PRESSURE | old.output_channel,
+                           old.output_note,
+                           message[2]]],
+    None => vec![] }}
+
+/// The output channels that the playable keys can land on.
+pub fn output_channels(
+  state: &Edo72Transformer
+) -> RangeInclusive<u8> {
+  if state.mode == OutputMode::Mts {
+    return state.mts_channel ..= state.mts_channel; }
+  let (lowest, _): (i16, i16) =
+    edo72_instruction(&state.pitch_class_shifts,
+                      octave_shifted(state, LOWEST_A as i16),
+                      state.transpose, state.out_of_range);
+  let highest_key: u8 = playable_keys(state).last()
+    .unwrap_or(LOWEST_A);
+  let (highest, _): (i16, i16) =
+    edo72_instruction(&state.pitch_class_shifts,
+                      octave_shifted(state, highest_key as i16),
+                      state.transpose, state.out_of_range);
+  lowest.clamp(0, 15) as u8 ..= highest.clamp(0, 15) as u8 }
+
+/// The piano's keys, less any the tuning controls take from it.
+fn playable_keys(
+  state: &Edo72Transformer
+) -> impl Iterator<Item = u8> + '_ {
+  (LOWEST_A ..= HIGHEST_KEY)
+    .filter( |&key| state.controls.channel.is_some()
+                    || ! state.controls.notes().contains(&key)) }
+
+/// The playable keys that land outside what MIDI can send
+/// (before `--out-of-range` does anything about it)
+/// with every pitch class shifted `shift` steps.
+fn unreachable_keys(
+  state: &Edo72Transformer,
+  shift: i8
+) -> Vec<u8> {
+  let shifts: HashMap<u8, i8> =
+    (0..12).map( |pitch_class| (pitch_class, shift)).collect();
+  playable_keys(state)
+    .filter( |&key| {
+      let (channel, note): (i16, i16) =
+        edo72_instruction(&shifts, octave_shifted(state, key as i16),
+                          state.transpose, OutOfRange::Drop);
+      match state.mode {
+        OutputMode::Channels => ! (0..=15).contains(&channel)
+                                || ! (0..=127).contains(&note),
+        OutputMode::Mts => ! (0.0..128.0)
+                               .contains(&layout_pitch(channel, note)), }})
+    .collect() }
+
+/// Which keys MIN_CHANNEL, MIN_NOTE and the transpose put out of
+/// range, unshifted or at the largest single shift either way, one
+/// sentence per case, for the host to warn of so they don't just go quiet.
+pub fn unreachable_key_warnings(
+  state: &Edo72Transformer
+) -> Vec<String> {
+  let fate: &str = match (state.mode, state.out_of_range) {
+    (OutputMode::Mts, _) | (_, OutOfRange::Drop) => "be silent",
+    (_, OutOfRange::Fold) => "move by octaves to fit",
+    (_, OutOfRange::Clamp) => "be clamped, sounding wrong", };
+  let mut warnings: Vec<String> = vec![];
+  let unshifted: Vec<u8> = unreachable_keys(state, 0);
+  if ! unshifted.is_empty() {
+    warnings.push(format!(
+      "with MIN_CHANNEL {}, MIN_NOTE {} and transpose {:+}, \
+       keys {} are out of MIDI's range and will {}",
+      MIN_CHANNEL, MIN_NOTE, state.transpose,
+      key_ranges(&unshifted), fate)); }
+  let zero: i8 = state.controls.zero_note() as i8;
+  for shift in [state.controls.start as i8 + 1 - zero,
+                state.controls.start as i8 + 11 - zero] {
+    let more: Vec<u8> = unreachable_keys(state, shift).into_iter()
+      .filter( |key| ! unshifted.contains(key)).collect();
+    if ! more.is_empty() {
+      warnings.push(format!("shifted {:+} steps, keys {} would also {}",
+                            shift, key_ranges(&more), fate)); }}
+  warnings }
+
+/// Keys in order, written as runs: "21-25, 30".
+fn key_ranges(
+  keys: &[u8]
+) -> String {
+  let mut runs: Vec<(u8, u8)> = vec![];
+  for &key in keys {
+    match runs.last_mut() {
+      Some((_, end)) if *end + 1 == key => *end = key,
+      _ => runs.push((key, key)), }}
+  runs.iter()
+    .map( |&(start, end)| if start == end { start.to_string() }
+                          else { format!("{}-{}", start, end) })
+    .collect::<Vec<String>>().join(", ") }
+
+/// The current tuning, as the print key reports it.
+fn tuning_table(
+  state: &Edo72Transformer
+) -> String {
+  let mut held: Vec<(&u8, &ShiftPress)> =
+    state.ongoing_shifts.iter().collect();
+  held.sort_by_key(|(note, _)| **note);
+  let mut table: String = String::new();
+  if state.octave_shift != 0 {
+    let _ = writeln!(table, "  octave shift: {:+}", state.octave_shift); }
+  let _ = writeln!(table, "  transpose: {:+}", state.transpose);
+  if held.is_empty() {
+    let _ = writeln!(table, "  held shift keys: none");
+  } else {
+    let held_text: Vec<String> = held.iter()
+      .map( |(note, s)| format!("{} ({:+})", note, s.shift_value))
+      .collect();
+    let _ = writeln!(table, "  held shift keys: {} (total {:+})",
+                     held_text.join(", "),
+                     current_total_shift(&state.ongoing_shifts)
+                       .unwrap_or(0)); }
+  let _ = writeln!(table, "  {:<5} {:>5} {:>8} {:>6} {:>8} {:>5}",
+                   "pc", "steps", "cents", "input", "channel", "note");
+  for pitch_class in 0..12u8 {
+    let shift: i8 = state.pitch_class_shifts
+      .get(&pitch_class).copied().unwrap_or(0);
+    let input_note: u8 = TABLE_OCTAVE_START + pitch_class;
+    let (channel, note): (i16, i16) =
+      edo72_instruction(&state.pitch_class_shifts, input_note,
+                        state.transpose, state.out_of_range);
+    let _ = writeln!(table, "  {:<5} {:>+5} {:>+8.1} {:>6} {:>8} {:>5}",
+                     PITCH_CLASS_NAMES[pitch_class as usize],
+                     shift,
+                     shift as f64 * CENTS_PER_STEP,
+                     input_note, channel, note); }
+  table }
+
+/// Modifies the set of shifts.
+fn handle_offset_control(
+  state: &mut Edo72Transformer,
+  message: &[u8]
+) -> Vec<Vec<u8>> {
+  // Top octave controls the offset (F#7 = 0, G7 = +1, F7 = -1, etc.)
+  // Total shift = sum of all held shift notes.
+  let input_note: u8 = message[1];
+  let pressed: bool = is_note_on(message);
+  if input_note == state.controls.reset_note() {
+    return if pressed { reset_tuning(state) }
+           else { vec![] }; }
+  let shifts: &mut HashMap<u8, ShiftPress> =
+    &mut state.ongoing_shifts;
+  if pressed {
+    let shift_value: i8 = input_note as i8
+                          - state.controls.zero_note() as i8;
+    shifts.insert(input_note,
+                  ShiftPress { shift_value });
+  } else if is_note_off(message) {
+    shifts.remove(&input_note); }
+  vec![] } // don't pass through offset control notes
+
+/// Moves the keyboard an octave, within MAX_OCTAVE_SHIFT.
+/// Held notes stay where they are; `ongoing_notes` still knows
+/// where each one went, so its note-off follows it there.
+fn shift_octave(
+  state: &mut Edo72Transformer,
+  step: i8
+) {
+  state.octave_shift = (state.octave_shift + step)
+    .clamp(-MAX_OCTAVE_SHIFT, MAX_OCTAVE_SHIFT);
+  state.notices.push(Notice::OctaveShift(state.octave_shift)); }
+
+/// An input key moved by the octave shift, kept within 0-127.
+fn octave_shifted(
+  state: &Edo72Transformer,
+  note: i16
+) -> u8 {
+  (note + 12 * state.octave_shift as i16).clamp(0, 127) as u8 }
+
+/// Sets the transpose. It moves the layout under the held notes,
+/// so they move with it, as they do on a reset.
+/// Returns the messages that move them.
+fn set_transpose(
+  state: &mut Edo72Transformer,
+  transpose: i8
+) -> Vec<Vec<u8>> {
+  if transpose == state.transpose {
+    return vec![]; }
+  state.transpose = transpose;
+  state.notices.push(Notice::Transpose(state.transpose));
+  remap_held(state) }
+
+/// Forgets all shifts, held or persistent.
+/// Returns the messages that move held notes to their new pitch.
+fn reset_tuning(
+  state: &mut Edo72Transformer
+) -> Vec<Vec<u8>> {
+  state.ongoing_shifts.clear();
+  state.pitch_class_shifts.clear();
+  log::info!("tuning reset; {} held notes", state.ongoing_notes.len());
+  state.notices.push(Notice::TuningReset);
+  if ! RETUNE_HELD_ON_RESET {
+    return vec![]; }
+  remap_held(state) }
+
+/// Moves each held note to where its key now lands: in MTS mode by
+/// retuning it where it sounds, otherwise by ending it and striking it
+/// again there (or just ending it, if it now lands out of range).
+fn remap_held(
+  state: &mut Edo72Transformer
+) -> Vec<Vec<u8>> {
+  let mut results: Vec<Vec<u8>> = vec![];
+  let held: Vec<u8> = state.ongoing_notes.keys().copied().collect();
+  for original_note in held {
+    let (new_channel, new_note, retune): (i16, i16, Vec<Vec<u8>>) =
+      realize(state, original_note);
+    let old: &TransformedNote = &state.ongoing_notes[&original_note];
+    if old.output_channel as i16 == new_channel &&
+       old.output_note as i16 == new_note
+    { results.extend(retune); // MTS retunes it where it sounds
+      continue; }
+    let velocity: u8 = old.velocity;
+    results.push(note_off(old.output_channel, old.output_note, 0));
+    state.ongoing_notes.remove(&original_note);
+    if (0..=15).contains(&new_channel) &&
+       (0..=127).contains(&new_note)
+    { state.ongoing_notes.insert(original_note, TransformedNote {
+        output_channel: new_channel as u8,
+        output_note: new_note as u8,
+        velocity });
+      results.extend(retune);
+      results.push(note_on(
+        new_channel as u8, new_note as u8,
+        scaled_velocity(&state.channel_gains,
+                        new_channel as u8, velocity))); }}
+  results }
+
+fn handle_regular_note(
+  state: &mut Edo72Transformer,
+  message: &[u8]
+) -> Vec<Vec<u8>> {
+  let original_note: u8 = message[1];
+  let velocity: u8 = message[2];
+  let pressed: bool = is_note_on(message);
+  let released: bool = is_note_off(message);
+  if pressed {
+    // Update the persistent pitch class shift before transformation,
+    // but only if shift keys are being held (we find a Some).
+    if let Some(total_shift) =
+      current_total_shift(&state.ongoing_shifts) {
+      let pitch_class: u8 = original_note % 12;
+      state.pitch_class_shifts
+        .insert(pitch_class, total_shift as i8); }}
+  let (new_channel, new_note, retune): (i16, i16, Vec<Vec<u8>>) =
+    realize(state, original_note);
+  let output_in_range: bool = // what the MIDI standard allows
+    (0..=15).contains(&new_channel) &&
+    (0..=127).contains(&new_note);
+  let mut results: Vec<Vec<u8>> = vec![];
+  let ongoing: &mut HashMap<u8, TransformedNote> =
+    &mut state.ongoing_notes;
+  if pressed {
+    if let Some(old) = ongoing.get(&original_note) {
+      // The input note is already playing.
+      if !output_in_range ||
+         old.output_channel != new_channel as u8 ||
+         old.output_note != new_note as u8
+      { // The old note is somehow different. Silence it.
+        results.push(note_off(old.output_channel, old.output_note, 0)); }}
+    if output_in_range {
+      // Send the new note.
+      ongoing.insert(original_note, TransformedNote {
+        output_channel: new_channel as u8,
+        output_note: new_note as u8,
+        velocity });
+      results.extend(retune);
+      results.push(note_on(
+        new_channel as u8, new_note as u8,
+        scaled_velocity(&state.channel_gains,
+                        new_channel as u8, velocity))); }
+  } else if released {
+    if let Some(old) = ongoing.remove(&original_note) {
+      // Look up what output the earlier note-on produced.
+      results.push(note_off(old.output_channel, old.output_note, velocity));
+    } else if output_in_range {
+      // Somehow there is no record of the earlier note-on.
+      // Send a note-off anyway, using current settings.
+      results.push(note_off(new_channel as u8, new_note as u8, velocity)); }}
+  results }
+
+/// The channel and note an input note is sent as,
+/// and what has to go before its note-on:
+/// nothing for the channel layout, a tuning change for MTS.
+/// A note that can't be sent comes back with note -1.
+fn realize(
+  state: &Edo72Transformer,
+  original_note: u8
+) -> (i16, i16, Vec<Vec<u8>>) {
+  let shifted: i16 = original_note as i16 + 12 * state.octave_shift as i16;
+  if ! (0..=127).contains(&shifted) {
+    return (MIN_CHANNEL as i16, -1, vec![]); }
+  match state.mode {
+    OutputMode::Channels => {
+      let (channel, note): (i16, i16) =
+        edo72_instruction(&state.pitch_class_shifts, shifted as u8,
+                          state.transpose, state.out_of_range);
+      (channel, note, vec![]) }
+    OutputMode::Mts => {
+      // The key pressed is the one retuned, to the shifted pitch.
+      let (channel, note): (i16, i16) =
+        edo72_instruction(&state.pitch_class_shifts, shifted as u8,
+                          state.transpose, OutOfRange::Drop);
+      let semitones: f64 = layout_pitch(channel, note);
+      if (0.0..128.0).contains(&semitones) {
+        (state.mts_channel as i16, original_note as i16,
+         vec![single_note_tuning(original_note, semitones)])
+      } else { (state.mts_channel as i16, -1, vec![]) } }}}
+
+/// The pitch, in 12-EDO semitones above MIDI note 0,
+/// of a channel and note in the channel layout.
+fn layout_pitch(
+  channel: i16,
+  note: i16
+) -> f64 {
+  let steps: i16 = (channel - MIN_CHANNEL as i16) * 12 * EDO_OVER_12 as i16
+                   + note - MIN_NOTE as i16;
+  LOWEST_A as f64 + steps as f64 / EDO_OVER_12 as f64 }
+
+/// Where an input note goes. Under `OutOfRange::Drop`
+/// the result may lie outside MIDI's range, for the caller to reject;
+/// otherwise it is brought into range.
+fn edo72_instruction(
+  pitch_class_shifts: &HashMap<u8, i8>,
+  original_note: u8,
+  transpose: i8,
+  out_of_range: OutOfRange
+) -> (i16, // channel
+      i16) { // note
+  let normalized: i16 = original_note as i16
+                        - LOWEST_A as i16
+                        + transpose as i16;
+  let channel_offset: i16 = normalized.div_euclid(12);
+  let note_offset: i16 = normalized.rem_euclid(12);
+  let channel: i16 = MIN_CHANNEL as i16 + channel_offset;
+  let pitch_class: u8 = original_note % 12;
+  let shift :  i16 =
+    pitch_class_shifts
+    . get(&pitch_class) . copied()
+    . unwrap_or(0) as i16;
+  let note: i16 = MIN_NOTE as i16
+                  + note_offset * EDO_OVER_12 as i16
+                  + shift;
+  match out_of_range {
+    OutOfRange::Drop => (channel, note),
+    OutOfRange::Fold => fold_into_range(channel, note),
+    OutOfRange::Clamp => (channel.clamp(0, 15), note.clamp(0, 127)), }}
+
+/// Moves a note by whole octaves until MIDI can send it.
+/// First the note is re-expressed at the same pitch
+/// on a neighboring channel; then the channel is pinned,
+/// which moves the pitch by however many octaves that takes.
+fn fold_into_range(
+  mut channel: i16,
+  mut note: i16
+) -> (i16, i16) {
+  let octave: i16 = 12 * EDO_OVER_12 as i16;
+  while note > 127 {
+    note -= octave;
+    channel += 1; }
+  while note < 0 {
+    note += octave;
+    channel -= 1; }
+  (channel.clamp(0, 15), note) }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::harness::{feed, messages};
+  use crate::single_note_tuning;
+
+  #[test]
+  fn instruction_wraps_to_next_channel_every_12_keys() {
+    let no_shifts: HashMap<u8, i8> = HashMap::new();
+    // 37 is the last key on MIN_CHANNEL; 38 starts the next one.
+    assert_eq!(edo72_instruction(&no_shifts, 37, SHIFT_IN_12_EDO, OutOfRange::Drop),
+               (1, MIN_NOTE as i16 + 11 * 6));
+    assert_eq!(edo72_instruction(&no_shifts, 38, SHIFT_IN_12_EDO, OutOfRange::Drop),
+               (2, MIN_NOTE as i16)); }
+
+  #[test]
+  fn note_on_off_pairs_across_channel_boundary() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    assert_eq!(transform_message(&mut state, &[0x90, 37, 100]),
+               vec![vec![0x91, 94, 100]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x92, 28, 90]]);
+    assert_eq!(transform_message(&mut state, &[0x80, 38, 0]),
+               vec![vec![0x82, 28, 0]]);
+    // velocity-0 note-on is a note-off
+    assert_eq!(transform_message(&mut state, &[0x90, 37, 0]),
+               vec![vec![0x81, 94, 0]]);
+    assert!(state.ongoing_notes.is_empty()); }
+
+  #[test]
+  fn running_status_input_is_played_like_full_messages() {
+    // Keys 37 and 38 (0x25, 0x26); each second message leaves out its status byte.
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    assert_eq!(feed(&mut state, &messages("90 25 64, 26 5A, 80 25 00, 26 00"), transform_message),
+               messages("91 5E 64, 92 1C 5A, 81 5E 00, 82 1C 00"));
+    assert!(state.ongoing_notes.is_empty()); }
+
+  #[test]
+  fn note_off_matches_note_on_after_retuning() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    transform_message(&mut state, &[0x90, 38, 90]);
+    state.pitch_class_shifts.insert(38 % 12, 3);
+    assert_eq!(transform_message(&mut state, &[0x80, 38, 0]),
+               vec![vec![0x82, 28, 0]]); }
+
+  #[test]
+  fn sysex_and_short_messages_pass_through_whole() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    let sysex: Vec<u8> = vec![0xF0, 0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00, 0xF7];
+    assert_eq!(transform_message(&mut state, &sysex), vec![sysex.clone()]);
+    assert_eq!(transform_message(&mut state, &[0xF8]), vec![vec![0xF8]]);
+    assert!(transform_message(&mut state, &[0xC0, 5]).iter().all(|m| m.len() == 2));
+  }
+
+  #[test]
+  fn out_of_range_note_is_suppressed() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    // Far below the piano, so the channel would be negative.
+    assert_eq!(edo72_instruction(&state.pitch_class_shifts, 0,
+                                 SHIFT_IN_12_EDO, OutOfRange::Drop).0, -2);
+    assert!(transform_message(&mut state, &[0x90, 0, 100]).is_empty());
+    assert!(state.ongoing_notes.is_empty());
+    assert!(transform_message(&mut state, &[0x80, 0, 0]).is_empty()); }
+
+  #[test]
+  fn piano_extremes_are_in_range_however_handled() {
+    let no_shifts: HashMap<u8, i8> = HashMap::new();
+    for mode in [OutOfRange::Drop, OutOfRange::Fold, OutOfRange::Clamp] {
+      assert_eq!(edo72_instruction(&no_shifts, LOWEST_A, SHIFT_IN_12_EDO, mode),
+                 (0, MIN_NOTE as i16 + 7 * 6));
+      assert_eq!(edo72_instruction(&no_shifts, 108, SHIFT_IN_12_EDO, mode),
+                 (7, MIN_NOTE as i16 + 10 * 6)); }}
+
+  #[test]
+  fn fold_moves_by_octaves_and_clamp_pins() {
+    let mut shifts: HashMap<u8, i8> = HashMap::new();
+    // Below the piano: channel -2 folds up to 0, same note.
+    assert_eq!(edo72_instruction(&shifts, 0, SHIFT_IN_12_EDO, OutOfRange::Fold),
+               (0, MIN_NOTE as i16 + 10 * 6));
+    // Shifted past note 127: an octave (72) down, on the next channel.
+    shifts.insert(94 % 12, 60);
+    assert_eq!(edo72_instruction(&shifts, 94, SHIFT_IN_12_EDO, OutOfRange::Drop),
+               (6, MIN_NOTE as i16 + 8 * 6 + 60));
+    assert_eq!(edo72_instruction(&shifts, 94, SHIFT_IN_12_EDO, OutOfRange::Fold),
+               (7, MIN_NOTE as i16 + 8 * 6 + 60 - 72));
+    assert_eq!(edo72_instruction(&shifts, 94, SHIFT_IN_12_EDO, OutOfRange::Clamp),
+               (6, 127)); }
+
+  #[test]
+  fn folded_note_off_matches_its_note_on() {
+    let mut state: Edo72Transformer =
+      Edo72Transformer { out_of_range: OutOfRange::Fold, ..Edo72Transformer::new() };
+    assert_eq!(transform_message(&mut state, &[0x90, 0, 100]),
+               vec![vec![0x90, 88, 100]]);
+    state.out_of_range = OutOfRange::Drop;
+    assert_eq!(transform_message(&mut state, &[0x80, 0, 0]),
+               vec![vec![0x80, 88, 0]]);
+    assert!(state.ongoing_notes.is_empty()); }
+
+  #[test]
+  fn offset_control_note_produces_nothing() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    assert!(transform_message(&mut state, &[0x90, 103, 100]).is_empty());
+    assert_eq!(current_total_shift(&state.ongoing_shifts), Some(1));
+    // A note played while the shift is held is raised one step.
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x92, 29, 90]]);
+    assert!(transform_message(&mut state, &[0x80, 103, 0]).is_empty());
+    assert_eq!(current_total_shift(&state.ongoing_shifts), None); }
+
+  #[test]
+  fn control_zone_can_move_and_take_its_own_channel() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    let low: ControlZone =
+      ControlZone::from_args(&args(&["--offset-octave-start", "25"])).unwrap();
+    let mut state: Edo72Transformer = Edo72Transformer { controls: low, ..Edo72Transformer::new() };
+    assert!(transform_message(&mut state, &[0x90, 31, 100]).is_empty()); // +1
+    assert_eq!(current_total_shift(&state.ongoing_shifts), Some(1));
+    transform_message(&mut state, &[0x80, 31, 0]);
+    // Above the zone, the old control keys play.
+    assert_eq!(transform_message(&mut state, &[0x90, 103, 100]).len(), 1);
+    assert!(ControlZone::from_args(&args(&["--offset-octave-start", "20"])).is_err());
+    assert!(ControlZone::from_args(&args(&["--offset-octave-start", "117"])).is_err());
+    // On its own channel the zone can sit anywhere, and other channels play.
+    let apart: ControlZone = ControlZone::from_args(
+      &args(&["--offset-octave-start", "20", "--control-channel", "15"])).unwrap();
+    let mut state: Edo72Transformer = Edo72Transformer { controls: apart, ..Edo72Transformer::new() };
+    assert!(transform_message(&mut state, &[0x9F, 21, 100]).is_empty());
+    assert_eq!(transform_message(&mut state, &[0x90, 21, 100]).len(), 1);
+    assert_eq!(transform_message(&mut state, &[0x90, 108, 100]).len(), 1);
+    assert_eq!(output_channels(&state), 0..=7); }
+
+  #[test]
+  fn octave_keys_move_the_keyboard_and_held_notes_stay_put() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    let controls: ControlZone = ControlZone::from_args(
+      &args(&["--octave-down-key", "22", "--octave-up-key", "23"])).unwrap();
+    let mut state: Edo72Transformer = Edo72Transformer { controls, ..Edo72Transformer::new() };
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x92, 28, 90]]);
+    assert!(transform_message(&mut state, &[0x90, 23, 100]).is_empty()); // up
+    assert!(transform_message(&mut state, &[0x80, 23, 0]).is_empty());
+    assert_eq!(state.octave_shift, 1);
+    assert_eq!(state.take_notices(), vec![Notice::OctaveShift(1)]);
+    // The held key is released where it began; played again, it's an octave up.
+    assert_eq!(transform_message(&mut state, &[0x80, 38, 0]),
+               vec![vec![0x82, 28, 0]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x93, 28, 90]]);
+    for _ in 0..10 {
+      transform_message(&mut state, &[0x90, 22, 100]); }
+    assert_eq!(state.octave_shift, -MAX_OCTAVE_SHIFT);
+    // Shifted below note 0, a key has nowhere to go.
+    assert!(transform_message(&mut state, &[0x90, 40, 90]).is_empty());
+    assert!(ControlZone::from_args(&args(&["--octave-up-key", "100"])).is_err());
+    assert!(ControlZone::from_args(
+      &args(&["--octave-down-key", "22", "--octave-up-key", "22"])).is_err()); }
+
+  #[test]
+  fn transpose_moves_the_layout_and_held_notes_with_it() {
+    let mut state: Edo72Transformer = Edo72Transformer {
+      controls: ControlZone { transpose_cc: Some(20), channel: Some(15),
+                              ..Edo72Transformer::new().controls },
+      ..Edo72Transformer::new() };
+    // With no transpose, the lowest A lands on MIN_CHANNEL's MIN_NOTE.
+    assert_eq!(edo72_instruction(&state.pitch_class_shifts, LOWEST_A, 0,
+                                 OutOfRange::Drop),
+               (MIN_CHANNEL as i16, MIN_NOTE as i16));
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x92, 28, 90]]);
+    // The held key ends and is struck again where it now lands.
+    assert_eq!(transform_message(&mut state, &[0xBF, 20, 66]), // +2
+               vec![vec![0x82, 28, 0], vec![0x92, 28 + 7 * 6, 90]]);
+    assert_eq!(state.transpose, 2);
+    assert_eq!(state.take_notices(), vec![Notice::Transpose(2)]);
+    assert!(transform_message(&mut state, &[0xBF, 20, 66]).is_empty()); // no change
+    assert_eq!(transform_message(&mut state, &[0x80, 38, 0]),
+               vec![vec![0x82, 28 + 7 * 6, 0]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 90]),
+               vec![vec![0x92, 28 + 7 * 6, 90]]);
+    // Off the control channel, the same CC is broadcast like any other.
+    assert!(transform_message(&mut state, &[0xB0, 20, 66]).len() > 1);
+    assert_eq!(state.transpose, 2); }
+
+  #[test]
+  fn changes_come_back_as_notices_and_nothing_is_printed() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    assert!(transform_message(&mut state, &[0x90, OFFSET_OCTAVE_START - 1, 100]).is_empty());
+    transform_message(&mut state, &[0x90, OFFSET_OCTAVE_START, 100]);
+    let notices: Vec<Notice> = state.take_notices();
+    assert_eq!(notices.len(), 2);
+    match &notices[0] {
+      Notice::TuningTable(table) => {
+        assert!(table.starts_with("  transpose: -5\n  held shift keys: none\n"));
+        assert_eq!(table.lines().count(), 3 + 12); }
+      other => panic!("expected the tuning table, got {:?}", other), }
+    assert_eq!(notices[1], Notice::TuningReset);
+    assert!(state.take_notices().is_empty()); }
+
+  #[test]
+  fn transpose_retunes_held_notes_in_mts_mode() {
+    let mut state: Edo72Transformer = Edo72Transformer {
+      mode: OutputMode::Mts,
+      controls: ControlZone { transpose_cc: Some(20), ..Edo72Transformer::new().controls },
+      ..Edo72Transformer::new() };
+    transform_message(&mut state, &[0x90, 69, 100]);
+    assert_eq!(transform_message(&mut state, &[0xB0, 20, 66]), // +2
+               vec![single_note_tuning(69, 71.0)]);
+    assert_eq!(transform_message(&mut state, &[0x80, 69, 0]),
+               vec![vec![0x81, 69, 0]]); }
+
+  #[test]
+  fn keys_out_of_range_are_found_ahead_of_time() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    assert!(unreachable_keys(&state, 0).is_empty());
+    // The top of each channel, MIN_NOTE + 66, passes 127 when shifted 34 steps.
+    assert_eq!(key_ranges(&unreachable_keys(&state, 33)), "");
+    assert_eq!(key_ranges(&unreachable_keys(&state, 34)), "25, 37, 49, 61, 73, 85");
+    state.transpose = -40; // below channel 0
+    assert_eq!(key_ranges(&unreachable_keys(&state, 0)), "21-48");
+    state.mode = OutputMode::Mts; // below MIDI note 0
+    assert_eq!(key_ranges(&unreachable_keys(&state, 0)), "21-39");
+    assert_eq!(key_ranges(&[21, 22, 23, 30, 40, 41]), "21-23, 30, 40-41"); }
+
+  #[test]
+  fn channel_gain_scales_note_ons_only() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    state.channel_gains.insert(2, 1.5);
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 100]),
+               vec![vec![0x92, 28, 127]]);
+    assert_eq!(transform_message(&mut state, &[0x80, 38, 64]),
+               vec![vec![0x82, 28, 64]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 37, 100]),
+               vec![vec![0x91, 94, 100]]);
+    state.channel_gains.insert(2, 0.01);
+    assert_eq!(transform_message(&mut state, &[0x90, 38, 10]),
+               vec![vec![0x92, 28, 1]]); }
+
+  #[test]
+  fn mts_mode_retunes_the_pressed_key_on_one_channel() {
+    let mut state: Edo72Transformer =
+      Edo72Transformer { mode: OutputMode::Mts, ..Edo72Transformer::new() };
+    assert_eq!(output_channels(&state), MIN_CHANNEL..=MIN_CHANNEL);
+    // A4 (69), unshifted, sounds SHIFT_IN_12_EDO semitones away.
+    assert_eq!(transform_message(&mut state, &[0x90, 69, 100]),
+               vec![single_note_tuning(69, 64.0), vec![0x91, 69, 100]]);
+    transform_message(&mut state, &[0x80, 69, 0]);
+    transform_message(&mut state, &[0x90, 105, 100]); // +3 steps: a quarter tone
+    assert_eq!(transform_message(&mut state, &[0x90, 62, 90]),
+               vec![single_note_tuning(62, 57.5), vec![0x91, 62, 90]]);
+    transform_message(&mut state, &[0x80, 105, 0]);
+    // A reset retunes the held D without restriking it.
+    assert_eq!(transform_message(&mut state, &[0x90, OFFSET_OCTAVE_START, 100]),
+               vec![single_note_tuning(62, 57.0)]);
+    assert_eq!(transform_message(&mut state, &[0x80, 62, 0]),
+               vec![vec![0x81, 62, 0]]); }
+
+  #[test]
+  fn reset_clears_shifts_and_retunes_held_notes() {
+    let mut state: Edo72Transformer = Edo72Transformer::new();
+    transform_message(&mut state, &[0x90, 104, 100]); // +2
+    transform_message(&mut state, &[0x90, 38, 90]);
+    transform_message(&mut state, &[0x80, 104, 0]);
+    assert_eq!(state.pitch_class_shifts.get(&(38 % 12)), Some(&2));
+    assert_eq!(transform_message(&mut state, &[0x90, OFFSET_OCTAVE_START, 100]),
+               vec![vec![0x82, 30, 0],
+                    vec![0x92, 28, 90]]);
+    assert!(state.pitch_class_shifts.is_empty());
+    assert!(state.ongoing_shifts.is_empty());
+    assert!(transform_message(&mut state, &[0x80, OFFSET_OCTAVE_START, 0])
+            .is_empty()); }
+}
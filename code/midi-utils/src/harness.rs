@@ -0,0 +1,59 @@
+//! Driving a binary's message handling without MIDI ports.
+//!
+//! Each binary keeps what it does to a message in a plain function of
+//! its state and the message's bytes (`transform_message` and the like),
+//! so that tests can play a script of messages into it and check what
+//! comes out, the way the input callback would but with no device, no
+//! threads and no clock. `feed` does that, restoring running status as
+//! the callbacks do, and `messages` writes a script compactly in hex.
+
+use crate::running_status::RunningStatus;
+
+/// Plays `input` through `transform` in order, as one input connection
+/// would, and collects everything it emits.
+pub fn feed<S, M: AsRef<[u8]>>(
+  state: &mut S,
+  input: &[M],
+  mut transform: impl FnMut(&mut S, &[u8]) -> Vec<Vec<u8>>,
+) -> Vec<Vec<u8>> {
+  let mut running_status: RunningStatus = RunningStatus::new();
+  input.iter()
+    .flat_map(|message| transform(state, &running_status.expand(message.as_ref())))
+    .collect()
+}
+
+/// Messages written as hex bytes, with messages separated by commas,
+/// e.g. `"90 3C 64, 80 3C 00"`. Panics on anything else, since scripts
+/// are written by tests.
+pub fn messages(script: &str) -> Vec<Vec<u8>> {
+  script.split(',')
+    .map(|message| message.split_whitespace()
+         .map(|byte| u8::from_str_radix(byte, 16)
+              .unwrap_or_else(|_| panic!("not a hex byte: '{}'", byte)))
+         .collect())
+    .filter(|message: &Vec<u8>| !message.is_empty())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scripts_parse_as_hex() {
+    assert_eq!(messages("90 3C 64, 3E 64,F8"),
+               vec![vec![0x90, 0x3C, 0x64], vec![0x3E, 0x64], vec![0xF8]]);
+    assert!(messages("").is_empty());
+  }
+
+  #[test]
+  fn feeding_restores_running_status_and_keeps_state() {
+    let mut seen: usize = 0;
+    let out: Vec<Vec<u8>> = feed(&mut seen, &messages("91 3C 64, 3E 50, F8"), |seen, message| {
+      *seen += 1;
+      vec![message.to_vec()]
+    });
+    assert_eq!(out, messages("91 3C 64, 91 3E 50, F8"));
+    assert_eq!(seen, 3);
+  }
+}
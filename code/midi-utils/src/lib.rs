@@ -0,0 +1,28 @@
+//! Helpers shared by the live-midi binaries.
+//!
+//! Messages are raw byte slices, as midir delivers them:
+//! a status byte (kind in the high nibble, channel in the low one)
+//! followed by data bytes.
+
+pub mod args;
+pub mod cc14;
+pub mod clock;
+pub mod config;
+pub mod delay;
+pub mod edo72;
+pub mod harness;
+pub mod logging;
+mod message;
+pub mod note_names;
+pub mod note_stack;
+pub mod ports;
+pub mod rng;
+pub mod running_status;
+pub mod sink;
+pub mod smf;
+pub mod sync;
+pub mod timing;
+pub mod voices;
+pub mod watchdog;
+
+pub use message::*;
@@ -0,0 +1,77 @@
+//! Timestamped logging to stderr, through the `log` crate's macros.
+//!
+//! `RUST_LOG` picks the level, as with env_logger: `error`, `warn`
+//! (the default), `info`, `debug`, `trace` or `off`. Only a bare level
+//! is understood; per-module settings like `sampler=debug` are ignored.
+//! At `warn`, failed sends show up; `info` adds state changes like
+//! recording starting and stopping. Each line starts with the seconds
+//! since the program started, so events can be lined up against each other.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+struct StderrLogger {
+  start: OnceLock<Instant>,
+}
+
+static LOGGER: StderrLogger = StderrLogger { start: OnceLock::new() };
+
+impl Log for StderrLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= log::max_level()
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let elapsed: f64 = self.start.get_or_init(Instant::now).elapsed().as_secs_f64();
+    eprintln!("[{:9.3}s {:<5} {}] {}", elapsed, record.level(), record.target(), record.args());
+  }
+
+  fn flush(&self) {}
+}
+
+/// Installs the logger. Call it once, first thing in `main`;
+/// later calls change nothing.
+pub fn init() {
+  LOGGER.start.get_or_init(Instant::now);
+  if log::set_logger(&LOGGER).is_ok() {
+    log::set_max_level(parse_level(std::env::var("RUST_LOG").ok().as_deref()));
+  }
+}
+
+/// The level a `RUST_LOG` value asks for: its first bare level name.
+fn parse_level(spec: Option<&str>) -> LevelFilter {
+  spec.into_iter()
+    .flat_map(|s| s.split(','))
+    .find_map(|item| item.trim().parse::<LevelFilter>().ok())
+    .unwrap_or(LevelFilter::Warn)
+}
+
+/// Message bytes in hex, for logs.
+pub fn hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn levels_from_rust_log() {
+    assert_eq!(parse_level(None), LevelFilter::Warn);
+    assert_eq!(parse_level(Some("info")), LevelFilter::Info);
+    assert_eq!(parse_level(Some("DEBUG")), LevelFilter::Debug);
+    assert_eq!(parse_level(Some("sampler=trace,error")), LevelFilter::Error);
+    assert_eq!(parse_level(Some("off")), LevelFilter::Off);
+    assert_eq!(parse_level(Some("loud")), LevelFilter::Warn);
+  }
+
+  #[test]
+  fn hex_bytes() {
+    assert_eq!(hex(&[0x90, 60, 0x7F]), "90 3C 7F");
+    assert_eq!(hex(&[]), "");
+  }
+}
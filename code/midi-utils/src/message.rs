@@ -0,0 +1,210 @@
+//! Parsing and building channel voice messages.
+
+pub const NOTE_OFF: u8 = 0x80;
+pub const NOTE_ON: u8 = 0x90;
+pub const POLY_PRESSURE: u8 = 0xA0;
+pub const CONTROL_CHANGE: u8 = 0xB0;
+pub const PROGRAM_CHANGE: u8 = 0xC0;
+pub const CHANNEL_PRESSURE: u8 = 0xD0;
+pub const PITCH_BEND: u8 = 0xE0;
+
+// System real-time messages are a single byte, and may arrive at any time.
+pub const TIMING_CLOCK: u8 = 0xF8; // 24 per quarter note
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const ACTIVE_SENSING: u8 = 0xFE; // "still here"; harmless sent once
+pub const CLOCKS_PER_QUARTER: u32 = 24;
+
+/// The note number of a note-on or note-off.
+pub fn get_note(data: &[u8]) -> Option<u8> {
+  if data.len() >= 2 && is_note_event(data) {
+    Some(data[1])
+  } else {
+    None
+  }
+}
+
+/// The channel (0-15) of a channel voice message.
+/// System messages (0xF0 and up) have no channel.
+pub fn get_channel(data: &[u8]) -> Option<u8> {
+  if !data.is_empty() && data[0] < 0xF0 {
+    Some(data[0] & 0x0F)
+  } else {
+    None
+  }
+}
+
+pub fn is_note_on(data: &[u8]) -> bool {
+  if data.len() >= 3 {
+    let status: u8 = data[0] & 0xF0;
+    status == NOTE_ON && data[2] > 0
+  } else {
+    false
+  }
+}
+
+pub fn is_note_off(data: &[u8]) -> bool {
+  if data.len() >= 3 {
+    let status: u8 = data[0] & 0xF0;
+    // Note off, or note on with velocity 0
+    status == NOTE_OFF || (status == NOTE_ON && data[2] == 0)
+  } else {
+    false
+  }
+}
+
+/// How fast a note-off says the key came up: its velocity for a real
+/// note-off, 0 for a note-on with velocity 0 (or anything else),
+/// which carries none.
+pub fn release_velocity(data: &[u8]) -> u8 {
+  if data.len() >= 3 && data[0] & 0xF0 == NOTE_OFF {
+    data[2]
+  } else {
+    0
+  }
+}
+
+pub fn is_note_event(data: &[u8]) -> bool {
+  if data.is_empty() {
+    return false;
+  }
+  let status: u8 = data[0] & 0xF0;
+  status == NOTE_OFF || status == NOTE_ON
+}
+
+pub fn note_on(channel: u8, note: u8, velocity: u8) -> Vec<u8> {
+  vec![NOTE_ON | (channel & 0x0F), note & 0x7F, velocity & 0x7F]
+}
+
+pub fn note_off(channel: u8, note: u8, velocity: u8) -> Vec<u8> {
+  vec![NOTE_OFF | (channel & 0x0F), note & 0x7F, velocity & 0x7F]
+}
+
+pub fn control_change(channel: u8, controller: u8, value: u8) -> Vec<u8> {
+  vec![CONTROL_CHANGE | (channel & 0x0F), controller & 0x7F, value & 0x7F]
+}
+
+pub const BEND_CENTER: u16 = 0x2000;
+
+/// A pitch bend to the 14-bit `value`, where `BEND_CENTER` is no bend.
+/// Values above 0x3FFF are clamped.
+pub fn pitch_bend(channel: u8, value: u16) -> Vec<u8> {
+  let value: u16 = value.min(0x3FFF);
+  vec![PITCH_BEND | (channel & 0x0F), (value & 0x7F) as u8, (value >> 7) as u8]
+}
+
+/// CC 123, which asks a synth to release every note on the channel.
+pub fn all_notes_off(channel: u8) -> Vec<u8> {
+  control_change(channel, 123, 0)
+}
+
+/// CC 120, which asks a synth to cut every sound on the channel at once,
+/// release tails included.
+pub fn all_sound_off(channel: u8) -> Vec<u8> {
+  control_change(channel, 120, 0)
+}
+
+/// Everything needed to silence a synth that may ignore CC 123:
+/// all-notes-off, all-sound-off and a note-off for every note,
+/// on all 16 channels.
+pub fn panic_messages() -> Vec<Vec<u8>> {
+  (0..16)
+    .flat_map(|channel| [all_notes_off(channel), all_sound_off(channel)].into_iter()
+              .chain((0..128).map(move |note| note_off(channel, note, 0))))
+    .collect()
+}
+
+/// A real-time MIDI Tuning Standard single-note tuning change:
+/// from now on, `key` (in tuning program 0, on every device)
+/// sounds `semitones` above MIDI note 0, e.g. 69.5 for a quarter tone
+/// above A4. Notes already sounding on that key are retuned too.
+/// `semitones` is clamped to the range MTS can express, 0 to just under 128.
+pub fn single_note_tuning(key: u8, semitones: f64) -> Vec<u8> {
+  // Fractions of a semitone are in units of 1/16384. 7F 7F 7F is reserved.
+  let units: u32 = (semitones.clamp(0.0, 128.0) * 16384.0).round().min(128.0 * 16384.0 - 2.0) as u32;
+  let (semitone, fraction): (u32, u32) = (units / 16384, units % 16384);
+  vec![0xF0, 0x7F, 0x7F, 0x08, 0x02, 0, 1, key & 0x7F,
+       semitone as u8, (fraction >> 7) as u8, (fraction & 0x7F) as u8, 0xF7]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn release_velocities() {
+    assert_eq!(release_velocity(&[0x83, 60, 45]), 45);
+    assert_eq!(release_velocity(&[0x93, 60, 0]), 0);
+    assert_eq!(release_velocity(&[0x93, 60, 100]), 0);
+  }
+
+  #[test]
+  fn velocity_zero_note_on_is_a_note_off() {
+    let data: [u8; 3] = [0x93, 60, 0];
+    assert!(!is_note_on(&data));
+    assert!(is_note_off(&data));
+    assert!(is_note_event(&data));
+    assert_eq!(get_note(&data), Some(60));
+    assert_eq!(get_channel(&data), Some(3));
+  }
+
+  #[test]
+  fn note_on_and_off() {
+    assert!(is_note_on(&[0x90, 60, 1]));
+    assert!(!is_note_off(&[0x90, 60, 1]));
+    assert!(is_note_off(&[0x80, 60, 64]));
+    assert!(!is_note_on(&[0x80, 60, 64]));
+  }
+
+  #[test]
+  fn other_messages_are_not_notes() {
+    let cc: [u8; 3] = [0xB0, 64, 127];
+    assert!(!is_note_event(&cc));
+    assert!(!is_note_on(&cc));
+    assert!(!is_note_off(&cc));
+    assert_eq!(get_note(&cc), None);
+    assert_eq!(get_channel(&[0xC5, 3]), Some(5));
+    assert_eq!(get_channel(&[0xF8]), None);
+    assert_eq!(get_channel(&[]), None);
+  }
+
+  #[test]
+  fn panic_covers_every_channel_and_note() {
+    let messages: Vec<Vec<u8>> = panic_messages();
+    assert_eq!(messages.len(), 16 * 130);
+    assert_eq!(messages[0], vec![0xB0, 123, 0]);
+    assert_eq!(messages[1], vec![0xB0, 120, 0]);
+    assert_eq!(messages[2], vec![0x80, 0, 0]);
+    assert_eq!(messages.last().unwrap(), &vec![0x8F, 127, 0]);
+  }
+
+  #[test]
+  fn truncated_messages_are_not_notes() {
+    assert!(!is_note_on(&[0x90, 60]));
+    assert!(!is_note_off(&[0x80, 60]));
+    assert_eq!(get_note(&[0x90]), None);
+  }
+
+  #[test]
+  fn constructors_round_trip() {
+    assert_eq!(note_on(2, 60, 100), vec![0x92, 60, 100]);
+    assert_eq!(note_off(15, 127, 0), vec![0x8F, 127, 0]);
+    assert_eq!(control_change(0, 64, 127), vec![0xB0, 64, 127]);
+    assert_eq!(pitch_bend(1, BEND_CENTER), vec![0xE1, 0, 0x40]);
+    assert_eq!(pitch_bend(0, 0xFFFF), vec![0xE0, 0x7F, 0x7F]);
+    let on: Vec<u8> = note_on(9, 36, 90);
+    assert!(is_note_on(&on));
+    assert_eq!((get_channel(&on), get_note(&on)), (Some(9), Some(36)));
+  }
+
+  #[test]
+  fn single_note_tuning_encodes_semitones_and_fraction() {
+    assert_eq!(single_note_tuning(60, 60.0),
+               vec![0xF0, 0x7F, 0x7F, 0x08, 0x02, 0, 1, 60, 60, 0, 0, 0xF7]);
+    // A quarter tone up is 8192/16384: 0x40 0x00.
+    assert_eq!(single_note_tuning(69, 69.5)[8..11], [69, 0x40, 0]);
+    assert_eq!(single_note_tuning(0, -3.0)[8..11], [0, 0, 0]);
+    assert_eq!(single_note_tuning(127, 200.0)[8..11], [127, 0x7F, 0x7E]);
+  }
+}
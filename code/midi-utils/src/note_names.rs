@@ -0,0 +1,147 @@
+//! Note names like `C8` or `F#2`, for flags and printouts.
+//!
+//! A name is a letter A-G, optionally `#` (or, when parsing, `b` for
+//! a flat), then an octave number, which may be negative.
+//! Which octave number goes with which MIDI note is only a convention,
+//! and synths disagree; an `OctaveConvention` says which octave
+//! middle C (note 60) is in. The default is C4 = 60,
+//! under which note 0 is C-1 and note 127 is G9.
+//! Binaries take `--octave-convention C3` (or `C4`, or `C5`)
+//! to match a synth that labels its keys otherwise; see `Args::note`.
+
+use std::fmt;
+use std::str::FromStr;
+
+const PITCH_CLASSES: [&str; 12] =
+  ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OctaveConvention {
+  middle_c_octave: i8,
+}
+
+impl OctaveConvention {
+  pub const C3: OctaveConvention = OctaveConvention { middle_c_octave: 3 };
+  pub const C4: OctaveConvention = OctaveConvention { middle_c_octave: 4 };
+  pub const C5: OctaveConvention = OctaveConvention { middle_c_octave: 5 };
+
+  /// The name of `note`, e.g. "C8" for 108 under C4 = 60.
+  pub fn name(self, note: u8) -> String {
+    let octave: i32 = i32::from(note / 12) - 5 + i32::from(self.middle_c_octave);
+    format!("{}{}", PITCH_CLASSES[usize::from(note % 12)], octave)
+  }
+
+  /// The note `text` names, if it is a name of a note in 0-127.
+  pub fn parse(self, text: &str) -> Option<u8> {
+    let mut chars = text.trim().chars();
+    let pitch_class: i32 = match chars.next()?.to_ascii_uppercase() {
+      'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5,
+      'G' => 7, 'A' => 9, 'B' => 11,
+      _ => return None,
+    };
+    let rest: &str = chars.as_str();
+    let (accidental, octave): (i32, &str) =
+      if let Some(octave) = rest.strip_prefix('#') { (1, octave) }
+      else if let Some(octave) = rest.strip_prefix('b') { (-1, octave) }
+      else { (0, rest) };
+    let octave: i32 = octave.parse().ok()?;
+    let note: i32 = (octave - i32::from(self.middle_c_octave) + 5) * 12
+      + pitch_class + accidental;
+    u8::try_from(note).ok().filter(|&n| n <= 127)
+  }
+}
+
+impl Default for OctaveConvention {
+  fn default() -> Self {
+    OctaveConvention::C4
+  }
+}
+
+impl FromStr for OctaveConvention {
+  type Err = String;
+  /// The name of middle C: "C3", "C4" or "C5".
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_uppercase().as_str() {
+      "C3" => Ok(OctaveConvention::C3),
+      "C4" => Ok(OctaveConvention::C4),
+      "C5" => Ok(OctaveConvention::C5),
+      _ => Err("expected C3, C4 or C5, the name of note 60".to_string()),
+    }
+  }
+}
+
+impl fmt::Display for OctaveConvention {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "C{} = 60", self.middle_c_octave)
+  }
+}
+
+/// The name of `note` under the default convention, C4 = 60.
+pub fn note_number_to_name(note: u8) -> String {
+  OctaveConvention::default().name(note)
+}
+
+/// Parses a note name under the default convention, C4 = 60.
+pub fn parse_note_name(text: &str) -> Option<u8> {
+  OctaveConvention::default().parse(text)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn numbers_to_names() {
+    assert_eq!(note_number_to_name(0), "C-1");
+    assert_eq!(note_number_to_name(60), "C4");
+    assert_eq!(note_number_to_name(61), "C#4");
+    assert_eq!(note_number_to_name(108), "C8");
+    assert_eq!(note_number_to_name(127), "G9");
+    assert_eq!(OctaveConvention::C3.name(0), "C-2");
+    assert_eq!(OctaveConvention::C3.name(60), "C3");
+    assert_eq!(OctaveConvention::C3.name(127), "G8");
+  }
+
+  #[test]
+  fn names_to_numbers() {
+    assert_eq!(parse_note_name("C-1"), Some(0));
+    assert_eq!(parse_note_name("C4"), Some(60));
+    assert_eq!(parse_note_name("c#4"), Some(61));
+    assert_eq!(parse_note_name("Db4"), Some(61));
+    assert_eq!(parse_note_name("A#7"), Some(106));
+    assert_eq!(parse_note_name("C8"), Some(108));
+    assert_eq!(parse_note_name("G9"), Some(127));
+    assert_eq!(OctaveConvention::C3.parse("C-2"), Some(0));
+    assert_eq!(OctaveConvention::C3.parse("C3"), Some(60));
+    assert_eq!(OctaveConvention::C3.parse("G8"), Some(127));
+  }
+
+  #[test]
+  fn names_out_of_range_or_malformed() {
+    assert_eq!(parse_note_name("Cb-1"), None);
+    assert_eq!(parse_note_name("G#9"), None);
+    assert_eq!(parse_note_name("H4"), None);
+    assert_eq!(parse_note_name("C"), None);
+    assert_eq!(parse_note_name("C##4"), None);
+    assert_eq!(parse_note_name(""), None);
+  }
+
+  #[test]
+  fn conventions_by_name() {
+    assert_eq!("C3".parse(), Ok(OctaveConvention::C3));
+    assert_eq!("c5".parse(), Ok(OctaveConvention::C5));
+    assert!("C6".parse::<OctaveConvention>().is_err());
+    assert_eq!(OctaveConvention::C5.name(60), "C5");
+    assert_eq!(OctaveConvention::C5.parse("C0"), Some(0));
+    assert_eq!(OctaveConvention::C5.to_string(), "C5 = 60");
+  }
+
+  #[test]
+  fn every_note_round_trips() {
+    for convention in [OctaveConvention::C3, OctaveConvention::C4, OctaveConvention::C5] {
+      for note in 0..=127 {
+        assert_eq!(convention.parse(&convention.name(note)), Some(note));
+      }
+    }
+  }
+}
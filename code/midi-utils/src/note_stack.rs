@@ -0,0 +1,102 @@
+//! Which of the held keys a monophonic voice plays.
+//!
+//! A `NoteStack` remembers every key held, in the order pressed, and
+//! picks one by its `Priority`; releasing the winner falls back to
+//! whichever held key wins next.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+  Last,
+  Low,
+  High,
+}
+
+impl FromStr for Priority {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "last" => Ok(Priority::Last),
+      "low" => Ok(Priority::Low),
+      "high" => Ok(Priority::High),
+      _ => Err("expected last, low or high".to_string()),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key {
+  pub channel: u8,
+  pub note: u8,
+  pub velocity: u8,
+}
+
+impl Key {
+  /// Whether `self` and `other` are the same key, however hard each was struck.
+  pub fn same_key(&self, other: &Key) -> bool {
+    (self.channel, self.note) == (other.channel, other.note)
+  }
+}
+
+pub struct NoteStack {
+  priority: Priority,
+  held: Vec<Key>, // in the order pressed
+}
+
+impl NoteStack {
+  pub fn new(priority: Priority) -> Self {
+    NoteStack { priority, held: Vec::new() }
+  }
+
+  /// A key struck again moves to the top, with its new velocity.
+  pub fn press(&mut self, key: Key) {
+    self.release(key.channel, key.note);
+    self.held.push(key);
+  }
+
+  pub fn release(&mut self, channel: u8, note: u8) {
+    self.held.retain(|k| (k.channel, k.note) != (channel, note));
+  }
+
+  /// The key that should sound, if any are held.
+  pub fn winner(&self) -> Option<Key> {
+    match self.priority {
+      Priority::Last => self.held.last().copied(),
+      Priority::Low => self.held.iter().min_by_key(|k| k.note).copied(),
+      Priority::High => self.held.iter().max_by_key(|k| k.note).copied(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key(note: u8) -> Key {
+    Key { channel: 0, note, velocity: 100 }
+  }
+
+  #[test]
+  fn priorities() {
+    for (priority, winner) in [(Priority::Last, 64), (Priority::Low, 55), (Priority::High, 67)] {
+      let mut stack: NoteStack = NoteStack::new(priority);
+      for note in [60, 67, 55, 64] {
+        stack.press(key(note));
+      }
+      assert_eq!(stack.winner().map(|k| k.note), Some(winner));
+    }
+  }
+
+  #[test]
+  fn releasing_falls_back() {
+    let mut stack: NoteStack = NoteStack::new(Priority::Last);
+    stack.press(key(60));
+    stack.press(key(64));
+    stack.press(key(60)); // struck again: on top
+    stack.release(0, 60);
+    assert_eq!(stack.winner(), Some(key(64)));
+    stack.release(0, 64);
+    assert_eq!(stack.winner(), None);
+  }
+}
@@ -0,0 +1,477 @@
+//! Opening MIDI ports: either a virtual port that other programs
+//! connect to, or a direct connection to an existing port,
+//! chosen by a substring of its name.
+//!
+//! The binaries share these flags for it; each one's docs list those
+//! it takes:
+//! - `--input-port <name>`, `--output-port <name>`: connect to the first
+//!   existing port whose name contains `<name>` instead of making a
+//!   virtual one (`open_input`, `open_output`). A lost input port ends
+//!   the binary, releasing its notes (`wait_for_exit`)
+//! - `--connect-from <name>`, `--connect-to <name>`: keep the virtual
+//!   ports, and wire that port in or out at startup (`auto_connect`;
+//!   needs ALSA's `aconnect`, from alsa-utils, on the PATH)
+//! - `--list-ports`: print the available ports and exit
+//! - `--self-test`: send each output one inaudible message at startup,
+//!   saying whether it went through (`self_test`)
+//! - `--zero-release-velocity`: send every note-off with velocity 0, for
+//!   synths that misread release velocity (`sink::ReleaseVelocity`)
+
+use midir::{MidiInput, MidiInputConnection, MidiInputPort,
+            MidiOutput, MidiOutputConnection, MidiOutputPort};
+use midir::os::unix::{VirtualInput, VirtualOutput};
+use crate::args::Args;
+use crate::logging::hex;
+use crate::sink::MidiSink;
+use crate::{all_notes_off, ACTIVE_SENSING};
+use std::error::Error;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use std::{io, thread};
+
+/// How often `wait_for_exit` checks that the input port is still there.
+const INPUT_CHECK_MS: u64 = 1000;
+
+/// If `wanted` is given, connects to the first input port
+/// whose name contains it. Otherwise creates a virtual input
+/// called `virtual_name`.
+pub fn open_input<F, T: Send>(
+  midi_in: MidiInput,
+  wanted: Option<&str>,
+  virtual_name: &str,
+  callback: F,
+  data: T,
+) -> Result<MidiInputConnection<T>, Box<dyn Error>>
+where F: FnMut(u64, &[u8], &mut T) + Send + 'static {
+  match wanted {
+    None => Ok(midi_in.create_virtual(virtual_name, callback, data)?),
+    Some(wanted) => {
+      let (port, name): (MidiInputPort, String) =
+        find_input_port(&midi_in, wanted)?;
+      println!("Connecting input to '{}'", name);
+      Ok(midi_in.connect(&port, virtual_name, callback, data)?)
+    }
+  }
+}
+
+/// Connects each `--extra-input <name>` (repeatable) to the first input
+/// port whose name contains it, as `--input-port` does, so that more
+/// than one controller can play into a binary. `make_callback` is
+/// called once per connection for its callback and data, which should
+/// all feed the same shared state. Events from the different inputs
+/// are merged in the order their callbacks run: roughly, but not
+/// exactly, the order they arrived. Each is a port `port` on a client
+/// named `client` with 2, 3, ... after it.
+pub fn open_extra_inputs<F, T: Send>(
+  args: &Args,
+  client: &str,
+  port: &str,
+  mut make_callback: impl FnMut() -> (F, T),
+) -> Result<Vec<MidiInputConnection<T>>, Box<dyn Error>>
+where F: FnMut(u64, &[u8], &mut T) + Send + 'static {
+  args.values("--extra-input").into_iter().enumerate()
+    .map(|(i, wanted)| {
+      let midi_in: MidiInput = MidiInput::new(&format!("{}-{}", client, i + 2))?;
+      let (callback, data): (F, T) = make_callback();
+      open_input(midi_in, Some(wanted), port, callback, data)
+    })
+    .collect()
+}
+
+/// Like `open_input`, for outputs.
+pub fn open_output(
+  midi_out: MidiOutput,
+  wanted: Option<&str>,
+  virtual_name: &str,
+) -> Result<MidiOutputConnection, Box<dyn Error>> {
+  match wanted {
+    None => Ok(midi_out.create_virtual(virtual_name)?),
+    Some(wanted) => {
+      let (port, name): (MidiOutputPort, String) =
+        find_output_port(&midi_out, wanted)?;
+      println!("Connecting output to '{}'", name);
+      Ok(midi_out.connect(&port, virtual_name)?)
+    }
+  }
+}
+
+/// Wires our ports to others at startup, as `aconnect` would by hand:
+/// `--connect-from <name>` feeds the first port whose name contains it
+/// into `input`, and `--connect-to <name>` feeds `output` into the first
+/// such port. `input` and `output` name our own ports as "client:port",
+/// e.g. "arp-in:midi-in"; None for a binary without one. It says what it
+/// connected, and only warns if a name matches nothing or ALSA refuses.
+/// midir can't connect two ports other than its own, so this runs the
+/// `aconnect` program; if that isn't installed, the warning says so.
+pub fn auto_connect(args: &Args, input: Option<&str>, output: Option<&str>) {
+  if let Some((from, input)) = requested(args, "--connect-from", input) {
+    report_connection(from, connect(from, input));
+  }
+  if let Some((to, output)) = requested(args, "--connect-to", output) {
+    report_connection(to, connect(output, to));
+  }
+}
+
+/// The port `flag` names, paired with ours, if there's both.
+fn requested<'a>(args: &'a Args, flag: &str, ours: Option<&'a str>) -> Option<(&'a str, &'a str)> {
+  let wanted: Option<&str> = args.value(flag);
+  if wanted.is_some() && ours.is_none() {
+    eprintln!("Warning: {} ignored; there's no port to connect", flag);
+  }
+  wanted.zip(ours)
+}
+
+/// Connects the first port whose name contains `source` to the first
+/// whose name contains `destination`.
+fn connect(source: &str, destination: &str) -> Result<String, String> {
+  let midi_in: MidiInput = MidiInput::new("live-midi-connect").map_err(|e| e.to_string())?;
+  let midi_out: MidiOutput = MidiOutput::new("live-midi-connect").map_err(|e| e.to_string())?;
+  // Ports that can be read from are listed as inputs, and the other way round.
+  let (_, source): (MidiInputPort, String) = find_input_port(&midi_in, source)?;
+  let (_, destination): (MidiOutputPort, String) = find_output_port(&midi_out, destination)?;
+  aconnect(&source, &destination)?;
+  Ok(format!("'{}' -> '{}'", source, destination))
+}
+
+fn report_connection(wanted: &str, result: Result<String, String>) {
+  match result {
+    Ok(connected) => println!("Connected {}", connected),
+    Err(e) => eprintln!("Warning: couldn't connect '{}': {}", wanted, e),
+  }
+}
+
+/// The "client:port" numbers ALSA port names end with, as midir gives them.
+fn alsa_address(name: &str) -> Option<&str> {
+  let address: &str = name.rsplit(' ').next()?;
+  let (client, port): (&str, &str) = address.split_once(':')?;
+  let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+  (numeric(client) && numeric(port)).then_some(address)
+}
+
+fn aconnect(source: &str, destination: &str) -> Result<(), String> {
+  let status: ExitStatus = Command::new("aconnect")
+    .arg(address_of(source)?)
+    .arg(address_of(destination)?)
+    .status()
+    .map_err(|e| couldnt_run_aconnect(&e))?;
+  if !status.success() {
+    return Err(format!("aconnect failed ({})", status));
+  }
+  Ok(())
+}
+
+fn couldnt_run_aconnect(e: &io::Error) -> String {
+  match e.kind() {
+    io::ErrorKind::NotFound =>
+      "aconnect isn't installed (it comes with alsa-utils); connect the ports by hand".into(),
+    _ => format!("couldn't run aconnect: {}", e),
+  }
+}
+
+fn address_of(name: &str) -> Result<&str, String> {
+  alsa_address(name).ok_or_else(|| format!("can't tell the ALSA address of '{}'", name))
+}
+
+/// Sends a message, logging a failure (usually a port that went away)
+/// instead of dropping it silently. Returns whether it was sent.
+pub fn send_or_warn(sink: &mut impl MidiSink, data: &[u8]) -> bool {
+  let sent: bool = sink.send(data);
+  if !sent {
+    log::warn!("send failed: {}", hex(data));
+  }
+  sent
+}
+
+/// With `--self-test`, sends the output `name` a single active-sensing
+/// message and prints whether it was taken, so a port that can't be
+/// written (no ALSA sequencer, say) shows up at startup rather than as
+/// silence. Nothing sounds; a synth that heeds active sensing just goes
+/// back to normal after 300 ms without another. Returns whether it
+/// passed, or true if the flag wasn't given.
+pub fn self_test(args: &Args, sink: &mut impl MidiSink, name: &str) -> bool {
+  if !args.flag("--self-test") {
+    return true;
+  }
+  let sent: bool = sink.send(&[ACTIVE_SENSING]);
+  if sent {
+    println!("Self-test: output '{}' ok", name);
+  } else {
+    eprintln!("Self-test: output '{}' FAILED: a test message couldn't be sent", name);
+  }
+  sent
+}
+
+/// How many sends in a row can fail before an output is reported
+/// as probably disconnected.
+pub const FAILURES_BEFORE_WARNING: u32 = 8;
+
+/// Keeps count of failed sends to one output, so that a port that has
+/// gone away gets reported once rather than just going quiet.
+#[derive(Default)]
+pub struct SendMonitor {
+  consecutive: u32,
+  total: u64,
+}
+
+impl SendMonitor {
+  pub fn new() -> Self {
+    SendMonitor::default()
+  }
+
+  /// Sends `data`, counting a failure. Once the output looks lost,
+  /// further failures are only logged at debug level. Returns whether it was sent.
+  pub fn send(&mut self, sink: &mut impl MidiSink, data: &[u8]) -> bool {
+    let sent: bool = if self.lost() {
+      let sent: bool = sink.send(data);
+      if !sent {
+        log::debug!("send failed: {}", hex(data));
+      }
+      sent
+    } else {
+      send_or_warn(sink, data)
+    };
+    self.record(sent);
+    sent
+  }
+
+  /// Counts the outcome of a send. The first time enough fail in a row,
+  /// warns that the port may be disconnected, and says so when it recovers.
+  pub fn record(&mut self, sent: bool) {
+    if sent {
+      if self.lost() {
+        eprintln!("Output working again after {} failed sends", self.consecutive);
+      }
+      self.consecutive = 0;
+    } else {
+      self.consecutive += 1;
+      self.total += 1;
+      if self.consecutive == FAILURES_BEFORE_WARNING {
+        eprintln!("Warning: the last {} MIDI sends failed; \
+                   is the output port disconnected?", self.consecutive);
+      }
+    }
+  }
+
+  /// Whether the recent sends failed often enough to give up on.
+  pub fn lost(&self) -> bool {
+    self.consecutive >= FAILURES_BEFORE_WARNING
+  }
+
+  pub fn total_failures(&self) -> u64 {
+    self.total
+  }
+}
+
+/// Why `wait_for_exit` or `run_console` returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+  Enter, // or quit, or stdin closed
+  InputLost,
+}
+
+/// Waits until Enter is pressed, which is how every binary is stopped.
+///
+/// An input connected to an existing port (`--input-port`) is watched
+/// too: if that port disappears (its device unplugged, its program quit,
+/// or it was removed with `aconnect -d`), this says so and returns
+/// `Exit::InputLost`, since nothing more will arrive and in particular
+/// no note-offs for the notes still sounding. Callers then release them.
+/// A virtual input can't be watched this way: its port is ours, and
+/// lives as long as we do, whether or not anything is connected to it.
+pub fn wait_for_exit(input_port: Option<&str>) -> io::Result<Exit> {
+  run_console(input_port, |_| false)
+}
+
+/// Cleans up after `wait_for_exit`. If the input was lost, nothing will
+/// come to end the notes still sounding, so this closes `inputs` (whose
+/// callbacks hold senders), stops `timer` (clearing its flag, which it
+/// checks each time round, and waiting for it), sends all-notes-off on
+/// every channel through `tx` and waits for `out_thread` to send it all.
+/// The output thread has to end once its senders are gone. After Enter
+/// it does nothing: the process ends, and with it the ports.
+pub fn silence_after<I, M: From<Vec<u8>>>(
+  exit: Exit,
+  inputs: I,
+  timer: Option<(&AtomicBool, thread::JoinHandle<()>)>,
+  tx: mpsc::Sender<M>,
+  out_thread: thread::JoinHandle<()>,
+) {
+  if exit != Exit::InputLost {
+    return;
+  }
+  drop(inputs);
+  if let Some((running, thread)) = timer {
+    running.store(false, Ordering::SeqCst);
+    let _ = thread.join();
+  }
+  for msg in (0..16).map(all_notes_off) {
+    let _ = tx.send(M::from(msg));
+  }
+  drop(tx);
+  let _ = out_thread.join();
+}
+
+/// Like `wait_for_exit`, but hands each line typed to `on_line`
+/// (without its newline) and keeps going while it returns true.
+/// Lines are read on a thread of their own, so the input port
+/// is still watched while nobody types.
+pub fn run_console(
+  input_port: Option<&str>,
+  mut on_line: impl FnMut(&str) -> bool,
+) -> io::Result<Exit> {
+  let (tx, rx): (mpsc::Sender<String>, mpsc::Receiver<String>) = mpsc::channel();
+  thread::spawn(move || {
+    let mut line: String = String::new();
+    while matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+      if tx.send(line.trim_end_matches(['\r', '\n']).to_string()).is_err() {
+        break;
+      }
+      line.clear();
+    }
+  });
+  let watcher: Option<(MidiInput, &str)> = input_port
+    .and_then(|wanted| MidiInput::new("live-midi-watch").ok().map(|m| (m, wanted)));
+  loop {
+    match rx.recv_timeout(Duration::from_millis(INPUT_CHECK_MS)) {
+      Ok(line) if on_line(&line) => {}
+      Ok(_) | Err(RecvTimeoutError::Disconnected) => return Ok(Exit::Enter),
+      Err(RecvTimeoutError::Timeout) => {}
+    }
+    if let Some((midi_in, wanted)) = &watcher {
+      if find_input_port(midi_in, wanted).is_err() {
+        println!("Input port '{}' has gone away; stopping.", wanted);
+        log::warn!("input port '{}' lost", wanted);
+        return Ok(Exit::InputLost);
+      }
+    }
+  }
+}
+
+/// Prints every input and output port, with its index,
+/// in the form the `--input-port`/`--output-port` matchers see.
+pub fn list_ports() -> Result<(), Box<dyn Error>> {
+  let midi_in: MidiInput = MidiInput::new("live-midi-list-ports")?;
+  let midi_out: MidiOutput = MidiOutput::new("live-midi-list-ports")?;
+  println!("Input ports:");
+  for (i, port) in midi_in.ports().iter().enumerate() {
+    println!("  {}: {}", i, midi_in.port_name(port)?);
+  }
+  println!("Output ports:");
+  for (i, port) in midi_out.ports().iter().enumerate() {
+    println!("  {}: {}", i, midi_out.port_name(port)?);
+  }
+  Ok(())
+}
+
+pub fn find_input_port(
+  midi_in: &MidiInput,
+  wanted: &str,
+) -> Result<(MidiInputPort, String), String> {
+  for port in midi_in.ports() {
+    if let Ok(name) = midi_in.port_name(&port) {
+      if name.contains(wanted) {
+        return Ok((port, name));
+      }
+    }
+  }
+  Err(format!("No MIDI input port matches '{}'", wanted))
+}
+
+pub fn find_output_port(
+  midi_out: &MidiOutput,
+  wanted: &str,
+) -> Result<(MidiOutputPort, String), String> {
+  for port in midi_out.ports() {
+    if let Ok(name) = midi_out.port_name(&port) {
+      if name.contains(wanted) {
+        return Ok((port, name));
+      }
+    }
+  }
+  Err(format!("No MIDI output port matches '{}'", wanted))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn alsa_addresses() {
+    assert_eq!(alsa_address("arp-in:midi-in 128:0"), Some("128:0"));
+    assert_eq!(alsa_address("Keystation 49:Keystation 49 MIDI 1 20:0"), Some("20:0"));
+    assert_eq!(alsa_address("arp-in:midi-in"), None);
+  }
+
+  #[test]
+  fn a_missing_aconnect_is_named() {
+    let missing: String = couldnt_run_aconnect(&io::Error::from(io::ErrorKind::NotFound));
+    assert!(missing.contains("isn't installed") && missing.contains("alsa-utils"));
+    let denied: String = couldnt_run_aconnect(&io::Error::from(io::ErrorKind::PermissionDenied));
+    assert!(denied.starts_with("couldn't run aconnect"));
+  }
+
+  #[test]
+  fn self_test_sends_only_when_asked() {
+    let mut sent: Vec<Vec<u8>> = Vec::new();
+    assert!(self_test(&Args::new(vec![]), &mut sent, "out"));
+    assert!(sent.is_empty());
+    let args: Args = Args::new(vec!["--self-test".to_string()]);
+    assert!(self_test(&args, &mut sent, "out"));
+    assert_eq!(sent, vec![vec![ACTIVE_SENSING]]);
+    struct Unplugged;
+    impl MidiSink for Unplugged {
+      fn send(&mut self, _: &[u8]) -> bool {
+        false
+      }
+    }
+    assert!(!self_test(&args, &mut Unplugged, "out"));
+  }
+
+  #[test]
+  fn a_lost_input_stops_the_timer_and_silences_every_channel() {
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    let out_thread: thread::JoinHandle<()> = thread::spawn(|| ());
+    silence_after(Exit::Enter, (), None, tx.clone(), out_thread);
+    assert!(rx.try_recv().is_err());
+
+    let sent: Arc<Mutex<Vec<Vec<u8>>>> = Arc::new(Mutex::new(vec![]));
+    let sent_by_out: Arc<Mutex<Vec<Vec<u8>>>> = Arc::clone(&sent);
+    let out_thread: thread::JoinHandle<()> =
+      thread::spawn(move || sent_by_out.lock().unwrap().extend(rx.iter()));
+    let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+    let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+    let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+    let timer: thread::JoinHandle<()> = thread::spawn(move || {
+      while running_for_timer.load(Ordering::SeqCst) {
+        let _ = tx_for_timer.send(vec![0x90, 60, 100]);
+        thread::sleep(Duration::from_millis(1));
+      }
+    });
+    thread::sleep(Duration::from_millis(5));
+    silence_after(Exit::InputLost, (), Some((&running, timer)), tx, out_thread);
+    let sent: Vec<Vec<u8>> = sent.lock().unwrap().clone();
+    assert!(sent.len() > 16);
+    // Nothing from the timer after the all-notes-off.
+    assert_eq!(sent[sent.len() - 16..], (0..16).map(all_notes_off).collect::<Vec<Vec<u8>>>());
+  }
+
+  #[test]
+  fn a_run_of_failures_marks_the_output_lost() {
+    let mut monitor: SendMonitor = SendMonitor::new();
+    for _ in 0..FAILURES_BEFORE_WARNING - 1 {
+      monitor.record(false);
+    }
+    assert!(!monitor.lost());
+    monitor.record(true); // the run is broken
+    for _ in 0..FAILURES_BEFORE_WARNING {
+      monitor.record(false);
+    }
+    assert!(monitor.lost());
+    monitor.record(true);
+    assert!(!monitor.lost());
+    assert_eq!(monitor.total_failures(), 2 * FAILURES_BEFORE_WARNING as u64 - 1);
+  }
+}
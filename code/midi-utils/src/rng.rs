@@ -0,0 +1,102 @@
+//! A small xorshift random number generator.
+//! Good enough for musical randomness, and seedable for tests.
+
+use crate::args::Args;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Rng {
+  state: u64,
+}
+
+impl Rng {
+  pub fn seeded(seed: u64) -> Self {
+    // xorshift gets stuck at zero
+    Rng { state: seed.max(1) }
+  }
+
+  /// Seeded from the clock.
+  pub fn from_time() -> Self {
+    let nanos: u64 = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_nanos() as u64)
+      .unwrap_or(1);
+    Rng::seeded(nanos)
+  }
+
+  /// Seeded from `--seed` if given, so a run can be repeated,
+  /// and otherwise from the clock.
+  pub fn from_args(args: &Args) -> Result<Self, String> {
+    Ok(match args.parse::<u64>("--seed")? {
+      Some(seed) => Rng::seeded(seed),
+      None => Rng::from_time(),
+    })
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    let mut x: u64 = self.state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    self.state = x;
+    x
+  }
+
+  /// Uniform in 0..n. `n` must be positive.
+  pub fn below(&mut self, n: usize) -> usize {
+    (self.next_u64() % n as u64) as usize
+  }
+
+  /// Uniform in [0, 1).
+  pub fn unit(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  /// True with probability `p`. A certainty draws nothing,
+  /// so it leaves the sequence as it was.
+  pub fn chance(&mut self, p: f64) -> bool {
+    p >= 1.0 || self.unit() < p
+  }
+}
+
+/// A velocity moved up or down by up to `jitter`, staying within 1-127.
+pub fn jitter_velocity(rng: &mut Rng, velocity: u8, jitter: u8) -> u8 {
+  let change: i16 = rng.below(2 * jitter as usize + 1) as i16 - jitter as i16;
+  (velocity as i16 + change).clamp(1, 127) as u8
+}
+
+/// Reads `--probability`, the chance each step plays (default 1, always).
+pub fn parse_probability(args: &Args) -> Result<f64, String> {
+  let probability: f64 = args.parse_or("--probability", 1.0)?;
+  if !(0.0..=1.0).contains(&probability) {
+    return Err("--probability must be in 0.0-1.0".to_string());
+  }
+  Ok(probability)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chances() {
+    let mut rng: Rng = Rng::seeded(5);
+    assert!((0..100).all(|_| rng.chance(1.0)));
+    assert!((0..100).all(|_| !rng.chance(0.0)));
+    let hits: usize = (0..10_000).filter(|_| rng.chance(0.8)).count();
+    assert!((7_700..8_300).contains(&hits), "{} hits", hits);
+    let mut same: Rng = Rng::seeded(9);
+    let mut again: Rng = Rng::seeded(9);
+    assert!((0..100).all(|_| same.chance(0.5) == again.chance(0.5)));
+  }
+
+  #[test]
+  fn velocity_stays_within_jitter_and_range() {
+    let mut rng: Rng = Rng::seeded(3);
+    for _ in 0..500 {
+      let v: u8 = jitter_velocity(&mut rng, 64, 10);
+      assert!((54..=74).contains(&v));
+      assert!((1..=5).contains(&jitter_velocity(&mut rng, 1, 4)));
+      assert!(jitter_velocity(&mut rng, 127, 4) >= 123);
+    }
+  }
+}
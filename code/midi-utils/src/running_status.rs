@@ -0,0 +1,64 @@
+//! Restoring omitted status bytes.
+//!
+//! A source may send "running status": after one channel message,
+//! further messages of the same kind and channel leave out the status
+//! byte. midir usually hands over complete messages, but not every
+//! source plays along, and everything else here reads `data[0]` as
+//! the status. Keep one `RunningStatus` per input connection.
+
+#[derive(Debug, Default)]
+pub struct RunningStatus {
+  status: Option<u8>,
+}
+
+impl RunningStatus {
+  pub fn new() -> Self {
+    RunningStatus { status: None }
+  }
+
+  /// Returns the message with its status byte restored if it was left out.
+  /// Channel messages set the running status; system common messages
+  /// (SysEx included) cancel it; real-time messages leave it alone.
+  /// Data with no running status to apply comes back unchanged.
+  pub fn expand(&mut self, message: &[u8]) -> Vec<u8> {
+    match message.first() {
+      Some(&b) if b < 0x80 => match self.status {
+        Some(status) => [&[status], message].concat(),
+        None => message.to_vec(),
+      },
+      Some(&b) if b < 0xF0 => {
+        self.status = Some(b);
+        message.to_vec()
+      }
+      Some(&b) if b < 0xF8 => {
+        self.status = None;
+        message.to_vec()
+      }
+      _ => message.to_vec(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn data_only_messages_get_the_last_status() {
+    let mut running: RunningStatus = RunningStatus::new();
+    assert_eq!(running.expand(&[0x91, 60, 100]), vec![0x91, 60, 100]);
+    assert_eq!(running.expand(&[62, 90]), vec![0x91, 62, 90]);
+    assert_eq!(running.expand(&[60, 0]), vec![0x91, 60, 0]);
+  }
+
+  #[test]
+  fn realtime_keeps_and_system_common_cancels() {
+    let mut running: RunningStatus = RunningStatus::new();
+    assert_eq!(running.expand(&[64, 0]), vec![64, 0]); // nothing to apply yet
+    running.expand(&[0xB0, 64, 127]);
+    running.expand(&[0xF8]);
+    assert_eq!(running.expand(&[64, 0]), vec![0xB0, 64, 0]);
+    running.expand(&[0xF0, 0x7E, 0xF7]);
+    assert_eq!(running.expand(&[64, 0]), vec![64, 0]);
+  }
+}
@@ -0,0 +1,180 @@
+//! Where output goes.
+//!
+//! Output threads send to a `MidiSink` rather than straight to a port,
+//! so tests can hand them a `Vec` that records what they sent, and one
+//! thread can feed more than one sink at once: a pair of sinks gets
+//! every message twice, e.g. to a port and to a recording of it.
+//! For a port, `send` is just the connection's own.
+
+use crate::NOTE_OFF;
+use midir::MidiOutputConnection;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait MidiSink {
+  /// Sends one whole message. Returns whether it was taken.
+  fn send(&mut self, message: &[u8]) -> bool;
+}
+
+impl MidiSink for MidiOutputConnection {
+  #[inline]
+  fn send(&mut self, message: &[u8]) -> bool {
+    MidiOutputConnection::send(self, message)
+      .inspect_err(|e| log::debug!("send failed: {}", e))
+      .is_ok()
+  }
+}
+
+/// Records every message, in order.
+impl MidiSink for Vec<Vec<u8>> {
+  fn send(&mut self, message: &[u8]) -> bool {
+    self.push(message.to_vec());
+    true
+  }
+}
+
+impl<S: MidiSink + ?Sized> MidiSink for &mut S {
+  #[inline]
+  fn send(&mut self, message: &[u8]) -> bool {
+    (**self).send(message)
+  }
+}
+
+impl<S: MidiSink + ?Sized> MidiSink for Box<S> {
+  #[inline]
+  fn send(&mut self, message: &[u8]) -> bool {
+    (**self).send(message)
+  }
+}
+
+/// Both get everything; a message counts as sent only if both took it.
+impl<A: MidiSink, B: MidiSink> MidiSink for (A, B) {
+  fn send(&mut self, message: &[u8]) -> bool {
+    let first: bool = self.0.send(message);
+    let second: bool = self.1.send(message);
+    first && second
+  }
+}
+
+/// Every sink gets everything, in turn; a message counts as sent only
+/// if all of them took it. For outputs counted at runtime, where a pair
+/// won't do.
+pub struct Broadcast<S>(pub Vec<S>);
+
+impl<S: MidiSink> MidiSink for Broadcast<S> {
+  fn send(&mut self, message: &[u8]) -> bool {
+    let mut all: bool = true;
+    for sink in &mut self.0 {
+      all &= sink.send(message); // every one gets it, whatever the last did
+    }
+    all
+  }
+}
+
+/// Passes everything on to `sink`, except that with `zero` set (by
+/// `--zero-release-velocity`), note-offs go with velocity 0, for synths
+/// that misread release velocity. Without it, note-offs keep the release
+/// velocity they were played with, echoed and looped ones included.
+pub struct ReleaseVelocity<S> {
+  pub sink: S,
+  pub zero: bool,
+}
+
+impl<S: MidiSink> MidiSink for ReleaseVelocity<S> {
+  fn send(&mut self, message: &[u8]) -> bool {
+    if self.zero && message.len() == 3 && message[0] & 0xF0 == NOTE_OFF && message[2] != 0 {
+      return self.sink.send(&[message[0], message[1], 0]);
+    }
+    self.sink.send(message)
+  }
+}
+
+/// Passes everything on to `sink`, but never two messages less than
+/// `gap` apart: one that comes too soon waits out the rest of the gap
+/// first. For synths that drop messages arriving all at once, like the
+/// note-off and note-on of a retune. A zero gap never waits.
+pub struct MinGap<S> {
+  pub sink: S,
+  pub gap: Duration,
+  last: Option<Instant>, // when the last message went
+}
+
+impl<S> MinGap<S> {
+  pub fn new(sink: S, gap: Duration) -> Self {
+    MinGap { sink, gap, last: None }
+  }
+}
+
+impl<S: MidiSink> MidiSink for MinGap<S> {
+  fn send(&mut self, message: &[u8]) -> bool {
+    if self.gap.is_zero() {
+      return self.sink.send(message);
+    }
+    if let Some(last) = self.last {
+      thread::sleep(self.gap.saturating_sub(last.elapsed()));
+    }
+    self.last = Some(Instant::now());
+    self.sink.send(message)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Unplugged;
+
+  impl MidiSink for Unplugged {
+    fn send(&mut self, _: &[u8]) -> bool {
+      false
+    }
+  }
+
+  #[test]
+  fn pairs_fan_out() {
+    let mut recording: Vec<Vec<u8>> = Vec::new();
+    let mut pair = (&mut recording, Box::new(Vec::new()) as Box<dyn MidiSink>);
+    assert!(pair.send(&[0x90, 60, 100]));
+    assert!(pair.send(&[0xF8]));
+    assert_eq!(recording, vec![vec![0x90, 60, 100], vec![0xF8]]);
+
+    let mut half_lost: (Vec<Vec<u8>>, Unplugged) = (Vec::new(), Unplugged);
+    assert!(!half_lost.send(&[0xF8]));
+    assert_eq!(half_lost.0, vec![vec![0xF8]]); // still got it
+  }
+
+  #[test]
+  fn broadcasts_reach_every_sink() {
+    let mut all: Broadcast<Box<dyn MidiSink>> =
+      Broadcast(vec![Box::new(Vec::<Vec<u8>>::new()), Box::new(Unplugged), Box::new(Vec::new())]);
+    assert!(!all.send(&[0xF8])); // one couldn't take it
+    let mut both: Broadcast<Vec<Vec<u8>>> = Broadcast(vec![Vec::new(), Vec::new()]);
+    assert!(both.send(&[0x90, 60, 100]));
+    assert!(both.0.iter().all(|sent| sent == &vec![vec![0x90, 60, 100]]));
+    assert!(Broadcast::<Unplugged>(vec![]).send(&[0xF8]));
+  }
+
+  #[test]
+  fn messages_keep_their_distance() {
+    let mut spaced: MinGap<Vec<Vec<u8>>> = MinGap::new(Vec::new(), Duration::from_millis(5));
+    let start: Instant = Instant::now();
+    for note in [60, 64, 67] {
+      assert!(spaced.send(&[0x90, note, 100]));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(10)); // two gaps between three
+    assert_eq!(spaced.sink.len(), 3);
+    let mut unspaced: MinGap<Vec<Vec<u8>>> = MinGap::new(Vec::new(), Duration::ZERO);
+    unspaced.send(&[0xF8]);
+    assert!(unspaced.last.is_none());
+  }
+
+  #[test]
+  fn release_velocity_can_be_zeroed() {
+    for zero in [false, true] {
+      let mut out: ReleaseVelocity<Vec<Vec<u8>>> = ReleaseVelocity { sink: Vec::new(), zero };
+      out.send(&[0x81, 60, 45]);
+      out.send(&[0x91, 60, 45]);
+      assert_eq!(out.sink, vec![vec![0x81, 60, if zero { 0 } else { 45 }], vec![0x91, 60, 45]]);
+    }
+  }
+}
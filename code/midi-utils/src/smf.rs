@@ -0,0 +1,350 @@
+//! Reading and writing Standard MIDI Files.
+//!
+//! Only what the binaries need: channel messages, SysEx and tempo.
+//! Other meta events are skipped when reading, and all tracks
+//! are merged into one time-ordered list.
+
+use std::io::{self, Read, Write};
+
+pub const DEFAULT_TEMPO: u32 = 500_000; // microseconds per quarter note, i.e. 120 BPM
+pub const MAX_TEMPO: u32 = 0xFF_FFFF; // a tempo event holds 3 bytes, so ~3.58 BPM at the slowest
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+  /// A channel message, or a complete SysEx (starting with 0xF0).
+  Midi(Vec<u8>),
+  /// Microseconds per quarter note.
+  Tempo(u32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackEvent {
+  /// Absolute time, in ticks.
+  pub tick: u64,
+  pub event: Event,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Smf {
+  pub ticks_per_quarter: u16,
+  /// Sorted by tick; events at the same tick keep their file order.
+  pub events: Vec<TrackEvent>,
+  /// Where the longest track ends, which may be after its last event.
+  pub end_tick: u64,
+}
+
+impl Smf {
+  /// Each MIDI event with its time in microseconds, following the tempo map.
+  pub fn timed_messages(&self) -> Vec<(u64, &[u8])> {
+    let mut result: Vec<(u64, &[u8])> = Vec::new();
+    let mut tempo: u32 = DEFAULT_TEMPO;
+    let mut last_tick: u64 = 0;
+    let mut micros: u64 = 0;
+    for e in &self.events {
+      micros += ticks_to_micros(e.tick - last_tick, self.ticks_per_quarter, tempo);
+      last_tick = e.tick;
+      match &e.event {
+        Event::Tempo(t) => tempo = *t,
+        Event::Midi(data) => result.push((micros, data)),
+      }
+    }
+    result
+  }
+
+  /// How long the file lasts, to its end of track, in microseconds.
+  pub fn length_micros(&self) -> u64 {
+    let mut tempo: u32 = DEFAULT_TEMPO;
+    let mut last_tick: u64 = 0;
+    let mut micros: u64 = 0;
+    for e in self.events.iter().take_while(|e| e.tick <= self.end_tick) {
+      micros += ticks_to_micros(e.tick - last_tick, self.ticks_per_quarter, tempo);
+      last_tick = e.tick;
+      if let Event::Tempo(t) = e.event {
+        tempo = t;
+      }
+    }
+    micros + ticks_to_micros(self.end_tick - last_tick, self.ticks_per_quarter, tempo)
+  }
+}
+
+pub fn micros_to_ticks(micros: u64, ticks_per_quarter: u16, tempo: u32) -> u64 {
+  (micros as u128 * ticks_per_quarter as u128 / tempo as u128) as u64
+}
+
+pub fn ticks_to_micros(ticks: u64, ticks_per_quarter: u16, tempo: u32) -> u64 {
+  (ticks as u128 * tempo as u128 / ticks_per_quarter as u128) as u64
+}
+
+/// Writes a format-0 (single track) file. `events` must be sorted by tick.
+/// System real-time messages have no place in a file and are skipped.
+pub fn write_format0<W: Write>(
+  w: W,
+  ticks_per_quarter: u16,
+  events: &[TrackEvent],
+) -> io::Result<()> {
+  write_format0_with_length(w, ticks_per_quarter, events, 0)
+}
+
+/// Like `write_format0`, but the track ends at `end_tick`
+/// if that comes after the last event, as a loop's rest would.
+pub fn write_format0_with_length<W: Write>(
+  mut w: W,
+  ticks_per_quarter: u16,
+  events: &[TrackEvent],
+  end_tick: u64,
+) -> io::Result<()> {
+  let mut track: Vec<u8> = Vec::new();
+  let mut last_tick: u64 = 0;
+  for e in events {
+    let bytes: Vec<u8> = match &e.event {
+      Event::Tempo(t) => vec![0xFF, 0x51, 0x03, (t >> 16) as u8, (t >> 8) as u8, *t as u8],
+      Event::Midi(data) if data.first() == Some(&0xF0) => {
+        let mut b: Vec<u8> = vec![0xF0];
+        write_varlen(&mut b, data.len() as u64 - 1);
+        b.extend_from_slice(&data[1..]);
+        b
+      }
+      Event::Midi(data) if data.is_empty() || data[0] >= 0xF8 => continue,
+      Event::Midi(data) => data.clone(),
+    };
+    write_varlen(&mut track, e.tick.saturating_sub(last_tick));
+    last_tick = e.tick.max(last_tick);
+    track.extend_from_slice(&bytes);
+  }
+  write_varlen(&mut track, end_tick.saturating_sub(last_tick));
+  track.extend_from_slice(&[0xFF, 0x2F, 0x00]); // end of track
+
+  w.write_all(b"MThd")?;
+  w.write_all(&6u32.to_be_bytes())?;
+  w.write_all(&0u16.to_be_bytes())?; // format 0
+  w.write_all(&1u16.to_be_bytes())?; // one track
+  w.write_all(&ticks_per_quarter.to_be_bytes())?;
+  w.write_all(b"MTrk")?;
+  w.write_all(&(track.len() as u32).to_be_bytes())?;
+  w.write_all(&track)?;
+  w.flush()
+}
+
+pub fn read<R: Read>(mut r: R) -> Result<Smf, String> {
+  let mut bytes: Vec<u8> = Vec::new();
+  r.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+  let mut cursor: Cursor = Cursor { bytes: &bytes, pos: 0 };
+  if cursor.take(4)? != b"MThd" {
+    return Err("not a MIDI file (no MThd)".to_string());
+  }
+  let header_len: usize = cursor.u32()? as usize;
+  let header: &[u8] = cursor.take(header_len)?;
+  if header_len < 6 {
+    return Err("MIDI header too short".to_string());
+  }
+  let track_count: u16 = u16::from_be_bytes([header[2], header[3]]);
+  let division: u16 = u16::from_be_bytes([header[4], header[5]]);
+  if division & 0x8000 != 0 {
+    return Err("SMPTE time division is not supported".to_string());
+  }
+  if division == 0 {
+    return Err("MIDI header gives 0 ticks per quarter note".to_string());
+  }
+  let mut events: Vec<TrackEvent> = Vec::new();
+  let mut end_tick: u64 = 0;
+  let mut tracks_read: u16 = 0;
+  while tracks_read < track_count && cursor.pos < bytes.len() {
+    let id: &[u8] = cursor.take(4)?;
+    let len: usize = cursor.u32()? as usize;
+    let body: &[u8] = cursor.take(len)?;
+    if id == b"MTrk" {
+      end_tick = end_tick.max(read_track(body, &mut events)?);
+      tracks_read += 1;
+    } // other chunk types are skipped
+  }
+  events.sort_by_key(|e| e.tick); // stable
+  Ok(Smf { ticks_per_quarter: division, events, end_tick })
+}
+
+/// Reads a track's events into `events`, returning the tick it ends on.
+fn read_track(body: &[u8], events: &mut Vec<TrackEvent>) -> Result<u64, String> {
+  let mut cursor: Cursor = Cursor { bytes: body, pos: 0 };
+  let mut tick: u64 = 0;
+  let mut running_status: Option<u8> = None;
+  while cursor.pos < body.len() {
+    tick += cursor.varlen()?;
+    let first: u8 = cursor.byte()?;
+    match first {
+      0xFF => {
+        let kind: u8 = cursor.byte()?;
+        let len: usize = cursor.varlen()? as usize;
+        let data: &[u8] = cursor.take(len)?;
+        match kind {
+          0x2F => return Ok(tick),
+          0x51 if len == 3 => events.push(TrackEvent {
+            tick,
+            event: Event::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])),
+          }),
+          _ => {}
+        }
+      }
+      0xF0 | 0xF7 => {
+        let len: usize = cursor.varlen()? as usize;
+        let data: &[u8] = cursor.take(len)?;
+        let mut message: Vec<u8> = if first == 0xF0 { vec![0xF0] } else { vec![] };
+        message.extend_from_slice(data);
+        events.push(TrackEvent { tick, event: Event::Midi(message) });
+        running_status = None;
+      }
+      _ => {
+        let (status, mut message): (u8, Vec<u8>) = if first >= 0x80 {
+          running_status = Some(first);
+          (first, vec![first])
+        } else {
+          let status: u8 = running_status
+            .ok_or("data byte without a status byte")?;
+          (status, vec![status, first])
+        };
+        let data_len: usize = match status & 0xF0 {
+          0xC0 | 0xD0 => 1,
+          _ => 2,
+        };
+        while message.len() < data_len + 1 {
+          message.push(cursor.byte()?);
+        }
+        events.push(TrackEvent { tick, event: Event::Midi(message) });
+      }
+    }
+  }
+  Ok(tick)
+}
+
+fn write_varlen(out: &mut Vec<u8>, mut value: u64) {
+  let mut groups: Vec<u8> = vec![(value & 0x7F) as u8];
+  value >>= 7;
+  while value > 0 {
+    groups.push((value & 0x7F) as u8 | 0x80);
+    value >>= 7;
+  }
+  out.extend(groups.iter().rev());
+}
+
+struct Cursor<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+    if self.pos + n > self.bytes.len() {
+      return Err("unexpected end of MIDI file".to_string());
+    }
+    let slice: &'a [u8] = &self.bytes[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(slice)
+  }
+
+  fn byte(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    let b: &[u8] = self.take(4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  fn varlen(&mut self) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for _ in 0..4 {
+      let b: u8 = self.byte()?;
+      value = (value << 7) | (b & 0x7F) as u64;
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err("variable-length number too long".to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn midi(tick: u64, data: &[u8]) -> TrackEvent {
+    TrackEvent { tick, event: Event::Midi(data.to_vec()) }
+  }
+
+  #[test]
+  fn varlen_encoding() {
+    let mut out: Vec<u8> = Vec::new();
+    write_varlen(&mut out, 0);
+    write_varlen(&mut out, 0x7F);
+    write_varlen(&mut out, 0x80);
+    write_varlen(&mut out, 0x0FFFFFFF);
+    assert_eq!(out, vec![0x00, 0x7F, 0x81, 0x00, 0xFF, 0xFF, 0xFF, 0x7F]);
+  }
+
+  #[test]
+  fn round_trip() {
+    let events: Vec<TrackEvent> = vec![
+      TrackEvent { tick: 0, event: Event::Tempo(400_000) },
+      midi(0, &[0x90, 60, 100]),
+      midi(240, &[0xC1, 5]),
+      midi(480, &[0xF0, 0x7E, 0x01, 0xF7]),
+      midi(480, &[0x80, 60, 0]),
+    ];
+    let mut file: Vec<u8> = Vec::new();
+    write_format0(&mut file, 480, &events).unwrap();
+    let smf: Smf = read(&file[..]).unwrap();
+    assert_eq!(smf, Smf { ticks_per_quarter: 480, events, end_tick: 480 });
+  }
+
+  #[test]
+  fn a_track_can_end_after_its_last_event() {
+    let mut file: Vec<u8> = Vec::new();
+    let events: [TrackEvent; 2] = [midi(0, &[0x90, 60, 100]), midi(240, &[0x80, 60, 0])];
+    write_format0_with_length(&mut file, 480, &events, 1920).unwrap();
+    let smf: Smf = read(&file[..]).unwrap();
+    assert_eq!(smf.end_tick, 1920);
+    assert_eq!(smf.length_micros(), 2_000_000); // 4 beats at 120 BPM
+    let mut early: Vec<u8> = Vec::new();
+    write_format0_with_length(&mut early, 480, &events, 100).unwrap(); // before the last event
+    assert_eq!(read(&early[..]).unwrap().end_tick, 240);
+  }
+
+  #[test]
+  fn real_time_messages_are_not_written() {
+    let mut file: Vec<u8> = Vec::new();
+    write_format0(&mut file, 96, &[midi(0, &[0xF8]), midi(10, &[0x90, 60, 1])]).unwrap();
+    assert_eq!(read(&file[..]).unwrap().events, vec![midi(10, &[0x90, 60, 1])]);
+  }
+
+  #[test]
+  fn reads_running_status() {
+    let mut file: Vec<u8> = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+    let track: [u8; 14] = [0x00, 0x90, 60, 100, 0x10, 62, 100, 0x10, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+    file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    file.extend_from_slice(&track);
+    let smf: Smf = read(&file[..]).unwrap();
+    assert_eq!(smf.events, vec![midi(0, &[0x90, 60, 100]),
+                                midi(16, &[0x90, 62, 100]),
+                                midi(32, &[0x90, 60, 0])]);
+  }
+
+  #[test]
+  fn zero_ticks_per_quarter_is_refused() {
+    let file: Vec<u8> = b"MThd\0\0\0\x06\0\0\0\x01\0\0MTrk\0\0\0\0".to_vec();
+    assert!(read(&file[..]).is_err());
+  }
+
+  #[test]
+  fn timing_follows_tempo_map() {
+    let smf: Smf = Smf {
+      ticks_per_quarter: 100,
+      events: vec![
+        midi(100, &[0x90, 60, 1]), // 1 beat at 120 BPM
+        TrackEvent { tick: 100, event: Event::Tempo(1_000_000) },
+        midi(200, &[0x80, 60, 0]), // then 1 beat at 60 BPM
+      ],
+      end_tick: 300,
+    };
+    let times: Vec<u64> = smf.timed_messages().iter().map(|(t, _)| *t).collect();
+    assert_eq!(times, vec![500_000, 1_500_000]);
+    assert_eq!(smf.length_micros(), 2_500_000);
+  }
+}
@@ -0,0 +1,39 @@
+//! Locking that survives a panic elsewhere.
+//!
+//! A mutex is "poisoned" when a thread panics while holding it,
+//! and from then on `lock().unwrap()` panics too. For these binaries
+//! that would silently kill MIDI processing in the input callback,
+//! so instead we warn and carry on with whatever state was left.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|poisoned: PoisonError<MutexGuard<'_, T>>| {
+    log::warn!("a thread panicked while holding a lock; recovering its state");
+    mutex.clear_poison(); // so the warning appears once per panic
+    poisoned.into_inner()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn recovers_from_poisoning() {
+    let shared: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(vec![1]));
+    let shared_for_thread: Arc<Mutex<Vec<u8>>> = Arc::clone(&shared);
+    let result = thread::spawn(move || {
+      let mut v = shared_for_thread.lock().unwrap();
+      v.push(2);
+      panic!("deliberate");
+    }).join();
+    assert!(result.is_err());
+    assert!(shared.is_poisoned());
+    lock(&shared).push(3);
+    assert!(!shared.is_poisoned());
+    assert_eq!(*lock(&shared), vec![1, 2, 3]);
+  }
+}
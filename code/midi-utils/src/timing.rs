@@ -0,0 +1,84 @@
+//! Scheduling evenly spaced events, optionally with swing.
+//!
+//! Swing is the fraction by which every odd-numbered interval
+//! (the first, third, ...) is lengthened; the interval after it is
+//! shortened by the same amount, so each pair keeps its total length.
+//! - 0.0 is straight
+//! - 1/3 (about 0.33) is a triplet shuffle: the pair splits 2:1
+//! - 0.5 is a hard, dotted swing: 3:1
+//!
+//! Offsets are measured from the first event rather than summed
+//! interval by interval, so callers that sleep until
+//! `start + swung_offset(n, ..)` never accumulate drift.
+
+use crate::args::Args;
+use std::time::Duration;
+
+pub const MAX_SWING: f64 = 0.75;
+
+/// Reads `--swing`, defaulting to straight time.
+pub fn parse_swing(args: &Args) -> Result<f64, String> {
+  let swing: f64 = args.parse_or("--swing", 0.0)?;
+  if !(0.0..=MAX_SWING).contains(&swing) {
+    return Err(format!("--swing must be in 0.0-{}", MAX_SWING));
+  }
+  Ok(swing)
+}
+
+/// When the `n`th event (counting from 0) falls, relative to the first.
+pub fn swung_offset(n: u64, interval: Duration, swing: f64) -> Duration {
+  let pairs: Duration = Duration::from_secs_f64(interval.as_secs_f64() * 2.0 * (n / 2) as f64);
+  if n.is_multiple_of(2) {
+    pairs
+  } else {
+    pairs + interval.mul_f64(1.0 + swing)
+  }
+}
+
+/// The point on the swung grid closest to `offset`.
+pub fn nearest_swung(offset: Duration, interval: Duration, swing: f64) -> Duration {
+  let pair: u64 = (offset.as_secs_f64() / (2.0 * interval.as_secs_f64())) as u64;
+  (2 * pair..=2 * pair + 2)
+    .map(|n| swung_offset(n, interval, swing))
+    .min_by_key(|point| point.abs_diff(offset))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn straight_time_is_even() {
+    let interval: Duration = Duration::from_millis(100);
+    let offsets: Vec<Duration> = (0..4).map(|n| swung_offset(n, interval, 0.0)).collect();
+    assert_eq!(offsets, [0, 100, 200, 300].map(Duration::from_millis));
+  }
+
+  #[test]
+  fn swing_keeps_pairs_the_same_length() {
+    let interval: Duration = Duration::from_millis(100);
+    let offsets: Vec<Duration> = (0..5).map(|n| swung_offset(n, interval, 0.5)).collect();
+    assert_eq!(offsets, [0, 150, 200, 350, 400].map(Duration::from_millis));
+  }
+
+  #[test]
+  fn snapping_to_the_swung_grid() {
+    let interval: Duration = Duration::from_millis(100);
+    let ms = Duration::from_millis;
+    assert_eq!(nearest_swung(ms(40), interval, 0.0), ms(0));
+    assert_eq!(nearest_swung(ms(60), interval, 0.0), ms(100));
+    assert_eq!(nearest_swung(ms(110), interval, 0.5), ms(150));
+    assert_eq!(nearest_swung(ms(180), interval, 0.5), ms(200));
+    assert_eq!(nearest_swung(ms(390), interval, 0.5), ms(400));
+  }
+
+  #[test]
+  fn swing_is_validated() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    assert_eq!(parse_swing(&args(&[])), Ok(0.0));
+    assert_eq!(parse_swing(&args(&["--swing", "0.5"])), Ok(0.5));
+    assert!(parse_swing(&args(&["--swing", "0.8"])).is_err());
+    assert!(parse_swing(&args(&["--swing", "-0.1"])).is_err());
+  }
+}
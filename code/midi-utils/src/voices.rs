@@ -0,0 +1,141 @@
+//! Spreading notes across output channels.
+//!
+//! When one input note becomes several output notes, or lands on a
+//! channel of its own, expression has to follow it there:
+//! `fan_out` copies a channel-wide message onto each output channel,
+//! and `PressureSummer` turns poly aftertouch into channel pressure
+//! for synths that only listen to the latter.
+
+use crate::CHANNEL_PRESSURE;
+use std::collections::HashMap;
+
+/// Hands out channels from a list in rotation, one per sounding note,
+/// and remembers each note's channel so its note-off goes to the same place.
+/// Notes are keyed by (input channel, note).
+pub struct ChannelAllocator {
+  channels: Vec<u8>,
+  next: usize, // index into `channels`
+  assigned: HashMap<(u8, u8), u8>,
+}
+
+impl ChannelAllocator {
+  /// `channels` must not be empty.
+  pub fn new(channels: Vec<u8>) -> Self {
+    assert!(!channels.is_empty(), "ChannelAllocator needs at least one channel");
+    ChannelAllocator { channels, next: 0, assigned: HashMap::new() }
+  }
+
+  /// The channel for a new note. A note struck again before its release
+  /// keeps the channel it already has.
+  pub fn note_on(&mut self, key: (u8, u8)) -> u8 {
+    if let Some(&channel) = self.assigned.get(&key) {
+      return channel;
+    }
+    let channel: u8 = self.channels[self.next];
+    self.next = (self.next + 1) % self.channels.len();
+    self.assigned.insert(key, channel);
+    channel
+  }
+
+  /// The channel a note was given, forgetting it.
+  pub fn note_off(&mut self, key: (u8, u8)) -> Option<u8> {
+    self.assigned.remove(&key)
+  }
+
+  /// The channel a sounding note was given.
+  pub fn channel_of(&self, key: (u8, u8)) -> Option<u8> {
+    self.assigned.get(&key).copied()
+  }
+
+  pub fn channels(&self) -> &[u8] {
+    &self.channels
+  }
+}
+
+/// Copies a channel-wide message (CC, program change, channel pressure,
+/// pitch bend) onto each of `channels`, so it reaches every voice.
+pub fn fan_out(message: &[u8], channels: impl IntoIterator<Item = u8>) -> Vec<Vec<u8>> {
+  channels.into_iter()
+    .map(|channel| {
+      let mut msg: Vec<u8> = message.to_vec();
+      msg[0] = (message[0] & 0xF0) | channel;
+      msg
+    })
+    .collect()
+}
+
+/// Turns poly aftertouch on output notes into one channel pressure per
+/// output channel: the hardest press among the notes held there.
+/// Messages go out only when that changes.
+#[derive(Default)]
+pub struct PressureSummer {
+  pressures: HashMap<(u8, u8), u8>, // (output channel, output note) -> pressure
+  sent: HashMap<u8, u8>,            // output channel -> channel pressure last sent
+}
+
+impl PressureSummer {
+  pub fn new() -> Self {
+    PressureSummer::default()
+  }
+
+  /// Poly pressure for one output note.
+  pub fn press(&mut self, channel: u8, note: u8, pressure: u8) -> Option<Vec<u8>> {
+    self.pressures.insert((channel, note), pressure);
+    self.update(channel)
+  }
+
+  /// An output note has ended, so its pressure no longer counts.
+  pub fn release(&mut self, channel: u8, note: u8) -> Option<Vec<u8>> {
+    self.pressures.remove(&(channel, note))?;
+    self.update(channel)
+  }
+
+  fn update(&mut self, channel: u8) -> Option<Vec<u8>> {
+    let pressure: u8 = self.pressures.iter()
+      .filter(|((c, _), _)| *c == channel)
+      .map(|(_, &p)| p)
+      .max()
+      .unwrap_or(0);
+    if self.sent.get(&channel).copied().unwrap_or(0) == pressure {
+      return None;
+    }
+    self.sent.insert(channel, pressure);
+    Some(vec![CHANNEL_PRESSURE | channel, pressure])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fan_out_rewrites_the_channel() {
+    assert_eq!(fan_out(&[0xE2, 0, 64], [0, 5]), vec![vec![0xE0, 0, 64], vec![0xE5, 0, 64]]);
+    assert!(fan_out(&[0xB0, 1, 3], []).is_empty());
+  }
+
+  #[test]
+  fn channel_pressure_follows_the_hardest_press() {
+    let mut summer: PressureSummer = PressureSummer::new();
+    assert_eq!(summer.press(1, 60, 40), Some(vec![0xD1, 40]));
+    assert_eq!(summer.press(1, 64, 90), Some(vec![0xD1, 90]));
+    assert_eq!(summer.press(1, 60, 50), None); // still 90
+    assert_eq!(summer.press(2, 60, 10), Some(vec![0xD2, 10]));
+    assert_eq!(summer.release(1, 64), Some(vec![0xD1, 50]));
+    assert_eq!(summer.release(1, 64), None);
+    assert_eq!(summer.release(1, 60), Some(vec![0xD1, 0]));
+  }
+
+  #[test]
+  fn rotates_and_remembers() {
+    let mut voices: ChannelAllocator = ChannelAllocator::new(vec![2, 3, 5]);
+    assert_eq!(voices.note_on((0, 60)), 2);
+    assert_eq!(voices.note_on((0, 64)), 3);
+    assert_eq!(voices.note_on((0, 60)), 2); // retriggered
+    assert_eq!(voices.note_on((0, 67)), 5);
+    assert_eq!(voices.note_on((0, 72)), 2);
+    assert_eq!(voices.note_off((0, 64)), Some(3));
+    assert_eq!(voices.note_off((0, 64)), None);
+    assert_eq!(voices.channel_of((0, 67)), Some(5));
+  }
+}
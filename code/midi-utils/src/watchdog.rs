@@ -0,0 +1,161 @@
+//! Releasing notes whose note-off never came.
+//!
+//! If a source crashes or is unplugged between a note-on and its
+//! note-off, the synth downstream holds the note forever. A `Watchdog`
+//! watches what goes out, remembers when each note started, and sends
+//! the missing note-off once a note has sounded longer than a limit.
+//!
+//! It is off unless asked for: `--watchdog` turns it on with a limit of
+//! `DEFAULT_MAX_NOTE_SECS`, long enough for most drones, and
+//! `--max-note-secs <s>` sets another limit (and turns it on too).
+//! A note struck again starts its clock again. Binaries that take these
+//! flags list them and point here.
+
+use crate::args::Args;
+use crate::ports::SendMonitor;
+use crate::sink::MidiSink;
+use crate::{get_channel, get_note, is_note_off, is_note_on, note_off};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_NOTE_SECS: f64 = 60.0;
+const IDLE_WAIT: Duration = Duration::from_secs(3600); // when nothing is sounding
+
+pub struct Watchdog {
+  max: Option<Duration>, // None when off
+  sounding: HashMap<(u8, u8), Instant>, // (channel, note) -> when it started
+}
+
+impl Watchdog {
+  pub fn new(max: Option<Duration>) -> Self {
+    Watchdog { max, sounding: HashMap::new() }
+  }
+
+  /// Reads `--watchdog` and `--max-note-secs`.
+  pub fn from_args(args: &Args) -> Result<Self, String> {
+    let max_secs: Option<f64> = match args.parse::<f64>("--max-note-secs")? {
+      Some(secs) if secs <= 0.0 || !secs.is_finite() => {
+        return Err("--max-note-secs must be positive".to_string());
+      }
+      Some(secs) => Some(secs),
+      None if args.flag("--watchdog") => Some(DEFAULT_MAX_NOTE_SECS),
+      None => None,
+    };
+    Ok(Watchdog::new(max_secs.map(Duration::from_secs_f64)))
+  }
+
+  /// Notes a message on its way out.
+  pub fn observe(&mut self, data: &[u8], now: Instant) {
+    if self.max.is_none() {
+      return;
+    }
+    if let (Some(channel), Some(note)) = (get_channel(data), get_note(data)) {
+      if is_note_on(data) {
+        self.sounding.insert((channel, note), now);
+      } else if is_note_off(data) {
+        self.sounding.remove(&(channel, note));
+      }
+    }
+  }
+
+  /// Note-offs for every note that has sounded too long, which are
+  /// then forgotten.
+  pub fn expired(&mut self, now: Instant) -> Vec<Vec<u8>> {
+    let max: Duration = match self.max {
+      Some(max) => max,
+      None => return Vec::new(),
+    };
+    let mut stuck: Vec<(u8, u8)> = self.sounding.iter()
+      .filter(|(_, &start)| now.saturating_duration_since(start) >= max)
+      .map(|(&key, _)| key)
+      .collect();
+    stuck.sort();
+    for key in &stuck {
+      self.sounding.remove(key);
+    }
+    if !stuck.is_empty() {
+      log::warn!("watchdog released {} note(s) held over {:?}", stuck.len(), max);
+    }
+    stuck.into_iter().map(|(channel, note)| note_off(channel, note, 0)).collect()
+  }
+
+  /// How long until the next note could expire.
+  pub fn wait(&self, now: Instant) -> Duration {
+    match self.max {
+      None => IDLE_WAIT,
+      Some(max) => self.sounding.values()
+        .map(|&start| (start + max).saturating_duration_since(now))
+        .min()
+        .unwrap_or(IDLE_WAIT),
+    }
+  }
+}
+
+/// Sends everything from `rx` to `sink` until every sender is gone,
+/// with the watchdog releasing stuck notes along the way.
+/// Binaries run this as their output thread.
+pub fn relay<S: MidiSink>(mut sink: S, rx: mpsc::Receiver<Vec<u8>>, mut watchdog: Watchdog) {
+  let mut monitor: SendMonitor = SendMonitor::new();
+  loop {
+    match rx.recv_timeout(watchdog.wait(Instant::now())) {
+      Ok(data) => {
+        watchdog.observe(&data, Instant::now());
+        monitor.send(&mut sink, &data);
+      }
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return,
+    }
+    for msg in watchdog.expired(Instant::now()) {
+      monitor.send(&mut sink, &msg);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn releases_only_notes_held_too_long() {
+    let start: Instant = Instant::now();
+    let secs = |s: u64| start + Duration::from_secs(s);
+    let mut watchdog: Watchdog = Watchdog::new(Some(Duration::from_secs(10)));
+    watchdog.observe(&[0x90, 60, 100], secs(0));
+    watchdog.observe(&[0x91, 62, 100], secs(0));
+    watchdog.observe(&[0x91, 62, 0], secs(3)); // released normally
+    watchdog.observe(&[0x90, 64, 100], secs(5));
+    assert_eq!(watchdog.wait(secs(1)), Duration::from_secs(9));
+    assert!(watchdog.expired(secs(9)).is_empty());
+    assert_eq!(watchdog.expired(secs(10)), vec![note_off(0, 60, 0)]);
+    assert_eq!(watchdog.wait(secs(10)), Duration::from_secs(5));
+    watchdog.observe(&[0x90, 64, 100], secs(12)); // struck again
+    assert!(watchdog.expired(secs(16)).is_empty());
+    assert_eq!(watchdog.expired(secs(22)), vec![note_off(0, 64, 0)]);
+    assert_eq!(watchdog.wait(secs(22)), IDLE_WAIT);
+  }
+
+  #[test]
+  fn relays_until_the_senders_are_gone() {
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    tx.send(vec![0x90, 60, 100]).unwrap();
+    tx.send(vec![0x80, 60, 0]).unwrap();
+    drop(tx);
+    let mut out: Vec<Vec<u8>> = Vec::new();
+    relay(&mut out, rx, Watchdog::new(None));
+    assert_eq!(out, vec![vec![0x90, 60, 100], vec![0x80, 60, 0]]);
+  }
+
+  #[test]
+  fn off_unless_asked_for() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    let mut off: Watchdog = Watchdog::from_args(&args(&[])).unwrap();
+    off.observe(&[0x90, 60, 100], Instant::now());
+    assert!(off.expired(Instant::now() + Duration::from_secs(1_000_000)).is_empty());
+    assert_eq!(Watchdog::from_args(&args(&["--watchdog"])).unwrap().max,
+               Some(Duration::from_secs(60)));
+    assert_eq!(Watchdog::from_args(&args(&["--max-note-secs", "30"])).unwrap().max,
+               Some(Duration::from_secs(30)));
+    assert!(Watchdog::from_args(&args(&["--max-note-secs", "0"])).is_err());
+  }
+}
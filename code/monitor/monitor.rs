@@ -0,0 +1,385 @@
+//! Monitor - prints every incoming MIDI message in readable form
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin monitor
+//! ```
+//!
+//! Creates a virtual input "midi-in". Connect anything to it
+//! (or pass `--input-port <substring>`, or `--connect-from <substring>`
+//! to have it connected at startup) and each message is printed as
+//! `<timestamp> (+<time since previous>) <description>  [<raw hex>]`.
+//! The timestamp is midir's, in seconds since the port was opened.
+//! Channels are shown 1-16; note names use C4 = 60, unless
+//! `--octave-convention C3` (or `C5`) says otherwise.
+//!
+//! Running status (a data-only message reusing the previous status byte)
+//! is expanded before decoding. Long SysEx messages are abbreviated.
+//!
+//! # Measuring latency
+//!
+//! `--measure-latency` times the trip from an output back to the input
+//! instead. It opens a second port, "monitor-out", and once that is
+//! looped back to "midi-in" (with `aconnect`, directly or through the
+//! binary being measured, such as the sampler or add_echo) and Enter is
+//! pressed, it sends `--trials <n>` short probe notes (default 100),
+//! `--interval-ms <ms>` apart (default 50), and reports how many came
+//! back and the min/avg/max round trip, with a histogram.
+//! Probes are note `--probe-note` (default 60) on channel 16, each
+//! tagged by its velocity; other messages are ignored.
+//! With `--self-test`, "monitor-out" is first sent one inaudible
+//! message, and whether it went through is printed.
+//!
+//! Arrival times are the input callback's timestamps, so delays in
+//! running the callback don't count. Those timestamps run on their own
+//! clock, which is lined up with the one send times are read from by
+//! the probe that was handed over fastest.
+
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::note_names::OctaveConvention;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        wait_for_exit};
+use midi_utils::running_status::RunningStatus;
+use midi_utils::sync::lock;
+use midi_utils::{get_channel, get_note, is_note_on, note_off, note_on};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const SYSEX_BYTES_SHOWN: usize = 16;
+const PROBE_CHANNEL: u8 = 15; // channel 16, out of most setups' way
+const PROBE_TAGS: usize = 127; // velocities 1-127 tell probes apart
+const HISTOGRAM_BINS: usize = 10;
+const HISTOGRAM_WIDTH: usize = 40; // characters in the longest bar
+
+struct MonitorState {
+  running_status: RunningStatus,
+  last_timestamp: Option<u64>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let octaves: OctaveConvention = args.octave_convention()?;
+  if args.flag("--measure-latency") {
+    return measure_latency(&args);
+  }
+  let mut midi_in: MidiInput = MidiInput::new("monitor-in")?;
+  midi_in.ignore(Ignore::None); // we want to see clock and SysEx too
+
+  let state: MonitorState =
+    MonitorState { running_status: RunningStatus::new(), last_timestamp: None };
+  let _conn_in: MidiInputConnection<MonitorState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |timestamp: u64, message: &[u8], state: &mut MonitorState| {
+      let full: Vec<u8> = state.running_status.expand(message);
+      let delta_ms: f64 = state.last_timestamp
+        .map(|last| timestamp.saturating_sub(last) as f64 / 1000.0)
+        .unwrap_or(0.0);
+      state.last_timestamp = Some(timestamp);
+      println!("{:>12.6}s (+{:>8.1}ms) {:<40} [{}]",
+               timestamp as f64 / 1_000_000.0,
+               delta_ms,
+               describe(&full, octaves),
+               hex(message));
+    },
+    state,
+  )?;
+
+  println!("MIDI monitor started. Connect a source to 'monitor-in:midi-in'.");
+  auto_connect(&args, Some("monitor-in:midi-in"), None);
+  println!("Press Enter to exit...");
+
+  wait_for_exit(args.value("--input-port"))?;
+
+  Ok(())
+}
+
+/// A probe heard coming back.
+#[derive(Clone, Copy, Debug)]
+struct Arrival {
+  tag: u8,
+  timestamp: u64, // midir's, in microseconds
+  received: Instant, // when the callback ran
+}
+
+fn measure_latency(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+  let trials: usize = args.parse_or("--trials", 100)?;
+  let interval: Duration = Duration::from_millis(args.parse_or("--interval-ms", 50)?);
+  let probe_note: u8 = args.note_or("--probe-note", 60)?;
+  if trials == 0 {
+    return Err("--trials must be at least 1".into());
+  }
+
+  let mut midi_in: MidiInput = MidiInput::new("monitor-in")?;
+  midi_in.ignore(Ignore::None);
+  let midi_out: MidiOutput = MidiOutput::new("monitor-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "monitor-out")?;
+  self_test(args, &mut conn_out, "monitor-out");
+  let arrivals: Arc<Mutex<Vec<Arrival>>> = Arc::new(Mutex::new(Vec::new()));
+  let arrivals_for_callback: Arc<Mutex<Vec<Arrival>>> = Arc::clone(&arrivals);
+  let _conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |timestamp: u64, message: &[u8], _: &mut ()| {
+      let received: Instant = Instant::now();
+      if is_note_on(message) && get_channel(message) == Some(PROBE_CHANNEL)
+        && get_note(message) == Some(probe_note) {
+        lock(&arrivals_for_callback).push(Arrival { tag: message[2], timestamp, received });
+      }
+    },
+    (),
+  )?;
+
+  println!("Latency measurement: connect 'monitor-out:monitor-out' back to");
+  println!("'monitor-in:midi-in', directly or through what's being measured,");
+  println!("then press Enter to send {} probes {:?} apart.", trials, interval);
+  auto_connect(args, Some("monitor-in:midi-in"), Some("monitor-out:monitor-out"));
+  std::io::stdin().read_line(&mut String::new())?;
+
+  let start: Instant = Instant::now();
+  let mut sent: Vec<Instant> = Vec::with_capacity(trials);
+  for trial in 0..trials {
+    thread::sleep((start + interval * trial as u32).saturating_duration_since(Instant::now()));
+    sent.push(Instant::now());
+    let _ = conn_out.send(&note_on(PROBE_CHANNEL, probe_note, probe_tag(trial)));
+    let _ = conn_out.send(&note_off(PROBE_CHANNEL, probe_note, 0));
+  }
+  thread::sleep(interval.max(Duration::from_millis(500))); // for stragglers
+
+  let latencies: Vec<Duration> = round_trips(&sent, &lock(&arrivals));
+  println!("{} of {} probes came back.", latencies.len(), trials);
+  for line in report(&latencies) {
+    println!("{}", line);
+  }
+  Ok(())
+}
+
+/// The velocity that tags probe number `trial`.
+fn probe_tag(trial: usize) -> u8 {
+  (trial % PROBE_TAGS) as u8 + 1
+}
+
+/// How long each probe that came back took, in order of arrival.
+/// A tag is matched to the latest probe sent with it before it arrived.
+fn round_trips(sent: &[Instant], arrivals: &[Arrival]) -> Vec<Duration> {
+  // The timestamp clock's zero, read on the other clock: the callback
+  // runs after its timestamp, so the earliest reading is the closest.
+  let origin: Instant = match arrivals.iter()
+    .filter_map(|a| a.received.checked_sub(Duration::from_micros(a.timestamp)))
+    .min() {
+      Some(origin) => origin,
+      None => return Vec::new(),
+    };
+  let mut used: Vec<bool> = vec![false; sent.len()];
+  let mut latencies: Vec<Duration> = Vec::new();
+  for arrival in arrivals {
+    let arrived: Instant = origin + Duration::from_micros(arrival.timestamp);
+    let trial: Option<usize> = (0..sent.len()).rev()
+      .find(|&i| !used[i] && probe_tag(i) == arrival.tag && sent[i] <= arrived);
+    if let Some(trial) = trial {
+      used[trial] = true;
+      latencies.push(arrived - sent[trial]);
+    }
+  }
+  latencies
+}
+
+/// Min/avg/max and a histogram, as lines to print.
+fn report(latencies: &[Duration]) -> Vec<String> {
+  let (min, max): (Duration, Duration) = match (latencies.iter().min(), latencies.iter().max()) {
+    (Some(&min), Some(&max)) => (min, max),
+    _ => return Vec::new(),
+  };
+  let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+  let avg: Duration = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+  let mut lines: Vec<String> =
+    vec![format!("min {:.3} ms, avg {:.3} ms, max {:.3} ms", ms(min), ms(avg), ms(max))];
+  let width: f64 = (ms(max) - ms(min)) / HISTOGRAM_BINS as f64;
+  let mut counts: [usize; HISTOGRAM_BINS] = [0; HISTOGRAM_BINS];
+  for &latency in latencies {
+    let bin: usize = if width > 0.0 { ((ms(latency) - ms(min)) / width) as usize } else { 0 };
+    counts[bin.min(HISTOGRAM_BINS - 1)] += 1;
+  }
+  let most: usize = counts.iter().copied().max().unwrap_or(1);
+  for (bin, &count) in counts.iter().enumerate() {
+    if width == 0.0 && bin > 0 {
+      break; // every trial took the same time
+    }
+    let low: f64 = ms(min) + width * bin as f64;
+    let bar: String = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(most));
+    lines.push(format!("{:>9.3} ms {:>5} {}", low, count, bar));
+  }
+  lines
+}
+
+fn hex(data: &[u8]) -> String {
+  let shown: Vec<String> = data.iter()
+    .take(SYSEX_BYTES_SHOWN)
+    .map(|b| format!("{:02X}", b))
+    .collect();
+  if data.len() > SYSEX_BYTES_SHOWN {
+    format!("{} ... ({} bytes)", shown.join(" "), data.len())
+  } else {
+    shown.join(" ")
+  }
+}
+
+fn cc_name(controller: u8) -> Option<&'static str> {
+  Some(match controller {
+    0 => "Bank Select",
+    1 => "Mod Wheel",
+    2 => "Breath",
+    4 => "Foot",
+    5 => "Portamento Time",
+    7 => "Volume",
+    10 => "Pan",
+    11 => "Expression",
+    32 => "Bank Select LSB",
+    64 => "Sustain",
+    65 => "Portamento",
+    66 => "Sostenuto",
+    67 => "Soft Pedal",
+    71 => "Resonance",
+    74 => "Cutoff",
+    120 => "All Sound Off",
+    121 => "Reset All Controllers",
+    123 => "All Notes Off",
+    _ => return None,
+  })
+}
+
+fn describe(data: &[u8], octaves: OctaveConvention) -> String {
+  let status: u8 = match data.first() {
+    Some(&s) => s,
+    None => return "(empty)".to_string(),
+  };
+  if status < 0x80 {
+    return "data without status".to_string();
+  }
+  if status >= 0xF0 {
+    return describe_system(data);
+  }
+  let channel: u8 = (status & 0x0F) + 1;
+  let d1: Option<u8> = data.get(1).copied();
+  let d2: Option<u8> = data.get(2).copied();
+  let note_name = |note: u8| octaves.name(note);
+  let body: String = match (status & 0xF0, d1, d2) {
+    (0x90, Some(n), Some(0)) =>
+      format!("Note Off  {:<4} ({}) vel 0", note_name(n), n),
+    (0x90, Some(n), Some(v)) =>
+      format!("Note On   {:<4} ({}) vel {}", note_name(n), n, v),
+    (0x80, Some(n), Some(v)) =>
+      format!("Note Off  {:<4} ({}) vel {}", note_name(n), n, v),
+    (0xA0, Some(n), Some(p)) =>
+      format!("Poly AT   {:<4} ({}) {}", note_name(n), n, p),
+    (0xB0, Some(c), Some(v)) => match cc_name(c) {
+      Some(name) => format!("CC {:>3} {} = {}", c, name, v),
+      None => format!("CC {:>3} = {}", c, v),
+    },
+    (0xC0, Some(p), _) => format!("Program   {}", p),
+    (0xD0, Some(p), _) => format!("Chan AT   {}", p),
+    (0xE0, Some(lsb), Some(msb)) => {
+      let value: i16 = ((msb as i16) << 7 | lsb as i16) - 8192;
+      format!("Pitch Bend {:+}", value)
+    }
+    _ => "truncated message".to_string(),
+  };
+  format!("ch {:>2}  {}", channel, body)
+}
+
+fn describe_system(data: &[u8]) -> String {
+  match data[0] {
+    0xF0 => {
+      let complete: bool = data.last() == Some(&0xF7);
+      format!("SysEx, {} bytes{}", data.len(),
+              if complete { "" } else { " (continues)" })
+    }
+    0xF1 => "MTC Quarter Frame".to_string(),
+    0xF2 => match (data.get(1), data.get(2)) {
+      (Some(&lsb), Some(&msb)) =>
+        format!("Song Position {}", (msb as u16) << 7 | lsb as u16),
+      _ => "Song Position (truncated)".to_string(),
+    },
+    0xF3 => format!("Song Select {}", data.get(1).copied().unwrap_or(0)),
+    0xF6 => "Tune Request".to_string(),
+    0xF7 => "SysEx end".to_string(),
+    0xF8 => "Clock".to_string(),
+    0xFA => "Start".to_string(),
+    0xFB => "Continue".to_string(),
+    0xFC => "Stop".to_string(),
+    0xFE => "Active Sensing".to_string(),
+    0xFF => "Reset".to_string(),
+    s => format!("Undefined system message {:02X}", s),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn describes_channel_messages() {
+    assert_eq!(describe(&[0x90, 60, 100], OctaveConvention::C4), "ch  1  Note On   C4   (60) vel 100");
+    assert_eq!(describe(&[0x93, 21, 0], OctaveConvention::C4), "ch  4  Note Off  A0   (21) vel 0");
+    assert_eq!(describe(&[0xB0, 64, 127], OctaveConvention::C4), "ch  1  CC  64 Sustain = 127");
+    assert_eq!(describe(&[0xE0, 0, 64], OctaveConvention::C4), "ch  1  Pitch Bend +0");
+    assert_eq!(describe(&[0xE0, 0, 0], OctaveConvention::C4), "ch  1  Pitch Bend -8192");
+  }
+
+  #[test]
+  fn note_names_follow_the_octave_convention() {
+    assert_eq!(describe(&[0x90, 60, 100], OctaveConvention::C3), "ch  1  Note On   C3   (60) vel 100");
+    assert_eq!(describe(&[0x80, 0, 0], OctaveConvention::C4), "ch  1  Note Off  C-1  (0) vel 0");
+    assert_eq!(describe(&[0x80, 127, 0], OctaveConvention::C5), "ch  1  Note Off  G10  (127) vel 0");
+  }
+
+  #[test]
+  fn round_trips_match_probes_by_tag() {
+    let t0: Instant = Instant::now();
+    let ms = |ms: u64| t0 + Duration::from_millis(ms);
+    let sent: Vec<Instant> = vec![ms(0), ms(50), ms(100)];
+    // Timestamps count from t0: the first callback ran on time, the
+    // second 5 ms after its timestamp. Probe 1 was lost.
+    let arrivals: Vec<Arrival> = vec![
+      Arrival { tag: 1, timestamp: 2_000, received: ms(2) },
+      Arrival { tag: 3, timestamp: 105_000, received: ms(110) },
+    ];
+    assert_eq!(round_trips(&sent, &arrivals),
+               vec![Duration::from_millis(2), Duration::from_millis(5)]);
+    assert!(round_trips(&sent, &[]).is_empty());
+    assert_eq!(probe_tag(126), 127);
+    assert_eq!(probe_tag(127), 1);
+  }
+
+  #[test]
+  fn report_has_a_summary_and_histogram() {
+    let latencies: Vec<Duration> = [1, 1, 1, 2, 11].map(Duration::from_millis).to_vec();
+    let lines: Vec<String> = report(&latencies);
+    assert_eq!(lines[0], "min 1.000 ms, avg 3.200 ms, max 11.000 ms");
+    assert_eq!(lines.len(), 1 + HISTOGRAM_BINS);
+    assert_eq!(lines[1], format!("    1.000 ms     3 {}", "#".repeat(HISTOGRAM_WIDTH)));
+    assert_eq!(lines[2], format!("    2.000 ms     1 {}", "#".repeat(14)));
+    assert!(lines[3].ends_with("    0 "));
+    assert!(lines[10].ends_with("    1 ##############"));
+    assert_eq!(report(&[Duration::from_millis(2); 3]).len(), 2);
+    assert!(report(&[]).is_empty());
+  }
+
+  #[test]
+  fn long_sysex_is_abbreviated() {
+    let sysex: Vec<u8> = [vec![0xF0], vec![0x01; 30], vec![0xF7]].concat();
+    assert_eq!(describe(&sysex, OctaveConvention::C4), "SysEx, 32 bytes");
+    assert!(hex(&sysex).ends_with("... (32 bytes)"));
+  }
+}
@@ -0,0 +1,187 @@
+//! Mono - makes any playing monophonic
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin mono -- --priority last --legato
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "mono-out".
+//! However many keys are held, only one note sounds: the most recently
+//! pressed (`--priority last`, the default), the lowest or the highest.
+//! Releasing it falls back to whichever held key wins next, striking it
+//! again with its original velocity. A note-off for the sounding key
+//! keeps its release velocity; notes cut off by another key get 0.
+//!
+//! Normally the old note is released before the new one starts.
+//! With `--legato` the new note starts first and the old one is released
+//! just after, so a mono synth in legato mode glides instead of
+//! retriggering. Non-note messages pass straight through.
+//!
+//! Flags:
+//! - `--priority last|low|high` (default last)
+//! - `--legato`
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::note_stack::{Key, NoteStack, Priority};
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, release_velocity};
+use std::sync::mpsc;
+use std::thread;
+
+struct MonoState {
+  legato: bool,
+  held: NoteStack,
+  sounding: Option<Key>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let priority: Priority = args.parse_or("--priority", Priority::Last)?;
+  let legato: bool = args.flag("--legato");
+
+  let midi_in: MidiInput = MidiInput::new("mono-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("mono-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "mono-out")?;
+  self_test(&args, &mut conn_out, "mono-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<MonoState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut MonoState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    MonoState { legato, held: NoteStack::new(priority), sounding: None },
+  )?;
+
+  println!("Mono started! Priority: {:?}{}", priority, if legato { ", legato" } else { "" });
+  println!("Ports: 'mono-in:midi-in' (input), 'mono-out:mono-out' (output)");
+  auto_connect(&args, Some("mono-in:midi-in"), Some("mono-out:mono-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn transform_message(state: &mut MonoState, message: &[u8]) -> Vec<Vec<u8>> {
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (channel, note): (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) => (c, n),
+    _ => return vec![message.to_vec()],
+  };
+  if is_note_on(message) {
+    state.held.press(Key { channel, note, velocity: message[2] });
+  } else if is_note_off(message) {
+    state.held.release(channel, note);
+  } else {
+    return vec![];
+  }
+  let wanted: Option<Key> = state.held.winner();
+  let sounding: Option<Key> = state.sounding;
+  state.sounding = wanted;
+  // A note let go by its own key keeps that key's release velocity.
+  let off = |k: Key| {
+    let velocity: u8 = if (k.channel, k.note) == (channel, note) { release_velocity(message) } else { 0 };
+    note_off(k.channel, k.note, velocity)
+  };
+  let on = |k: Key| note_on(k.channel, k.note, k.velocity);
+  match (sounding, wanted) {
+    (Some(old), Some(new)) if old.same_key(&new) => {
+      // Still the same note. Restart it only if it was struck again.
+      if is_note_on(message) && (channel, note) == (new.channel, new.note) {
+        vec![off(old), on(new)]
+      } else {
+        vec![]
+      }
+    }
+    (Some(old), Some(new)) if state.legato => vec![on(new), off(old)],
+    (Some(old), Some(new)) => vec![off(old), on(new)],
+    (Some(old), None) => vec![off(old)],
+    (None, Some(new)) => vec![on(new)],
+    (None, None) => vec![],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(priority: Priority, legato: bool) -> MonoState {
+    MonoState { legato, held: NoteStack::new(priority), sounding: None }
+  }
+
+  #[test]
+  fn last_note_priority_falls_back_on_release() {
+    let mut s: MonoState = state(Priority::Last, false);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 80]), vec![vec![0x90, 60, 80]]);
+    assert_eq!(transform_message(&mut s, &[0x90, 64, 90]),
+               vec![vec![0x80, 60, 0], vec![0x90, 64, 90]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 64, 0]),
+               vec![vec![0x80, 64, 0], vec![0x90, 60, 80]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert!(s.sounding.is_none());
+  }
+
+  #[test]
+  fn only_the_released_key_keeps_its_release_velocity() {
+    let mut s: MonoState = state(Priority::Last, false);
+    transform_message(&mut s, &[0x90, 60, 80]);
+    assert_eq!(transform_message(&mut s, &[0x90, 64, 90]),
+               vec![vec![0x80, 60, 0], vec![0x90, 64, 90]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 64, 33]),
+               vec![vec![0x80, 64, 33], vec![0x90, 60, 80]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 70]), vec![vec![0x80, 60, 70]]);
+  }
+
+  #[test]
+  fn legato_overlaps_instead_of_retriggering() {
+    let mut s: MonoState = state(Priority::Last, true);
+    transform_message(&mut s, &[0x90, 60, 80]);
+    assert_eq!(transform_message(&mut s, &[0x90, 62, 80]),
+               vec![vec![0x90, 62, 80], vec![0x80, 60, 0]]);
+  }
+
+  #[test]
+  fn low_and_high_priority() {
+    let mut low: MonoState = state(Priority::Low, false);
+    transform_message(&mut low, &[0x90, 60, 80]);
+    assert!(transform_message(&mut low, &[0x90, 67, 80]).is_empty()); // 60 still lowest
+    assert!(transform_message(&mut low, &[0x80, 67, 0]).is_empty());
+    let mut high: MonoState = state(Priority::High, false);
+    transform_message(&mut high, &[0x90, 60, 80]);
+    assert_eq!(transform_message(&mut high, &[0x90, 67, 80]),
+               vec![vec![0x80, 60, 0], vec![0x90, 67, 80]]);
+    assert!(transform_message(&mut high, &[0x80, 60, 0]).is_empty()); // not sounding
+    assert_eq!(transform_message(&mut high, &[0x90, 67, 99]),
+               vec![vec![0x80, 67, 0], vec![0x90, 67, 99]]);
+  }
+}
@@ -0,0 +1,175 @@
+//! Note2cc - turns chosen keys into controller switches
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin note2cc -- --map 36:20 --map 38:21:toggle
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "note2cc-out".
+//! A mapped key sends its CC on the key's channel instead of a note.
+//! Momentary mappings (the default) send 127 on press and 0 on release;
+//! `toggle` mappings flip between 127 and 0 on each press and ignore
+//! releases. Toggles start off, and each channel keeps its own.
+//! Other notes and all other messages pass straight through.
+//!
+//! Flags:
+//! - `--map <note>:<cc>[:momentary|:toggle]`: repeatable, at least one
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{control_change, get_channel, get_note, is_note_off, is_note_on};
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Mapping {
+  note: u8,
+  cc: u8,
+  toggle: bool,
+}
+
+struct Note2CcState {
+  mappings: Vec<Mapping>,
+  toggled_on: HashSet<(u8, u8)>, // (channel, cc)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let mappings: Vec<Mapping> = args.values("--map").iter()
+    .map(|m| parse_mapping(m))
+    .collect::<Result<Vec<Mapping>, String>>()?;
+  if mappings.is_empty() {
+    return Err("give at least one --map <note>:<cc>[:toggle]".into());
+  }
+
+  let midi_in: MidiInput = MidiInput::new("note2cc-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("note2cc-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "note2cc-out")?;
+  self_test(&args, &mut conn_out, "note2cc-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Note to CC started!");
+  for m in &mappings {
+    println!("  - note {} -> CC {}{}", m.note, m.cc, if m.toggle { ", toggle" } else { "" });
+  }
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<Note2CcState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut Note2CcState| {
+      if let Some(msg) = transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    Note2CcState { mappings, toggled_on: HashSet::new() },
+  )?;
+
+  println!("Ports: 'note2cc-in:midi-in' (input), 'note2cc-out:note2cc-out' (output)");
+
+  auto_connect(&args, Some("note2cc-in:midi-in"), Some("note2cc-out:note2cc-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_mapping(text: &str) -> Result<Mapping, String> {
+  let parts: Vec<&str> = text.split(':').collect();
+  if !(2..=3).contains(&parts.len()) {
+    return Err(format!("bad mapping '{}', expected <note>:<cc>[:toggle]", text));
+  }
+  let note: u8 = parse_value("--map", parts[0])?;
+  let cc: u8 = parse_value("--map", parts[1])?;
+  if note > 127 || cc > 127 {
+    return Err(format!("note and CC must be in 0-127 in '{}'", text));
+  }
+  let toggle: bool = match parts.get(2) {
+    None | Some(&"momentary") => false,
+    Some(&"toggle") => true,
+    Some(other) => return Err(format!("bad mode '{}', expected momentary or toggle", other)),
+  };
+  Ok(Mapping { note, cc, toggle })
+}
+
+fn transform_message(state: &mut Note2CcState, message: &[u8]) -> Option<Vec<u8>> {
+  let (channel, note): (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) if message.len() >= 3 => (c, n),
+    _ => return Some(message.to_vec()),
+  };
+  let mapping: Mapping = match state.mappings.iter().find(|m| m.note == note) {
+    Some(&m) => m,
+    None => return Some(message.to_vec()),
+  };
+  let on: bool = if mapping.toggle {
+    if !is_note_on(message) {
+      return None;
+    }
+    let key: (u8, u8) = (channel, mapping.cc);
+    if !state.toggled_on.remove(&key) {
+      state.toggled_on.insert(key);
+      true
+    } else {
+      false
+    }
+  } else {
+    !is_note_off(message)
+  };
+  Some(control_change(channel, mapping.cc, if on { 127 } else { 0 }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> Note2CcState {
+    Note2CcState {
+      mappings: vec![parse_mapping("36:20").unwrap(), parse_mapping("38:21:toggle").unwrap()],
+      toggled_on: HashSet::new(),
+    }
+  }
+
+  #[test]
+  fn momentary_follows_the_key() {
+    let mut s: Note2CcState = state();
+    assert_eq!(transform_message(&mut s, &[0x99, 36, 100]), Some(vec![0xB9, 20, 127]));
+    assert_eq!(transform_message(&mut s, &[0x99, 36, 0]), Some(vec![0xB9, 20, 0]));
+    assert_eq!(transform_message(&mut s, &[0x89, 36, 64]), Some(vec![0xB9, 20, 0]));
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 100]), Some(vec![0x90, 60, 100]));
+    assert_eq!(transform_message(&mut s, &[0xB0, 20, 5]), Some(vec![0xB0, 20, 5]));
+  }
+
+  #[test]
+  fn toggle_flips_on_each_press() {
+    let mut s: Note2CcState = state();
+    assert_eq!(transform_message(&mut s, &[0x90, 38, 100]), Some(vec![0xB0, 21, 127]));
+    assert_eq!(transform_message(&mut s, &[0x80, 38, 0]), None);
+    assert_eq!(transform_message(&mut s, &[0x91, 38, 100]), Some(vec![0xB1, 21, 127]));
+    assert_eq!(transform_message(&mut s, &[0x90, 38, 100]), Some(vec![0xB0, 21, 0]));
+    assert!(parse_mapping("38:21:latch").is_err());
+    assert!(parse_mapping("38:200").is_err());
+  }
+}
@@ -0,0 +1,52 @@
+//! Panic - silences everything, then exits
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin panic -- --output-port "FluidSynth"
+//! ```
+//!
+//! Sends all-notes-off (CC 123), all-sound-off (CC 120) and a note-off
+//! for every note, on every channel. For killing stuck notes from a
+//! script or a key binding.
+//!
+//! With `--output-port` it connects, sends and exits at once.
+//! Without it, it creates a virtual output "panic-out" and waits for
+//! Enter, so there's time to connect it (e.g. with aconnect) first.
+//!
+//! Flags:
+//! - `--output-port`, `--connect-to`, `--list-ports`, `--self-test`: see
+//!   `midi_utils::ports`
+
+use midir::{MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_output, self_test};
+use midi_utils::panic_messages;
+use std::io;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+
+  let midi_out: MidiOutput = MidiOutput::new("panic")?;
+  let wanted: Option<&str> = args.value("--output-port");
+  let mut conn: MidiOutputConnection = open_output(midi_out, wanted, "panic-out")?;
+  self_test(&args, &mut conn, "panic-out");
+  auto_connect(&args, None, Some("panic:panic-out"));
+  if wanted.is_none() {
+    println!("Created virtual port 'panic:panic-out'. Connect it, then press Enter...");
+    let mut input: String = String::new();
+    io::stdin().read_line(&mut input)?;
+  }
+
+  let messages: Vec<Vec<u8>> = panic_messages();
+  for msg in &messages {
+    conn.send(msg)?;
+  }
+  println!("Sent {} messages.", messages.len());
+  Ok(())
+}
@@ -0,0 +1,190 @@
+//! Pedal - does the sustain pedal's job for synths that ignore CC 64
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin pedal
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "pedal-out".
+//! While a channel's sustain pedal (CC 64) is at 64 or above, note-offs
+//! on that channel are held back; lifting the pedal sends them all,
+//! each with the release velocity it arrived with.
+//! Pressing a key again while its note is sustained releases the old
+//! note just before striking the new one, so voices don't stack.
+//! The pedal messages themselves aren't passed on, so a synth that does
+//! understand CC 64 won't sustain twice. Exiting releases anything still
+//! sustained. Everything else passes straight through.
+//!
+//! Flags:
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        wait_for_exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_off, is_note_on, note_off, release_velocity,
+                 CONTROL_CHANGE};
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::thread;
+
+const SUSTAIN_CC: u8 = 64;
+
+struct PedalState {
+  pedal_down: [bool; 16],
+  // (channel, note) -> release velocity: released keys still sounding
+  sustained: BTreeMap<(u8, u8), u8>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+
+  let midi_in: MidiInput = MidiInput::new("pedal-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("pedal-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "pedal-out")?;
+  self_test(&args, &mut conn_out, "pedal-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<PedalState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut PedalState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    PedalState { pedal_down: [false; 16], sustained: BTreeMap::new() },
+  )?;
+
+  println!("Software sustain started!");
+  println!("Ports: 'pedal-in:midi-in' (input), 'pedal-out:pedal-out' (output)");
+  auto_connect(&args, Some("pedal-in:midi-in"), Some("pedal-out:pedal-out"));
+  println!("Press Enter to exit...");
+
+  wait_for_exit(args.value("--input-port"))?;
+
+  let (_, mut state): (MidiInput, PedalState) = conn_in.close();
+  for msg in release(&mut state, |_| true) {
+    let _ = tx.send(msg);
+  }
+  drop(tx);
+  let _ = out_thread.join();
+  Ok(())
+}
+
+fn transform_message(state: &mut PedalState, message: &[u8]) -> Vec<Vec<u8>> {
+  let channel: u8 = match get_channel(message) {
+    Some(c) if message.len() >= 3 => c,
+    _ => return vec![message.to_vec()],
+  };
+  if message[0] & 0xF0 == CONTROL_CHANGE && message[1] == SUSTAIN_CC {
+    let down: bool = message[2] >= 64;
+    state.pedal_down[channel as usize] = down;
+    return if down { vec![] } else { release(state, |c| c == channel) };
+  }
+  let note: u8 = match get_note(message) {
+    Some(n) => n,
+    None => return vec![message.to_vec()],
+  };
+  if is_note_on(message) {
+    if let Some(velocity) = state.sustained.remove(&(channel, note)) {
+      return vec![note_off(channel, note, velocity), message.to_vec()];
+    }
+  } else if is_note_off(message) && state.pedal_down[channel as usize] {
+    state.sustained.insert((channel, note), release_velocity(message));
+    return vec![];
+  }
+  vec![message.to_vec()]
+}
+
+/// Note-offs for the sustained notes on the channels `which` picks,
+/// each with the velocity its key was released at.
+fn release(state: &mut PedalState, which: impl Fn(u8) -> bool) -> Vec<Vec<u8>> {
+  let released: Vec<((u8, u8), u8)> = state.sustained.iter()
+    .map(|(&key, &velocity)| (key, velocity))
+    .filter(|&((c, _), _)| which(c))
+    .collect();
+  state.sustained.retain(|&(c, _), _| !which(c));
+  released.into_iter()
+    .map(|((channel, note), velocity)| note_off(channel, note, velocity))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> PedalState {
+    PedalState { pedal_down: [false; 16], sustained: BTreeMap::new() }
+  }
+
+  #[test]
+  fn releases_wait_for_the_pedal() {
+    let mut s: PedalState = state();
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert!(transform_message(&mut s, &[0xB0, 64, 127]).is_empty());
+    transform_message(&mut s, &[0x90, 60, 90]);
+    transform_message(&mut s, &[0x90, 64, 90]);
+    assert!(transform_message(&mut s, &[0x80, 64, 0]).is_empty());
+    assert!(transform_message(&mut s, &[0x90, 60, 0]).is_empty());
+    assert_eq!(transform_message(&mut s, &[0xB0, 64, 0]),
+               vec![vec![0x80, 60, 0], vec![0x80, 64, 0]]);
+    assert_eq!(transform_message(&mut s, &[0xB0, 1, 3]), vec![vec![0xB0, 1, 3]]);
+  }
+
+  #[test]
+  fn repressing_a_sustained_note_restrikes_it() {
+    let mut s: PedalState = state();
+    transform_message(&mut s, &[0xB0, 64, 127]);
+    transform_message(&mut s, &[0x90, 60, 90]);
+    transform_message(&mut s, &[0x80, 60, 0]);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 70]),
+               vec![vec![0x80, 60, 0], vec![0x90, 60, 70]]);
+    // Still held when the pedal lifts, so it keeps sounding.
+    assert!(transform_message(&mut s, &[0xB0, 64, 0]).is_empty());
+  }
+
+  #[test]
+  fn deferred_releases_keep_their_velocity() {
+    let mut s: PedalState = state();
+    transform_message(&mut s, &[0xB0, 64, 127]);
+    transform_message(&mut s, &[0x90, 60, 90]);
+    transform_message(&mut s, &[0x90, 64, 90]);
+    transform_message(&mut s, &[0x80, 60, 40]);
+    transform_message(&mut s, &[0x80, 64, 100]);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 70]),
+               vec![vec![0x80, 60, 40], vec![0x90, 60, 70]]);
+    assert_eq!(transform_message(&mut s, &[0xB0, 64, 0]), vec![vec![0x80, 64, 100]]);
+  }
+
+  #[test]
+  fn pedals_are_per_channel() {
+    let mut s: PedalState = state();
+    transform_message(&mut s, &[0xB1, 64, 127]);
+    transform_message(&mut s, &[0xB2, 64, 127]);
+    transform_message(&mut s, &[0x81, 60, 0]);
+    transform_message(&mut s, &[0x82, 62, 0]);
+    assert_eq!(transform_message(&mut s, &[0x80, 64, 0]), vec![vec![0x80, 64, 0]]);
+    assert_eq!(transform_message(&mut s, &[0xB1, 64, 0]), vec![vec![0x81, 60, 0]]);
+    assert_eq!(release(&mut s, |_| true), vec![vec![0x82, 62, 0]]);
+  }
+}
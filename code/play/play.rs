@@ -0,0 +1,155 @@
+//! Play - sends a Standard MIDI File out a virtual port
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin play -- --file take1.mid --loop
+//! ```
+//!
+//! Creates a virtual output "play-out" and plays the file through it,
+//! following the file's tempo changes. When playback ends, or on Ctrl+C,
+//! every note still sounding gets a note-off, and every channel
+//! gets an all-notes-off (CC 123). Ctrl+C also sends the same full
+//! panic as the `panic` binary.
+//!
+//! Flags:
+//! - `--file <path>`: the file to play (required)
+//! - `--loop`: start over at the end, until Ctrl+C
+//! - `--channel-offset <n>`: added to every channel, wrapping past 16
+//! - `--output-port`, `--connect-to`, `--list-ports`, `--self-test`,
+//!   `--zero-release-velocity`: see `midi_utils::ports`
+
+use midir::{MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_output, self_test, SendMonitor};
+use midi_utils::sink::{MidiSink, ReleaseVelocity};
+use midi_utils::smf::{self, Smf};
+use midi_utils::{all_notes_off, get_channel, get_note, is_note_off, is_note_on, note_off,
+                 panic_messages};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STOP_CHECK_MS: u64 = 3;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let path: &str = args.value("--file").ok_or("--file <path> is required")?;
+  let looping: bool = args.flag("--loop");
+  let channel_offset: i8 = args.parse_or("--channel-offset", 0)?;
+
+  let smf: Smf = smf::read(BufReader::new(File::open(path)?))?;
+  let events: Vec<(Duration, Vec<u8>)> = smf.timed_messages().into_iter()
+    .map(|(micros, data)| (Duration::from_micros(micros),
+                           shift_channel(data, channel_offset)))
+    .collect();
+
+  let midi_out: MidiOutput = MidiOutput::new("play-out")?;
+  let mut conn: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "play-out")?;
+  self_test(&args, &mut conn, "play-out");
+  let mut conn: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn, zero: args.flag("--zero-release-velocity") };
+  auto_connect(&args, None, Some("play-out:play-out"));
+
+  let stop: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+  let stop_for_handler: Arc<AtomicBool> = Arc::clone(&stop);
+  ctrlc::set_handler(move || stop_for_handler.store(true, Ordering::SeqCst))?;
+
+  println!("Playing '{}' ({} events){}. Ctrl+C to stop.",
+           path, events.len(), if looping { ", looping" } else { "" });
+
+  let mut active_notes: HashSet<(u8, u8)> = HashSet::new();
+  let mut monitor: SendMonitor = SendMonitor::new();
+  loop {
+    let finished: bool = play_once(&events, &mut conn, &mut monitor, &stop, &mut active_notes);
+    // A file with no duration would loop as fast as it could send.
+    let zero_length: bool = events.last().is_none_or(|(t, _)| t.is_zero());
+    if !finished || !looping || zero_length {
+      break;
+    }
+  }
+  if monitor.lost() {
+    println!("Stopped: the output isn't taking messages.");
+    return Ok(());
+  }
+  for &(channel, note) in &active_notes {
+    monitor.send(&mut conn, &note_off(channel, note, 0));
+  }
+  // Interrupted mid-file, the synth may be left with anything sounding.
+  let cleanup: Vec<Vec<u8>> = if stop.load(Ordering::SeqCst) {
+    panic_messages()
+  } else {
+    (0..16).map(all_notes_off).collect()
+  };
+  for msg in cleanup {
+    monitor.send(&mut conn, &msg);
+  }
+  println!("Stopped.");
+  Ok(())
+}
+
+/// Adds `offset` to the channel of a channel message, wrapping mod 16.
+fn shift_channel(data: &[u8], offset: i8) -> Vec<u8> {
+  let mut shifted: Vec<u8> = data.to_vec();
+  if let Some(channel) = get_channel(data) {
+    let new_channel: u8 = (channel as i16 + offset as i16).rem_euclid(16) as u8;
+    shifted[0] = (data[0] & 0xF0) | new_channel;
+  }
+  shifted
+}
+
+/// Returns false if interrupted by `stop`, or if the output stops
+/// taking messages.
+fn play_once(
+  events: &[(Duration, Vec<u8>)],
+  conn: &mut impl MidiSink,
+  monitor: &mut SendMonitor,
+  stop: &AtomicBool,
+  active_notes: &mut HashSet<(u8, u8)>,
+) -> bool {
+  let start: Instant = Instant::now();
+  for (offset, data) in events {
+    let target_time: Instant = start + *offset;
+    while Instant::now() < target_time {
+      if stop.load(Ordering::SeqCst) {
+        return false;
+      }
+      let remaining: Duration = target_time - Instant::now();
+      thread::sleep(remaining.min(Duration::from_millis(STOP_CHECK_MS)));
+    }
+    if let (Some(note), Some(channel)) = (get_note(data), get_channel(data)) {
+      if is_note_on(data) {
+        active_notes.insert((channel, note));
+      } else if is_note_off(data) {
+        active_notes.remove(&(channel, note));
+      }
+    }
+    if !monitor.send(conn, data) && monitor.lost() {
+      return false;
+    }
+  }
+  !stop.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn channel_offset_wraps() {
+    assert_eq!(shift_channel(&[0x90, 60, 100], 3), vec![0x93, 60, 100]);
+    assert_eq!(shift_channel(&[0xBF, 64, 0], 2), vec![0xB1, 64, 0]);
+    assert_eq!(shift_channel(&[0xC0, 5], -1), vec![0xCF, 5]);
+    assert_eq!(shift_channel(&[0xF0, 1, 0xF7], 3), vec![0xF0, 1, 0xF7]);
+  }
+}
@@ -0,0 +1,133 @@
+//! Record - writes everything played into it to a Standard MIDI File
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin record -- --out take1.mid
+//! ```
+//!
+//! Creates a virtual input "midi-in" and records from the moment
+//! it starts until Enter is pressed. The file is a single track
+//! (format 0) at 480 ticks per quarter note and 120 BPM;
+//! the tempo only sets the tick size, so playback timing is exact.
+//! Notes still held when recording stops get note-offs at the stop time.
+//!
+//! Flags:
+//! - `--out <path>`: where to write (default recording.mid)
+//! - `--input-port`, `--connect-from`, `--list-ports`: see `midi_utils::ports`
+
+use midir::{MidiInput, MidiInputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, wait_for_exit};
+use midi_utils::smf::{self, Event, TrackEvent, DEFAULT_TEMPO};
+use midi_utils::sync::lock;
+use midi_utils::{get_channel, get_note, is_note_off, is_note_on, note_off};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const TICKS_PER_QUARTER: u16 = 480;
+
+struct Recording {
+  start: Instant,
+  events: Vec<(Duration, Vec<u8>)>,
+  held: HashSet<(u8, u8)>, // (channel, note)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let path: String = args.value("--out").unwrap_or("recording.mid").to_string();
+
+  let midi_in: MidiInput = MidiInput::new("record-in")?;
+  let recording: Arc<Mutex<Recording>> = Arc::new(Mutex::new(Recording {
+    start: Instant::now(),
+    events: Vec::new(),
+    held: HashSet::new(),
+  }));
+  let recording_for_callback: Arc<Mutex<Recording>> = Arc::clone(&recording);
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let mut rec = lock(&recording_for_callback);
+      record_message(&mut rec, message);
+    },
+    (),
+  )?;
+
+  println!("Recording to '{}'. Connect a source to 'record-in:midi-in'.", path);
+  auto_connect(&args, Some("record-in:midi-in"), None);
+  println!("Press Enter to stop and save...");
+
+  wait_for_exit(args.value("--input-port"))?;
+  conn_in.close();
+
+  let mut rec = lock(&recording);
+  let stop: Duration = rec.start.elapsed();
+  close_held_notes(&mut rec, stop);
+  let events: Vec<TrackEvent> = to_track(&rec.events);
+  smf::write_format0(BufWriter::new(File::create(&path)?), TICKS_PER_QUARTER, &events)?;
+  println!("Wrote {} events ({:.1}s) to '{}'", rec.events.len(), stop.as_secs_f64(), path);
+  Ok(())
+}
+
+fn record_message(rec: &mut Recording, message: &[u8]) {
+  if message.is_empty() || message[0] >= 0xF8 {
+    return; // clock and other real-time messages don't belong in a file
+  }
+  if let (Some(note), Some(channel)) = (get_note(message), get_channel(message)) {
+    if is_note_on(message) {
+      rec.held.insert((channel, note));
+    } else if is_note_off(message) {
+      rec.held.remove(&(channel, note));
+    }
+  }
+  let offset: Duration = rec.start.elapsed();
+  rec.events.push((offset, message.to_vec()));
+}
+
+fn close_held_notes(rec: &mut Recording, stop: Duration) {
+  let mut held: Vec<(u8, u8)> = rec.held.drain().collect();
+  held.sort();
+  for (channel, note) in held {
+    rec.events.push((stop, note_off(channel, note, 0)));
+  }
+}
+
+fn to_track(events: &[(Duration, Vec<u8>)]) -> Vec<TrackEvent> {
+  let mut track: Vec<TrackEvent> = vec![TrackEvent { tick: 0, event: Event::Tempo(DEFAULT_TEMPO) }];
+  track.extend(events.iter().map(|(offset, data)| TrackEvent {
+    tick: smf::micros_to_ticks(offset.as_micros() as u64, TICKS_PER_QUARTER, DEFAULT_TEMPO),
+    event: Event::Midi(data.clone()),
+  }));
+  track
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn held_notes_are_closed_at_stop() {
+    let mut rec: Recording =
+      Recording { start: Instant::now(), events: Vec::new(), held: HashSet::new() };
+    record_message(&mut rec, &[0x90, 60, 100]);
+    record_message(&mut rec, &[0x91, 64, 100]);
+    record_message(&mut rec, &[0xF8]);
+    record_message(&mut rec, &[0x90, 60, 0]);
+    let stop: Duration = Duration::from_secs(2);
+    close_held_notes(&mut rec, stop);
+    assert_eq!(rec.events.len(), 4);
+    assert_eq!(rec.events[3], (stop, vec![0x81, 64, 0]));
+    let track: Vec<TrackEvent> = to_track(&rec.events);
+    assert_eq!(track.last().unwrap().tick, 2 * 2 * TICKS_PER_QUARTER as u64);
+  }
+}
@@ -0,0 +1,188 @@
+//! Remap - moves messages from one channel to another
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin remap -- --map 0:9 --map 1:9
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "remap-out".
+//! Each `--map <from>:<to>` sends every channel message arriving on
+//! channel `from` out on channel `to` instead (channels 0-15); only the
+//! channel changes, never the kind of message or its data. Channels with
+//! no mapping pass unchanged, or are dropped with `--default-drop`.
+//! System messages have no channel and always pass.
+//!
+//! Several channels can be merged onto one, as in the example, which
+//! puts two keyboard zones on the drum channel. If they sound the same
+//! note at once, it is released only when the last of them lets go, so
+//! one zone's note-off doesn't cut the other's note short.
+//!
+//! Flags:
+//! - `--map <from>:<to>`: repeatable, at least one
+//! - `--default-drop`: drop messages on channels with no mapping
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_off, is_note_on};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+struct RemapState {
+  map: [Option<u8>; 16], // input channel -> output channel; None if unmapped
+  default_drop: bool,
+  // (output channel, note) -> how many input keys are holding it
+  sounding: HashMap<(u8, u8), u32>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let map: [Option<u8>; 16] = parse_map(&args.values("--map"))?;
+
+  let midi_in: MidiInput = MidiInput::new("remap-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("remap-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "remap-out")?;
+  self_test(&args, &mut conn_out, "remap-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Channel remap started!");
+  for (from, to) in map.iter().enumerate().filter_map(|(from, to)| Some((from, (*to)?))) {
+    println!("  - channel {} -> {}", from, to);
+  }
+  let state: RemapState =
+    RemapState { map, default_drop: args.flag("--default-drop"), sounding: HashMap::new() };
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<RemapState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut RemapState| {
+      if let Some(msg) = transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    state,
+  )?;
+
+  println!("Ports: 'remap-in:midi-in' (input), 'remap-out:remap-out' (output)");
+  auto_connect(&args, Some("remap-in:midi-in"), Some("remap-out:remap-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_map(entries: &[&str]) -> Result<[Option<u8>; 16], String> {
+  if entries.is_empty() {
+    return Err("give at least one --map <from>:<to>".to_string());
+  }
+  let mut map: [Option<u8>; 16] = [None; 16];
+  for entry in entries {
+    let (from, to): (&str, &str) = entry.split_once(':')
+      .ok_or(format!("bad --map '{}', expected <from>:<to>", entry))?;
+    let (from, to): (u8, u8) = (parse_value("--map", from)?, parse_value("--map", to)?);
+    if from > 15 || to > 15 {
+      return Err(format!("bad --map '{}': channels are 0-15", entry));
+    }
+    if map[from as usize].replace(to).is_some() {
+      return Err(format!("channel {} is mapped twice", from));
+    }
+  }
+  Ok(map)
+}
+
+/// The message on its new channel, or None if it's dropped, or if it's
+/// a note-off for a note another input channel still holds.
+fn transform_message(state: &mut RemapState, message: &[u8]) -> Option<Vec<u8>> {
+  let channel: u8 = match get_channel(message) {
+    Some(c) => c,
+    None => return Some(message.to_vec()),
+  };
+  let to: u8 = match state.map[channel as usize] {
+    Some(to) => to,
+    None if state.default_drop => return None,
+    None => channel,
+  };
+  if let Some(note) = get_note(message) {
+    if is_note_on(message) {
+      *state.sounding.entry((to, note)).or_insert(0) += 1;
+    } else if is_note_off(message) {
+      if let Some(count) = state.sounding.get_mut(&(to, note)) {
+        *count -= 1;
+        if *count > 0 {
+          return None;
+        }
+        state.sounding.remove(&(to, note));
+      }
+    }
+  }
+  let mut out: Vec<u8> = message.to_vec();
+  out[0] = (message[0] & 0xF0) | to;
+  Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use midi_utils::harness::{feed, messages};
+
+  fn state(entries: &[&str], default_drop: bool) -> RemapState {
+    RemapState { map: parse_map(entries).unwrap(), default_drop, sounding: HashMap::new() }
+  }
+
+  fn remap(state: &mut RemapState, script: &str) -> Vec<Vec<u8>> {
+    feed(state, &messages(script), |state, message| {
+      transform_message(state, message).into_iter().collect()
+    })
+  }
+
+  #[test]
+  fn only_the_channel_changes() {
+    let mut s: RemapState = state(&["0:9", "2:3"], false);
+    assert_eq!(remap(&mut s, "90 24 64, B2 07 50, E0 00 40, C1 05, F8"),
+               messages("99 24 64, B3 07 50, E9 00 40, C1 05, F8"));
+  }
+
+  #[test]
+  fn unmapped_channels_can_be_dropped() {
+    let mut s: RemapState = state(&["0:9"], true);
+    assert_eq!(remap(&mut s, "90 24 64, 91 24 64, 81 24 00, F8"), messages("99 24 64, F8"));
+  }
+
+  #[test]
+  fn merged_notes_wait_for_the_last_release() {
+    let mut s: RemapState = state(&["0:9", "1:9"], false);
+    assert_eq!(remap(&mut s, "90 24 64, 91 24 50, 80 24 00"), messages("99 24 64, 99 24 50"));
+    assert_eq!(remap(&mut s, "81 24 00, 80 24 00"), messages("89 24 00, 89 24 00"));
+  }
+
+  #[test]
+  fn maps_are_checked() {
+    assert!(parse_map(&[]).is_err());
+    assert!(parse_map(&["0:16"]).is_err());
+    assert!(parse_map(&["0:9", "0:8"]).is_err());
+    assert!(parse_map(&["0-9"]).is_err());
+  }
+}
@@ -0,0 +1,239 @@
+//! Repeat - re-strikes each held note at a steady rate, like a drum roll
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin repeat -- --rate-ms 80 --gate 0.3
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "repeat-out".
+//! Pressing a key plays it at once and then again every `--rate-ms`,
+//! each repeat timed from that key's own press, until it's released.
+//! Releasing a key stops its repeats at once, with a note-off if
+//! one of them is sounding. Non-note messages pass straight through.
+//!
+//! Flags:
+//! - `--rate-ms <ms>`: time between strikes (default 100)
+//! - `--gate <fraction>`: how much of that time each strike lasts,
+//!   above 0 and at most 1 (default 0.5)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const TIMER_CHECK_MS: u64 = 1;
+
+struct HeldNote {
+  velocity: u8,
+  pressed: Instant,
+  strikes: u32, // how many have started, counting the press itself
+  sounding: bool,
+}
+
+struct RepeatState {
+  rate: Duration,
+  gate: f64,
+  held: HashMap<(u8, u8), HeldNote>, // (channel, note)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let rate: Duration = Duration::from_millis(args.parse_or("--rate-ms", 100)?);
+  let gate: f64 = args.parse_or("--gate", 0.5)?;
+  if rate.is_zero() {
+    return Err("--rate-ms must be at least 1".into());
+  }
+  if !(gate > 0.0 && gate <= 1.0) {
+    return Err("--gate must be above 0 and at most 1".into());
+  }
+
+  let midi_in: MidiInput = MidiInput::new("repeat-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("repeat-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "repeat-out")?;
+  self_test(&args, &mut conn_out, "repeat-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let state: Arc<Mutex<RepeatState>> =
+    Arc::new(Mutex::new(RepeatState { rate, gate, held: HashMap::new() }));
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<RepeatState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_timer_thread(state_for_timer, tx_for_timer, running_for_timer)
+  });
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      if !is_note_event(message) {
+        let _ = tx_for_callback.send(message.to_vec());
+        return;
+      }
+      let mut state = lock(&state);
+      for msg in handle_note(&mut state, Instant::now(), message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  println!("Note repeat started! Every {:?}, gate {}", rate, gate);
+  println!("Ports: 'repeat-in:midi-in' (input), 'repeat-out:repeat-out' (output)");
+  auto_connect(&args, Some("repeat-in:midi-in"), Some("repeat-out:repeat-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<RepeatState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+) {
+  while running.load(Ordering::SeqCst) {
+    let due: Vec<Vec<u8>> = {
+      let mut state = lock(&state);
+      due_messages(&mut state, Instant::now())
+    };
+    for msg in due {
+      if tx.send(msg).is_err() {
+        return;
+      }
+    }
+    thread::sleep(Duration::from_millis(TIMER_CHECK_MS));
+  }
+}
+
+/// A press strikes at once and starts repeating; a release stops the
+/// repeats, silencing the note if a repeat of it is sounding.
+fn handle_note(state: &mut RepeatState, now: Instant, message: &[u8]) -> Vec<Vec<u8>> {
+  let key: (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) if message.len() >= 3 => (c, n),
+    _ => return vec![message.to_vec()],
+  };
+  if is_note_on(message) {
+    let old: Option<HeldNote> = state.held.insert(key, HeldNote {
+      velocity: message[2], pressed: now, strikes: 1, sounding: true });
+    let mut out: Vec<Vec<u8>> = vec![];
+    if old.is_some_and(|n| n.sounding) {
+      out.push(note_off(key.0, key.1, 0));
+    }
+    out.push(message.to_vec());
+    out
+  } else if is_note_off(message) {
+    match state.held.remove(&key) {
+      Some(note) if note.sounding => vec![message.to_vec()],
+      _ => vec![],
+    }
+  } else {
+    vec![]
+  }
+}
+
+/// Note-offs for strikes whose gate has closed, and note-ons for
+/// strikes that are due. Strikes missed entirely (if the timer ran
+/// late) are skipped rather than played in a burst.
+fn due_messages(state: &mut RepeatState, now: Instant) -> Vec<Vec<u8>> {
+  let (rate, gate): (Duration, f64) = (state.rate, state.gate);
+  let mut out: Vec<Vec<u8>> = vec![];
+  for (&(channel, note), held) in state.held.iter_mut() {
+    let last_strike: Instant = held.pressed + rate * (held.strikes - 1);
+    if held.sounding && now >= last_strike + rate.mul_f64(gate) {
+      out.push(note_off(channel, note, 0));
+      held.sounding = false;
+    }
+    let next_strike: Instant = held.pressed + rate * held.strikes;
+    if !held.sounding && now >= next_strike {
+      out.push(note_on(channel, note, held.velocity));
+      held.sounding = true;
+      held.strikes = ((now - held.pressed).as_nanos() / rate.as_nanos()) as u32 + 1;
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> RepeatState {
+    RepeatState { rate: Duration::from_millis(100), gate: 0.5, held: HashMap::new() }
+  }
+
+  fn ms(t0: Instant, ms: u64) -> Instant {
+    t0 + Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn held_note_repeats_at_the_rate() {
+    let mut s: RepeatState = state();
+    let t0: Instant = Instant::now();
+    assert_eq!(handle_note(&mut s, t0, &[0x90, 38, 90]), vec![vec![0x90, 38, 90]]);
+    assert!(due_messages(&mut s, ms(t0, 49)).is_empty());
+    assert_eq!(due_messages(&mut s, ms(t0, 50)), vec![vec![0x80, 38, 0]]);
+    assert!(due_messages(&mut s, ms(t0, 99)).is_empty());
+    assert_eq!(due_messages(&mut s, ms(t0, 100)), vec![vec![0x90, 38, 90]]);
+    assert_eq!(due_messages(&mut s, ms(t0, 150)), vec![vec![0x80, 38, 0]]);
+  }
+
+  #[test]
+  fn release_stops_repeats_immediately() {
+    let mut s: RepeatState = state();
+    let t0: Instant = Instant::now();
+    handle_note(&mut s, t0, &[0x90, 38, 90]);
+    due_messages(&mut s, ms(t0, 50));
+    due_messages(&mut s, ms(t0, 100)); // second strike sounding
+    assert_eq!(handle_note(&mut s, ms(t0, 120), &[0x80, 38, 0]), vec![vec![0x80, 38, 0]]);
+    assert!(due_messages(&mut s, ms(t0, 500)).is_empty());
+    // Released between strikes: nothing is sounding, so nothing to send.
+    handle_note(&mut s, t0, &[0x90, 40, 90]);
+    due_messages(&mut s, ms(t0, 50));
+    assert!(handle_note(&mut s, ms(t0, 60), &[0x90, 40, 0]).is_empty());
+  }
+
+  #[test]
+  fn late_timer_skips_missed_strikes() {
+    let mut s: RepeatState = state();
+    let t0: Instant = Instant::now();
+    handle_note(&mut s, t0, &[0x90, 38, 90]);
+    assert_eq!(due_messages(&mut s, ms(t0, 420)),
+               vec![vec![0x80, 38, 0], vec![0x90, 38, 90]]);
+    assert_eq!(due_messages(&mut s, ms(t0, 450)), vec![vec![0x80, 38, 0]]);
+    assert!(due_messages(&mut s, ms(t0, 499)).is_empty());
+    due_messages(&mut s, ms(t0, 10_000)); // long holds count strikes correctly
+    assert_eq!(due_messages(&mut s, ms(t0, 10_050)), vec![vec![0x80, 38, 0]]);
+  }
+}
@@ -0,0 +1,199 @@
+//! Robin - deals notes out to channels in turn
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin robin -- --channels 0,1,2,3
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "robin-out".
+//! Each note-on goes to the next channel in the list, round-robin,
+//! and its note-off (and poly pressure) follow it there. A note struck
+//! again before its release stays on its channel and is released
+//! before being struck, so no voice is left hanging.
+//! Other channel messages (CCs, channel pressure, pitch bend, ...) are
+//! copied to every channel in the list, so the sustain pedal, the mod
+//! wheel and bend reach every voice.
+//! System messages pass straight through.
+//!
+//! Flags:
+//! - `--channels <c,...>`: channels 0-15 to rotate through (default 0,1,2,3)
+//! - `--mono-pressure`: send each note's poly aftertouch as channel pressure
+//!   on its channel (the hardest press there, should notes share one),
+//!   for synths that only respond to channel pressure
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_list, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::voices::{fan_out, ChannelAllocator, PressureSummer};
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_on, is_note_off, note_off, NOTE_ON, POLY_PRESSURE};
+use std::sync::mpsc;
+use std::thread;
+
+struct RobinState {
+  voices: ChannelAllocator,
+  pressure: Option<PressureSummer>, // Some if --mono-pressure
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let channels: Vec<u8> = parse_channels(args.value("--channels").unwrap_or("0,1,2,3"))?;
+
+  let midi_in: MidiInput = MidiInput::new("robin-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("robin-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "robin-out")?;
+  self_test(&args, &mut conn_out, "robin-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Round robin started! Channels: {:?}", channels);
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let state: RobinState = RobinState {
+    voices: ChannelAllocator::new(channels),
+    pressure: args.flag("--mono-pressure").then(PressureSummer::new),
+  };
+  let conn_in: MidiInputConnection<RobinState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut RobinState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    state,
+  )?;
+
+  println!("Ports: 'robin-in:midi-in' (input), 'robin-out:robin-out' (output)");
+
+  auto_connect(&args, Some("robin-in:midi-in"), Some("robin-out:robin-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_channels(text: &str) -> Result<Vec<u8>, String> {
+  let channels: Vec<u8> = parse_list("--channels", text)?;
+  if channels.iter().any(|&c| c > 15) {
+    return Err("--channels must be in 0-15".to_string());
+  }
+  Ok(channels)
+}
+
+fn on_channel(message: &[u8], channel: u8) -> Vec<u8> {
+  let mut msg: Vec<u8> = message.to_vec();
+  msg[0] = (message[0] & 0xF0) | channel;
+  msg
+}
+
+fn transform_message(state: &mut RobinState, message: &[u8]) -> Vec<Vec<u8>> {
+  let voices: &mut ChannelAllocator = &mut state.voices;
+  let channel: u8 = match get_channel(message) {
+    Some(c) if message.len() >= 2 => c,
+    _ => return vec![message.to_vec()],
+  };
+  let status: u8 = message[0] & 0xF0;
+  if let Some(note) = get_note(message).filter(|_| message.len() >= 3) {
+    let key: (u8, u8) = (channel, note);
+    if is_note_on(message) {
+      let retriggered: Option<u8> = voices.channel_of(key);
+      let out: u8 = voices.note_on(key);
+      return match retriggered {
+        Some(_) => vec![note_off(out, note, 0), on_channel(message, out)],
+        None => vec![on_channel(message, out)],
+      };
+    }
+    if is_note_off(message) {
+      let out: u8 = match voices.note_off(key) {
+        Some(out) => out,
+        None => return vec![],
+      };
+      let pressure: Option<Vec<u8>> = state.pressure.as_mut().and_then(|p| p.release(out, note));
+      return std::iter::once(on_channel(message, out)).chain(pressure).collect();
+    }
+  }
+  if status == POLY_PRESSURE && message.len() >= 3 {
+    let (note, pressure): (u8, u8) = (message[1], message[2]);
+    return match (voices.channel_of((channel, note)), &mut state.pressure) {
+      (None, _) => vec![],
+      (Some(out), None) => vec![on_channel(message, out)],
+      (Some(out), Some(summer)) => summer.press(out, note, pressure).into_iter().collect(),
+    };
+  }
+  if status == NOTE_ON || status == POLY_PRESSURE {
+    return vec![]; // truncated
+  }
+  fan_out(message, voices.channels().iter().copied())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn robin(channels: &[u8]) -> RobinState {
+    RobinState { voices: ChannelAllocator::new(channels.to_vec()), pressure: None }
+  }
+
+  #[test]
+  fn notes_rotate_and_offs_follow() {
+    let mut state: RobinState = robin(&[0, 1, 2]);
+    assert_eq!(transform_message(&mut state, &[0x90, 60, 100]), vec![vec![0x90, 60, 100]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 64, 100]), vec![vec![0x91, 64, 100]]);
+    assert_eq!(transform_message(&mut state, &[0x80, 60, 0]), vec![vec![0x80, 60, 0]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 67, 100]), vec![vec![0x92, 67, 100]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 64, 0]), vec![vec![0x91, 64, 0]]);
+  }
+
+  #[test]
+  fn retrigger_reuses_the_channel() {
+    let mut state: RobinState = robin(&[4, 5]);
+    transform_message(&mut state, &[0x90, 60, 100]);
+    assert_eq!(transform_message(&mut state, &[0x90, 60, 90]),
+               vec![vec![0x84, 60, 0], vec![0x94, 60, 90]]);
+    assert_eq!(transform_message(&mut state, &[0x90, 62, 90]), vec![vec![0x95, 62, 90]]);
+  }
+
+  #[test]
+  fn channel_messages_reach_every_voice() {
+    let mut state: RobinState = robin(&[0, 3]);
+    assert_eq!(transform_message(&mut state, &[0xB0, 64, 127]),
+               vec![vec![0xB0, 64, 127], vec![0xB3, 64, 127]]);
+    transform_message(&mut state, &[0x90, 60, 100]);
+    transform_message(&mut state, &[0x90, 62, 100]);
+    assert_eq!(transform_message(&mut state, &[0xA0, 62, 50]), vec![vec![0xA3, 62, 50]]);
+    assert_eq!(transform_message(&mut state, &[0xF8]), vec![vec![0xF8]]);
+    assert!(parse_channels("0,16").is_err());
+  }
+
+  #[test]
+  fn mono_pressure_goes_to_the_note_s_channel() {
+    let mut state: RobinState = RobinState { pressure: Some(PressureSummer::new()), ..robin(&[0, 1]) };
+    transform_message(&mut state, &[0x90, 60, 100]);
+    transform_message(&mut state, &[0x90, 64, 100]);
+    assert_eq!(transform_message(&mut state, &[0xA0, 64, 70]), vec![vec![0xD1, 70]]);
+    assert_eq!(transform_message(&mut state, &[0xA0, 64, 70]), Vec::<Vec<u8>>::new());
+    assert_eq!(transform_message(&mut state, &[0x80, 64, 0]),
+               vec![vec![0x81, 64, 0], vec![0xD1, 0]]);
+    assert_eq!(transform_message(&mut state, &[0xD0, 20]), vec![vec![0xD0, 20], vec![0xD1, 20]]);
+  }
+}
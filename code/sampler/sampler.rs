@@ -10,63 +10,725 @@
 //! - "immediate-out": Pass-through for all normal notes
 //! - "sample-out": Plays back recorded loop
 //!
+//! Everything that isn't a control key passes through and is recorded,
+//! whatever its length: program changes, SysEx and so on are stored
+//! and replayed whole.
+//! Pitch bend and pedals are replayed as recorded, but a loop that ends
+//! bent or with a pedal down would carry that into its next pass, or
+//! past its stop. So each pass starts with any bend the loop made put
+//! back to center and any pedal it held (sustain, sostenuto, soft)
+//! lifted, and stopping does the same.
+//!
+//! Port flags:
+//! - `--sample-port <substring>`: play the loop to that existing port
+//!   instead of a virtual "sample-out", as `--output-port` does for the
+//!   pass-through
+//! - `--extra-input <substring>`, repeatable: connects another existing
+//!   port as well, for a second controller; everything from the inputs
+//!   is merged, in the order it reaches the sampler, and plays, records
+//!   and works the special keys alike. Extra inputs are timed by when
+//!   their messages are handled rather than by timestamp, so they may be
+//!   a little less exact, and only `--input-port` is watched for going away.
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`: see `midi_utils::ports`
+//! - `--zero-release-velocity`: see `midi_utils::sink::ReleaseVelocity`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+//!
 //! Special keys (not passed through):
-//! - Bb7 (note 106): Stop - ends loop, silences hanging notes, stops recording if it's going
+//! - Bb7 (note 106): Stop - ends loop, silences hanging notes, stops recording if it's going.
+//!   It also sends all-notes-off and a note-off for every note on all 16 channels
+//!   of both outputs, as a panic button; `--no-panic` limits it to the loop's own notes.
 //! - B7 (note 107): Record - starts/stops recording
 //! - C8 (note 108): Trigger - stops recording (if active) and starts looping
+//! - A7 (note 105): Pause - freezes the loop where it is, releasing its notes;
+//!   pressing it again resumes from the same point, re-striking the notes
+//!   that were sounding. Stop and trigger also clear a pause.
+//! - G7 (note 103): Clear - stops the loop and throws the clip away (abandoning
+//!   a recording in progress), so trigger plays nothing until the next take.
+//! - F7 (note 101): Undo - brings back the clip from before the last
+//!   recording or clear; pressing it again swaps back. During a recording
+//!   it abandons the take and restores the clip it replaced. The restored
+//!   clip plays from the next trigger.
+//!
+//! For keyboards without those keys, `--stop-note`, `--record-note`,
+//! `--trigger-note`, `--pause-note`, `--clear-note` and `--undo-note` take
+//! other notes, as numbers (`108`) or names (`C8`, `A#7`). Names take
+//! C4 = 60 unless `--octave-convention C3` (or `C5`) says otherwise.
+//!
+//! With `--mute-base <note>`, the 16 keys from that note up toggle muting
+//! of loop channels 0-15. Muting a channel
+//! silences its notes at once; unmuting lets it back in at the top of
+//! the next pass, so no note starts halfway.
+//!
+//! Between events, a playing loop looks for stop, pause and mute every
+//! `--stop-responsiveness-ms` (1-1000, default 3), which is also the
+//! longest they can take to act. A loop following MIDI clock reads the
+//! clock that often too, and a quantized trigger checks for a cancel. Finer suits tight live use; coarser
+//! wakes the loop less often, saving CPU on a slow machine.
+//!
+//! # Lookback
+//!
+//! Hitting record a little late still catches the downbeat: the most
+//! recent note-on from the last `--lookback-ms` (default 50, 0 disables)
+//! before the record key becomes offset zero. Note events that came after
+//! it in that window are kept at their offsets from it; anything earlier
+//! is dropped, so of several notes in the window only the last one
+//! played and what follows it make it into the clip.
+//!
+//! `--record-mode` chooses how a take begins:
+//! - `lookback` (the default): as above.
+//! - `immediate`: at the record key, with no lookback.
+//! - `wait-for-note`: the record key only arms recording, and the
+//!   first note-on after it becomes offset zero, however long that
+//!   takes, so silence before playing starts isn't recorded. Anything
+//!   else arriving before it passes through but isn't recorded. Both the
+//!   arming and the start of capture are printed; stopping while still
+//!   armed records nothing. It can't be combined with a count-in.
+//!
+//! # Tapping out a loop
+//!
+//! With `--tap-loop`, the trigger key both records and plays: the first
+//! tap marks the loop's downbeat and starts recording there, and the
+//! second marks its end and starts the loop at once, however much
+//! silence came before it, with no `--trigger-quantize` wait. The loop
+//! is exactly as long as the time between the taps: the metronome
+//! doesn't round it and `--loop-bars` and `--trim` don't apply.
+//! The record key works as usual meanwhile, and stopping a tapped take
+//! any other way (record, stop) also takes the tapped length.
+//! Lookback, count-in and `--record-mode` only apply to the record key.
+//!
+//! # Metronome
+//!
+//! With `--click-bpm <bpm>` (1-999), a third port "click-out" clicks on
+//! every beat while recording (note `--click-note`, default 76, on channel
+//! `--click-channel`, 0-15, default 9 = General MIDI drums;
+//! `--click-port` connects it to an existing port). The loop length is then
+//! rounded to the nearest whole number of beats, measured from the start of
+//! recording to the moment it stops, so loops recorded at one tempo line up.
+//! Anything recorded after that length is moved to the end of the loop.
+//! With `--round-to-bars`, it rounds to whole bars instead
+//! (of `--beats-per-bar` beats, default 4).
+//!
+//! `--count-in <beats>` (needs `--click-bpm`) clicks that many beats
+//! after the record key before capture begins; the loop starts on the
+//! beat after the count. Notes played during the count-in pass through
+//! but aren't recorded, and the note-before-record lookback is skipped.
+//! Stop, record or trigger during the count-in abandons the take.
+//!
+//! `--record-hard-threshold <v>` (1-126, needs `--click-bpm`) lets the
+//! record key do both: a press with velocity above v counts in, and a
+//! softer one starts recording at once, as without a count-in. The
+//! count is `--count-in`, or 4 beats if that isn't given. Stopping
+//! works at any velocity.
+//!
+//! `--trigger-quantize bar|beat` (needs `--click-bpm`; default off) holds
+//! a trigger back to the next bar or beat of the last take, counted
+//! from where its recording began, so a loop started while playing
+//! along lands on the grid. A loop already playing carries on until
+//! then; stop, clear or another trigger in the meantime cancels it.
+//! With `--sync-clock` the bars and beats are the incoming clock's.
+//! Before the first take, or while the clock is stopped, triggers start
+//! at once.
+//!
+//! # Quantizing
+//!
+//! `--quantize <n>` (needs `--click-bpm`) splits each beat into n steps
+//! and moves every recorded note-on to the nearest one when recording
+//! stops; its note-off moves by the same amount, so the note keeps its
+//! length. With `--swing <s>` (0-0.75, as in arp) every other step is
+//! late by that fraction of a step, so `--quantize 4 --swing 0.33`
+//! snaps to swung sixteenths. Other events stay where they were played.
+//!
+//! # Following a MIDI clock
+//!
+//! With `--sync-clock` (needs `--click-bpm`, which sets how many beats
+//! the loop is), playback follows the MIDI clock arriving on the input
+//! instead of the computer's own: the loop takes its place on the beat
+//! count since the last Start, so it stays locked to the sender's tempo
+//! and bars. Start or Continue puts it back at the top; Stop silences it
+//! until the next Start. Triggered while the clock runs, it joins at
+//! the clock's current place in the loop. Pause mutes it without losing
+//! its place. Clock messages pass through but aren't recorded.
+//!
+//! # Crossfade
+//!
+//! With `--crossfade-ms <n>`, the loop's channels fade out over the last
+//! n ms of each pass and back in over the first n ms of the next, hiding
+//! the click where the loop's end meets its start. The fade is sent as
+//! CC 7 (channel volume), so it only works on synths that respond to
+//! channel volume while notes sound; volume goes back to full when the
+//! loop stops. Any CC 7 the clip recorded is overridden during the fades.
+//! A fade longer than half the loop is shortened to half.
+//!
+//! # Humanizing
+//!
+//! `--humanize-ms <ms>` moves each of the loop's notes by a random
+//! amount, up to that many ms early or late, afresh on every pass, so
+//! no two passes are quite the same; a note-off moves with its note-on,
+//! keeping the note's length. `--humanize-vel <n>` likewise moves each
+//! note-on's velocity up or down by up to n (staying within 1-127).
+//! The recorded clip itself is never changed, so a save is as recorded.
+//! No event moves ahead of one that came before it, or out of the loop.
+//! `--seed <n>` makes the randomness repeatable. Playback that follows
+//! a MIDI clock isn't humanized.
+//!
+//! # Loop length
+//!
+//! When recording stops, the sampler prints the loop's length in seconds
+//! (measured to the last event, or as the metronome rounded it).
+//! Given a tempo, from `--bpm <bpm>` (1-999) or else `--click-bpm`, it also
+//! prints it in bars and beats, `--beats-per-bar` (default 4) to the bar,
+//! so it's easy to see whether a take came out a clean 4 bars.
+//! If the metronome rounded the length, it says by how much.
+//!
+//! With `--loop-bars <n>`, every take is exactly n bars at that tempo
+//! (as set by `--bpm`, the `bpm` command or `--click-bpm`, which
+//! `--sync-clock` follows), however early the last note ended or the
+//! record key was pressed, so the silence that fills out the last bar is
+//! kept. What was played after the end is dropped, with a warning, except
+//! note-offs for notes that started in time, which move to the end.
+//! It takes the place of the metronome's rounding, and can't be
+//! combined with `--trim`.
+//!
+//! # Trimming
+//!
+//! With `--trim`, a take loses the silence before its first event, and the
+//! loop ends at its last event plus `--rest-ms` (default 0) of rest.
+//! Since everything moves by the same amount, no note is cut off from its
+//! note-off. With the metronome on, only whole beats are cut from the
+//! front and the length rounds up to a whole beat, so the loop stays on
+//! the click's grid.
+//!
+//! # Console
+//!
+//! While it runs, the sampler reads commands typed on stdin, one per line;
+//! the MIDI threads carry on meanwhile. `record`, `play`, `stop`, `clear`
+//! and `undo` do what the control keys do. `save <file.mid>` writes the
+//! clip to a MIDI file that lasts exactly as long as the loop (at the
+//! slowest tempo a MIDI file can hold, ~3.6 BPM, if the clip's is slower),
+//! and `load <file.mid>` makes a file the clip (undo brings back the old
+//! one), to play from the next trigger. `dump <file.txt>` writes the clip as
+//! text instead, for reading or editing by hand: a `loop <ms>` line with
+//! the loop's length, then a line per event, its offset in ms and its
+//! bytes in hex, e.g. `250.000 90 3C 64`. `load` reads that back from
+//! any file whose name ends in `.txt`; blank lines and `#` comments are
+//! skipped, and events needn't be in order. `bpm <bpm>` (1-999) sets the
+//! tempo loop lengths are read out in (the metronome keeps its own). An empty line or
+//! `status` shows what's going on, `help` lists the commands, and `quit`
+//! (or the end of stdin) stops the loop, sends all-notes-off on the
+//! pass-through and exits.
+//!
+//! Set `RUST_LOG=info` to log recording and looping, with timestamps,
+//! on stderr; failed sends are logged at the default level, `warn`.
+//! If sends to the loop output keep failing, the port is probably gone,
+//! so the loop stops rather than playing on into nothing.
+//! Likewise, if the `--input-port` device disappears, the loop stops
+//! and the sampler exits. `--watchdog` and `--max-note-secs` (see
+//! `midi_utils::watchdog`) watch only the pass-through; the loop's output
+//! is left alone, since a held note there was recorded so.
+//!
+//! Recorded timing comes from the timestamps midir attaches to incoming
+//! messages (microseconds), so lock contention in the callback doesn't
+//! add jitter to the loop. Where the backend gives no timestamp (0),
+//! the time the callback ran is used instead.
 
 use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
-use midir::os::unix::{VirtualInput, VirtualOutput};
-use std::collections::HashSet;
-use std::sync::atomic::{AtomicU64, Ordering};
+use midi_utils::args::Args;
+use midi_utils::logging::{self, hex};
+use midi_utils::clock::ClockFollower;
+use midi_utils::ports::{auto_connect, list_ports, open_extra_inputs, open_input, open_output,
+                        run_console, self_test, Exit, SendMonitor};
+use midi_utils::note_names::OctaveConvention;
+use midi_utils::rng::{jitter_velocity, Rng};
+use midi_utils::running_status::RunningStatus;
+use midi_utils::sink::{MidiSink, ReleaseVelocity};
+use midi_utils::smf::{self, Event, Smf, TrackEvent, DEFAULT_TEMPO, MAX_TEMPO};
+use midi_utils::sync::lock;
+use midi_utils::timing::{nearest_swung, parse_swing};
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{all_notes_off, get_channel, get_note, is_note_event, is_note_off, is_note_on,
+                 note_off, note_on, control_change, panic_messages, pitch_bend, release_velocity,
+                 BEND_CENTER, CLOCKS_PER_QUARTER, CONTROL_CHANGE, PITCH_BEND};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
-use std::{io, thread};
+use std::thread;
 
 const TOP_BFLAT: u8 = 106; // Bb7 - stop control
 const TOP_B: u8 = 107; // B7 - record control
 const TOP_C: u8 = 108; // C8 - trigger control
-const LOOKBACK_MS: u64 = 50;
-const TRIGGER_SLEEP_MS: u64 = 3;
+const TOP_A: u8 = 105; // A7 - pause control
+const TOP_G: u8 = 103; // G7 - clear control
+const TOP_F: u8 = 101; // F7 - undo control
+const LOOKBACK_MS: u64 = 50; // default for --lookback-ms
+const RECENT_NOTES_MAX: usize = 64; // bounds the lookback buffer however fast notes come
+const TRIGGER_SLEEP_MS: u64 = 3; // also the default --stop-responsiveness-ms
+const CLICK_NOTE: u8 = 76; // Hi Wood Block in the General MIDI drum map
+const CLICK_CHANNEL: u8 = 9; // General MIDI drums
+const CLICK_VELOCITY: u8 = 100;
+const CLICK_LENGTH_MS: u64 = 20;
+const HARD_COUNT_IN: u32 = 4; // --count-in default with --record-hard-threshold
+const CHANNEL_VOLUME: u8 = 7; // the CC a crossfade ramps
+const CROSSFADE_STEPS: u32 = 16; // volume changes per fade
+const TICKS_PER_QUARTER: u16 = 480; // for saved clips
+const MIN_BPM: f64 = 1.0; // the tempos the flags and `bpm` take
+const MAX_BPM: f64 = 999.0;
 
 struct TimestampedMessage {
   data: Vec<u8>,
   offset: Duration,
 }
 
+/// When an input event arrived.
+#[derive(Clone, Copy, Debug)]
+struct EventTime {
+  micros: u64,      // midir's callback timestamp: microseconds, arbitrary origin, 0 if unknown
+  instant: Instant, // when the callback ran, for when `micros` is 0
+}
+
+impl EventTime {
+  fn now(micros: u64) -> Self {
+    EventTime { micros, instant: Instant::now() }
+  }
+
+  fn plus(&self, duration: Duration) -> EventTime {
+    EventTime {
+      micros: if self.micros == 0 { 0 } else { self.micros + duration.as_micros() as u64 },
+      instant: self.instant + duration,
+    }
+  }
+
+  fn is_before(&self, other: &EventTime) -> bool {
+    if self.micros != 0 && other.micros != 0 {
+      self.micros < other.micros
+    } else {
+      self.instant < other.instant
+    }
+  }
+
+  fn since(&self, earlier: &EventTime) -> Duration {
+    if self.micros != 0 && earlier.micros != 0 && self.micros >= earlier.micros {
+      Duration::from_micros(self.micros - earlier.micros)
+    } else {
+      self.instant.saturating_duration_since(earlier.instant)
+    }
+  }
+}
+
 struct SamplerState {
   recording: bool,
   clip: Vec<TimestampedMessage>,
-  record_start: Option<Instant>,
-  last_normal_note: Option<(Instant, Vec<u8>)>,
+  loop_length: Duration,
+  record_start: Option<EventTime>, // None while recording only if armed, waiting for a note
+  record_mode: RecordMode,
+  armed_at: Option<EventTime>, // when --record-mode wait-for-note was last armed
+  tap_loop: bool,
+  first_tap: Option<EventTime>, // the downbeat of a take --tap-loop began, till the second tap
+  lookback: Duration,
+  trim: Option<Duration>, // Some(rest to leave at the end) if --trim
+  quantize: Option<Grid>,
+  tempo: Option<Duration>, // the beat loop lengths are read out in
+  beats_per_bar: u32,
+  round_to_bars: bool,
+  loop_bars: Option<u32>, // --loop-bars: every take is this many bars of `tempo`
+  crossfade: Duration, // zero for none
+  stop_check: Duration, // how often a playing loop looks for stop, pause and mute
+  humanize: Humanize,
+  rng: Rng, // --seed; each loop started draws its own from it
+  previous: Option<(Vec<TimestampedMessage>, Duration)>, // (clip, loop_length) undo brings back
+  recent_notes: VecDeque<(EventTime, Vec<u8>)>, // note events within `lookback` of the newest
+  click: Option<Click>,
+  record_hard_threshold: Option<u8>, // record presses above this count in; softer ones don't
+  trigger_quantize: TriggerQuantize,
+  grid_origin: Option<Instant>, // beat 1 of the last take, which triggers line up with
 }
 
 impl SamplerState {
-  fn new() -> Self {
+  fn new(click: Option<Click>, lookback: Duration) -> Self {
     SamplerState {
       recording: false,
       clip: Vec::new(),
+      loop_length: Duration::ZERO,
       record_start: None,
-      last_normal_note: None,
+      record_mode: RecordMode::Lookback,
+      armed_at: None,
+      tap_loop: false,
+      first_tap: None,
+      lookback,
+      trim: None,
+      quantize: None,
+      tempo: None,
+      beats_per_bar: 4,
+      round_to_bars: false,
+      loop_bars: None,
+      crossfade: Duration::ZERO,
+      stop_check: Duration::from_millis(TRIGGER_SLEEP_MS),
+      humanize: Humanize::default(),
+      rng: Rng::from_time(),
+      previous: None,
+      recent_notes: VecDeque::new(),
+      click,
+      record_hard_threshold: None,
+      trigger_quantize: TriggerQuantize::Off,
+      grid_origin: None,
+    }
+  }
+
+  fn meter(&self) -> Option<Meter> {
+    self.tempo.map(|beat| Meter { beat, beats_per_bar: self.beats_per_bar })
+  }
+}
+
+/// How far each pass may move the loop's notes, by `--humanize-ms`
+/// and `--humanize-vel`; none by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Humanize {
+  time: Duration, // either way
+  velocity: u8,
+}
+
+impl Humanize {
+  fn is_off(&self) -> bool {
+    self.time.is_zero() && self.velocity == 0
+  }
+}
+
+/// Where `--quantize` moves notes to.
+#[derive(Clone, Copy, Debug)]
+struct Grid {
+  step: Duration,
+  swing: f64,
+}
+
+/// How a take begins, by `--record-mode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RecordMode {
+  Lookback, // at the record key, reaching back for a note just played
+  Immediate, // at the record key
+  WaitForNote, // at the first note-on after the record key
+}
+
+impl FromStr for RecordMode {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "lookback" => Ok(RecordMode::Lookback),
+      "immediate" => Ok(RecordMode::Immediate),
+      "wait-for-note" => Ok(RecordMode::WaitForNote),
+      _ => Err("expected lookback, immediate or wait-for-note".to_string()),
+    }
+  }
+}
+
+/// What `--trigger-quantize` holds a triggered loop back to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TriggerQuantize {
+  Off,
+  Beat,
+  Bar,
+}
+
+impl FromStr for TriggerQuantize {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "off" => Ok(TriggerQuantize::Off),
+      "beat" => Ok(TriggerQuantize::Beat),
+      "bar" => Ok(TriggerQuantize::Bar),
+      _ => Err("expected bar, beat or off".to_string()),
+    }
+  }
+}
+
+/// What a quantized trigger waits for before the loop starts.
+enum TriggerWait {
+  Until(Instant),
+  // The clock passing `ticks` since Start number `starts`, or starting again.
+  Clock { clock: Arc<Mutex<ClockFollower>>, starts: u64, ticks: f64 },
+}
+
+impl TriggerWait {
+  /// Waits its time, looking every `check_every`. Returns false if the
+  /// playback generation moved from `my_gen` meanwhile, which cancels
+  /// the trigger.
+  fn wait(&self, gen: &AtomicU64, my_gen: u64, check_every: Duration) -> bool {
+    loop {
+      if gen.load(Ordering::SeqCst) != my_gen {
+        return false;
+      }
+      let remaining: Duration = match self {
+        TriggerWait::Until(at) => at.saturating_duration_since(Instant::now()),
+        TriggerWait::Clock { clock, starts, ticks } =>
+          match lock(clock).position(Instant::now()) {
+            Some((s, t)) if s != *starts || t >= *ticks => Duration::ZERO,
+            _ => check_every,
+          },
+      };
+      if remaining.is_zero() {
+        return true;
+      }
+      thread::sleep(remaining.min(check_every));
+    }
+  }
+}
+
+/// The tempo and bar length loop lengths are read out in.
+#[derive(Clone, Copy, Debug)]
+struct Meter {
+  beat: Duration,
+  beats_per_bar: u32,
+}
+
+impl Meter {
+  fn bar(&self) -> Duration {
+    self.beat * self.beats_per_bar
+  }
+}
+
+/// The metronome, as seen from the recording logic.
+struct Click {
+  beat: Duration,
+  count_in: u32, // beats clicked before capture begins
+  tx: mpsc::Sender<ClickCommand>,
+}
+
+enum ClickCommand {
+  Start(Instant), // beat 1 falls here
+  Stop,
+}
+
+/// Which keys act as controls instead of being played.
+#[derive(Clone, Copy)]
+struct ControlNotes {
+  stop: u8,
+  record: u8,
+  trigger: u8,
+  pause: u8,
+  clear: u8,
+  undo: u8,
+  mute_base: Option<u8>, // first of the 16 channel-mute keys
+}
+
+impl ControlNotes {
+  fn from_args(args: &Args) -> Result<Self, String> {
+    let controls: ControlNotes = ControlNotes {
+      stop: args.note_or("--stop-note", TOP_BFLAT)?,
+      record: args.note_or("--record-note", TOP_B)?,
+      trigger: args.note_or("--trigger-note", TOP_C)?,
+      pause: args.note_or("--pause-note", TOP_A)?,
+      clear: args.note_or("--clear-note", TOP_G)?,
+      undo: args.note_or("--undo-note", TOP_F)?,
+      mute_base: args.note("--mute-base")?,
+    };
+    let notes: [u8; 6] = [controls.stop, controls.record, controls.trigger, controls.pause,
+                          controls.clear, controls.undo];
+    if notes.iter().enumerate().any(|(i, n)| notes[i + 1..].contains(n)) {
+      return Err("control notes must be distinct".to_string());
     }
+    if let Some(base) = controls.mute_base {
+      if base > 112 {
+        return Err("--mute-base must be at most 112".to_string());
+      }
+      if notes.iter().any(|n| (base..base + 16).contains(n)) {
+        return Err("the --mute-base keys can't include control notes".to_string());
+      }
+    }
+    Ok(controls)
   }
 }
 
 enum Command {
   StartLoop,
   Stop,
+  Panic,
+}
+
+/// An incoming clock that playback follows, and how long a beat of the
+/// recorded clip is.
+struct ClockSync {
+  clock: Arc<Mutex<ClockFollower>>,
+  beat: Duration,
+}
+
+/// What a playing loop watches to know whether to keep going.
+struct LoopControl<'a> {
+  gen: &'a AtomicU64,
+  my_gen: u64,
+  paused: &'a AtomicBool,
+  muted: &'a [AtomicBool; 16], // by channel
+  output_lost: &'a AtomicBool, // set when sends keep failing
+  check_every: Duration, // --stop-responsiveness-ms
+}
+
+impl LoopControl<'_> {
+  fn stopped(&self) -> bool {
+    self.gen.load(Ordering::SeqCst) != self.my_gen
+      || self.output_lost.load(Ordering::SeqCst)
+  }
+}
+
+/// The channels muted for the current pass of a loop. Muting counts
+/// at once; unmuting only from the next pass.
+#[derive(Default)]
+struct PassMutes([bool; 16]);
+
+impl PassMutes {
+  fn start_pass(&mut self, control: &LoopControl) {
+    for (muted, shared) in self.0.iter_mut().zip(control.muted.iter()) {
+      *muted = shared.load(Ordering::SeqCst);
+    }
+  }
+
+  /// Takes in channels muted since the pass began, releasing their notes.
+  fn catch_up(
+    &mut self,
+    control: &LoopControl,
+    sink: &mut impl MidiSink,
+    active_notes: &mut ActiveNotes,
+    releases: &HashMap<(u8, u8), u8>,
+  ) {
+    for channel in 0..16u8 {
+      if !self.0[channel as usize] && control.muted[channel as usize].load(Ordering::SeqCst) {
+        self.0[channel as usize] = true;
+        active_notes.retain(|&key, sounding| {
+          if key.0 == channel {
+            send_offs(sink, key, *sounding, releases);
+          }
+          key.0 != channel
+        });
+      }
+    }
+  }
+
+  fn allows(&self, data: &[u8]) -> bool {
+    get_channel(data).is_none_or(|c| !self.0[c as usize])
+  }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let controls: ControlNotes = ControlNotes::from_args(&args)?;
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let panic_on_stop: bool = !args.flag("--no-panic");
   let midi_in: MidiInput = MidiInput::new("sampler-in")?;
   let midi_out_immediate: MidiOutput = MidiOutput::new("sampler-immediate")?;
   let midi_out_sample: MidiOutput = MidiOutput::new("sampler-sample")?;
 
-  let conn_immediate: MidiOutputConnection =
-    midi_out_immediate.create_virtual("immediate-out")?;
-  let conn_sample: MidiOutputConnection = midi_out_sample.create_virtual("sample-out")?;
+  let mut conn_immediate: MidiOutputConnection =
+    open_output(midi_out_immediate, args.value("--output-port"), "immediate-out")?;
+  self_test(&args, &mut conn_immediate, "immediate-out");
+  let mut conn_sample: MidiOutputConnection =
+    open_output(midi_out_sample, args.value("--sample-port"), "sample-out")?;
+  self_test(&args, &mut conn_sample, "sample-out");
 
-  let state: Arc<Mutex<SamplerState>> = Arc::new(Mutex::new(SamplerState::new()));
+  let lookback: Duration = Duration::from_millis(args.parse_or("--lookback-ms", LOOKBACK_MS)?);
+  let record_hard_threshold: Option<u8> = args.parse("--record-hard-threshold")?;
+  if record_hard_threshold.is_some_and(|t| !(1..=126).contains(&t)) {
+    return Err("--record-hard-threshold must be in 1-126".into());
+  }
+  let count_in: u32 = args.parse_or(
+    "--count-in", if record_hard_threshold.is_some() { HARD_COUNT_IN } else { 0 })?;
+  let record_mode: RecordMode = args.parse_or("--record-mode", RecordMode::Lookback)?;
+  if record_mode == RecordMode::WaitForNote && count_in > 0 {
+    return Err("--record-mode wait-for-note can't be combined with a count-in".into());
+  }
+  let click: Option<Click> = match args.parse::<f64>("--click-bpm")? {
+    None if record_hard_threshold.is_some() => {
+      return Err("--record-hard-threshold needs --click-bpm".into());
+    }
+    None if count_in > 0 => return Err("--count-in needs --click-bpm".into()),
+    None => None,
+    Some(bpm) => {
+      let beat: Duration = beat_at(bpm).ok_or("--click-bpm must be in 1-999")?;
+      let note: u8 = args.note_or("--click-note", CLICK_NOTE)?;
+      let channel: u8 = args.parse_or("--click-channel", CLICK_CHANNEL)?;
+      if channel > 15 {
+        return Err("--click-channel must be in 0-15".into());
+      }
+      let midi_out_click: MidiOutput = MidiOutput::new("sampler-click")?;
+      let mut conn_click: MidiOutputConnection =
+        open_output(midi_out_click, args.value("--click-port"), "click-out")?;
+      self_test(&args, &mut conn_click, "click-out");
+      let (tx, rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+        mpsc::channel();
+      let _click_thread: thread::JoinHandle<()> =
+        thread::spawn(move || run_click_thread(conn_click, rx, beat, note, channel));
+      Some(Click { beat, count_in, tx })
+    }
+  };
+  let click_beat: Option<Duration> = click.as_ref().map(|c| c.beat);
+  let mut sampler_state: SamplerState = SamplerState::new(click, lookback);
+  if args.flag("--trim") {
+    sampler_state.trim = Some(Duration::from_millis(args.parse_or("--rest-ms", 0)?));
+  }
+  let swing: f64 = parse_swing(&args)?;
+  match (args.parse::<u32>("--quantize")?, click_beat) {
+    (None, _) => {}
+    (Some(0), _) => return Err("--quantize must be at least 1".into()),
+    (Some(_), None) => return Err("--quantize needs --click-bpm".into()),
+    (Some(steps), Some(beat)) => sampler_state.quantize = Some(Grid { step: beat / steps, swing }),
+  }
+  sampler_state.crossfade = Duration::from_millis(args.parse_or("--crossfade-ms", 0)?);
+  sampler_state.humanize = Humanize {
+    time: Duration::from_millis(args.parse_or("--humanize-ms", 0)?),
+    velocity: args.parse_or("--humanize-vel", 0)?,
+  };
+  if sampler_state.humanize.velocity > 126 {
+    return Err("--humanize-vel must be at most 126".into());
+  }
+  sampler_state.rng = Rng::from_args(&args)?;
+  let stop_check: u64 = args.parse_or("--stop-responsiveness-ms", TRIGGER_SLEEP_MS)?;
+  if !(1..=1000).contains(&stop_check) {
+    return Err("--stop-responsiveness-ms must be in 1-1000".into());
+  }
+  sampler_state.stop_check = Duration::from_millis(stop_check);
+  let beats_per_bar: u32 = args.parse_or("--beats-per-bar", 4)?;
+  if beats_per_bar == 0 {
+    return Err("--beats-per-bar must be at least 1".into());
+  }
+  let readout_beat: Option<Duration> = match args.parse::<f64>("--bpm")? {
+    Some(bpm) => Some(beat_at(bpm).ok_or("--bpm must be in 1-999")?),
+    None => click_beat,
+  };
+  sampler_state.tempo = readout_beat;
+  sampler_state.beats_per_bar = beats_per_bar;
+  sampler_state.round_to_bars = args.flag("--round-to-bars");
+  sampler_state.record_hard_threshold = record_hard_threshold;
+  sampler_state.record_mode = record_mode;
+  sampler_state.tap_loop = args.flag("--tap-loop");
+  sampler_state.trigger_quantize = args.parse_or("--trigger-quantize", TriggerQuantize::Off)?;
+  if sampler_state.trigger_quantize != TriggerQuantize::Off && click_beat.is_none() {
+    return Err("--trigger-quantize needs --click-bpm".into());
+  }
+  if sampler_state.round_to_bars && click_beat.is_none() {
+    return Err("--round-to-bars needs --click-bpm".into());
+  }
+  sampler_state.loop_bars = args.parse::<u32>("--loop-bars")?;
+  match sampler_state.loop_bars {
+    None => {}
+    Some(0) => return Err("--loop-bars must be at least 1".into()),
+    Some(_) if readout_beat.is_none() => return Err("--loop-bars needs --bpm or --click-bpm".into()),
+    Some(_) if sampler_state.trim.is_some() => return Err("--loop-bars and --trim don't mix".into()),
+    Some(_) => {}
+  }
+  let state: Arc<Mutex<SamplerState>> = Arc::new(Mutex::new(sampler_state));
+  let clock: Option<Arc<Mutex<ClockFollower>>> = match (args.flag("--sync-clock"), click_beat) {
+    (false, _) => None,
+    (true, None) => return Err("--sync-clock needs --click-bpm".into()),
+    (true, Some(_)) => Some(Arc::new(Mutex::new(ClockFollower::new()))),
+  };
+  let clock_for_callback: Option<Arc<Mutex<ClockFollower>>> = clock.clone();
+  let sync: Option<ClockSync> = clock.zip(click_beat)
+    .map(|(clock, beat)| ClockSync { clock, beat });
 
   let (tx_immediate, rx_immediate): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
     mpsc::channel();
@@ -75,167 +737,887 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   let playback_gen: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
 
-  let _immediate_thread: thread::JoinHandle<()> =
-    thread::spawn(move || run_immediate_thread(conn_immediate, rx_immediate));
+  let zero_release: bool = args.flag("--zero-release-velocity");
+  let conn_immediate: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_immediate, zero: zero_release };
+  let conn_sample: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_sample, zero: zero_release };
+  let immediate_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_immediate, rx_immediate, watchdog));
 
   let state_for_sample: Arc<Mutex<SamplerState>> = Arc::clone(&state);
   let gen_for_sample: Arc<AtomicU64> = Arc::clone(&playback_gen);
-  let _sample_thread: thread::JoinHandle<()> = thread::spawn(move || {
-    run_sample_thread(conn_sample, rx_sample, state_for_sample, gen_for_sample)
+  let paused: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+  let paused_for_sample: Arc<AtomicBool> = Arc::clone(&paused);
+  let muted: Arc<[AtomicBool; 16]> = Arc::new(std::array::from_fn(|_| AtomicBool::new(false)));
+  let muted_for_sample: Arc<[AtomicBool; 16]> = Arc::clone(&muted);
+  let sample_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_sample_thread(conn_sample, rx_sample, state_for_sample, gen_for_sample, paused_for_sample,
+                      muted_for_sample, sync)
   });
 
-  let state_for_callback: Arc<Mutex<SamplerState>> = Arc::clone(&state);
-  let gen_for_callback: Arc<AtomicU64> = Arc::clone(&playback_gen);
+  let console: Console = Console {
+    clock: clock_for_callback.clone(),
+    state: Arc::clone(&state),
+    gen: Arc::clone(&playback_gen),
+    tx_sample: tx_sample.clone(),
+    paused: Arc::clone(&paused),
+    muted: Arc::clone(&muted),
+  };
+  let tx_immediate_for_quit: mpsc::Sender<Vec<u8>> = tx_immediate.clone();
+  let input: Arc<Input> = Arc::new(Input {
+    controls,
+    panic_on_stop,
+    clock: clock_for_callback,
+    state: Arc::clone(&state),
+    gen: Arc::clone(&playback_gen),
+    tx_immediate,
+    tx_sample,
+    paused,
+    muted,
+  });
 
-  let _conn_in: MidiInputConnection<()> = midi_in.create_virtual(
+  let input_for_main: Arc<Input> = Arc::clone(&input);
+  let conn_in: MidiInputConnection<RunningStatus> = open_input(
+    midi_in,
+    args.value("--input-port"),
     "midi-in",
-    move |_timestamp: u64, message: &[u8], _: &mut ()| {
-      let data: Vec<u8> = message.to_vec();
-      let note: Option<u8> = get_note(&data);
-      let is_on: bool = is_note_on(&data);
-
-      if let Some(n) = note {
-        if n == TOP_BFLAT && is_on {
-          handle_stop(&state_for_callback, &gen_for_callback, &tx_sample);
-          return;
-        }
+    move |timestamp: u64, message: &[u8], running_status: &mut RunningStatus| {
+      input_for_main.handle(running_status.expand(message), EventTime::now(timestamp));
+    },
+    RunningStatus::new(),
+  )?;
+  // Each connection's timestamps have their own origin, so the extra
+  // inputs go by when their callbacks run instead.
+  let extra_ins: Vec<MidiInputConnection<RunningStatus>> =
+    open_extra_inputs(&args, "sampler-in", "midi-in", || {
+      let input: Arc<Input> = Arc::clone(&input);
+      (move |_timestamp: u64, message: &[u8], running_status: &mut RunningStatus| {
+         input.handle(running_status.expand(message), EventTime::now(0));
+       },
+       RunningStatus::new())
+    })?;
+  drop(input);
 
-        if n == TOP_B && is_on {
-          let mut state: MutexGuard<SamplerState> = state_for_callback.lock().unwrap();
-          handle_record_toggle(&mut state);
-          return;
-        }
+  print_startup_message(&controls, click_beat, args.octave_convention()?);
+  auto_connect(&args, Some("sampler-in:midi-in"), Some("sampler-immediate:immediate-out"));
 
-        if n == TOP_C && is_on {
-          handle_trigger(&state_for_callback, &gen_for_callback, &tx_sample);
-          return;
+  let exit: Exit = run_console(args.value("--input-port"), |line| console.handle_line(line))?;
+  // Stop the loop, which releases its notes, and let it finish.
+  playback_gen.fetch_add(1, Ordering::SeqCst);
+  if exit == Exit::Enter {
+    for msg in (0..16).map(all_notes_off) {
+      let _ = tx_immediate_for_quit.send(msg);
+    }
+  }
+  drop(conn_in); // its callback holds the other senders
+  drop(extra_ins); // and so do theirs
+  drop(console);
+  drop(tx_immediate_for_quit);
+  let _ = sample_thread.join();
+  let _ = immediate_thread.join();
+
+  Ok(())
+}
+
+/// Where the input callback sends what it hears, and the state it acts
+/// on; `handle` is the callback, so tests can play messages into it.
+struct Input {
+  controls: ControlNotes,
+  panic_on_stop: bool,
+  clock: Option<Arc<Mutex<ClockFollower>>>, // Some if --sync-clock
+  state: Arc<Mutex<SamplerState>>,
+  gen: Arc<AtomicU64>,
+  tx_immediate: mpsc::Sender<Vec<u8>>,
+  tx_sample: mpsc::Sender<Command>,
+  paused: Arc<AtomicBool>,
+  muted: Arc<[AtomicBool; 16]>,
+}
+
+impl Input {
+  /// Acts on one complete message (running status already restored).
+  fn handle(&self, data: Vec<u8>, time: EventTime) {
+    let note: Option<u8> = get_note(&data);
+    let is_on: bool = is_note_on(&data);
+
+    if let Some(clock) = &self.clock {
+      if lock(clock).handle(&data, time.instant) {
+        let _ = self.tx_immediate.send(data);
+        return;
+      }
+    }
+
+    if let Some(n) = note {
+      if n == self.controls.stop && is_on {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_stop(&self.state, time, &self.gen, &self.tx_sample);
+        if self.panic_on_stop {
+          for msg in panic_messages() {
+            let _ = self.tx_immediate.send(msg);
+          }
+          let _ = self.tx_sample.send(Command::Panic);
         }
+        return;
       }
 
-      let mut state: MutexGuard<SamplerState> = state_for_callback.lock().unwrap();
-      handle_normal_event(data, &mut state, &tx_immediate);
-    },
-    (),
-  )?;
+      if n == self.controls.record && is_on {
+        let mut state: MutexGuard<SamplerState> = lock(&self.state);
+        handle_record_toggle(&mut state, time, data[2]);
+        return;
+      }
+
+      if n == self.controls.trigger && is_on {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_trigger(&self.state, time, &self.gen, &self.tx_sample, self.clock.as_ref());
+        return;
+      }
 
-  print_startup_message();
+      if n == self.controls.pause && is_on {
+        let was_paused: bool = self.paused.fetch_xor(true, Ordering::SeqCst);
+        println!("[Sampler] {}", if was_paused { "Resumed" } else { "Paused" });
+        return;
+      }
 
-  let mut input: String = String::new();
-  io::stdin().read_line(&mut input)?;
+      if n == self.controls.clear && is_on {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_clear(&self.state, &self.gen, &self.tx_sample);
+        return;
+      }
 
-  Ok(())
+      if n == self.controls.undo && is_on {
+        handle_undo(&mut lock(&self.state));
+        return;
+      }
+
+      if let Some(base) = self.controls.mute_base.filter(|&b| is_on && (b..b + 16).contains(&n)) {
+        let channel: u8 = n - base;
+        let was_muted: bool = self.muted[channel as usize].fetch_xor(true, Ordering::SeqCst);
+        println!("[Sampler] Channel {} {}", channel, if was_muted { "unmuted" } else { "muted" });
+        return;
+      }
+    }
+
+    let mut state: MutexGuard<SamplerState> = lock(&self.state);
+    handle_normal_event(data, time, &mut state, &self.tx_immediate);
+  }
+}
+
+/// How long a beat lasts at `bpm`, if it's in MIN_BPM-MAX_BPM. Past
+/// that a beat gets too long for a Duration, or so short it rounds to
+/// nothing and can't divide the loop.
+fn beat_at(bpm: f64) -> Option<Duration> {
+  (MIN_BPM..=MAX_BPM).contains(&bpm).then(|| Duration::from_secs_f64(60.0 / bpm))
 }
 
-fn print_startup_message() {
+fn print_startup_message(
+  controls: &ControlNotes, click_beat: Option<Duration>, octaves: OctaveConvention
+) {
+  // e.g. "C8 (note 108)"
+  let key_name = |note: u8| format!("{} (note {})", octaves.name(note), note);
   println!("Sampler started!");
   println!();
   println!("Virtual ports created:");
   println!("  - 'sampler-in:midi-in' (input)");
   println!("  - 'sampler-immediate:immediate-out' (pass-through)");
   println!("  - 'sampler-sample:sample-out' (loop playback)");
+  if let Some(beat) = click_beat {
+    println!("  - 'sampler-click:click-out' (metronome, {:.1} BPM)", 60.0 / beat.as_secs_f64());
+  }
   println!();
   println!("Controls:");
-  println!("  - Bb7 (note 106): Stop loop");
-  println!("  - B7 (note 107): Start/stop recording");
-  println!("  - C8 (note 108): Start loop (restarts if already playing)");
+  println!("  - {}: Stop loop", key_name(controls.stop));
+  println!("  - {}: Start/stop recording", key_name(controls.record));
+  println!("  - {}: Start loop (restarts if already playing)", key_name(controls.trigger));
+  println!("  - {}: Pause/resume loop", key_name(controls.pause));
+  println!("  - {}: Clear the clip", key_name(controls.clear));
+  println!("  - {}: Undo the last recording or clear", key_name(controls.undo));
+  if let Some(base) = controls.mute_base {
+    println!("  - {} to {}: Mute/unmute loop channels 0-15",
+             key_name(base), key_name(base + 15));
+  }
   println!();
   println!("Use 'aconnect -l' to see ports, 'aconnect <src> <dst>' to connect.");
-  println!("Press Enter to exit...");
+  println!("Type 'help' for commands, Enter for status, 'quit' to exit.");
+}
+
+/// What the stdin console reaches: the same things the control keys do.
+struct Console {
+  clock: Option<Arc<Mutex<ClockFollower>>>, // Some if --sync-clock
+  state: Arc<Mutex<SamplerState>>,
+  gen: Arc<AtomicU64>,
+  tx_sample: mpsc::Sender<Command>,
+  paused: Arc<AtomicBool>,
+  muted: Arc<[AtomicBool; 16]>,
+}
+
+impl Console {
+  /// Carries out one typed command. Returns false to quit.
+  fn handle_line(&self, line: &str) -> bool {
+    let (command, argument): (&str, &str) = match line.trim().split_once(' ') {
+      Some((command, argument)) => (command, argument.trim()),
+      None => (line.trim(), ""),
+    };
+    let now: EventTime = EventTime::now(0);
+    match command {
+      "" | "status" => self.print_status(),
+      "help" => print_console_help(),
+      "quit" | "exit" => return false,
+      "record" => handle_record_toggle(&mut lock(&self.state), now, 127),
+      "play" => {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_trigger(&self.state, now, &self.gen, &self.tx_sample, self.clock.as_ref());
+      }
+      "stop" => {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_stop(&self.state, now, &self.gen, &self.tx_sample);
+      }
+      "clear" => {
+        self.paused.store(false, Ordering::SeqCst);
+        handle_clear(&self.state, &self.gen, &self.tx_sample);
+      }
+      "undo" => handle_undo(&mut lock(&self.state)),
+      "bpm" => match argument.parse::<f64>() {
+        Ok(bpm) => match beat_at(bpm) {
+          Some(beat) => {
+            lock(&self.state).tempo = Some(beat);
+            println!("[Sampler] Loop lengths now read out at {:.1} BPM", bpm);
+          }
+          None => println!("[Sampler] bpm must be in 1-999"),
+        },
+        Err(_) => println!("[Sampler] Usage: bpm <beats per minute>"),
+      },
+      "save" if !argument.is_empty() => match save_clip(&lock(&self.state), argument) {
+        Ok(()) => println!("[Sampler] Saved the clip to '{}'", argument),
+        Err(e) => println!("[Sampler] Couldn't save '{}': {}", argument, e),
+      },
+      "load" if !argument.is_empty() => match load_clip(&mut lock(&self.state), argument) {
+        Ok(()) => {}
+        Err(e) => println!("[Sampler] Couldn't load '{}': {}", argument, e),
+      },
+      "dump" if !argument.is_empty() => match dump_clip(&lock(&self.state), argument) {
+        Ok(()) => println!("[Sampler] Wrote the clip to '{}'", argument),
+        Err(e) => println!("[Sampler] Couldn't write '{}': {}", argument, e),
+      },
+      "save" | "load" => println!("[Sampler] Usage: {} <file.mid>", command),
+      "dump" => println!("[Sampler] Usage: dump <file.txt>"),
+      _ => println!("[Sampler] Unknown command '{}'; 'help' lists them", command),
+    }
+    true
+  }
+
+  fn print_status(&self) {
+    let state: MutexGuard<SamplerState> = lock(&self.state);
+    println!("[Sampler] {}{}",
+             if state.recording { "Recording" } else { "Not recording" },
+             if self.paused.load(Ordering::SeqCst) { ", loop paused" } else { "" });
+    if state.clip.is_empty() {
+      println!("[Sampler] No clip");
+    } else {
+      println!("[Sampler] Clip: {} events, {}",
+               state.clip.len(), describe_length(state.loop_length, None, state.meter()));
+    }
+    let muted: Vec<String> = (0..16)
+      .filter(|&channel| self.muted[channel].load(Ordering::SeqCst))
+      .map(|channel| channel.to_string())
+      .collect();
+    if !muted.is_empty() {
+      println!("[Sampler] Muted channels: {}", muted.join(" "));
+    }
+    if state.previous.is_some() {
+      println!("[Sampler] Undo would bring back the previous clip");
+    }
+  }
+}
+
+fn print_console_help() {
+  println!("Commands:");
+  println!("  (Enter) or status  show what the sampler is doing");
+  println!("  record             start/stop recording");
+  println!("  play               start the loop (restarts if already playing)");
+  println!("  stop               stop the loop");
+  println!("  clear              throw the clip away");
+  println!("  undo               bring back the clip before the last recording or clear");
+  println!("  save <file.mid>    write the clip to a MIDI file");
+  println!("  load <file.mid>    replace the clip with a MIDI file (undo brings it back)");
+  println!("  dump <file.txt>    write the clip as text, one event per line (load reads it)");
+  println!("  bpm <bpm>          read loop lengths out at this tempo");
+  println!("  quit               silence everything and exit");
+}
+
+/// Writes the clip as a format-0 MIDI file that lasts as long as the loop.
+fn save_clip(state: &SamplerState, path: &str) -> Result<(), String> {
+  if state.recording {
+    return Err("still recording".to_string());
+  }
+  if state.clip.is_empty() {
+    return Err("there's no clip".to_string());
+  }
+  let (events, end_tick): (Vec<TrackEvent>, u64) =
+    clip_to_track(&state.clip, state.loop_length, state.tempo);
+  let file: File = File::create(path).map_err(|e| e.to_string())?;
+  smf::write_format0_with_length(BufWriter::new(file), TICKS_PER_QUARTER, &events, end_tick)
+    .map_err(|e| e.to_string())
+}
+
+/// Writes the clip as text, as `clip_to_text` lays it out.
+fn dump_clip(state: &SamplerState, path: &str) -> Result<(), String> {
+  if state.recording {
+    return Err("still recording".to_string());
+  }
+  if state.clip.is_empty() {
+    return Err("there's no clip".to_string());
+  }
+  fs::write(path, clip_to_text(&state.clip, state.loop_length)).map_err(|e| e.to_string())
+}
+
+/// Replaces the clip with a MIDI file's events, or a text dump's if the
+/// name ends in `.txt`, keeping the old one for undo.
+/// It plays from the next trigger.
+fn load_clip(state: &mut MutexGuard<SamplerState>, path: &str) -> Result<(), String> {
+  if state.recording {
+    return Err("still recording".to_string());
+  }
+  let (clip, loop_length): (Vec<TimestampedMessage>, Duration) = if path.ends_with(".txt") {
+    clip_from_text(&fs::read_to_string(path).map_err(|e| e.to_string())?)?
+  } else {
+    let file: File = File::open(path).map_err(|e| e.to_string())?;
+    clip_from_smf(&smf::read(BufReader::new(file))?)
+  };
+  if clip.is_empty() {
+    return Err("it has no events".to_string());
+  }
+  save_for_undo(state);
+  state.clip = clip;
+  state.loop_length = loop_length;
+  log::info!("loaded '{}': {} events", path, state.clip.len());
+  println!("[Sampler] Loaded {} events, {}",
+           state.clip.len(), describe_length(state.loop_length, None, state.meter()));
+  Ok(())
+}
+
+/// The clip as file events, with a tempo from the readout tempo if any,
+/// and the tick the loop ends on.
+fn clip_to_track(
+  clip: &[TimestampedMessage],
+  loop_length: Duration,
+  tempo: Option<Duration>,
+) -> (Vec<TrackEvent>, u64) {
+  // Slower than a tempo event can say, the file gets its slowest tempo and
+  // the ticks are counted at that, so the timing still comes back exactly.
+  let micros_per_quarter: u32 =
+    tempo.map_or(DEFAULT_TEMPO, |beat| beat.as_micros().min(MAX_TEMPO as u128) as u32);
+  let ticks = |offset: Duration| -> u64 {
+    smf::micros_to_ticks(offset.as_micros() as u64, TICKS_PER_QUARTER, micros_per_quarter)
+  };
+  let mut events: Vec<TrackEvent> = vec![TrackEvent { tick: 0, event: Event::Tempo(micros_per_quarter) }];
+  events.extend(clip.iter().map(|m| TrackEvent { tick: ticks(m.offset), event: Event::Midi(m.data.clone()) }));
+  (events, ticks(loop_length))
+}
+
+/// The clip as text: its length, then one line per event.
+fn clip_to_text(clip: &[TimestampedMessage], loop_length: Duration) -> String {
+  let millis = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1000.0);
+  let mut text: String = format!("loop {}\n", millis(loop_length));
+  for m in clip {
+    text += &format!("{} {}\n", millis(m.offset), hex(&m.data));
+  }
+  text
+}
+
+/// Reads what `clip_to_text` writes. Events are put in order, and the
+/// loop is at least as long as the last of them.
+fn clip_from_text(text: &str) -> Result<(Vec<TimestampedMessage>, Duration), String> {
+  let millis = |field: &str, line: usize| -> Result<Duration, String> {
+    field.parse::<f64>().ok()
+      .filter(|ms| *ms >= 0.0 && ms.is_finite())
+      .map(|ms| Duration::from_micros((ms * 1000.0).round() as u64))
+      .ok_or_else(|| format!("line {}: '{}' isn't a time in ms", line, field))
+  };
+  let mut clip: Vec<TimestampedMessage> = Vec::new();
+  let mut loop_length: Duration = Duration::ZERO;
+  for (i, line) in text.lines().enumerate() {
+    let line_number: usize = i + 1;
+    let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+    match fields.next() {
+      None => {}
+      Some("loop") => {
+        loop_length = millis(fields.next().unwrap_or(""), line_number)?;
+      }
+      Some(offset) => {
+        let offset: Duration = millis(offset, line_number)?;
+        let data: Vec<u8> = fields
+          .map(|byte| u8::from_str_radix(byte, 16)
+               .map_err(|_| format!("line {}: '{}' isn't a hex byte", line_number, byte)))
+          .collect::<Result<Vec<u8>, String>>()?;
+        if data.first().is_none_or(|&status| status < 0x80) {
+          return Err(format!("line {}: an event needs a status byte first", line_number));
+        }
+        clip.push(TimestampedMessage { data, offset });
+      }
+    }
+  }
+  clip.sort_by_key(|m| m.offset);
+  let last: Duration = clip.last().map(|m| m.offset).unwrap_or(Duration::ZERO);
+  Ok((clip, loop_length.max(last)))
+}
+
+/// A file's events as a clip, looping at the file's end
+/// (or its last event, if that's later).
+fn clip_from_smf(smf: &Smf) -> (Vec<TimestampedMessage>, Duration) {
+  let clip: Vec<TimestampedMessage> = smf.timed_messages().into_iter()
+    .map(|(micros, data)| TimestampedMessage { data: data.to_vec(), offset: Duration::from_micros(micros) })
+    .collect();
+  let last: Duration = clip.last().map(|m| m.offset).unwrap_or(Duration::ZERO);
+  let loop_length: Duration = Duration::from_micros(smf.length_micros()).max(last);
+  (clip, loop_length)
+}
+
+/// Clicks on every beat between a Start and the next Stop.
+/// Waiting on the channel with a timeout keeps it responsive to commands
+/// without a separate sleep loop.
+fn run_click_thread(
+  mut sink: impl MidiSink,
+  rx: mpsc::Receiver<ClickCommand>,
+  beat: Duration,
+  note: u8,
+  channel: u8,
+) {
+  let mut origin: Option<Instant> = None;
+  let mut next_beat: u32 = 0;
+  let mut monitor: SendMonitor = SendMonitor::new();
+  loop {
+    let received: Result<ClickCommand, RecvTimeoutError> = match origin {
+      None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+      Some(start) => {
+        let target: Instant = start + beat * next_beat;
+        rx.recv_timeout(target.saturating_duration_since(Instant::now()))
+      }
+    };
+    match received {
+      Ok(ClickCommand::Start(start)) => {
+        origin = Some(start);
+        next_beat = 0;
+      }
+      Ok(ClickCommand::Stop) => origin = None,
+      Err(RecvTimeoutError::Timeout) => {
+        monitor.send(&mut sink, &note_on(channel, note, CLICK_VELOCITY));
+        thread::sleep(Duration::from_millis(CLICK_LENGTH_MS));
+        monitor.send(&mut sink, &note_off(channel, note, 0));
+        if let Some(start) = origin {
+          // Skip any beats we fell behind on rather than rushing through them.
+          next_beat = (start.elapsed().as_nanos() / beat.as_nanos()) as u32 + 1;
+        }
+      }
+      Err(RecvTimeoutError::Disconnected) => return,
+    }
+  }
+}
+
+/// What a loop plays into: `sink`, watched by `monitor`, with
+/// `lost` set once the sends have failed long enough to give up.
+struct LoopOutput<'a, S> {
+  sink: &'a mut S,
+  monitor: &'a mut SendMonitor,
+  lost: &'a AtomicBool,
 }
 
-fn run_immediate_thread(
-  mut conn: MidiOutputConnection,
-  rx: mpsc::Receiver<Vec<u8>>)
-  { while let Ok(data) = rx.recv()
-      { let _ = conn.send(&data); }}
+impl<S: MidiSink> MidiSink for LoopOutput<'_, S> {
+  fn send(&mut self, message: &[u8]) -> bool {
+    let sent: bool = self.monitor.send(self.sink, message);
+    if !sent && self.monitor.lost() {
+      self.lost.store(true, Ordering::SeqCst);
+    }
+    sent
+  }
+}
 
 fn run_sample_thread(
-  mut conn: MidiOutputConnection,
+  mut sink: impl MidiSink,
   rx: mpsc::Receiver<Command>,
   state: Arc<Mutex<SamplerState>>,
   gen: Arc<AtomicU64>,
+  paused: Arc<AtomicBool>,
+  muted: Arc<[AtomicBool; 16]>,
+  sync: Option<ClockSync>,
 ) {
+  let mut monitor: SendMonitor = SendMonitor::new();
+  let output_lost: AtomicBool = AtomicBool::new(false);
   while let Ok(cmd) = rx.recv() {
     match cmd {
       Command::StartLoop => {
         let my_gen: u64 = gen.load(Ordering::SeqCst);
-        let clip: Vec<TimestampedMessage> = {
-          let state: MutexGuard<SamplerState> = state.lock().unwrap();
-          copy_clip(&state)
+        let (clip, loop_length, crossfade, humanize, mut rng, check_every):
+          (Vec<TimestampedMessage>, Duration, Duration, Humanize, Rng, Duration) = {
+          let mut state: MutexGuard<SamplerState> = lock(&state);
+          let rng: Rng = Rng::seeded(state.rng.next_u64());
+          (copy_clip(&state), state.loop_length, state.crossfade, state.humanize, rng,
+           state.stop_check)
         };
+        let channels: Vec<u8> = clip_channels(&clip);
+        let clip: Vec<TimestampedMessage> = with_crossfade(clip, loop_length, crossfade, &channels);
 
         if clip.is_empty() {
           println!("[Sampler] No clip to play");
           continue;
         }
 
-        play_loop(&clip, &mut conn, &gen, my_gen);
+        log::info!("loop started: {} events, length {:?}", clip.len(), loop_length);
+        output_lost.store(false, Ordering::SeqCst);
+        let control: LoopControl = LoopControl {
+          gen: &gen, my_gen, paused: &paused, muted: &muted, output_lost: &output_lost,
+          check_every };
+        let mut out: LoopOutput<_> =
+          LoopOutput { sink: &mut sink, monitor: &mut monitor, lost: &output_lost };
+        match &sync {
+          Some(sync) => play_synced_loop(&clip, loop_length, sync, &mut out, &control),
+          None => play_loop(&clip, loop_length, humanize, &mut rng, &mut out, &control),
+        }
+        if output_lost.load(Ordering::SeqCst) {
+          log::info!("loop stopped: output lost");
+          println!("[Sampler] Loop stopped: the output isn't taking messages");
+          continue;
+        }
+        if !crossfade.is_zero() {
+          for &channel in &channels {
+            out.send(&control_change(channel, CHANNEL_VOLUME, 127));
+          }
+        }
+        log::info!("loop stopped");
         println!("[Sampler] Loop stopped");
       }
       Command::Stop => {
         // Generation already incremented, loop will stop on its own
       }
+      Command::Panic => {
+        for msg in panic_messages() {
+          monitor.send(&mut sink, &msg);
+        }
+      }
     }
   }
 }
 
 fn play_loop(
   clip: &[TimestampedMessage],
-  conn: &mut MidiOutputConnection,
-  gen: &AtomicU64,
-  my_gen: u64,
+  loop_duration: Duration,
+  humanize: Humanize,
+  rng: &mut Rng,
+  sink: &mut impl MidiSink,
+  control: &LoopControl,
 ) {
   if clip.is_empty() {
     return;
   }
 
-  // Calculate loop duration from last event
-  let loop_duration: Duration = clip.last().map(|m| m.offset).unwrap_or(Duration::ZERO);
-
-  let mut active_notes: HashSet<(u8, u8)> = HashSet::new();
+  let mut active_notes: ActiveNotes = HashMap::new();
+  let releases: HashMap<(u8, u8), u8> = release_velocities(clip);
+  let mut mutes: PassMutes = PassMutes::default();
+  let mut controls: HeldControls = HeldControls::default();
 
   println!("[Sampler] Looping {} events (duration: {:?})", clip.len(), loop_duration);
 
   loop {
-    let loop_start: Instant = Instant::now();
-
-    for msg in clip.iter() {
-      if gen.load(Ordering::SeqCst) != my_gen {
-        send_all_notes_off(conn, &active_notes);
-        return;
-      }
+    let mut loop_start: Instant = Instant::now();
+    controls.release(sink); // each pass starts unbent, pedals up
+    mutes.start_pass(control);
+    let humanized: Vec<TimestampedMessage>;
+    let pass: &[TimestampedMessage] = if humanize.is_off() {
+      clip
+    } else {
+      humanized = humanized_pass(clip, loop_duration, humanize, rng);
+      &humanized
+    };
 
+    for msg in pass {
       let target_time: Instant = loop_start + msg.offset;
-      let now: Instant = Instant::now();
-      if target_time > now {
-        if interruptible_sleep(target_time - now, gen, my_gen) {
-          send_all_notes_off(conn, &active_notes);
+      match interruptible_sleep(target_time.saturating_duration_since(Instant::now()),
+                                sink, &mut active_notes, &releases, &mut mutes, control) {
+        Some(paused_for) => loop_start += paused_for,
+        None => {
+          send_all_notes_off(sink, &active_notes, &releases);
+          controls.release(sink);
           return;
         }
       }
+      if !mutes.allows(&msg.data) {
+        continue;
+      }
+      play_event(&msg.data, sink, &mut active_notes, false); // whole, SysEx included
+      controls.track(&msg.data);
+    }
 
-      // Track active notes
-      if let (Some(note), Some(channel))
-        = (get_note(&msg.data), get_channel(&msg.data))
-        { if is_note_on(&msg.data) {
-            active_notes.insert((channel, note));
-          } else if is_note_off(&msg.data) {
-            active_notes.remove(&(channel, note));
-          }
-        }
+    // Wait for loop duration before repeating (if clip ends before loop_duration)
+    let remaining: Duration = loop_duration.saturating_sub(loop_start.elapsed());
+    if interruptible_sleep(remaining, sink, &mut active_notes, &releases, &mut mutes, control)
+      .is_none() {
+      send_all_notes_off(sink, &active_notes, &releases);
+      controls.release(sink);
+      return;
+    }
+  }
+}
+
+/// Like `play_loop`, but the loop's clock is the incoming MIDI clock:
+/// each lap is the loop's length in beats, counted in clock ticks from
+/// the last Start.
+fn play_synced_loop(
+  clip: &[TimestampedMessage],
+  loop_duration: Duration,
+  sync: &ClockSync,
+  sink: &mut impl MidiSink,
+  control: &LoopControl,
+) {
+  let ticks_per_beat: f64 = CLOCKS_PER_QUARTER as f64;
+  let to_ticks = |d: Duration| d.as_secs_f64() / sync.beat.as_secs_f64() * ticks_per_beat;
+  let lap_ticks: f64 = (to_ticks(loop_duration) / ticks_per_beat).round().max(1.0) * ticks_per_beat;
+  let event_ticks: Vec<f64> = clip.iter().map(|m| to_ticks(m.offset)).collect();
+  let mut active_notes: ActiveNotes = HashMap::new();
+  let releases: HashMap<(u8, u8), u8> = release_velocities(clip);
+  let mut place: Option<(u64, u64, usize)> = None;
+  let mut mutes: PassMutes = PassMutes::default();
+  let mut controls: HeldControls = HeldControls::default();
+
+  println!("[Sampler] Looping {} events ({} beats, following MIDI clock)",
+           clip.len(), lap_ticks / ticks_per_beat);
 
-      let _ = conn.send(&msg.data);
+  loop {
+    if control.stopped() {
+      send_all_notes_off(sink, &active_notes, &releases);
+      controls.release(sink);
+      return;
+    }
+    let muted: bool = control.paused.load(Ordering::SeqCst);
+    if muted {
+      send_all_notes_off(sink, &active_notes, &releases);
+      active_notes.clear();
+    }
+    let position: Option<(u64, f64)> = lock(&sync.clock).position(Instant::now());
+    let (starts, ticks): (u64, f64) = match position {
+      Some(p) => p,
+      None => {
+        // Stopped: silence, and start from the top when it starts again.
+        send_all_notes_off(sink, &active_notes, &releases);
+        active_notes.clear();
+        controls.release(sink);
+        place = None;
+        thread::sleep(control.check_every);
+        continue;
+      }
+    };
+    let lap_before: Option<(u64, u64)> = place.map(|(s, l, _)| (s, l));
+    let (restarted, due): (bool, Vec<usize>) =
+      due_events(&mut place, starts, ticks, &event_ticks, lap_ticks);
+    if restarted {
+      send_all_notes_off(sink, &active_notes, &releases);
+      active_notes.clear();
+    }
+    if place.map(|(s, l, _)| (s, l)) != lap_before {
+      controls.release(sink);
+      mutes.start_pass(control);
+    }
+    mutes.catch_up(control, sink, &mut active_notes, &releases);
+    for i in due {
+      if mutes.allows(&clip[i].data) {
+        play_event(&clip[i].data, sink, &mut active_notes, muted);
+        controls.track(&clip[i].data);
+      }
+    }
+    thread::sleep(control.check_every);
+  }
+}
+
+/// Which events of a clock-synced loop are due at clock position
+/// `ticks` since Start number `starts`, moving `place` on past them.
+/// The flag is set if the clock was restarted since the last call.
+fn due_events(
+  place: &mut Option<(u64, u64, usize)>, // (which Start, which lap, next event)
+  starts: u64,
+  ticks: f64,
+  event_ticks: &[f64],
+  lap_ticks: f64,
+) -> (bool, Vec<usize>) {
+  let lap: u64 = (ticks / lap_ticks) as u64;
+  let within: f64 = ticks - lap as f64 * lap_ticks;
+  let mut due: Vec<usize> = vec![];
+  let mut restarted: bool = false;
+  let next: usize = match *place {
+    Some((s, l, next)) if s == starts && l == lap => next,
+    Some((s, _, next)) if s == starts => {
+      due.extend(next..event_ticks.len()); // finish the old lap first
+      0
+    }
+    Some(_) => {
+      restarted = true;
+      0
     }
+    // Joining mid-loop: skip what's already gone by.
+    None => event_ticks.iter().position(|&t| t >= within).unwrap_or(event_ticks.len()),
+  };
+  let end: usize = next + event_ticks[next..].iter().take_while(|&&t| t <= within).count();
+  due.extend(next..end);
+  *place = Some((starts, lap, end));
+  (restarted, due)
+}
 
-    // Wait for loop duration before repeating (if clip ends before loop_duration)
-    let elapsed: Duration = loop_start.elapsed();
-    if elapsed < loop_duration {
-      if interruptible_sleep(loop_duration - elapsed, gen, my_gen) {
-        send_all_notes_off(conn, &active_notes);
+/// A note the loop has sounding: how hard it was last struck, so a
+/// resume can strike it again, and how many of its note-ons are still
+/// waiting for their note-offs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sounding {
+  velocity: u8,
+  count: u32,
+}
+
+/// (channel, note) -> how it's sounding.
+type ActiveNotes = HashMap<(u8, u8), Sounding>;
+
+/// Sends one event of the loop, keeping track of which notes it leaves
+/// sounding. While `muted`, note-ons are held back. A key struck again
+/// before its note-off (a trill played unevenly, say) is only released
+/// by the note-off that answers its last strike, so it neither cuts
+/// out early nor gets released twice.
+fn play_event(
+  data: &[u8],
+  sink: &mut impl MidiSink,
+  active_notes: &mut ActiveNotes,
+  muted: bool,
+) {
+  if let (Some(note), Some(channel)) = (get_note(data), get_channel(data)) {
+    if is_note_on(data) {
+      if muted {
         return;
       }
+      let sounding: &mut Sounding = active_notes.entry((channel, note))
+        .or_insert(Sounding { velocity: data[2], count: 0 });
+      sounding.velocity = data[2];
+      sounding.count += 1;
+    } else if is_note_off(data) {
+      if let Some(sounding) = active_notes.get_mut(&(channel, note)) {
+        sounding.count -= 1;
+        if sounding.count > 0 {
+          return;
+        }
+        active_notes.remove(&(channel, note));
+      }
+    }
+  }
+  sink.send(data);
+}
+
+/// The pedals put back up when the loop stops or comes round:
+/// sustain, sostenuto and soft.
+const PEDAL_CCS: [u8; 3] = [64, 66, 67];
+
+/// What the loop has left away from rest, besides notes: the channels
+/// it has bent, and the pedals it has down, per channel.
+#[derive(Debug, Default)]
+struct HeldControls {
+  bent: [bool; 16],
+  pedals: BTreeSet<(u8, u8)>, // (channel, controller)
+}
+
+impl HeldControls {
+  fn track(&mut self, data: &[u8]) {
+    let channel: u8 = match get_channel(data) {
+      Some(c) if data.len() == 3 => c,
+      _ => return,
+    };
+    match data[0] & 0xF0 {
+      PITCH_BEND =>
+        self.bent[channel as usize] = u16::from(data[1]) | u16::from(data[2]) << 7 != BEND_CENTER,
+      CONTROL_CHANGE if PEDAL_CCS.contains(&data[1]) => {
+        if data[2] >= 64 {
+          self.pedals.insert((channel, data[1]));
+        } else {
+          self.pedals.remove(&(channel, data[1]));
+        }
+      }
+      _ => {}
+    }
+  }
+
+  /// Centers the bends and lifts the pedals, so what comes next
+  /// doesn't start bent or ring on.
+  fn release(&mut self, sink: &mut impl MidiSink) {
+    for channel in 0..16u8 {
+      if std::mem::take(&mut self.bent[channel as usize]) {
+        sink.send(&pitch_bend(channel, BEND_CENTER));
+      }
+    }
+    for (channel, controller) in std::mem::take(&mut self.pedals) {
+      sink.send(&control_change(channel, controller, 0));
+    }
+  }
+}
+
+/// The channels a clip's channel messages are on, in order.
+fn clip_channels(clip: &[TimestampedMessage]) -> Vec<u8> {
+  let mut channels: Vec<u8> = clip.iter().filter_map(|m| get_channel(&m.data)).collect();
+  channels.sort_unstable();
+  channels.dedup();
+  channels
+}
+
+/// A copy of the clip for one pass, its notes moved as `humanize` says.
+/// A note-off moves with its note-on; other events stay put. Offsets
+/// are kept in order, so nothing passes what came before it.
+fn humanized_pass(
+  clip: &[TimestampedMessage],
+  loop_duration: Duration,
+  humanize: Humanize,
+  rng: &mut Rng,
+) -> Vec<TimestampedMessage> {
+  let mut moves: HashMap<(u8, u8), f64> = HashMap::new(); // (channel, note) -> seconds moved
+  let mut earliest: Duration = Duration::ZERO;
+  clip.iter()
+    .map(|msg| {
+      let mut data: Vec<u8> = msg.data.clone();
+      let key: Option<(u8, u8)> = get_channel(&data).zip(get_note(&data));
+      let moved: f64 = match key {
+        Some(key) if is_note_on(&data) => {
+          data[2] = jitter_velocity(rng, data[2], humanize.velocity);
+          let moved: f64 = humanize.time.as_secs_f64() * (2.0 * rng.unit() - 1.0);
+          moves.insert(key, moved);
+          moved
+        }
+        Some(key) if is_note_off(&data) => moves.remove(&key).unwrap_or(0.0),
+        _ => 0.0,
+      };
+      let wanted: f64 = (msg.offset.as_secs_f64() + moved).clamp(0.0, loop_duration.as_secs_f64());
+      let offset: Duration = Duration::from_secs_f64(wanted).max(earliest);
+      earliest = offset;
+      TimestampedMessage { data, offset }
+    })
+    .collect()
+}
+
+/// Adds the CC 7 ramps that fade each pass in and out.
+fn with_crossfade(
+  mut clip: Vec<TimestampedMessage>,
+  loop_duration: Duration,
+  crossfade: Duration,
+  channels: &[u8],
+) -> Vec<TimestampedMessage> {
+  let fade: Duration = crossfade.min(loop_duration / 2);
+  if fade.is_zero() {
+    return clip;
+  }
+  for k in 0..=CROSSFADE_STEPS {
+    let volume: u8 = (127 * k / CROSSFADE_STEPS) as u8;
+    let into_fade: Duration = fade * k / CROSSFADE_STEPS;
+    for &channel in channels {
+      clip.push(TimestampedMessage {
+        data: control_change(channel, CHANNEL_VOLUME, volume),
+        offset: into_fade,
+      });
+      clip.push(TimestampedMessage {
+        data: control_change(channel, CHANNEL_VOLUME, 127 - volume),
+        offset: loop_duration - fade + into_fade,
+      });
     }
   }
+  clip.sort_by_key(|m| m.offset); // stable: ramps go after events at the same time
+  clip
 }
 
 fn copy_clip(state: &MutexGuard<SamplerState>) -> Vec<TimestampedMessage> {
@@ -249,136 +1631,1332 @@ fn copy_clip(state: &MutexGuard<SamplerState>) -> Vec<TimestampedMessage> {
     .collect()
 }
 
-fn interruptible_sleep(duration: Duration, gen: &AtomicU64, my_gen: u64) -> bool {
-  let chunk: Duration = Duration::from_millis(TRIGGER_SLEEP_MS);
+/// Sleeps for `duration` of loop time. A pause stops that clock:
+/// the sounding notes are released, and struck again on resume.
+/// Channels muted meanwhile are silenced straight away.
+/// Returns how long it spent paused, or None if the loop was stopped.
+fn interruptible_sleep(
+  duration: Duration,
+  sink: &mut impl MidiSink,
+  active_notes: &mut ActiveNotes,
+  releases: &HashMap<(u8, u8), u8>,
+  mutes: &mut PassMutes,
+  control: &LoopControl,
+) -> Option<Duration> {
+  let chunk: Duration = control.check_every;
   let mut remaining: Duration = duration;
-  while remaining > Duration::ZERO {
-    if gen.load(Ordering::SeqCst) != my_gen {
-      return true;
+  let mut paused_for: Duration = Duration::ZERO;
+  loop {
+    if control.stopped() {
+      return None;
+    }
+    mutes.catch_up(control, sink, active_notes, releases);
+    if control.paused.load(Ordering::SeqCst) {
+      let pause_start: Instant = Instant::now();
+      send_all_notes_off(sink, active_notes, releases);
+      while control.paused.load(Ordering::SeqCst) {
+        if control.stopped() {
+          return None;
+        }
+        thread::sleep(chunk);
+      }
+      for (&(channel, note), sounding) in active_notes.iter() {
+        sink.send(&note_on(channel, note, sounding.velocity));
+      }
+      paused_for += pause_start.elapsed();
+      continue;
+    }
+    if remaining.is_zero() {
+      return Some(paused_for);
     }
     let to_sleep: Duration = remaining.min(chunk);
     thread::sleep(to_sleep);
     remaining = remaining.saturating_sub(to_sleep);
   }
-  false
 }
 
-fn send_all_notes_off(conn: &mut MidiOutputConnection, active_notes: &HashSet<(u8, u8)>) {
-  for &(channel, note) in active_notes.iter() {
-    let note_off: [u8; 3] = [0x80 | channel, note, 0];
-    let _ = conn.send(&note_off);
+/// Cuts the sounding notes short, each with the release velocity the
+/// clip would have ended it with.
+fn send_all_notes_off(
+  sink: &mut impl MidiSink,
+  active_notes: &ActiveNotes,
+  releases: &HashMap<(u8, u8), u8>,
+) {
+  for (&key, &sounding) in active_notes.iter() {
+    send_offs(sink, key, sounding, releases);
+  }
+}
+
+/// A note-off for each strike still sounding, for synths that gave each
+/// its own voice.
+fn send_offs(
+  sink: &mut impl MidiSink,
+  key: (u8, u8),
+  sounding: Sounding,
+  releases: &HashMap<(u8, u8), u8>,
+) {
+  for _ in 0..sounding.count {
+    sink.send(&note_off(key.0, key.1, release_of(releases, key)));
   }
 }
 
+/// (channel, note) -> the release velocity of the clip's last note-off for it.
+fn release_velocities(clip: &[TimestampedMessage]) -> HashMap<(u8, u8), u8> {
+  clip.iter()
+    .filter(|m| is_note_off(&m.data))
+    .filter_map(|m| Some(((get_channel(&m.data)?, get_note(&m.data)?), release_velocity(&m.data))))
+    .collect()
+}
+
+fn release_of(releases: &HashMap<(u8, u8), u8>, key: (u8, u8)) -> u8 {
+  releases.get(&key).copied().unwrap_or(0)
+}
+
 fn handle_stop(
   state: &Arc<Mutex<SamplerState>>,
+  time: EventTime,
   gen: &AtomicU64,
   tx: &mpsc::Sender<Command>,
-) {{ let mut state: MutexGuard<SamplerState> = state.lock().unwrap();
+) {{ let mut state: MutexGuard<SamplerState> = lock(state);
      if state.recording {
-     stop_recording(&mut state);
+     stop_recording(&mut state, time);
      }}
   gen.fetch_add(1, Ordering::SeqCst);
   let _ = tx.send(Command::Stop);
   println!("[Sampler] Stop requested"); }
 
-fn handle_record_toggle(state: &mut MutexGuard<SamplerState>) {
+/// Stops the loop and empties the clip, abandoning any take in progress.
+fn handle_clear(state: &Arc<Mutex<SamplerState>>, gen: &AtomicU64, tx: &mpsc::Sender<Command>) {
+  { let mut state: MutexGuard<SamplerState> = lock(state);
+    if state.recording {
+      state.recording = false;
+      if let Some(click) = &state.click {
+        let _ = click.tx.send(ClickCommand::Stop);
+      }
+      state.clip.clear(); // keep what undo had: the clip before this take
+    } else {
+      save_for_undo(&mut state);
+    }
+    state.record_start = None;
+    state.first_tap = None;
+    state.loop_length = Duration::ZERO; }
+  gen.fetch_add(1, Ordering::SeqCst);
+  let _ = tx.send(Command::Stop);
+  log::info!("clip cleared");
+  println!("[Sampler] Clip cleared"); }
+
+/// Swaps the clip with the one saved before the last recording or
+/// clear, abandoning a recording in progress.
+fn handle_undo(state: &mut MutexGuard<SamplerState>) {
+  if state.recording {
+    state.recording = false;
+    state.record_start = None;
+    state.first_tap = None;
+    if let Some(click) = &state.click {
+      let _ = click.tx.send(ClickCommand::Stop);
+    }
+    let (clip, loop_length): (Vec<TimestampedMessage>, Duration) =
+      state.previous.take().unwrap_or_default();
+    state.clip = clip;
+    state.loop_length = loop_length;
+    log::info!("recording abandoned by undo");
+    println!("[Sampler] Recording abandoned, previous clip restored");
+    return;
+  }
+  match state.previous.take() {
+    None => println!("[Sampler] Nothing to undo"),
+    Some((clip, loop_length)) => {
+      save_for_undo(state);
+      state.clip = clip;
+      state.loop_length = loop_length;
+      log::info!("undo: restored a clip of {} events", state.clip.len());
+      println!("[Sampler] Restored the previous clip ({} events)", state.clip.len());
+    }
+  }
+}
+
+/// Moves the clip into the undo slot, leaving it empty.
+fn save_for_undo(state: &mut MutexGuard<SamplerState>) {
+  let clip: Vec<TimestampedMessage> = std::mem::take(&mut state.clip);
+  let loop_length: Duration = std::mem::take(&mut state.loop_length);
+  state.previous = Some((clip, loop_length));
+}
+
+/// `velocity` is the record key's; with `--record-hard-threshold`,
+/// only a press harder than that counts in.
+fn handle_record_toggle(state: &mut MutexGuard<SamplerState>, time: EventTime, velocity: u8) {
   if state.recording
-  { stop_recording(state);
-  } else { start_recording(state); }}
+  { stop_recording(state, time);
+  } else {
+    let count_in: bool = state.record_hard_threshold.is_none_or(|t| velocity > t);
+    start_recording(state, time, count_in); }}
 
+/// Starts the loop, at once or, with `--trigger-quantize`, on the next
+/// beat or bar. Until then a loop already playing plays on, and a stop,
+/// clear or other trigger in the meantime cancels this one.
+/// With `--tap-loop`, a trigger while not recording starts a take
+/// instead, and the next one ends it and starts the loop at once.
 fn handle_trigger(
   state: &Arc<Mutex<SamplerState>>,
-  gen: &AtomicU64,
+  time: EventTime,
+  gen: &Arc<AtomicU64>,
   tx: &mpsc::Sender<Command>,
-) {{ let mut state: MutexGuard<SamplerState> = state.lock().unwrap();
-     if state.recording {
-     stop_recording(&mut state);
-     }}
-  gen.fetch_add(1, Ordering::SeqCst);
-  let _ = tx.send(Command::StartLoop); }
+  clock: Option<&Arc<Mutex<ClockFollower>>>,
+) {
+  let (wait, quantize, check_every): (Option<TriggerWait>, TriggerQuantize, Duration) =
+  { let mut state: MutexGuard<SamplerState> = lock(state);
+    if state.tap_loop && !state.recording {
+      start_tapped_take(&mut state, time);
+      return; }
+    let tapped: bool = state.first_tap.is_some();
+    if state.recording {
+    stop_recording(&mut state, time);
+    }
+    let wait: Option<TriggerWait> =
+      if tapped { None } else { trigger_wait(&state, time.instant, clock) };
+    (wait, state.trigger_quantize, state.stop_check) };
+  let wait: TriggerWait = match wait {
+    None => {
+      gen.fetch_add(1, Ordering::SeqCst);
+      let _ = tx.send(Command::StartLoop);
+      return;
+    }
+    Some(wait) => wait,
+  };
+  println!("[Sampler] Loop starts on the next {}",
+           if quantize == TriggerQuantize::Bar { "bar" } else { "beat" });
+  let my_gen: u64 = gen.load(Ordering::SeqCst);
+  let gen: Arc<AtomicU64> = Arc::clone(gen);
+  let tx: mpsc::Sender<Command> = tx.clone();
+  thread::spawn(move || {
+    if wait.wait(&gen, my_gen, check_every)
+      && gen.compare_exchange(my_gen, my_gen + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    { let _ = tx.send(Command::StartLoop); }
+  }); }
+
+/// The first tap of `--tap-loop`: the loop's downbeat, where recording
+/// starts, with no lookback or count-in. A loop already playing plays on.
+fn start_tapped_take(state: &mut MutexGuard<SamplerState>, time: EventTime) {
+  log::info!("recording started by a tap");
+  state.recording = true;
+  save_for_undo(state);
+  state.record_start = Some(time);
+  state.first_tap = Some(time);
+  start_click(state, time);
+  println!("[Sampler] Downbeat tapped, recording; tap again to loop..."); }
+
+/// What a trigger at `now` has to wait for, or None to start at once:
+/// the next beat or bar of the incoming clock with `--sync-clock`, or
+/// else of the grid the last take was recorded on. Before any take, or
+/// while the clock is stopped, there's nothing to wait for.
+fn trigger_wait(
+  state: &SamplerState,
+  now: Instant,
+  clock: Option<&Arc<Mutex<ClockFollower>>>,
+) -> Option<TriggerWait> {
+  let beats: u32 = match state.trigger_quantize {
+    TriggerQuantize::Off => return None,
+    TriggerQuantize::Beat => 1,
+    TriggerQuantize::Bar => state.beats_per_bar,
+  };
+  if let Some(clock) = clock {
+    let (starts, ticks): (u64, f64) = lock(clock).position(now)?;
+    let unit: f64 = f64::from(beats) * CLOCKS_PER_QUARTER as f64;
+    let boundary: f64 = (ticks / unit).ceil() * unit;
+    return (boundary > ticks)
+      .then(|| TriggerWait::Clock { clock: Arc::clone(clock), starts, ticks: boundary });
+  }
+  let unit: Duration = state.click.as_ref()?.beat * beats;
+  let boundary: Instant = next_boundary(state.grid_origin?, unit, now);
+  (boundary > now).then_some(TriggerWait::Until(boundary))
+}
+
+/// The first of `origin`, `origin + unit`, ... at or after `now`.
+fn next_boundary(origin: Instant, unit: Duration, now: Instant) -> Instant {
+  let elapsed: Duration = now.saturating_duration_since(origin);
+  let units: u32 = elapsed.as_nanos().div_ceil(unit.as_nanos()) as u32;
+  origin + unit * units
+}
 
 fn handle_normal_event(
   data: Vec<u8>,
+  time: EventTime,
   state: &mut MutexGuard<SamplerState>,
   tx_immediate: &mpsc::Sender<Vec<u8>>,
 ) {
   let _ = tx_immediate.send(data.clone());
-  let now: Instant = Instant::now();
   if is_note_event(&data)
-  { state.last_normal_note = Some((now,
-                                   data.clone() )); }
+  { remember_note(state, time, &data); }
+  if state.recording && state.record_start.is_none() && is_note_on(&data) {
+    begin_armed_take(state, time); }
   if state.recording {
     if let Some(start) = state.record_start {
-      let offset: Duration = now.duration_since(start);
+      if time.is_before(&start) { return; } // still counting in
+      let offset: Duration = time.since(&start);
       state.clip.push(TimestampedMessage { data, offset }); }} }
 
-fn stop_recording(state: &mut MutexGuard<SamplerState>) {
+/// Starts capture of an armed take at its first note.
+fn begin_armed_take(state: &mut MutexGuard<SamplerState>, time: EventTime) {
+  state.record_start = Some(time);
+  start_click(state, time);
+  log::info!("armed recording began at the first note");
+  match state.armed_at {
+    Some(armed) => println!(
+      "[Sampler] Recording started on the first note ({:.3} s after arming)...",
+      time.since(&armed).as_secs_f64()),
+    None => println!("[Sampler] Recording started on the first note..."),
+  }}
+
+fn stop_recording(state: &mut MutexGuard<SamplerState>, time: EventTime) {
   state.recording = false;
-  state.record_start = None;
+  let start: Option<EventTime> = state.record_start.take();
+  let tapped: Option<Duration> = state.first_tap.take().map(|first| time.since(&first));
+  if let Some(click) = &state.click {
+    let _ = click.tx.send(ClickCommand::Stop);
+  }
+  if start.is_some_and(|start| time.is_before(&start)) {
+    state.clip.clear();
+    state.loop_length = Duration::ZERO;
+    println!("[Sampler] Count-in aborted, nothing recorded.");
+    return;
+  }
+  if start.is_none() {
+    state.clip.clear();
+    state.loop_length = Duration::ZERO;
+    println!("[Sampler] No note came while armed, nothing recorded.");
+    return;
+  }
+  let recorded: Duration = match start {
+    Some(start) => time.since(&start),
+    None => Duration::ZERO,
+  };
+  state.grid_origin = start.map(|start| start.instant);
+  let beats_per_round: u32 = if state.round_to_bars { state.beats_per_bar } else { 1 };
+  let unit: Option<Duration> = state.click.as_ref().map(|c| c.beat * beats_per_round);
+  let fixed: Option<Duration> = tapped
+    .or_else(|| state.loop_bars.zip(state.meter()).map(|(bars, meter)| meter.bar() * bars));
+  let mut rounded_from: Option<Duration> = None;
+  state.loop_length = match (fixed, state.trim, unit) {
+    (Some(length), _, _) => {
+      let dropped: usize = fit_to_length(&mut state.clip, length);
+      if dropped > 0 {
+        println!("[Sampler] Dropped {} events past the end of the loop.", dropped);
+      }
+      length
+    }
+    (None, Some(rest), unit) => trim_clip(&mut state.clip, rest, unit),
+    (None, None, Some(unit)) => {
+      rounded_from = Some(recorded);
+      quantize_loop(&mut state.clip, recorded, unit)
+    }
+    (None, None, None) => state.clip.last().map(|m| m.offset).unwrap_or(Duration::ZERO),
+  };
+  if let Some(grid) = state.quantize {
+    let length: Duration = state.loop_length;
+    quantize_notes(&mut state.clip, grid, length);
+  }
+  log::info!("recording stopped: {} events, loop length {:?}", state.clip.len(), state.loop_length);
   println!(
     "[Sampler] Recording stopped. {} events captured.",
-    state.clip.len() ); }
+    state.clip.len() );
+  println!("[Sampler] Loop length: {}",
+           describe_length(state.loop_length, rounded_from, state.meter())); }
+
+/// e.g. "8.000 s = 4 bars at 120.0 BPM (rounded from 7.930 s, +0.070 s)".
+fn describe_length(
+  length: Duration,
+  rounded_from: Option<Duration>,
+  meter: Option<Meter>
+) -> String {
+  let mut text: String = format!("{:.3} s", length.as_secs_f64());
+  if let Some(meter) = meter {
+    let beats: f64 = length.as_secs_f64() / meter.beat.as_secs_f64();
+    let bars: u32 = (length.as_nanos() / meter.bar().as_nanos()) as u32;
+    let extra: f64 = beats - f64::from(bars * meter.beats_per_bar);
+    let bar_word: &str = if bars == 1 { "bar" } else { "bars" };
+    text += &if extra < 0.005 {
+      format!(" = {} {}", bars, bar_word)
+    } else {
+      format!(" = {} {} + {:.2} beats", bars, bar_word, extra)
+    };
+    text += &format!(" at {:.1} BPM", 60.0 / meter.beat.as_secs_f64());
+  }
+  if let Some(recorded) = rounded_from.filter(|&r| r != length) {
+    let correction: f64 = length.as_secs_f64() - recorded.as_secs_f64();
+    text += &format!(" (rounded from {:.3} s, {:+.3} s)", recorded.as_secs_f64(), correction);
+  }
+  text
+}
 
-fn start_recording(state: &mut MutexGuard<SamplerState>) {
+fn start_recording(state: &mut MutexGuard<SamplerState>, now: EventTime, count_in: bool) {
+  log::info!("recording started");
   state.recording = true;
-  state.clip.clear();
-  let now: Instant = Instant::now();
-  let last_note: Option<(Instant, Vec<u8>)> =
-    state.last_normal_note.clone();
-  if let Some((event_time, event_data)) = last_note {
-    let elapsed: Duration = now.duration_since(event_time);
-    if elapsed <= Duration::from_millis(LOOKBACK_MS) {
-      state.record_start = Some(event_time);
-      state.clip.push(TimestampedMessage {
-        data: event_data,
-        offset: Duration::ZERO, });
-      println!(
-        "[Sampler] Recording started (included note from {:?} ago)...",
-        elapsed );
-      return; }}
+  save_for_undo(state);
+  let count_in: Option<Duration> = state.click.as_ref()
+    .filter(|c| count_in && c.count_in > 0)
+    .map(|c| c.beat * c.count_in);
+  if let Some(count_in) = count_in {
+    state.record_start = Some(now.plus(count_in));
+    start_click(state, now);
+    println!("[Sampler] Counting in ({:?})...", count_in);
+    return;
+  }
+  if state.record_mode == RecordMode::WaitForNote {
+    state.record_start = None;
+    state.armed_at = Some(now);
+    println!("[Sampler] Armed; recording starts with the first note...");
+    return;
+  }
+  let lookback: Duration =
+    if state.record_mode == RecordMode::Immediate { Duration::ZERO } else { state.lookback };
+  let downbeat: Option<usize> = state.recent_notes.iter()
+    .rposition(|(time, data)| is_note_on(data) && now.since(time) <= lookback);
+  if let Some(index) = downbeat {
+    let event_time: EventTime = state.recent_notes[index].0;
+    let caught: Vec<TimestampedMessage> = state.recent_notes.iter().skip(index)
+      .map(|(time, data)| TimestampedMessage {
+        data: data.clone(),
+        offset: time.since(&event_time), })
+      .collect();
+    state.clip = caught;
+    state.record_start = Some(event_time);
+    start_click(state, event_time);
+    println!(
+      "[Sampler] Recording started (included {} note events from {:?} ago)...",
+      state.clip.len(), now.since(&event_time) );
+    return; }
   state.record_start = Some(now);
+  start_click(state, now);
   println!("[Sampler] Recording started..."); }
 
-fn get_note(data: &[u8]) -> Option<u8> {
-  if data.len() >= 2 && is_note_event(data) {
-    Some(data[1])
-  } else {
-    None
+/// Adds a note event to the lookback buffer, forgetting any that
+/// have fallen out of the window.
+fn remember_note(state: &mut MutexGuard<SamplerState>, time: EventTime, data: &[u8]) {
+  let lookback: Duration = state.lookback;
+  state.recent_notes.push_back((time, data.to_vec()));
+  while state.recent_notes.len() > RECENT_NOTES_MAX
+        || state.recent_notes.front().is_some_and(|(t, _)| time.since(t) > lookback)
+  { state.recent_notes.pop_front(); }}
+
+fn start_click(state: &MutexGuard<SamplerState>, beat_one: EventTime) {
+  if let Some(click) = &state.click {
+    let _ = click.tx.send(ClickCommand::Start(beat_one.instant));
+  }}
+
+/// Rounds the loop to the nearest whole number of beats (at least one),
+/// pulling anything recorded past the end back to the end.
+fn quantize_loop(clip: &mut [TimestampedMessage], recorded: Duration, beat: Duration) -> Duration {
+  let beats: u32 = ((recorded.as_secs_f64() / beat.as_secs_f64()).round() as u32).max(1);
+  let length: Duration = beat * beats;
+  for message in clip.iter_mut() {
+    message.offset = message.offset.min(length);
   }
+  length
 }
 
-fn get_channel(data: &[u8]) -> Option<u8> {
-  if !data.is_empty() {
-    Some(data[0] & 0x0F)
-  } else {
-    None
-  }
+/// Cuts the clip to a loop of `length`: what starts at or after the end
+/// is dropped, except note-offs for notes that started before it, which
+/// move to the end so nothing hangs. Returns how many were dropped.
+fn fit_to_length(clip: &mut Vec<TimestampedMessage>, length: Duration) -> usize {
+  let before: usize = clip.len();
+  let mut sounding: HashSet<(u8, u8)> = HashSet::new();
+  clip.retain_mut(|message| {
+    let key: Option<(u8, u8)> = get_channel(&message.data).zip(get_note(&message.data));
+    if message.offset < length {
+      if let Some(key) = key.filter(|_| is_note_on(&message.data)) {
+        sounding.insert(key);
+      } else if let Some(key) = key.filter(|_| is_note_off(&message.data)) {
+        sounding.remove(&key);
+      }
+      return true;
+    }
+    match key {
+      Some(key) if is_note_off(&message.data) && sounding.remove(&key) => {
+        message.offset = length;
+        true
+      }
+      _ => false,
+    }
+  });
+  before - clip.len()
 }
 
-fn is_note_on(data: &[u8]) -> bool {
-  if data.len() >= 3 {
-    let status: u8 = data[0] & 0xF0;
-    status == 0x90 && data[2] > 0
-  } else {
-    false
+/// Moves each note-on to the nearest grid point, and its note-off
+/// by the same amount, keeping everything within the loop.
+fn quantize_notes(clip: &mut [TimestampedMessage], grid: Grid, length: Duration) {
+  // (channel, note) -> (where its note-on was, where it went)
+  let mut moved: HashMap<(u8, u8), (Duration, Duration)> = HashMap::new();
+  for message in clip.iter_mut() {
+    let key: (u8, u8) = match (get_channel(&message.data), get_note(&message.data)) {
+      (Some(c), Some(n)) => (c, n),
+      _ => continue,
+    };
+    let (from, to): (Duration, Duration) = if is_note_on(&message.data) {
+      let to: Duration = nearest_swung(message.offset, grid.step, grid.swing);
+      moved.insert(key, (message.offset, to));
+      (message.offset, to)
+    } else if is_note_off(&message.data) {
+      match moved.remove(&key) {
+        Some(shift) => shift,
+        None => continue,
+      }
+    } else {
+      continue;
+    };
+    let shifted: Duration = if to >= from {
+      message.offset + (to - from)
+    } else {
+      message.offset.saturating_sub(from - to)
+    };
+    message.offset = shifted.min(length);
   }
+  clip.sort_by_key(|m| m.offset);
 }
 
-fn is_note_off(data: &[u8]) -> bool {
-  if data.len() >= 3 {
-    let status: u8 = data[0] & 0xF0;
-    // Note off, or note on with velocity 0
-    status == 0x80 || (status == 0x90 && data[2] == 0)
-  } else {
-    false
+/// Cuts the silence before the first event and after the last,
+/// leaving `rest` at the end. Returns the loop length.
+/// With a beat, only whole beats come off the front
+/// and the length rounds up to a whole number of beats.
+fn trim_clip(clip: &mut [TimestampedMessage], rest: Duration, beat: Option<Duration>) -> Duration {
+  let first: Duration = clip.first().map(|m| m.offset).unwrap_or(Duration::ZERO);
+  let lead_in: Duration = match beat {
+    Some(beat) => beat * (first.as_nanos() / beat.as_nanos()) as u32,
+    None => first,
+  };
+  for message in clip.iter_mut() {
+    message.offset -= lead_in;
+  }
+  let end: Duration = clip.last().map(|m| m.offset).unwrap_or(Duration::ZERO) + rest;
+  match beat {
+    Some(beat) => beat * (end.as_nanos().div_ceil(beat.as_nanos()) as u32).max(1),
+    None => end,
   }
 }
 
-fn is_note_event(data: &[u8]) -> bool {
-  if data.is_empty() {
-    return false;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use midi_utils::harness::messages;
+  use midi_utils::ports::FAILURES_BEFORE_WARNING;
+
+  /// Records what a loop sends, and stops it after `count` messages.
+  struct StopAfter<'a> {
+    sent: Vec<Vec<u8>>,
+    count: usize,
+    gen: &'a AtomicU64,
+  }
+
+  impl MidiSink for StopAfter<'_> {
+    fn send(&mut self, message: &[u8]) -> bool {
+      self.sent.push(message.to_vec());
+      if self.sent.len() == self.count {
+        self.gen.fetch_add(1, Ordering::SeqCst);
+      }
+      true
+    }
+  }
+
+  /// Takes nothing, counting the attempts.
+  struct Unplugged(usize);
+
+  impl MidiSink for Unplugged {
+    fn send(&mut self, _: &[u8]) -> bool {
+      self.0 += 1;
+      false
+    }
+  }
+
+  /// An `Input` with the default control keys, and the ends of its channels.
+  fn input() -> (Input, mpsc::Receiver<Vec<u8>>, mpsc::Receiver<Command>) {
+    let (tx_immediate, rx_immediate): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
+      mpsc::channel();
+    let (tx_sample, rx_sample): (mpsc::Sender<Command>, mpsc::Receiver<Command>) =
+      mpsc::channel();
+    let input: Input = Input {
+      controls: ControlNotes::from_args(&Args::new(vec!["--mute-base".to_string(), "0".to_string()]))
+        .unwrap(),
+      panic_on_stop: false,
+      clock: None,
+      state: Arc::new(Mutex::new(SamplerState::new(None, Duration::ZERO))),
+      gen: Arc::new(AtomicU64::new(0)),
+      tx_immediate,
+      tx_sample,
+      paused: Arc::new(AtomicBool::new(false)),
+      muted: Arc::new(std::array::from_fn(|_| AtomicBool::new(false))),
+    };
+    (input, rx_immediate, rx_sample)
+  }
+
+  #[test]
+  fn control_keys_act_and_everything_else_passes_through() {
+    let (input, rx_immediate, rx_sample) = input();
+    let script: Vec<Vec<u8>> = messages(
+      "90 6B 64, 90 3C 64, 80 3C 00, 90 6B 64, 90 6C 64, 90 69 64, 90 03 64");
+    for (i, message) in script.into_iter().enumerate() {
+      input.handle(message, at(1_000_000 + i as u64 * 100_000));
+    }
+    // Record, a note, record again, trigger, pause, mute channel 3.
+    assert_eq!(rx_immediate.try_iter().collect::<Vec<Vec<u8>>>(),
+               messages("90 3C 64, 80 3C 00"));
+    assert!(matches!(rx_sample.try_iter().collect::<Vec<Command>>()[..], [Command::StartLoop]));
+    let state: MutexGuard<SamplerState> = lock(&input.state);
+    assert_eq!(state.clip.iter().map(|m| m.data.clone()).collect::<Vec<Vec<u8>>>(),
+               messages("90 3C 64, 80 3C 00"));
+    assert!(input.paused.load(Ordering::SeqCst));
+    assert!(input.muted[3].load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn undo_swaps_back_the_previous_clip() {
+    let mutex: Mutex<SamplerState> = Mutex::new(SamplerState::new(None, Duration::ZERO));
+    let mut s: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    let take = |s: &mut MutexGuard<SamplerState>, note: u8, start: u64| {
+      handle_record_toggle(s, at(start), 100);
+      handle_normal_event(vec![0x90, note, 100], at(start + 100_000), s, &tx);
+      handle_record_toggle(s, at(start + 500_000), 100);
+    };
+    handle_undo(&mut s); // nothing yet
+    assert!(s.clip.is_empty());
+    take(&mut s, 60, 1_000_000);
+    take(&mut s, 62, 2_000_000);
+    handle_undo(&mut s);
+    assert_eq!(s.clip[0].data, vec![0x90, 60, 100]);
+    handle_undo(&mut s);
+    assert_eq!(s.clip[0].data, vec![0x90, 62, 100]);
+    // Undo during a take abandons it.
+    handle_record_toggle(&mut s, at(3_000_000), 100);
+    handle_normal_event(vec![0x90, 64, 100], at(3_100_000), &mut s, &tx);
+    handle_undo(&mut s);
+    assert!(!s.recording && s.record_start.is_none());
+    assert_eq!(s.clip[0].data, vec![0x90, 62, 100]);
+    assert_eq!(s.loop_length, Duration::from_millis(100));
+  }
+
+  fn at(micros: u64) -> EventTime {
+    EventTime::now(micros)
+  }
+
+  #[test]
+  fn offsets_come_from_callback_timestamps() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(1_250_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(1_500_123), &mut state, &tx);
+    let offsets: Vec<Duration> = state.clip.iter().map(|m| m.offset).collect();
+    assert_eq!(offsets, vec![Duration::from_micros(250_000), Duration::from_micros(500_123)]);
+  }
+
+  #[test]
+  fn lookback_note_uses_timestamps_too() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_normal_event(vec![0x90, 60, 100], at(2_000_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(2_000_000 + (LOOKBACK_MS - 10) * 1000), 100);
+    handle_normal_event(vec![0x80, 60, 0], at(2_100_000), &mut state, &tx);
+    let offsets: Vec<Duration> = state.clip.iter().map(|m| m.offset).collect();
+    assert_eq!(offsets, vec![Duration::ZERO, Duration::from_millis(100)]);
+  }
+
+  #[test]
+  fn lookback_takes_most_recent_note_on_and_what_follows() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(200)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_normal_event(vec![0x90, 55, 100], at(700_000), &mut state, &tx); // outside window
+    handle_normal_event(vec![0x90, 60, 100], at(850_000), &mut state, &tx);
+    handle_normal_event(vec![0x90, 64, 100], at(900_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(950_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    let clip: Vec<(Vec<u8>, Duration)> =
+      state.clip.iter().map(|m| (m.data.clone(), m.offset)).collect();
+    assert_eq!(clip, vec![(vec![0x90, 64, 100], Duration::ZERO),
+                          (vec![0x80, 60, 0], Duration::from_millis(50))]);
+  }
+
+  #[test]
+  fn lookback_ignores_notes_outside_window() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(200)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_normal_event(vec![0x90, 60, 100], at(650_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(900_000), &mut state, &tx);
+    assert_eq!(state.recent_notes.len(), 1);
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    assert!(state.clip.is_empty());
+  }
+
+  #[test]
+  fn loop_length_is_last_event_without_click() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(1_100_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(1_400_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(1_900_000), 100);
+    assert_eq!(state.loop_length, Duration::from_millis(400));
+  }
+
+  #[test]
+  fn loop_bars_fixes_the_length() {
+    let mutex: Mutex<SamplerState> = Mutex::new(SamplerState::new(None, Duration::ZERO));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    state.tempo = Some(Duration::from_millis(100)); // 400 ms bars
+    state.loop_bars = Some(2);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(1_100_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(1_300_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(1_500_000), 100); // stopped early
+    assert_eq!(state.loop_length, Duration::from_millis(800));
+    assert_eq!(state.clip.len(), 2);
+
+    handle_record_toggle(&mut state, at(2_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(2_700_000), &mut state, &tx);
+    handle_normal_event(vec![0x90, 62, 100], at(2_850_000), &mut state, &tx); // too late
+    handle_normal_event(vec![0x80, 60, 0], at(2_900_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 62, 0], at(2_950_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(3_000_000), 100);
+    assert_eq!(state.loop_length, Duration::from_millis(800));
+    assert_eq!(state.clip.iter().map(|m| (m.offset.as_millis(), m.data[1])).collect::<Vec<_>>(),
+               vec![(700, 60), (800, 60)]);
+  }
+
+  #[test]
+  fn click_quantizes_loop_to_whole_beats() {
+    let (click_tx, click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let beat: Duration = Duration::from_millis(500); // 120 BPM
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(Some(Click { beat, count_in: 0, tx: click_tx }), Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    assert!(matches!(click_rx.try_recv(), Ok(ClickCommand::Start(_))));
+    handle_normal_event(vec![0x90, 60, 100], at(1_000_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(2_950_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(2_960_000), 100); // 1.96s: rounds to 4 beats
+    assert!(matches!(click_rx.try_recv(), Ok(ClickCommand::Stop)));
+    assert_eq!(state.loop_length, Duration::from_secs(2));
+    assert_eq!(state.clip[1].offset, Duration::from_millis(1950));
+  }
+
+  #[test]
+  fn quantized_triggers_wait_for_the_take_grid() {
+    let (click_tx, _click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let beat: Duration = Duration::from_millis(500);
+    let mut state: SamplerState =
+      SamplerState::new(Some(Click { beat, count_in: 0, tx: click_tx }), Duration::ZERO);
+    let origin: Instant = Instant::now();
+    let later = |ms: u64| origin + Duration::from_millis(ms);
+    state.trigger_quantize = TriggerQuantize::Bar;
+    assert!(trigger_wait(&state, later(2300), None).is_none()); // no take yet
+    state.grid_origin = Some(origin);
+    assert!(matches!(trigger_wait(&state, later(2300), None),
+                     Some(TriggerWait::Until(at)) if at == later(4000)));
+    assert!(trigger_wait(&state, later(4000), None).is_none()); // right on the bar
+    state.trigger_quantize = TriggerQuantize::Beat;
+    assert!(matches!(trigger_wait(&state, later(2300), None),
+                     Some(TriggerWait::Until(at)) if at == later(2500)));
+    state.trigger_quantize = TriggerQuantize::Off;
+    assert!(trigger_wait(&state, later(2300), None).is_none());
+  }
+
+  #[test]
+  fn a_pending_trigger_fires_on_the_beat_unless_stopped() {
+    let (click_tx, _click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let beat: Duration = Duration::from_millis(30);
+    let mut sampler: SamplerState =
+      SamplerState::new(Some(Click { beat, count_in: 0, tx: click_tx }), Duration::ZERO);
+    sampler.trigger_quantize = TriggerQuantize::Beat;
+    sampler.grid_origin = Some(Instant::now() + beat / 2);
+    let state: Arc<Mutex<SamplerState>> = Arc::new(Mutex::new(sampler));
+    let gen: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+    let (tx, rx): (mpsc::Sender<Command>, mpsc::Receiver<Command>) = mpsc::channel();
+
+    handle_trigger(&state, at(0), &gen, &tx, None);
+    assert!(rx.try_recv().is_err() && gen.load(Ordering::SeqCst) == 0); // the old loop plays on
+    assert!(matches!(rx.recv_timeout(Duration::from_secs(1)), Ok(Command::StartLoop)));
+    assert_eq!(gen.load(Ordering::SeqCst), 1);
+
+    lock(&state).grid_origin = Some(Instant::now() + beat / 2);
+    handle_trigger(&state, at(0), &gen, &tx, None);
+    handle_stop(&state, at(0), &gen, &tx);
+    assert!(matches!(rx.recv(), Ok(Command::Stop)));
+    assert!(rx.recv_timeout(beat * 3).is_err()); // cancelled
+    assert_eq!(gen.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn round_to_bars_rounds_to_whole_bars() {
+    let (click_tx, _click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let beat: Duration = Duration::from_millis(500);
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(Some(Click { beat, count_in: 0, tx: click_tx }), Duration::ZERO));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    state.round_to_bars = true;
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(1_000_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(4_600_000), 100); // 3.6s: 7.2 beats, nearly 2 bars
+    assert_eq!(state.loop_length, Duration::from_secs(4));
+  }
+
+  #[test]
+  fn loop_length_readout() {
+    let ms = Duration::from_millis;
+    let meter: Meter = Meter { beat: ms(500), beats_per_bar: 4 };
+    assert_eq!(describe_length(ms(1234), None, None), "1.234 s");
+    assert_eq!(describe_length(ms(8000), None, Some(meter)), "8.000 s = 4 bars at 120.0 BPM");
+    assert_eq!(describe_length(ms(3250), None, Some(meter)),
+               "3.250 s = 1 bar + 2.50 beats at 120.0 BPM");
+    assert_eq!(describe_length(ms(8000), Some(ms(7930)), Some(meter)),
+               "8.000 s = 4 bars at 120.0 BPM (rounded from 7.930 s, +0.070 s)");
+    assert_eq!(describe_length(ms(2000), Some(ms(2000)), None), "2.000 s");
+  }
+
+  #[test]
+  fn tempos_outside_1_to_999_bpm_are_refused() {
+    assert_eq!(beat_at(120.0), Some(Duration::from_millis(500)));
+    assert_eq!(beat_at(1.0), Some(Duration::from_secs(60)));
+    for bpm in [0.0, 0.5, 1e-300, 1000.0, 1e300, f64::NAN, f64::INFINITY, -120.0] {
+      assert_eq!(beat_at(bpm), None);
+    }
+  }
+
+  #[test]
+  fn clips_survive_a_save_and_load() {
+    let clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x90, 60, 100], offset: Duration::ZERO },
+      TimestampedMessage { data: vec![0x80, 60, 0], offset: Duration::from_millis(250) },
+    ];
+    let tempo: Option<Duration> = Some(Duration::from_millis(400)); // 150 BPM
+    let (events, end_tick): (Vec<TrackEvent>, u64) =
+      clip_to_track(&clip, Duration::from_millis(1600), tempo);
+    assert_eq!(end_tick, 4 * TICKS_PER_QUARTER as u64);
+    let mut file: Vec<u8> = Vec::new();
+    smf::write_format0_with_length(&mut file, TICKS_PER_QUARTER, &events, end_tick).unwrap();
+    let (loaded, loop_length): (Vec<TimestampedMessage>, Duration) =
+      clip_from_smf(&smf::read(&file[..]).unwrap());
+    assert_eq!(loop_length, Duration::from_millis(1600));
+    assert_eq!(loaded.iter().map(|m| (m.data.clone(), m.offset)).collect::<Vec<_>>(),
+               clip.iter().map(|m| (m.data.clone(), m.offset)).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn a_tempo_too_slow_for_the_file_is_clamped() {
+    let clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x90, 60, 100], offset: Duration::from_secs(30) },
+    ];
+    let tempo: Option<Duration> = Some(Duration::from_secs(60)); // 1 BPM
+    let (events, end_tick): (Vec<TrackEvent>, u64) =
+      clip_to_track(&clip, Duration::from_secs(120), tempo);
+    assert_eq!(events[0].event, Event::Tempo(MAX_TEMPO));
+    let mut file: Vec<u8> = Vec::new();
+    smf::write_format0_with_length(&mut file, TICKS_PER_QUARTER, &events, end_tick).unwrap();
+    let (loaded, loop_length): (Vec<TimestampedMessage>, Duration) =
+      clip_from_smf(&smf::read(&file[..]).unwrap());
+    let near = |a: Duration, b: Duration| a.abs_diff(b) < Duration::from_millis(50);
+    assert!(near(loop_length, Duration::from_secs(120)));
+    assert!(near(loaded[0].offset, Duration::from_secs(30)));
+  }
+
+  #[test]
+  fn clips_survive_a_text_dump() {
+    let clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x90, 60, 100], offset: Duration::ZERO },
+      TimestampedMessage { data: vec![0xF0, 0x7E, 0x7F, 0xF7], offset: Duration::from_micros(1500) },
+      TimestampedMessage { data: vec![0x80, 60, 0], offset: Duration::from_millis(250) },
+    ];
+    let text: String = clip_to_text(&clip, Duration::from_millis(1600));
+    assert_eq!(text, "loop 1600.000\n0.000 90 3C 64\n1.500 F0 7E 7F F7\n250.000 80 3C 00\n");
+    let (loaded, loop_length): (Vec<TimestampedMessage>, Duration) = clip_from_text(&text).unwrap();
+    assert_eq!(loop_length, Duration::from_millis(1600));
+    assert_eq!(loaded.iter().map(|m| (m.data.clone(), m.offset)).collect::<Vec<_>>(),
+               clip.iter().map(|m| (m.data.clone(), m.offset)).collect::<Vec<_>>());
+
+    // Edited by hand: out of order, commented, and no loop line.
+    let (edited, loop_length): (Vec<TimestampedMessage>, Duration) =
+      clip_from_text("# a fifth\n500 80 43 00\n\n0 90 43 64 # G\n").unwrap();
+    assert_eq!(edited.iter().map(|m| m.offset.as_millis()).collect::<Vec<_>>(), vec![0, 500]);
+    assert_eq!(loop_length, Duration::from_millis(500));
+    assert!(clip_from_text("0 3C 64").is_err());
+    assert!(clip_from_text("soon 90 3C 64").is_err());
+  }
+
+  #[test]
+  fn console_commands() {
+    let (tx_sample, rx_sample): (mpsc::Sender<Command>, mpsc::Receiver<Command>) = mpsc::channel();
+    let console: Console = Console {
+      clock: None,
+      state: Arc::new(Mutex::new(SamplerState::new(None, Duration::ZERO))),
+      gen: Arc::new(AtomicU64::new(0)),
+      tx_sample,
+      paused: Arc::new(AtomicBool::new(true)),
+      muted: Arc::new(std::array::from_fn(|_| AtomicBool::new(false))),
+    };
+    assert!(console.handle_line("bpm 90"));
+    assert_eq!(lock(&console.state).tempo, Some(Duration::from_secs_f64(60.0 / 90.0)));
+    assert!(console.handle_line("bpm fast"));
+    assert!(console.handle_line("bpm 0") && console.handle_line("bpm 1e9"));
+    assert_eq!(lock(&console.state).tempo, Some(Duration::from_secs_f64(60.0 / 90.0)));
+    assert!(console.handle_line("record"));
+    assert!(lock(&console.state).recording);
+    assert!(console.handle_line("  play "));
+    assert!(!lock(&console.state).recording);
+    assert!(!console.paused.load(Ordering::SeqCst));
+    assert!(matches!(rx_sample.try_recv(), Ok(Command::StartLoop)));
+    assert!(console.handle_line("load"));
+    assert!(console.handle_line("frobnicate"));
+    assert!(console.handle_line(""));
+    assert!(!console.handle_line("quit"));
+  }
+
+  #[test]
+  fn count_in_delays_capture() {
+    let (click_tx, click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let beat: Duration = Duration::from_millis(500);
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(Some(Click { beat, count_in: 4, tx: click_tx }), Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_normal_event(vec![0x90, 50, 100], at(990_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    assert!(matches!(click_rx.try_recv(), Ok(ClickCommand::Start(_))));
+    handle_normal_event(vec![0x90, 60, 100], at(2_000_000), &mut state, &tx);
+    assert!(state.clip.is_empty()); // no lookback, and still counting
+    handle_normal_event(vec![0x90, 62, 100], at(3_000_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 62, 0], at(3_250_000), &mut state, &tx);
+    assert_eq!(rx.try_iter().count(), 4); // everything still passes through
+    let offsets: Vec<Duration> = state.clip.iter().map(|m| m.offset).collect();
+    assert_eq!(offsets, vec![Duration::ZERO, Duration::from_millis(250)]);
+  }
+
+  #[test]
+  fn an_armed_take_begins_at_its_first_note() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    state.record_mode = RecordMode::WaitForNote;
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_normal_event(vec![0x90, 50, 100], at(990_000), &mut state, &tx); // no lookback
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    assert!(state.recording && state.record_start.is_none());
+    handle_normal_event(vec![0xB0, 1, 30], at(2_000_000), &mut state, &tx);
+    handle_normal_event(vec![0x90, 60, 100], at(3_000_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(3_500_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(4_000_000), 100);
+    assert_eq!(rx.try_iter().count(), 4); // everything passes through
+    assert_eq!(offsets_ms(&state.clip), vec![0, 500]);
+    assert_eq!(state.loop_length, Duration::from_millis(500));
+    // Stopped before any note, nothing is kept.
+    handle_record_toggle(&mut state, at(5_000_000), 100);
+    handle_normal_event(vec![0x80, 60, 0], at(5_500_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(6_000_000), 100);
+    assert!(state.clip.is_empty() && !state.recording);
+    // Immediate mode starts at the key, reaching back for nothing.
+    state.record_mode = RecordMode::Immediate;
+    handle_normal_event(vec![0x90, 50, 100], at(6_990_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(7_000_000), 100);
+    assert_eq!(state.record_start.map(|t| t.micros), Some(7_000_000));
+    assert!(state.clip.is_empty());
+  }
+
+  #[test]
+  fn two_taps_make_a_loop_as_long_as_the_gap() {
+    let mut sampler: SamplerState = SamplerState::new(None, Duration::from_millis(LOOKBACK_MS));
+    sampler.tap_loop = true;
+    sampler.trigger_quantize = TriggerQuantize::Beat; // taps don't wait for it
+    let state: Arc<Mutex<SamplerState>> = Arc::new(Mutex::new(sampler));
+    let gen: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+    let (tx, rx): (mpsc::Sender<Command>, mpsc::Receiver<Command>) = mpsc::channel();
+    let (tx_immediate, _rx_immediate): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
+      mpsc::channel();
+    let play = |data: Vec<u8>, micros: u64| {
+      handle_normal_event(data, at(micros), &mut lock(&state), &tx_immediate) };
+    play(vec![0x90, 50, 100], 990_000); // not caught: taps don't look back
+    handle_trigger(&state, at(1_000_000), &gen, &tx, None);
+    assert!(lock(&state).recording && rx.try_recv().is_err());
+    play(vec![0x90, 60, 100], 1_250_000);
+    play(vec![0x80, 60, 0], 1_500_000);
+    handle_trigger(&state, at(3_000_000), &gen, &tx, None);
+    assert!(matches!(rx.try_recv(), Ok(Command::StartLoop)));
+    let sampler: MutexGuard<SamplerState> = lock(&state);
+    assert!(!sampler.recording && sampler.first_tap.is_none());
+    assert_eq!(offsets_ms(&sampler.clip), vec![250, 500]);
+    assert_eq!(sampler.loop_length, Duration::from_secs(2)); // not cut at the last event
+  }
+
+  #[test]
+  fn only_a_hard_record_press_counts_in() {
+    let (click_tx, _click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let mutex: Mutex<SamplerState> = Mutex::new(SamplerState::new(Some(Click {
+      beat: Duration::from_millis(500), count_in: 4, tx: click_tx }), Duration::ZERO));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    state.record_hard_threshold = Some(100);
+    handle_record_toggle(&mut state, at(1_000_000), 100); // soft: starts now
+    assert_eq!(state.record_start.map(|t| t.micros), Some(1_000_000));
+    handle_record_toggle(&mut state, at(2_000_000), 127); // stops at any velocity
+    assert!(!state.recording);
+    handle_record_toggle(&mut state, at(3_000_000), 101); // hard: four beats later
+    assert_eq!(state.record_start.map(|t| t.micros), Some(5_000_000));
+  }
+
+  #[test]
+  fn stopping_during_count_in_abandons_the_take() {
+    let (click_tx, click_rx): (mpsc::Sender<ClickCommand>, mpsc::Receiver<ClickCommand>) =
+      mpsc::channel();
+    let mutex: Mutex<SamplerState> = Mutex::new(SamplerState::new(Some(Click {
+      beat: Duration::from_millis(500), count_in: 4, tx: click_tx }), Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_record_toggle(&mut state, at(1_500_000), 100);
+    assert!(!state.recording);
+    assert!(state.clip.is_empty());
+    assert_eq!(state.loop_length, Duration::ZERO);
+    assert_eq!(click_rx.try_iter().count(), 2); // start, then stop
+  }
+
+  #[test]
+  fn quantizing_pulls_late_events_to_the_end() {
+    let mut clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x90, 60, 100], offset: Duration::ZERO },
+      TimestampedMessage { data: vec![0x80, 60, 0], offset: Duration::from_millis(1100) },
+    ];
+    let length: Duration =
+      quantize_loop(&mut clip, Duration::from_millis(1200), Duration::from_millis(500));
+    assert_eq!(length, Duration::from_secs(1));
+    assert_eq!(clip[1].offset, Duration::from_secs(1));
+    assert_eq!(quantize_loop(&mut clip, Duration::from_millis(10), Duration::from_millis(500)),
+               Duration::from_millis(500));
+  }
+
+  #[test]
+  fn clear_empties_the_clip_and_stops_the_loop() {
+    let state: Arc<Mutex<SamplerState>> =
+      Arc::new(Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS))));
+    let gen: AtomicU64 = AtomicU64::new(3);
+    let (tx, rx): (mpsc::Sender<Command>, mpsc::Receiver<Command>) = mpsc::channel();
+    let (tx_immediate, _rx_immediate): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
+      mpsc::channel();
+    { let mut s: MutexGuard<SamplerState> = lock(&state);
+      handle_record_toggle(&mut s, at(1_000_000), 100);
+      handle_normal_event(vec![0x90, 60, 100], at(1_100_000), &mut s, &tx_immediate);
+      handle_record_toggle(&mut s, at(2_000_000), 100);
+      handle_record_toggle(&mut s, at(3_000_000), 100); // recording again
+      handle_normal_event(vec![0x90, 62, 100], at(3_100_000), &mut s, &tx_immediate);
+      assert_eq!(s.clip.len(), 1);
+    }
+    handle_clear(&state, &gen, &tx);
+    let s: MutexGuard<SamplerState> = lock(&state);
+    assert!(s.clip.is_empty() && !s.recording && s.record_start.is_none());
+    assert_eq!(s.loop_length, Duration::ZERO);
+    assert_eq!(gen.load(Ordering::SeqCst), 4);
+    assert!(matches!(rx.try_recv(), Ok(Command::Stop)));
+    assert_eq!(s.previous.as_ref().unwrap().0[0].data, vec![0x90, 60, 100]); // undo still has it
+  }
+
+  #[test]
+  fn control_notes_must_be_distinct() {
+    let args = |list: &[&str]| Args::new(list.iter().map(|a| a.to_string()).collect());
+    let controls: ControlNotes = ControlNotes::from_args(&args(&[])).unwrap();
+    assert_eq!((controls.stop, controls.record, controls.trigger, controls.pause),
+               (106, 107, 108, 105));
+    assert!(ControlNotes::from_args(&args(&["--pause-note", "108"])).is_err());
+    assert!(ControlNotes::from_args(&args(&["--pause-note", "104"])).is_ok());
+  }
+
+  fn clip_at(offsets_ms: &[u64]) -> Vec<TimestampedMessage> {
+    offsets_ms.iter().enumerate()
+      .map(|(i, &ms)| TimestampedMessage {
+        data: if i % 2 == 0 { vec![0x90, 60, 100] } else { vec![0x80, 60, 0] },
+        offset: Duration::from_millis(ms) })
+      .collect()
+  }
+
+  fn offsets_ms(clip: &[TimestampedMessage]) -> Vec<u64> {
+    clip.iter().map(|m| m.offset.as_millis() as u64).collect()
+  }
+
+  #[test]
+  fn trim_removes_lead_in_and_tail() {
+    let mut clip: Vec<TimestampedMessage> = clip_at(&[700, 900, 1000, 1300]);
+    assert_eq!(trim_clip(&mut clip, Duration::ZERO, None), Duration::from_millis(600));
+    assert_eq!(offsets_ms(&clip), vec![0, 200, 300, 600]);
+    let mut clip: Vec<TimestampedMessage> = clip_at(&[700, 900]);
+    assert_eq!(trim_clip(&mut clip, Duration::from_millis(250), None),
+               Duration::from_millis(450));
+  }
+
+  #[test]
+  fn trim_with_click_keeps_the_beat_grid() {
+    let beat: Option<Duration> = Some(Duration::from_millis(500));
+    let mut clip: Vec<TimestampedMessage> = clip_at(&[1100, 1400, 2300, 2600]);
+    assert_eq!(trim_clip(&mut clip, Duration::ZERO, beat), Duration::from_millis(2000));
+    assert_eq!(offsets_ms(&clip), vec![100, 400, 1300, 1600]);
+  }
+
+  #[test]
+  fn trim_applies_when_recording_stops() {
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    state.trim = Some(Duration::from_millis(100));
+    let (tx, _rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0x90, 60, 100], at(1_500_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(1_700_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(3_000_000), 100);
+    assert_eq!(offsets_ms(&state.clip), vec![0, 200]);
+    assert_eq!(state.loop_length, Duration::from_millis(300));
+  }
+
+  #[test]
+  fn quantized_note_pairs_move_together() {
+    let straight: Grid = Grid { step: Duration::from_millis(100), swing: 0.0 };
+    let mut clip: Vec<TimestampedMessage> = clip_at(&[130, 170, 280, 390]);
+    quantize_notes(&mut clip, straight, Duration::from_millis(800));
+    assert_eq!(offsets_ms(&clip), vec![100, 140, 300, 410]);
+    // Swing 0.5 puts odd steps 50ms late.
+    let swung: Grid = Grid { step: Duration::from_millis(100), swing: 0.5 };
+    let mut clip: Vec<TimestampedMessage> = clip_at(&[130, 170, 280, 390]);
+    quantize_notes(&mut clip, swung, Duration::from_millis(800));
+    assert_eq!(offsets_ms(&clip), vec![150, 190, 350, 460]);
+  }
+
+  #[test]
+  fn quantizing_keeps_the_clip_in_order_and_in_the_loop() {
+    let grid: Grid = Grid { step: Duration::from_millis(100), swing: 0.0 };
+    let mut clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x90, 60, 100], offset: Duration::from_millis(40) },
+      TimestampedMessage { data: vec![0xB0, 1, 9], offset: Duration::from_millis(20) },
+      TimestampedMessage { data: vec![0x90, 64, 100], offset: Duration::from_millis(380) },
+      TimestampedMessage { data: vec![0x80, 64, 0], offset: Duration::from_millis(395) },
+    ];
+    clip.sort_by_key(|m| m.offset);
+    quantize_notes(&mut clip, grid, Duration::from_millis(400));
+    assert_eq!(offsets_ms(&clip), vec![0, 20, 400, 400]);
+    assert_eq!(clip[0].data, vec![0x90, 60, 100]);
+  }
+
+  #[test]
+  fn crossfade_ramps_volume_around_the_seam() {
+    let ms = Duration::from_millis;
+    let clip: Vec<TimestampedMessage> = vec![
+      TimestampedMessage { data: vec![0x91, 60, 100], offset: ms(0) },
+      TimestampedMessage { data: vec![0xF0, 0x7E, 0xF7], offset: ms(10) },
+      TimestampedMessage { data: vec![0x81, 60, 0], offset: ms(500) },
+    ];
+    let channels: Vec<u8> = clip_channels(&clip);
+    assert_eq!(channels, vec![1]);
+    let faded: Vec<TimestampedMessage> = with_crossfade(clip, ms(1000), ms(160), &channels);
+    let volumes: Vec<(u128, u8)> = faded.iter()
+      .filter(|m| m.data[0] == 0xB1 && m.data[1] == CHANNEL_VOLUME)
+      .map(|m| (m.offset.as_millis(), m.data[2])).collect();
+    assert_eq!(volumes.len(), 2 * (CROSSFADE_STEPS as usize + 1));
+    assert_eq!(volumes.first(), Some(&(0, 0)));
+    assert_eq!(volumes.iter().find(|v| v.0 == 160), Some(&(160, 127)));
+    assert_eq!(volumes.iter().find(|v| v.0 == 840), Some(&(840, 127)));
+    assert_eq!(volumes.last(), Some(&(1000, 0)));
+    assert_eq!(faded[0].data, vec![0x91, 60, 100]); // the note goes before its ramp
+    assert!(faded.windows(2).all(|w| w[0].offset <= w[1].offset));
+    // Too long a fade is cut to half the loop.
+    let one: Vec<TimestampedMessage> = vec![TimestampedMessage { data: vec![0x91, 60, 100], offset: ms(0) }];
+    let halved: Vec<TimestampedMessage> = with_crossfade(one, ms(200), ms(500), &[1]);
+    assert!(halved.iter().any(|m| m.offset == ms(100) && m.data[2] == 127));
+  }
+
+  #[test]
+  fn humanized_passes_differ_but_keep_order_and_lengths() {
+    let ms = Duration::from_millis;
+    let clip: Vec<TimestampedMessage> =
+      [(0x90, 60, 0), (0x90, 64, 0), (0xB0, 64, 100), (0x80, 60, 200), (0x80, 64, 200),
+       (0x90, 67, 400), (0x80, 67, 700)]
+      .into_iter()
+      .map(|(status, data1, at)| TimestampedMessage {
+        data: vec![status, data1, 100], offset: ms(at) })
+      .collect();
+    let humanize: Humanize = Humanize { time: ms(20), velocity: 10 };
+    let mut rng: Rng = Rng::seeded(11);
+    let first: Vec<TimestampedMessage> = humanized_pass(&clip, ms(1000), humanize, &mut rng);
+    let second: Vec<TimestampedMessage> = humanized_pass(&clip, ms(1000), humanize, &mut rng);
+    assert_ne!(first.iter().map(|m| m.offset).collect::<Vec<Duration>>(),
+               second.iter().map(|m| m.offset).collect::<Vec<Duration>>());
+    for pass in [&first, &second] {
+      assert!(pass.windows(2).all(|w| w[0].offset <= w[1].offset));
+      for (moved, was) in pass.iter().zip(&clip) {
+        assert!(moved.offset.abs_diff(was.offset) <= ms(20));
+        assert_eq!(moved.data[..2], was.data[..2]);
+        if is_note_on(&was.data) {
+          assert!(moved.data[2].abs_diff(100) <= 10);
+        }
+      }
+      assert_eq!(pass[2].offset, ms(100)); // the CC stays put
+      let length: Duration = pass[6].offset - pass[5].offset;
+      assert!(length.abs_diff(ms(300)) < Duration::from_micros(1)); // and the lone note its length
+    }
+    assert_eq!(clip[0].data, vec![0x90, 60, 100]); // the clip itself is left alone
+  }
+
+  #[test]
+  fn synced_loop_follows_clock_ticks() {
+    // A one-beat loop (24 ticks) with events on ticks 0, 12 and 24.
+    let events: [f64; 3] = [0.0, 12.0, 24.0];
+    let mut place: Option<(u64, u64, usize)> = None;
+    assert_eq!(due_events(&mut place, 1, 0.0, &events, 24.0), (false, vec![0]));
+    assert_eq!(due_events(&mut place, 1, 11.9, &events, 24.0), (false, vec![]));
+    assert_eq!(due_events(&mut place, 1, 12.5, &events, 24.0), (false, vec![1]));
+    // Next lap: the loop-end event, then the top.
+    assert_eq!(due_events(&mut place, 1, 24.2, &events, 24.0), (false, vec![2, 0]));
+    // Start again: back to the top.
+    assert_eq!(due_events(&mut place, 2, 0.0, &events, 24.0), (true, vec![0]));
+    // Joining mid-loop skips what's gone by.
+    let mut joined: Option<(u64, u64, usize)> = None;
+    assert_eq!(due_events(&mut joined, 1, 30.0, &events, 24.0), (false, vec![]));
+    assert_eq!(due_events(&mut joined, 1, 36.0, &events, 24.0), (false, vec![1]));
+  }
+
+  #[test]
+  fn sysex_round_trips_through_record_and_playback() {
+    let sysex: Vec<u8> = vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+    let mutex: Mutex<SamplerState> =
+      Mutex::new(SamplerState::new(None, Duration::from_millis(LOOKBACK_MS)));
+    let mut state: MutexGuard<SamplerState> = lock(&mutex);
+    let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+    handle_record_toggle(&mut state, at(1_000_000), 100);
+    handle_normal_event(vec![0xC0, 5], at(1_000_000), &mut state, &tx);
+    handle_normal_event(sysex.clone(), at(1_001_000), &mut state, &tx);
+    handle_normal_event(vec![0x90, 60, 100], at(1_002_000), &mut state, &tx);
+    handle_normal_event(vec![0x80, 60, 0], at(1_003_000), &mut state, &tx);
+    handle_record_toggle(&mut state, at(1_004_000), 100);
+    assert_eq!(rx.try_iter().nth(1), Some(sysex.clone())); // passed through whole
+    assert!(state.recent_notes.iter().all(|(_, data)| is_note_event(data)));
+
+    let gen: AtomicU64 = AtomicU64::new(0);
+    let paused: AtomicBool = AtomicBool::new(false);
+    let muted: [AtomicBool; 16] = std::array::from_fn(|_| AtomicBool::new(false));
+    let lost: AtomicBool = AtomicBool::new(false);
+    let control: LoopControl =
+      LoopControl { gen: &gen, my_gen: 0, paused: &paused, muted: &muted, output_lost: &lost,
+                    check_every: Duration::from_millis(TRIGGER_SLEEP_MS) };
+    let mut out: StopAfter = StopAfter { sent: Vec::new(), count: 4, gen: &gen }; // one pass
+    play_loop(&copy_clip(&state), state.loop_length, Humanize::default(), &mut Rng::seeded(1),
+              &mut out, &control);
+    assert_eq!(out.sent, vec![vec![0xC0, 5], sysex, vec![0x90, 60, 100], vec![0x80, 60, 0]]);
+  }
+
+  #[test]
+  fn a_lost_output_stops_the_loop() {
+    let gen: AtomicU64 = AtomicU64::new(0);
+    let paused: AtomicBool = AtomicBool::new(false);
+    let muted: [AtomicBool; 16] = std::array::from_fn(|_| AtomicBool::new(false));
+    let lost: AtomicBool = AtomicBool::new(false);
+    let control: LoopControl =
+      LoopControl { gen: &gen, my_gen: 0, paused: &paused, muted: &muted, output_lost: &lost,
+                    check_every: Duration::from_millis(TRIGGER_SLEEP_MS) };
+    let clip: Vec<TimestampedMessage> = clip_at(&[0, 1, 2, 3]);
+    let mut unplugged: Unplugged = Unplugged(0);
+    let mut monitor: SendMonitor = SendMonitor::new();
+    let mut out: LoopOutput<Unplugged> =
+      LoopOutput { sink: &mut unplugged, monitor: &mut monitor, lost: &lost };
+    play_loop(&clip, Duration::from_millis(4), Humanize::default(), &mut Rng::seeded(1), &mut out,
+              &control);
+    assert_eq!(unplugged.0, FAILURES_BEFORE_WARNING as usize); // returned instead of looping on
+  }
+
+  #[test]
+  fn bends_and_pedals_are_put_back_each_pass() {
+    let gen: AtomicU64 = AtomicU64::new(0);
+    let paused: AtomicBool = AtomicBool::new(false);
+    let muted: [AtomicBool; 16] = std::array::from_fn(|_| AtomicBool::new(false));
+    let lost: AtomicBool = AtomicBool::new(false);
+    let control: LoopControl =
+      LoopControl { gen: &gen, my_gen: 0, paused: &paused, muted: &muted, output_lost: &lost,
+                    check_every: Duration::from_millis(TRIGGER_SLEEP_MS) };
+    let clip: Vec<TimestampedMessage> = [(vec![0xE1, 0x00, 0x50], 0), (vec![0xB1, 64, 127], 1),
+                                         (vec![0xB2, 64, 0], 2), (vec![0x91, 60, 100], 3)]
+      .into_iter()
+      .map(|(data, ms)| TimestampedMessage { data, offset: Duration::from_millis(ms) })
+      .collect();
+    // Stopped just as the second pass begins.
+    let mut out: StopAfter = StopAfter { sent: Vec::new(), count: 6, gen: &gen };
+    play_loop(&clip, Duration::from_millis(5), Humanize::default(), &mut Rng::seeded(1), &mut out,
+              &control);
+    assert_eq!(out.sent[4..], [vec![0xE1, 0x00, 0x40], vec![0xB1, 64, 0], vec![0x81, 60, 0]]);
+  }
+
+  #[test]
+  fn muting_silences_at_once_and_unmuting_waits_for_the_pass() {
+    let gen: AtomicU64 = AtomicU64::new(0);
+    let paused: AtomicBool = AtomicBool::new(false);
+    let muted: [AtomicBool; 16] = std::array::from_fn(|_| AtomicBool::new(false));
+    let lost: AtomicBool = AtomicBool::new(false);
+    let control: LoopControl =
+      LoopControl { gen: &gen, my_gen: 0, paused: &paused, muted: &muted, output_lost: &lost,
+                    check_every: Duration::from_millis(TRIGGER_SLEEP_MS) };
+    let mut mutes: PassMutes = PassMutes::default();
+    let mut active_notes: ActiveNotes = HashMap::from([
+      ((1, 60), Sounding { velocity: 100, count: 1 }),
+      ((2, 64), Sounding { velocity: 90, count: 1 }),
+    ]);
+    let mut sent: Vec<Vec<u8>> = Vec::new();
+    mutes.start_pass(&control);
+    muted[1].store(true, Ordering::SeqCst);
+    mutes.catch_up(&control, &mut sent, &mut active_notes, &HashMap::new());
+    assert_eq!(sent, vec![vec![0x81, 60, 0]]);
+    assert!(active_notes.contains_key(&(2, 64)) && active_notes.len() == 1);
+    assert!(!mutes.allows(&[0x91, 62, 100]));
+    assert!(mutes.allows(&[0x92, 62, 100]) && mutes.allows(&[0xF0, 0x7E, 0xF7]));
+    muted[1].store(false, Ordering::SeqCst);
+    mutes.catch_up(&control, &mut sent, &mut active_notes, &HashMap::new());
+    assert!(!mutes.allows(&[0x91, 62, 100])); // still this pass
+    mutes.start_pass(&control);
+    assert!(mutes.allows(&[0x91, 62, 100]));
+  }
+
+  #[test]
+  fn nested_strikes_of_one_key_are_released_once_each() {
+    let mut active_notes: ActiveNotes = HashMap::new();
+    let mut sent: Vec<Vec<u8>> = Vec::new();
+    for data in messages("90 3C 64, 90 3C 50, 80 3C 00") {
+      play_event(&data, &mut sent, &mut active_notes, false);
+    }
+    // The second note-on's note is still held.
+    assert_eq!(sent, messages("90 3C 64, 90 3C 50"));
+    assert_eq!(active_notes[&(0, 60)], Sounding { velocity: 0x50, count: 1 });
+    play_event(&[0x80, 60, 0], &mut sent, &mut active_notes, false);
+    assert_eq!(sent.last(), Some(&vec![0x80, 60, 0]));
+    assert!(active_notes.is_empty());
+    play_event(&[0x80, 60, 0], &mut sent, &mut active_notes, false); // a stray one passes
+    assert_eq!(sent.len(), 4);
+
+    // Cut off mid-trill, each strike gets its note-off.
+    for data in messages("90 3E 64, 90 3E 64") {
+      play_event(&data, &mut sent, &mut active_notes, false);
+    }
+    let mut offs: Vec<Vec<u8>> = Vec::new();
+    send_all_notes_off(&mut offs, &active_notes, &HashMap::new());
+    assert_eq!(offs, messages("80 3E 00, 80 3E 00"));
+  }
+
+  #[test]
+  fn zero_timestamps_fall_back_to_instants() {
+    let start: EventTime = at(0);
+    let later: EventTime = EventTime { micros: 0, instant: start.instant + Duration::from_millis(7) };
+    assert_eq!(later.since(&start), Duration::from_millis(7));
   }
-  let status: u8 = data[0] & 0xF0;
-  status == 0x80 || status == 0x90
 }
@@ -0,0 +1,224 @@
+//! Smooth - glides chosen CCs between the values they're sent
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin smooth -- --cc 1,74 --glide-ms 80
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "smooth-out".
+//! When one of the chosen CCs jumps, the output moves from wherever it
+//! is now to the new value over `--glide-ms`, one step at a time,
+//! instead of leaping there. Each channel glides separately.
+//! The first value a CC receives is sent at once, since there's nothing
+//! to glide from. Other CCs and all other messages pass straight through.
+//!
+//! Flags:
+//! - `--cc <n,...>`: the controllers to smooth (default 1)
+//! - `--glide-ms <ms>`: how long each glide takes (default 80)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_list, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{control_change, get_channel, CONTROL_CHANGE};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const TIMER_CHECK_MS: u64 = 1;
+
+struct Glide {
+  from: f64,
+  target: u8,
+  start: Instant,
+  sent: u8, // the last value actually sent
+}
+
+impl Glide {
+  fn position(&self, now: Instant, length: Duration) -> f64 {
+    let progress: f64 =
+      (now.saturating_duration_since(self.start).as_secs_f64() / length.as_secs_f64()).min(1.0);
+    self.from + (self.target as f64 - self.from) * progress
+  }
+}
+
+struct SmoothState {
+  ccs: Vec<u8>,
+  glide: Duration,
+  glides: HashMap<(u8, u8), Glide>, // (channel, cc)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let ccs: Vec<u8> = parse_list("--cc", args.value("--cc").unwrap_or("1"))?;
+  if ccs.iter().any(|&cc| cc > 127) {
+    return Err("--cc must be in 0-127".into());
+  }
+  let glide: Duration = Duration::from_millis(args.parse_or("--glide-ms", 80)?);
+  if glide.is_zero() {
+    return Err("--glide-ms must be at least 1".into());
+  }
+
+  let midi_in: MidiInput = MidiInput::new("smooth-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("smooth-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "smooth-out")?;
+  self_test(&args, &mut conn_out, "smooth-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let state: Arc<Mutex<SmoothState>> = Arc::new(Mutex::new(SmoothState {
+    ccs: ccs.clone(), glide, glides: HashMap::new() }));
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<SmoothState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<Vec<u8>> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_timer_thread(state_for_timer, tx_for_timer, running_for_timer)
+  });
+
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let mut state = lock(&state);
+      if let Some(msg) = handle_message(&mut state, Instant::now(), message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  println!("Smoothing started! CCs {:?} over {:?}", ccs, glide);
+  println!("Ports: 'smooth-in:midi-in' (input), 'smooth-out:smooth-out' (output)");
+  auto_connect(&args, Some("smooth-in:midi-in"), Some("smooth-out:smooth-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<SmoothState>>,
+  tx: mpsc::Sender<Vec<u8>>,
+  running: Arc<AtomicBool>,
+) {
+  while running.load(Ordering::SeqCst) {
+    let due: Vec<Vec<u8>> = {
+      let mut state = lock(&state);
+      due_messages(&mut state, Instant::now())
+    };
+    for msg in due {
+      if tx.send(msg).is_err() {
+        return;
+      }
+    }
+    thread::sleep(Duration::from_millis(TIMER_CHECK_MS));
+  }
+}
+
+/// A smoothed CC sets a new target, gliding from wherever the output
+/// is now; everything else is returned to be sent as it is.
+fn handle_message(state: &mut SmoothState, now: Instant, message: &[u8]) -> Option<Vec<u8>> {
+  let channel: u8 = match get_channel(message) {
+    Some(c) if message[0] & 0xF0 == CONTROL_CHANGE && message.len() >= 3
+      && state.ccs.contains(&message[1]) => c,
+    _ => return Some(message.to_vec()),
+  };
+  let (cc, value): (u8, u8) = (message[1], message[2]);
+  let length: Duration = state.glide;
+  match state.glides.get_mut(&(channel, cc)) {
+    Some(glide) => {
+      glide.from = glide.position(now, length);
+      glide.target = value;
+      glide.start = now;
+      None
+    }
+    None => {
+      state.glides.insert((channel, cc), Glide { from: value as f64, target: value, start: now, sent: value });
+      Some(message.to_vec())
+    }
+  }
+}
+
+/// A CC message for each glide whose rounded value has moved since it was last sent.
+fn due_messages(state: &mut SmoothState, now: Instant) -> Vec<Vec<u8>> {
+  let length: Duration = state.glide;
+  let mut out: Vec<Vec<u8>> = vec![];
+  for (&(channel, cc), glide) in state.glides.iter_mut() {
+    let value: u8 = glide.position(now, length).round() as u8;
+    if value != glide.sent {
+      glide.sent = value;
+      out.push(control_change(channel, cc, value));
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> SmoothState {
+    SmoothState { ccs: vec![74], glide: Duration::from_millis(100), glides: HashMap::new() }
+  }
+
+  fn ms(t0: Instant, ms: u64) -> Instant {
+    t0 + Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn glides_to_the_new_value() {
+    let mut s: SmoothState = state();
+    let t0: Instant = Instant::now();
+    assert_eq!(handle_message(&mut s, t0, &[0xB0, 74, 0]), Some(vec![0xB0, 74, 0]));
+    assert_eq!(handle_message(&mut s, t0, &[0xB0, 74, 100]), None);
+    assert_eq!(due_messages(&mut s, ms(t0, 50)), vec![vec![0xB0, 74, 50]]);
+    assert!(due_messages(&mut s, ms(t0, 50)).is_empty());
+    assert_eq!(due_messages(&mut s, ms(t0, 100)), vec![vec![0xB0, 74, 100]]);
+    assert!(due_messages(&mut s, ms(t0, 300)).is_empty());
+  }
+
+  #[test]
+  fn a_new_target_glides_from_where_it_is() {
+    let mut s: SmoothState = state();
+    let t0: Instant = Instant::now();
+    handle_message(&mut s, t0, &[0xB2, 74, 0]);
+    handle_message(&mut s, t0, &[0xB2, 74, 100]);
+    handle_message(&mut s, ms(t0, 50), &[0xB2, 74, 0]); // turned back halfway
+    assert_eq!(due_messages(&mut s, ms(t0, 50)), vec![vec![0xB2, 74, 50]]);
+    assert_eq!(due_messages(&mut s, ms(t0, 100)), vec![vec![0xB2, 74, 25]]);
+  }
+
+  #[test]
+  fn other_messages_pass_through() {
+    let mut s: SmoothState = state();
+    let t0: Instant = Instant::now();
+    assert_eq!(handle_message(&mut s, t0, &[0xB0, 1, 64]), Some(vec![0xB0, 1, 64]));
+    assert_eq!(handle_message(&mut s, t0, &[0x90, 74, 64]), Some(vec![0x90, 74, 64]));
+    assert_eq!(handle_message(&mut s, t0, &[0xF8]), Some(vec![0xF8]));
+  }
+}
@@ -0,0 +1,247 @@
+//! Snap - moves every incoming note to the nearest note of a scale
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin snap -- --scale minor --root A
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "snap-out".
+//! Non-note messages pass through unchanged.
+//!
+//! Flags:
+//! - `--scale <scale>`: major, minor, harmonic-minor, pentatonic,
+//!   chromatic, or a comma-separated list of pitch classes
+//!   relative to the root, like `0,3,5,7,10` (default major)
+//! - `--root <pitch class>`: a name like `C`, `F#` or `Bb`,
+//!   or a number 0-11 (default C)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+//!
+//! A note exactly between two scale notes snaps down.
+//! Two keys can snap to the same note; that note is turned off
+//! only once both keys are released.
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on, note_off, note_on};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+const PITCH_CLASS_NAMES: [&str; 12] =
+  ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+struct SnapState {
+  // pitch classes 0-11 (absolute, root already applied)
+  allowed: [bool; 12],
+  // (channel, input note) -> output note its note-on was sent as
+  ongoing_notes: HashMap<(u8, u8), u8>,
+  // (channel, output note) -> how many held input notes produce it
+  sounding: HashMap<(u8, u8), u32>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let scale: Vec<u8> = parse_scale(args.value("--scale").unwrap_or("major"))?;
+  let root: u8 = parse_pitch_class(args.value("--root").unwrap_or("C"))?;
+  let mut allowed: [bool; 12] = [false; 12];
+  for pc in &scale {
+    allowed[((pc + root) % 12) as usize] = true;
+  }
+
+  let midi_in: MidiInput = MidiInput::new("snap-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("snap-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "snap-out")?;
+  self_test(&args, &mut conn_out, "snap-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let state: SnapState = SnapState {
+    allowed,
+    ongoing_notes: HashMap::new(),
+    sounding: HashMap::new(),
+  };
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<SnapState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut SnapState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    state,
+  )?;
+
+  let names: Vec<&str> = (0..12)
+    .filter(|&pc| allowed[pc])
+    .map(|pc| PITCH_CLASS_NAMES[pc])
+    .collect();
+  println!("Scale quantizer started!");
+  println!("  allowed pitch classes: {}", names.join(" "));
+  println!("Ports: 'snap-in:midi-in' (input), 'snap-out:snap-out' (output)");
+  auto_connect(&args, Some("snap-in:midi-in"), Some("snap-out:snap-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_scale(text: &str) -> Result<Vec<u8>, String> {
+  let scale: Vec<u8> = match text {
+    "major" => vec![0, 2, 4, 5, 7, 9, 11],
+    "minor" => vec![0, 2, 3, 5, 7, 8, 10],
+    "harmonic-minor" => vec![0, 2, 3, 5, 7, 8, 11],
+    "pentatonic" => vec![0, 2, 4, 7, 9],
+    "chromatic" => (0..12).collect(),
+    _ => text.split(',')
+      .map(|pc| parse_value::<u8>("--scale", pc))
+      .collect::<Result<Vec<u8>, String>>()?,
+  };
+  if scale.is_empty() || scale.iter().any(|&pc| pc > 11) {
+    return Err(format!("--scale needs pitch classes in 0-11, got '{}'", text));
+  }
+  Ok(scale)
+}
+
+fn parse_pitch_class(text: &str) -> Result<u8, String> {
+  if let Ok(n) = text.parse::<u8>() {
+    if n < 12 {
+      return Ok(n);
+    }
+  }
+  let mut chars = text.chars();
+  let letter: i8 = match chars.next().map(|c| c.to_ascii_uppercase()) {
+    Some('C') => 0, Some('D') => 2, Some('E') => 4, Some('F') => 5,
+    Some('G') => 7, Some('A') => 9, Some('B') => 11,
+    _ => return Err(format!("bad value for --root: '{}'", text)),
+  };
+  let accidental: i8 = match chars.as_str() {
+    "" => 0,
+    "#" => 1,
+    "b" => -1,
+    _ => return Err(format!("bad value for --root: '{}'", text)),
+  };
+  Ok((letter + accidental).rem_euclid(12) as u8)
+}
+
+/// The nearest note (searching down first) whose pitch class is allowed.
+fn snap(allowed: &[bool; 12], note: u8) -> Option<u8> {
+  for distance in 0..12i16 {
+    for candidate in [note as i16 - distance, note as i16 + distance] {
+      if (0..=127).contains(&candidate) && allowed[(candidate % 12) as usize] {
+        return Some(candidate as u8);
+      }
+    }
+  }
+  None
+}
+
+fn transform_message(state: &mut SnapState, message: &[u8]) -> Vec<Vec<u8>> {
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![message.to_vec()],
+    };
+  let velocity: u8 = message[2];
+  let mut results: Vec<Vec<u8>> = vec![];
+  if is_note_on(message) {
+    if let Some(old) = state.ongoing_notes.remove(&(channel, note)) {
+      // Retriggered before release; forget the earlier press.
+      release(state, channel, old, 0, &mut results);
+    }
+    if let Some(output) = snap(&state.allowed, note) {
+      state.ongoing_notes.insert((channel, note), output);
+      *state.sounding.entry((channel, output)).or_insert(0) += 1;
+      results.push(note_on(channel, output, velocity));
+    }
+  } else if is_note_off(message) {
+    if let Some(output) = state.ongoing_notes.remove(&(channel, note)) {
+      release(state, channel, output, velocity, &mut results);
+    }
+  }
+  results
+}
+
+/// Sends a note-off for `output` once nothing else holds it.
+fn release(
+  state: &mut SnapState,
+  channel: u8,
+  output: u8,
+  velocity: u8,
+  results: &mut Vec<Vec<u8>>,
+) {
+  let count: &mut u32 = state.sounding.entry((channel, output)).or_insert(1);
+  *count -= 1;
+  if *count == 0 {
+    state.sounding.remove(&(channel, output));
+    results.push(note_off(channel, output, velocity));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(scale: &[u8], root: u8) -> SnapState {
+    let mut allowed: [bool; 12] = [false; 12];
+    for pc in scale {
+      allowed[((pc + root) % 12) as usize] = true;
+    }
+    SnapState { allowed, ongoing_notes: HashMap::new(), sounding: HashMap::new() }
+  }
+
+  #[test]
+  fn snaps_to_nearest_scale_note() {
+    let s: SnapState = state(&parse_scale("major").unwrap(), 0);
+    assert_eq!(snap(&s.allowed, 60), Some(60));
+    assert_eq!(snap(&s.allowed, 61), Some(60)); // tie snaps down
+    assert_eq!(snap(&s.allowed, 66), Some(65));
+    let pent: SnapState = state(&parse_scale("pentatonic").unwrap(), 0);
+    assert_eq!(snap(&pent.allowed, 65), Some(64));
+    assert_eq!(snap(&pent.allowed, 66), Some(67));
+  }
+
+  #[test]
+  fn root_and_custom_scales() {
+    assert_eq!(parse_pitch_class("Bb"), Ok(10));
+    assert_eq!(parse_pitch_class("c#"), Ok(1));
+    assert_eq!(parse_pitch_class("7"), Ok(7));
+    assert!(parse_pitch_class("H").is_err());
+    assert_eq!(parse_scale("0,3,7"), Ok(vec![0, 3, 7]));
+    assert!(parse_scale("0,12").is_err());
+  }
+
+  #[test]
+  fn shared_output_note_is_released_once_both_keys_are_up() {
+    let mut s: SnapState = state(&parse_scale("major").unwrap(), 0);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 100]), vec![vec![0x90, 60, 100]]);
+    assert_eq!(transform_message(&mut s, &[0x90, 61, 90]), vec![vec![0x90, 60, 90]]);
+    assert!(transform_message(&mut s, &[0x80, 60, 0]).is_empty());
+    assert_eq!(transform_message(&mut s, &[0x80, 61, 0]), vec![vec![0x80, 60, 0]]);
+    assert_eq!(transform_message(&mut s, &[0xB0, 64, 127]), vec![vec![0xB0, 64, 127]]);
+  }
+}
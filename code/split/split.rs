@@ -0,0 +1,275 @@
+//! Split - sends each note to an output port chosen by its pitch
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin split -- --zone 0:59:bass:-12 --zone 60:127:lead
+//! ```
+//!
+//! Creates a virtual input "midi-in" and, for each zone,
+//! a virtual output "<name>-out" (client "split-<name>").
+//! A note goes to every zone whose range (inclusive) contains it,
+//! so overlapping zones layer. A zone may also transpose its notes by
+//! a number of semitones; notes pushed outside 0-127 are dropped.
+//! Non-note messages go to every output, or only to the zone named
+//! by `--default <name>`.
+//!
+//! While running, typing a new set of zones (like `0:47:bass:-24 48:127:lead`)
+//! moves the boundaries and transpositions; the names must be ones given
+//! at startup. A note is always released on the ports, and at the pitches,
+//! its note-on went to, even if the zones have changed since.
+//! An empty line exits.
+//!
+//! Flags:
+//! - `--zone <low>:<high>:<name>[:<semitones>]`: repeatable, at least one
+//! - `--default <name>`: where non-note messages go
+//! - `--input-port`, `--connect-from`, `--list-ports`, `--self-test`,
+//!   `--zero-release-velocity`: see `midi_utils::ports`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midir::os::unix::VirtualOutput;
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, self_test, SendMonitor};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on, note_off};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{io, thread};
+
+#[derive(Clone, Debug, PartialEq)]
+struct Zone {
+  low: u8,
+  high: u8,
+  name: String,
+  transpose: i8,
+}
+
+struct SplitState {
+  zones: Vec<Zone>, // index = output port index
+  default_port: Option<usize>,
+  // (channel, input note) -> the ports and output notes its note-on went to
+  ongoing_notes: HashMap<(u8, u8), Vec<(usize, u8)>>,
+}
+
+/// A message and the index of the output port it should go to.
+type Routed = (usize, Vec<u8>);
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let zones: Vec<Zone> = args.values("--zone").iter()
+    .map(|z| parse_zone(z))
+    .collect::<Result<Vec<Zone>, String>>()?;
+  if zones.is_empty() {
+    return Err("give at least one --zone <low>:<high>:<name>[:<semitones>]".into());
+  }
+  let default_port: Option<usize> = match args.value("--default") {
+    None => None,
+    Some(name) => Some(zones.iter().position(|z| z.name == name)
+                       .ok_or(format!("--default '{}' is not a zone", name))?),
+  };
+
+  let zero_release: bool = args.flag("--zero-release-velocity");
+  let mut conns: Vec<ReleaseVelocity<MidiOutputConnection>> = Vec::new();
+  for zone in &zones {
+    let midi_out: MidiOutput = MidiOutput::new(&format!("split-{}", zone.name))?;
+    let mut conn: MidiOutputConnection = midi_out.create_virtual(&format!("{}-out", zone.name))?;
+    self_test(&args, &mut conn, &format!("{}-out", zone.name));
+    conns.push(ReleaseVelocity { sink: conn, zero: zero_release });
+  }
+  let (tx, rx): (mpsc::Sender<Routed>, mpsc::Receiver<Routed>) = mpsc::channel();
+  let _out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || run_output_thread(conns, rx));
+
+  let state: Arc<Mutex<SplitState>> = Arc::new(Mutex::new(SplitState {
+    zones: zones.clone(),
+    default_port,
+    ongoing_notes: HashMap::new(),
+  }));
+  let state_for_callback: Arc<Mutex<SplitState>> = Arc::clone(&state);
+  let midi_in: MidiInput = MidiInput::new("split-in")?;
+  let _conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let mut state = lock(&state_for_callback);
+      for routed in route_message(&mut state, message) {
+        let _ = tx.send(routed);
+      }
+    },
+    (),
+  )?;
+
+  println!("Splitter started!");
+  for zone in &zones {
+    println!("  - notes {}-{} -> 'split-{}:{}-out', transposed {}",
+             zone.low, zone.high, zone.name, zone.name, zone.transpose);
+  }
+  auto_connect(&args, Some("split-in:midi-in"), None); // one output per zone
+  println!("Type new zones to move the boundaries, or press Enter to exit...");
+
+  loop {
+    let mut input: String = String::new();
+    io::stdin().read_line(&mut input)?;
+    if input.trim().is_empty() {
+      break;
+    }
+    let mut state = lock(&state);
+    match rezone(&mut state.zones, &input) {
+      Ok(()) => println!("[split] Zones: {:?}", state.zones),
+      Err(e) => println!("[split] {}", e),
+    }
+  }
+
+  Ok(())
+}
+
+fn run_output_thread(
+  mut conns: Vec<ReleaseVelocity<MidiOutputConnection>>,
+  rx: mpsc::Receiver<Routed>)
+  { let mut monitors: Vec<SendMonitor> = conns.iter().map(|_| SendMonitor::new()).collect();
+    while let Ok((port, data)) = rx.recv()
+      { monitors[port].send(&mut conns[port], &data); }}
+
+fn parse_zone(text: &str) -> Result<Zone, String> {
+  let parts: Vec<&str> = text.split(':').collect();
+  if !(3..=4).contains(&parts.len()) || parts[2].is_empty() {
+    return Err(format!("bad zone '{}', expected <low>:<high>:<name>[:<semitones>]", text));
+  }
+  let low: u8 = parse_value("--zone", parts[0])?;
+  let high: u8 = parse_value("--zone", parts[1])?;
+  if low > high || high > 127 {
+    return Err(format!("bad zone range in '{}'", text));
+  }
+  let transpose: i8 = match parts.get(3) {
+    Some(t) => parse_value("--zone", t)?,
+    None => 0,
+  };
+  Ok(Zone { low, high, name: parts[2].to_string(), transpose })
+}
+
+/// Replaces the ranges of the named zones. Ports can't be added live,
+/// so every name must already exist.
+fn rezone(zones: &mut [Zone], line: &str) -> Result<(), String> {
+  let new_zones: Vec<Zone> = line.split_whitespace()
+    .map(parse_zone)
+    .collect::<Result<Vec<Zone>, String>>()?;
+  for new in &new_zones {
+    if !zones.iter().any(|z| z.name == new.name) {
+      return Err(format!("no zone named '{}'", new.name));
+    }
+  }
+  for new in new_zones {
+    if let Some(zone) = zones.iter_mut().find(|z| z.name == new.name) {
+      *zone = new;
+    }
+  }
+  Ok(())
+}
+
+fn route_message(state: &mut SplitState, message: &[u8]) -> Vec<Routed> {
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) if is_note_event(message) && message.len() >= 3 => (n, c),
+      _ => {
+        let ports: Vec<usize> = match state.default_port {
+          Some(port) => vec![port],
+          None => (0..state.zones.len()).collect(),
+        };
+        return ports.into_iter().map(|p| (p, message.to_vec())).collect();
+      }
+    };
+  let targets: Vec<(usize, u8)> = if is_note_on(message) {
+    let targets: Vec<(usize, u8)> = state.zones.iter().enumerate()
+      .filter(|(_, z)| (z.low..=z.high).contains(&note))
+      .filter_map(|(i, z)| transpose_note(note, z.transpose).map(|n| (i, n)))
+      .collect();
+    if let Some(old) = state.ongoing_notes.insert((channel, note), targets.clone()) {
+      // Retriggered before release: make sure the old notes let go.
+      let stale: Vec<Routed> = old.into_iter()
+        .filter(|t| !targets.contains(t))
+        .map(|(p, n)| (p, note_off(channel, n, 0)))
+        .collect();
+      return stale.into_iter()
+        .chain(targets.into_iter().map(|(p, n)| (p, with_note(message, n))))
+        .collect();
+    }
+    targets
+  } else if is_note_off(message) {
+    state.ongoing_notes.remove(&(channel, note)).unwrap_or_default()
+  } else {
+    vec![]
+  };
+  targets.into_iter().map(|(p, n)| (p, with_note(message, n))).collect()
+}
+
+/// None if the result would be outside 0-127.
+fn transpose_note(note: u8, semitones: i8) -> Option<u8> {
+  let shifted: i16 = note as i16 + semitones as i16;
+  (0..=127).contains(&shifted).then_some(shifted as u8)
+}
+
+fn with_note(message: &[u8], note: u8) -> Vec<u8> {
+  let mut out: Vec<u8> = message.to_vec();
+  out[1] = note;
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> SplitState {
+    SplitState {
+      zones: vec![parse_zone("0:59:bass").unwrap(), parse_zone("60:127:lead").unwrap()],
+      default_port: None,
+      ongoing_notes: HashMap::new(),
+    }
+  }
+
+  #[test]
+  fn routes_by_range_and_broadcasts_the_rest() {
+    let mut s: SplitState = state();
+    assert_eq!(route_message(&mut s, &[0x90, 40, 100]), vec![(0, vec![0x90, 40, 100])]);
+    assert_eq!(route_message(&mut s, &[0x90, 60, 100]), vec![(1, vec![0x90, 60, 100])]);
+    assert_eq!(route_message(&mut s, &[0xB0, 64, 127]),
+               vec![(0, vec![0xB0, 64, 127]), (1, vec![0xB0, 64, 127])]);
+    s.default_port = Some(1);
+    assert_eq!(route_message(&mut s, &[0xB0, 64, 0]), vec![(1, vec![0xB0, 64, 0])]);
+  }
+
+  #[test]
+  fn note_off_follows_note_on_after_rezoning() {
+    let mut s: SplitState = state();
+    route_message(&mut s, &[0x90, 55, 100]);
+    rezone(&mut s.zones, "0:47:bass 48:127:lead").unwrap();
+    assert_eq!(route_message(&mut s, &[0x80, 55, 0]), vec![(0, vec![0x80, 55, 0])]);
+    assert_eq!(route_message(&mut s, &[0x90, 55, 100]), vec![(1, vec![0x90, 55, 100])]);
+    assert!(rezone(&mut s.zones, "0:10:drums").is_err());
+  }
+
+  #[test]
+  fn zones_transpose_and_releases_follow() {
+    let mut s: SplitState = state();
+    rezone(&mut s.zones, "0:59:bass:-12").unwrap();
+    assert_eq!(route_message(&mut s, &[0x90, 40, 100]), vec![(0, vec![0x90, 28, 100])]);
+    rezone(&mut s.zones, "0:59:bass:-24").unwrap();
+    assert_eq!(route_message(&mut s, &[0x90, 40, 0]), vec![(0, vec![0x90, 28, 0])]);
+    // Retriggered after a change: the old pitch is released.
+    route_message(&mut s, &[0x90, 41, 100]);
+    rezone(&mut s.zones, "0:59:bass:-12").unwrap();
+    assert_eq!(route_message(&mut s, &[0x90, 41, 90]),
+               vec![(0, vec![0x80, 17, 0]), (0, vec![0x90, 29, 90])]);
+    // Pushed out of range: dropped, and so is its release.
+    assert!(route_message(&mut s, &[0x90, 5, 100]).is_empty());
+    assert!(route_message(&mut s, &[0x80, 5, 0]).is_empty());
+    assert_eq!(parse_zone("60:127:lead:+7").unwrap().transpose, 7);
+    assert!(parse_zone("60:127:lead:x").is_err());
+  }
+}
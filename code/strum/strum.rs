@@ -0,0 +1,291 @@
+//! Strum - spreads the notes of a chord out in time, like a strummed guitar
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin strum -- --spread-ms 25 --direction down
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "strum-out".
+//! Note-ons arriving within `--window-ms` of the first one form a chord.
+//! When the window closes the chord is played in pitch order, one note
+//! every `--spread-ms`, from the bottom (`--direction up`) or the top.
+//! The window is counted from the chord's first note and never extended,
+//! so a fast run is cut into short chords rather than held back.
+//! Every note waits for its window, so keep it short.
+//!
+//! Note-offs pass through when they arrive, except that one never goes
+//! out before its own note-on. Other messages pass straight through.
+//!
+//! Flags:
+//! - `--spread-ms <ms>`: time between strummed notes (default 20)
+//! - `--window-ms <ms>`: how long to gather a chord (default 15)
+//! - `--direction up|down` (default up)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::delay::{run_delay_thread, DelayedMessage};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test,
+                        silence_after, wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::sync::lock;
+use midi_utils::{get_channel, get_note, is_note_off, is_note_on};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+const TIMER_CHECK_MS: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+  Up,
+  Down,
+}
+
+impl FromStr for Direction {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "up" => Ok(Direction::Up),
+      "down" => Ok(Direction::Down),
+      _ => Err("expected up or down".to_string()),
+    }
+  }
+}
+
+/// Note-ons still being gathered, and note-offs that came in before
+/// their note-ons went out.
+struct Chord {
+  start: Instant,
+  notes: Vec<Vec<u8>>,
+  offs: Vec<Vec<u8>>,
+}
+
+struct StrumState {
+  spread: Duration,
+  window: Duration,
+  direction: Direction,
+  chord: Option<Chord>,
+  // (channel, note) -> when its note-on goes out
+  scheduled: HashMap<(u8, u8), Instant>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let spread: Duration = Duration::from_millis(args.parse_or("--spread-ms", 20)?);
+  let window: Duration = Duration::from_millis(args.parse_or("--window-ms", 15)?);
+  let direction: Direction = args.parse_or("--direction", Direction::Up)?;
+
+  let midi_in: MidiInput = MidiInput::new("strum-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("strum-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "strum-out")?;
+  self_test(&args, &mut conn_out, "strum-out");
+  let (tx, rx): (mpsc::Sender<DelayedMessage>, mpsc::Receiver<DelayedMessage>) =
+    mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || run_delay_thread(conn_out, rx));
+
+  let state: Arc<Mutex<StrumState>> = Arc::new(Mutex::new(StrumState {
+    spread, window, direction, chord: None, scheduled: HashMap::new() }));
+  let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+  let state_for_timer: Arc<Mutex<StrumState>> = Arc::clone(&state);
+  let tx_for_timer: mpsc::Sender<DelayedMessage> = tx.clone();
+  let running_for_timer: Arc<AtomicBool> = Arc::clone(&running);
+  let timer_thread: thread::JoinHandle<()> = thread::spawn(move || {
+    run_timer_thread(state_for_timer, tx_for_timer, running_for_timer)
+  });
+
+  let tx_for_callback: mpsc::Sender<DelayedMessage> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let mut state = lock(&state);
+      for msg in handle_message(&mut state, Instant::now(), message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    (),
+  )?;
+
+  println!("Strum started! {:?}, {:?} apart, gathering for {:?}", direction, spread, window);
+  println!("Ports: 'strum-in:midi-in' (input), 'strum-out:strum-out' (output)");
+  auto_connect(&args, Some("strum-in:midi-in"), Some("strum-out:strum-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, Some((&running, timer_thread)), tx, out_thread);
+
+  Ok(())
+}
+
+fn run_timer_thread(
+  state: Arc<Mutex<StrumState>>,
+  tx: mpsc::Sender<DelayedMessage>,
+  running: Arc<AtomicBool>,
+) {
+  while running.load(Ordering::SeqCst) {
+    let due: Vec<DelayedMessage> = {
+      let mut state = lock(&state);
+      close_chord(&mut state, Instant::now())
+    };
+    for msg in due {
+      if tx.send(msg).is_err() {
+        return;
+      }
+    }
+    thread::sleep(Duration::from_millis(TIMER_CHECK_MS));
+  }
+}
+
+fn handle_message(state: &mut StrumState, now: Instant, message: &[u8]) -> Vec<DelayedMessage> {
+  let key: (u8, u8) = match (get_channel(message), get_note(message)) {
+    (Some(c), Some(n)) if message.len() >= 3 => (c, n),
+    _ => return vec![DelayedMessage { data: message.to_vec(), send_at: now }],
+  };
+  if is_note_on(message) {
+    // A chord whose window has passed goes out before a new one starts.
+    let out: Vec<DelayedMessage> = close_chord(state, now);
+    let chord: &mut Chord = state.chord.get_or_insert_with(
+      || Chord { start: now, notes: vec![], offs: vec![] });
+    // Pressed again while gathering: the earlier press and its release go.
+    let other_key = |m: &Vec<u8>| (get_channel(m), get_note(m)) != (Some(key.0), Some(key.1));
+    chord.notes.retain(other_key);
+    chord.offs.retain(other_key);
+    chord.notes.push(message.to_vec());
+    out
+  } else if is_note_off(message) {
+    if let Some(chord) = state.chord.as_mut().filter(|c| c.notes.iter()
+        .any(|n| (get_channel(n), get_note(n)) == (Some(key.0), Some(key.1)))) {
+      chord.offs.push(message.to_vec());
+      return vec![];
+    }
+    let send_at: Instant = state.scheduled.remove(&key).map_or(now, |on| on.max(now));
+    vec![DelayedMessage { data: message.to_vec(), send_at }]
+  } else {
+    vec![DelayedMessage { data: message.to_vec(), send_at: now }]
+  }
+}
+
+/// If the gathering window has passed, schedules the chord's notes in
+/// pitch order, and any note-offs that were waiting for them.
+fn close_chord(state: &mut StrumState, now: Instant) -> Vec<DelayedMessage> {
+  let due: bool = state.chord.as_ref().is_some_and(|c| now >= c.start + state.window);
+  if !due {
+    return vec![];
+  }
+  let chord: Chord = state.chord.take().unwrap();
+  let mut notes: Vec<Vec<u8>> = chord.notes;
+  notes.sort_by_key(|n| n[1]);
+  if state.direction == Direction::Down {
+    notes.reverse();
+  }
+  let first: Instant = now.max(chord.start + state.window);
+  let mut out: Vec<DelayedMessage> = vec![];
+  for (i, data) in notes.into_iter().enumerate() {
+    let send_at: Instant = first + state.spread * i as u32;
+    state.scheduled.insert((data[0] & 0x0F, data[1]), send_at);
+    out.push(DelayedMessage { data, send_at });
+  }
+  for data in chord.offs {
+    let send_at: Instant =
+      state.scheduled.remove(&(data[0] & 0x0F, data[1])).unwrap_or(first);
+    out.push(DelayedMessage { data, send_at });
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(direction: Direction) -> StrumState {
+    StrumState {
+      spread: Duration::from_millis(20),
+      window: Duration::from_millis(15),
+      direction,
+      chord: None,
+      scheduled: HashMap::new(),
+    }
+  }
+
+  fn ms(t0: Instant, ms: u64) -> Instant {
+    t0 + Duration::from_millis(ms)
+  }
+
+  fn timeline(t0: Instant, msgs: &[DelayedMessage]) -> Vec<(u64, Vec<u8>)> {
+    msgs.iter().map(|m| ((m.send_at - t0).as_millis() as u64, m.data.clone())).collect()
+  }
+
+  #[test]
+  fn chord_is_played_in_pitch_order() {
+    let mut s: StrumState = state(Direction::Up);
+    let t0: Instant = Instant::now();
+    assert!(handle_message(&mut s, t0, &[0x90, 67, 100]).is_empty());
+    assert!(handle_message(&mut s, ms(t0, 3), &[0x90, 60, 90]).is_empty());
+    assert!(handle_message(&mut s, ms(t0, 5), &[0x90, 64, 80]).is_empty());
+    assert!(close_chord(&mut s, ms(t0, 14)).is_empty());
+    assert_eq!(timeline(t0, &close_chord(&mut s, ms(t0, 15))),
+               vec![(15, vec![0x90, 60, 90]), (35, vec![0x90, 64, 80]), (55, vec![0x90, 67, 100])]);
+    // Released before its strum: waits for its note-on.
+    assert_eq!(timeline(t0, &handle_message(&mut s, ms(t0, 20), &[0x80, 67, 0])),
+               vec![(55, vec![0x80, 67, 0])]);
+    assert_eq!(timeline(t0, &handle_message(&mut s, ms(t0, 90), &[0x80, 60, 0])),
+               vec![(90, vec![0x80, 60, 0])]);
+  }
+
+  #[test]
+  fn down_strums_from_the_top() {
+    let mut s: StrumState = state(Direction::Down);
+    let t0: Instant = Instant::now();
+    handle_message(&mut s, t0, &[0x90, 60, 100]);
+    handle_message(&mut s, t0, &[0x90, 64, 100]);
+    handle_message(&mut s, ms(t0, 1), &[0x80, 60, 0]); // still gathering
+    assert_eq!(timeline(t0, &close_chord(&mut s, ms(t0, 15))),
+               vec![(15, vec![0x90, 64, 100]), (35, vec![0x90, 60, 100]),
+                    (35, vec![0x80, 60, 0])]);
+  }
+
+  #[test]
+  fn a_fast_run_is_not_held_back() {
+    let mut s: StrumState = state(Direction::Up);
+    let t0: Instant = Instant::now();
+    handle_message(&mut s, t0, &[0x90, 72, 100]);
+    handle_message(&mut s, ms(t0, 10), &[0x90, 71, 100]);
+    // The window doesn't stretch: 69 starts a new chord.
+    assert_eq!(timeline(t0, &handle_message(&mut s, ms(t0, 20), &[0x90, 69, 100])),
+               vec![(20, vec![0x90, 71, 100]), (40, vec![0x90, 72, 100])]);
+    assert_eq!(timeline(t0, &close_chord(&mut s, ms(t0, 35))), vec![(35, vec![0x90, 69, 100])]);
+    let cc: Vec<DelayedMessage> = handle_message(&mut s, ms(t0, 36), &[0xB0, 64, 127]);
+    assert_eq!(timeline(t0, &cc), vec![(36, vec![0xB0, 64, 127])]);
+  }
+
+  #[test]
+  fn a_key_pressed_again_while_gathering_is_not_cut_off() {
+    let mut s: StrumState = state(Direction::Up);
+    let t0: Instant = Instant::now();
+    handle_message(&mut s, t0, &[0x90, 60, 100]);
+    handle_message(&mut s, ms(t0, 2), &[0x80, 60, 0]);
+    handle_message(&mut s, ms(t0, 4), &[0x90, 60, 90]);
+    handle_message(&mut s, ms(t0, 5), &[0x90, 64, 100]);
+    assert_eq!(timeline(t0, &close_chord(&mut s, ms(t0, 15))),
+               vec![(15, vec![0x90, 60, 90]), (35, vec![0x90, 64, 100])]);
+    assert_eq!(timeline(t0, &handle_message(&mut s, ms(t0, 80), &[0x80, 60, 0])),
+               vec![(80, vec![0x80, 60, 0])]);
+  }
+}
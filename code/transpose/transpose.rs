@@ -0,0 +1,178 @@
+//! Transpose - shifts incoming notes by a number of semitones
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin transpose -- --semitones -12
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "transpose-out".
+//! Non-note messages pass through unchanged.
+//! Notes that would land outside 0-127 are dropped.
+//!
+//! # OFFSET CONTROL
+//! As in edo72, the top octave (notes 97-108, C#7 to C8)
+//! is not played but controls the offset. Pressing one of them sets
+//! the offset to `--semitones` plus that key's distance from F#7 (102):
+//! - F#7 (102) = back to `--semitones`
+//! - G7 (103) = +1, ... C8 (108) = +6
+//! - F7 (101) = -1, ... C#7 (97) = -5
+//!
+//! Notes above C8 are played like any others.
+//! The offset stays until another control key is pressed.
+//! A note is released with the offset it was pressed with,
+//! so changing the offset mid-performance never strands a held note.
+//!
+//! Flags:
+//! - `--semitones <n>`: the starting offset (default 0)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::Args;
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::{get_channel, get_note, is_note_event, is_note_off, is_note_on, note_off, note_on};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+const OFFSET_OCTAVE_START: u8 = 97;  // C#7 - first note of offset control octave (top 12 keys)
+const OFFSET_ZERO_NOTE   : u8 = 102; // F#7 - this note means no change from --semitones
+const OFFSET_OCTAVE_END  : u8 = 108; // C8 - last note of offset control octave
+
+struct TransposeState {
+  base: i8, // from --semitones
+  offset: i8,
+  // (channel, input note) -> output note its note-on was sent as
+  ongoing_notes: HashMap<(u8, u8), u8>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let semitones: i8 = args.parse_or("--semitones", 0)?;
+  if !(-127..=127).contains(&semitones) {
+    return Err("--semitones must be in -127..127".into());
+  }
+
+  let midi_in: MidiInput = MidiInput::new("transpose-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("transpose-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "transpose-out")?;
+  self_test(&args, &mut conn_out, "transpose-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  let state: TransposeState = TransposeState {
+    base: semitones,
+    offset: semitones,
+    ongoing_notes: HashMap::new(),
+  };
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<TransposeState> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], state: &mut TransposeState| {
+      for msg in transform_message(state, message) {
+        let _ = tx_for_callback.send(msg);
+      }
+    },
+    state,
+  )?;
+
+  println!("Transposer started! Offset: {:+} semitones", semitones);
+  println!("  offset control: notes {}-{} (F#7 = {:+})",
+           OFFSET_OCTAVE_START, OFFSET_OCTAVE_END, semitones);
+  println!("Ports: 'transpose-in:midi-in' (input), 'transpose-out:transpose-out' (output)");
+  auto_connect(&args, Some("transpose-in:midi-in"), Some("transpose-out:transpose-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn transform_message(state: &mut TransposeState, message: &[u8]) -> Vec<Vec<u8>> {
+  if !is_note_event(message) || message.len() < 3 {
+    return vec![message.to_vec()];
+  }
+  let (note, channel): (u8, u8) =
+    match (get_note(message), get_channel(message)) {
+      (Some(n), Some(c)) => (n, c),
+      _ => return vec![message.to_vec()],
+    };
+  let velocity: u8 = message[2];
+  if (OFFSET_OCTAVE_START..=OFFSET_OCTAVE_END).contains(&note) {
+    if is_note_on(message) {
+      state.offset = state.base.saturating_add(note as i8 - OFFSET_ZERO_NOTE as i8);
+      println!("[transpose] Offset: {:+} semitones", state.offset);
+    }
+    return vec![]; // don't pass through offset control notes
+  }
+  let mut results: Vec<Vec<u8>> = vec![];
+  if is_note_on(message) {
+    if let Some(old) = state.ongoing_notes.remove(&(channel, note)) {
+      results.push(note_off(channel, old, 0));
+    }
+    let output: i16 = note as i16 + state.offset as i16;
+    if (0..=127).contains(&output) {
+      state.ongoing_notes.insert((channel, note), output as u8);
+      results.push(note_on(channel, output as u8, velocity));
+    }
+  } else if is_note_off(message) {
+    if let Some(old) = state.ongoing_notes.remove(&(channel, note)) {
+      results.push(note_off(channel, old, velocity));
+    }
+  }
+  results
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state(semitones: i8) -> TransposeState {
+    TransposeState { base: semitones, offset: semitones, ongoing_notes: HashMap::new() }
+  }
+
+  #[test]
+  fn offset_change_does_not_strand_held_notes() {
+    let mut s: TransposeState = state(-12);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 100]), vec![vec![0x90, 48, 100]]);
+    assert!(transform_message(&mut s, &[0x90, 105, 100]).is_empty()); // +3
+    assert_eq!(s.offset, -9);
+    assert_eq!(transform_message(&mut s, &[0x80, 60, 0]), vec![vec![0x80, 48, 0]]);
+    assert_eq!(transform_message(&mut s, &[0x90, 60, 100]), vec![vec![0x90, 51, 100]]);
+  }
+
+  #[test]
+  fn out_of_range_notes_are_dropped() {
+    let mut s: TransposeState = state(-12);
+    assert!(transform_message(&mut s, &[0x90, 5, 100]).is_empty());
+    assert!(transform_message(&mut s, &[0x80, 5, 0]).is_empty());
+    assert_eq!(transform_message(&mut s, &[0xE0, 0, 64]), vec![vec![0xE0, 0, 64]]);
+  }
+
+  #[test]
+  fn notes_above_the_control_octave_are_transposed() {
+    let mut s: TransposeState = state(-12);
+    assert_eq!(transform_message(&mut s, &[0x90, 110, 100]), vec![vec![0x90, 98, 100]]);
+    assert_eq!(transform_message(&mut s, &[0x80, 110, 0]), vec![vec![0x80, 98, 0]]);
+    assert_eq!(s.offset, -12);
+  }
+}
@@ -0,0 +1,194 @@
+//! Velocity - reshapes note-on velocities through a curve
+//!
+//! # How to run
+//!
+//! ```sh
+//! cargo run --bin velocity -- --curve gamma --gamma 0.6
+//! ```
+//!
+//! Creates a virtual input "midi-in" and a virtual output "velocity-out".
+//! Only note-on velocities change. Note-offs, velocity-0 note-ons
+//! (which mean note-off) and everything else pass through untouched.
+//!
+//! Flags:
+//! - `--curve linear|gamma|table` (default linear)
+//! - `--gain <g>`: for linear, output = input * g (default 1.0)
+//! - `--gamma <g>`: for gamma, output = 127 * (input / 127)^g (default 1.0).
+//!   Below 1 makes soft playing louder; above 1 makes it softer.
+//! - `--table <in:out,...>`: for table, points joined by straight lines,
+//!   e.g. `1:1,64:90,127:127`. Inputs outside the points use the nearest one.
+//! - `--min <v>`, `--max <v>`: clamp the output (defaults 1 and 127)
+//! - `--input-port`, `--output-port`, `--connect-from`, `--connect-to`,
+//!   `--list-ports`, `--self-test`, `--zero-release-velocity`: see
+//!   `midi_utils::ports`
+//! - `--watchdog`, `--max-note-secs <s>`: see `midi_utils::watchdog`
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_utils::args::{parse_value, Args};
+use midi_utils::logging;
+use midi_utils::ports::{auto_connect, list_ports, open_input, open_output, self_test, silence_after,
+                        wait_for_exit, Exit};
+use midi_utils::sink::ReleaseVelocity;
+use midi_utils::watchdog::{relay, Watchdog};
+use midi_utils::is_note_on;
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Debug, PartialEq)]
+enum Curve {
+  Linear(f64),
+  Gamma(f64),
+  Table(Vec<(u8, u8)>), // sorted by input
+}
+
+struct VelocityConfig {
+  curve: Curve,
+  min: u8,
+  max: u8,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  logging::init();
+  let args: Args = Args::from_env()?;
+  if args.flag("--list-ports") {
+    return list_ports();
+  }
+  let watchdog: Watchdog = Watchdog::from_args(&args)?;
+  let config: VelocityConfig = parse_config(&args)?;
+
+  let midi_in: MidiInput = MidiInput::new("velocity-in")?;
+  let midi_out: MidiOutput = MidiOutput::new("velocity-out")?;
+  let mut conn_out: MidiOutputConnection =
+    open_output(midi_out, args.value("--output-port"), "velocity-out")?;
+  self_test(&args, &mut conn_out, "velocity-out");
+  let (tx, rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
+  let conn_out: ReleaseVelocity<MidiOutputConnection> =
+    ReleaseVelocity { sink: conn_out, zero: args.flag("--zero-release-velocity") };
+  let out_thread: thread::JoinHandle<()> =
+    thread::spawn(move || relay(conn_out, rx, watchdog));
+
+  println!("Velocity curve started! {:?}, output clamped to {}-{}",
+           config.curve, config.min, config.max);
+  let tx_for_callback: mpsc::Sender<Vec<u8>> = tx.clone();
+  let conn_in: MidiInputConnection<()> = open_input(
+    midi_in,
+    args.value("--input-port"),
+    "midi-in",
+    move |_timestamp: u64, message: &[u8], _: &mut ()| {
+      let _ = tx_for_callback.send(transform_message(&config, message));
+    },
+    (),
+  )?;
+
+  println!("Ports: 'velocity-in:midi-in' (input), 'velocity-out:velocity-out' (output)");
+
+  auto_connect(&args, Some("velocity-in:midi-in"), Some("velocity-out:velocity-out"));
+  println!("Press Enter to exit...");
+
+  let exit: Exit = wait_for_exit(args.value("--input-port"))?;
+  silence_after(exit, conn_in, None, tx, out_thread);
+
+  Ok(())
+}
+
+fn parse_config(args: &Args) -> Result<VelocityConfig, String> {
+  let curve: Curve = match args.value("--curve").unwrap_or("linear") {
+    "linear" => Curve::Linear(args.parse_or("--gain", 1.0)?),
+    "gamma" => {
+      let gamma: f64 = args.parse_or("--gamma", 1.0)?;
+      if gamma <= 0.0 {
+        return Err("--gamma must be positive".to_string());
+      }
+      Curve::Gamma(gamma)
+    }
+    "table" => Curve::Table(parse_table(args.value("--table")
+                                        .ok_or("--curve table needs --table")?)?),
+    other => return Err(format!("unknown --curve '{}'", other)),
+  };
+  let min: u8 = args.parse_or("--min", 1)?;
+  let max: u8 = args.parse_or("--max", 127)?;
+  if min < 1 || max > 127 || min > max {
+    return Err("need 1 <= --min <= --max <= 127".to_string());
+  }
+  Ok(VelocityConfig { curve, min, max })
+}
+
+fn parse_table(text: &str) -> Result<Vec<(u8, u8)>, String> {
+  let mut points: Vec<(u8, u8)> = text.split(',')
+    .map(|point| match point.split_once(':') {
+      Some((i, o)) => Ok((parse_value::<u8>("--table", i)?,
+                          parse_value::<u8>("--table", o)?)),
+      None => Err(format!("bad --table point '{}', expected in:out", point)),
+    })
+    .collect::<Result<Vec<(u8, u8)>, String>>()?;
+  points.sort();
+  Ok(points)
+}
+
+fn apply_curve(config: &VelocityConfig, velocity: u8) -> u8 {
+  let x: f64 = velocity as f64;
+  let y: f64 = match &config.curve {
+    Curve::Linear(gain) => x * gain,
+    Curve::Gamma(gamma) => 127.0 * (x / 127.0).powf(*gamma),
+    Curve::Table(points) => interpolate(points, x),
+  };
+  (y.round().clamp(0.0, 127.0) as u8).clamp(config.min, config.max)
+}
+
+fn interpolate(points: &[(u8, u8)], x: f64) -> f64 {
+  let (first, last): ((u8, u8), (u8, u8)) = (points[0], points[points.len() - 1]);
+  if x <= first.0 as f64 {
+    return first.1 as f64;
+  }
+  for pair in points.windows(2) {
+    let ((x0, y0), (x1, y1)): ((f64, f64), (f64, f64)) =
+      ((pair[0].0 as f64, pair[0].1 as f64), (pair[1].0 as f64, pair[1].1 as f64));
+    if x <= x1 {
+      return if x1 == x0 { y1 } else { y0 + (y1 - y0) * (x - x0) / (x1 - x0) };
+    }
+  }
+  last.1 as f64
+}
+
+fn transform_message(config: &VelocityConfig, message: &[u8]) -> Vec<u8> {
+  let mut out: Vec<u8> = message.to_vec();
+  if is_note_on(message) {
+    out[2] = apply_curve(config, message[2]);
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(curve: Curve) -> VelocityConfig {
+    VelocityConfig { curve, min: 1, max: 127 }
+  }
+
+  #[test]
+  fn curves() {
+    assert_eq!(apply_curve(&config(Curve::Linear(2.0)), 40), 80);
+    assert_eq!(apply_curve(&config(Curve::Linear(2.0)), 100), 127);
+    assert_eq!(apply_curve(&config(Curve::Linear(0.001)), 100), 1);
+    assert_eq!(apply_curve(&config(Curve::Gamma(0.5)), 127), 127);
+    assert_eq!(apply_curve(&config(Curve::Gamma(0.5)), 32), 64);
+    let table: Curve = Curve::Table(parse_table("1:1,64:96,127:127").unwrap());
+    assert_eq!(apply_curve(&config(table), 32), 48);
+  }
+
+  #[test]
+  fn clamps_to_min_and_max() {
+    let c: VelocityConfig = VelocityConfig { curve: Curve::Linear(1.0), min: 20, max: 100 };
+    assert_eq!(apply_curve(&c, 5), 20);
+    assert_eq!(apply_curve(&c, 120), 100);
+  }
+
+  #[test]
+  fn note_offs_are_untouched() {
+    let c: VelocityConfig = config(Curve::Linear(2.0));
+    assert_eq!(transform_message(&c, &[0x90, 60, 0]), vec![0x90, 60, 0]);
+    assert_eq!(transform_message(&c, &[0x80, 60, 30]), vec![0x80, 60, 30]);
+    assert_eq!(transform_message(&c, &[0x90, 60, 30]), vec![0x90, 60, 60]);
+  }
+}